@@ -0,0 +1,460 @@
+use crate::errors::EditorError;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+use std::fmt;
+
+// Long-lived tokens for scripts and integrations using the JSON API, sent as
+// `Authorization: Bearer fet_...` instead of a session id. A token acts as the
+// user who created it, narrowed by its scopes: which records it may touch,
+// whether it may change them, and whether it may read the audit log. Scopes
+// are checked by middleware in front of the API routes, before any handler
+// runs; routes the scopes say nothing about refuse tokens altogether.
+
+// What tokens start with, to tell them apart from session ids
+pub const TOKEN_PREFIX: &str = "fet_";
+
+// What a token may do, written as space-separated words: `write` to change
+// records as well as read them, `history` to read the audit log and
+// `record:<id>` (repeatable) to limit it to those records. A token without
+// `write` is read-only; `read` may be given for clarity but changes nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenScopes {
+    // The records the token may touch; all of the tenant's if None
+    pub records: Option<Vec<i64>>,
+    pub write: bool,
+    pub history: bool,
+}
+
+// What an API request needs its token's scopes to cover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiAccess {
+    Read(i64),
+    Write(i64),
+    // Changes to several records at once; each is checked against the scopes
+    // once the handler knows which they are
+    WriteMany,
+    // The whole audit log, across records
+    History,
+}
+
+impl TokenScopes {
+    pub fn parse(scopes: &str) -> Result<Self, String> {
+        let mut parsed = TokenScopes::default();
+        for word in scopes.split_whitespace() {
+            match word {
+                "read" => {}
+                "write" => parsed.write = true,
+                "history" => parsed.history = true,
+                _ => {
+                    let id = word
+                        .strip_prefix("record:")
+                        .and_then(|id| id.parse().ok())
+                        .ok_or_else(|| format!("Unknown scope {:?}", word))?;
+                    let records = parsed.records.get_or_insert_with(Vec::new);
+                    if !records.contains(&id) {
+                        records.push(id);
+                    }
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn covers_record(&self, id: i64) -> bool {
+        self.records.as_ref().is_none_or(|records| records.contains(&id))
+    }
+
+    // Whether a request needing `access` is within these scopes. The audit
+    // log can't be filtered by record, so a token limited to some records
+    // can't read it.
+    pub fn permits(&self, access: ApiAccess) -> bool {
+        match access {
+            ApiAccess::Read(id) => self.covers_record(id),
+            ApiAccess::Write(id) => self.write && self.covers_record(id),
+            ApiAccess::WriteMany => self.write,
+            ApiAccess::History => self.history && self.records.is_none(),
+        }
+    }
+}
+
+impl fmt::Display for TokenScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = vec![if self.write { "write".to_string() } else { "read".to_string() }];
+        if self.history {
+            words.push("history".to_string());
+        }
+        for id in self.records.iter().flatten() {
+            words.push(format!("record:{}", id));
+        }
+        f.write_str(&words.join(" "))
+    }
+}
+
+// A token as its owner sees it in the list; the secret is shown only once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: TokenScopes,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+// A token just created, with the secret to hand to the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NewApiToken {
+    pub info: ApiTokenInfo,
+    pub token: String,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ApiAccess, ApiTokenInfo, NewApiToken, TokenScopes, TOKEN_PREFIX};
+    use crate::db::DbManager;
+    use crate::rest::error;
+    use crate::tenant::{resolve_tenant, TenantId};
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{ServiceRequest, ServiceResponse};
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::middleware::Next;
+    use actix_web::{web, HttpMessage};
+    use sha2::{Digest, Sha256};
+
+    // Tokens are stored hashed, so a copy of the database doesn't hand them out
+    fn token_hash(token: &str) -> String {
+        Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // A token that checked out, for the handlers behind the middleware
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TokenGrant {
+        pub token_id: String,
+        pub user: String,
+        pub tenant: TenantId,
+        pub scopes: TokenScopes,
+    }
+
+    impl DbManager {
+        // A new token for `user` with this manager's tenant
+        pub async fn create_api_token(
+            &self,
+            user: &str,
+            name: &str,
+            scopes: &TokenScopes,
+        ) -> Result<NewApiToken, sqlx::Error> {
+            let mut secret = [0u8; 32];
+            getrandom::getrandom(&mut secret).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+            let token = format!(
+                "{}{}",
+                TOKEN_PREFIX,
+                secret.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+            );
+            let info = ApiTokenInfo {
+                id: self.new_id(),
+                name: name.to_string(),
+                scopes: scopes.clone(),
+                created_at: self.now(),
+                last_used_at: None,
+            };
+            sqlx::query(
+                r#"
+                INSERT INTO api_tokens (id, token_hash, tenant_id, user_name, name, scopes, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&info.id)
+            .bind(token_hash(&token))
+            .bind(self.tenant().as_str())
+            .bind(user)
+            .bind(name)
+            .bind(scopes.to_string())
+            .bind(info.created_at)
+            .execute(self.pool())
+            .await?;
+            Ok(NewApiToken { info, token })
+        }
+
+        // A user's tokens that haven't been revoked, newest first
+        pub async fn api_tokens(&self, user: &str) -> Result<Vec<ApiTokenInfo>, sqlx::Error> {
+            let rows: Vec<(String, String, String, i64, Option<i64>)> = sqlx::query_as(
+                r#"
+                SELECT id, name, scopes, created_at, last_used_at FROM api_tokens
+                WHERE tenant_id = ? AND user_name = ? AND revoked_at IS NULL
+                ORDER BY created_at DESC
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .bind(user)
+            .fetch_all(self.pool())
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(id, name, scopes, created_at, last_used_at)| ApiTokenInfo {
+                    id,
+                    name,
+                    scopes: TokenScopes::parse(&scopes).unwrap_or_default(),
+                    created_at,
+                    last_used_at,
+                })
+                .collect())
+        }
+
+        // Revoke one of a user's tokens. Returns false if they have no such token.
+        pub async fn revoke_api_token(&self, user: &str, id: &str) -> Result<bool, sqlx::Error> {
+            let revoked = sqlx::query(
+                r#"
+                UPDATE api_tokens SET revoked_at = ?
+                WHERE id = ? AND tenant_id = ? AND user_name = ? AND revoked_at IS NULL
+                "#,
+            )
+            .bind(self.now())
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .bind(user)
+            .execute(self.pool())
+            .await?;
+            Ok(revoked.rows_affected() > 0)
+        }
+
+        // The grant of a live token, whatever tenant this manager is for
+        pub async fn api_token_grant(&self, token: &str) -> Result<Option<TokenGrant>, sqlx::Error> {
+            let row: Option<(String, String, String, String)> = sqlx::query_as(
+                r#"
+                UPDATE api_tokens SET last_used_at = ?
+                WHERE token_hash = ? AND revoked_at IS NULL
+                RETURNING id, user_name, tenant_id, scopes
+                "#,
+            )
+            .bind(self.now())
+            .bind(token_hash(token))
+            .fetch_optional(self.pool())
+            .await?;
+            Ok(row.and_then(|(token_id, user, tenant, scopes)| {
+                Some(TokenGrant {
+                    token_id,
+                    user,
+                    tenant: TenantId::parse(&tenant)?,
+                    scopes: TokenScopes::parse(&scopes).ok()?,
+                })
+            }))
+        }
+    }
+
+    // The access an API route needs from a token; None for routes tokens
+    // can't be used with
+    pub fn required_access(method: &Method, path: &str) -> Option<ApiAccess> {
+        if let Some(id) = path.strip_prefix("/api/fields/").and_then(|id| id.parse().ok()) {
+            return match *method {
+                Method::GET | Method::HEAD => Some(ApiAccess::Read(id)),
+                Method::PUT | Method::PATCH => Some(ApiAccess::Write(id)),
+                _ => None,
+            };
+        }
+        match (method, path) {
+            (&Method::GET, "/api/history/export.jsonl") => Some(ApiAccess::History),
+            (&Method::POST, "/api/fields/batch") => Some(ApiAccess::WriteMany),
+            _ => None,
+        }
+    }
+
+    // Check requests carrying an API token against its scopes, and leave the
+    // grant in the request extensions for the handler. Requests without one
+    // pass through untouched.
+    pub async fn api_token_scopes(
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| token.starts_with(TOKEN_PREFIX))
+            .map(str::to_string);
+        let Some(token) = token else {
+            return next.call(req).await.map(ServiceResponse::map_into_left_body);
+        };
+        let refuse = |req: ServiceRequest, status: StatusCode, message: &str| {
+            Ok(req.into_response(error(status, message)).map_into_right_body())
+        };
+
+        let db = req
+            .app_data::<web::Data<DbManager>>()
+            .expect("DbManager is registered as app data")
+            .clone();
+        let grant = match db.api_token_grant(&token).await {
+            Ok(Some(grant)) => grant,
+            Ok(None) => return refuse(req, StatusCode::UNAUTHORIZED, "Unknown or revoked API token"),
+            Err(e) => {
+                tracing::error!(error = %e, "Could not look up API token");
+                return refuse(req, StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+            }
+        };
+        let Some(access) = required_access(req.method(), req.path()) else {
+            return refuse(req, StatusCode::FORBIDDEN, "API tokens can't be used here");
+        };
+        if !grant.scopes.permits(access) {
+            tracing::info!(token = %grant.token_id, user = %grant.user, ?access, "Denied by API token scopes");
+            return refuse(req, StatusCode::FORBIDDEN, "The API token's scopes don't allow this");
+        }
+        let tenant = grant.tenant.clone();
+        req.extensions_mut().insert(grant);
+        if resolve_tenant(&db, req.request()).await.ok() != Some(tenant) {
+            return refuse(req, StatusCode::FORBIDDEN, "This API token is for another tenant");
+        }
+        next.call(req).await.map(ServiceResponse::map_into_left_body)
+    }
+}
+
+// Create an API token acting as the signed-in user, with scopes as
+// `TokenScopes::parse` reads them
+#[server(CreateApiToken)]
+pub async fn create_api_token(
+    name: String,
+    scopes: String,
+    csrf_token: String,
+) -> Result<NewApiToken, ServerFnError<EditorError>> {
+    use crate::auth::{require_csrf, require_user};
+    use crate::field_editor::{db_error, open_db};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = require_user(&db).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    // A token would outlive the impersonation and act as the user unlogged
+    if user.impersonated_by.is_some() {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(
+            "API tokens can't be created while acting as someone else".to_string(),
+        )));
+    }
+    let scopes = TokenScopes::parse(&scopes).map_err(|e| ServerFnError::WrappedServerError(EditorError::Other(e)))?;
+    Ok(db
+        .create_api_token(&user.name, name.trim(), &scopes)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+// The signed-in user's API tokens
+#[server(ListApiTokens)]
+pub async fn list_api_tokens() -> Result<Vec<ApiTokenInfo>, ServerFnError> {
+    use crate::auth::require_user;
+    use crate::field_editor::{db_error, open_db};
+
+    let db = open_db().await?;
+    let user = require_user(&db).await?;
+    db.api_tokens(&user.name).await.map_err(db_error)
+}
+
+#[server(RevokeApiToken)]
+pub async fn revoke_api_token(id: String, csrf_token: String) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{require_csrf, require_user};
+    use crate::field_editor::{db_error, open_db};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = require_user(&db).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .revoke_api_token(&user.name, &id)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scopes(words: &str) -> TokenScopes {
+        TokenScopes::parse(words).expect("valid scopes")
+    }
+
+    #[test]
+    fn parses_scopes() {
+        assert_eq!(scopes(""), TokenScopes::default());
+        assert_eq!(scopes("read"), TokenScopes::default());
+        assert_eq!(
+            scopes("write history record:7 record:3 record:7"),
+            TokenScopes {
+                records: Some(vec![7, 3]),
+                write: true,
+                history: true,
+            }
+        );
+        assert!(TokenScopes::parse("admin").is_err());
+        assert!(TokenScopes::parse("record:").is_err());
+        assert!(TokenScopes::parse("record:seven").is_err());
+    }
+
+    #[test]
+    fn scopes_round_trip_through_display() {
+        for words in ["read", "write", "read history", "write history record:1 record:2"] {
+            assert_eq!(scopes(words).to_string(), words);
+            assert_eq!(scopes(&scopes(words).to_string()), scopes(words));
+        }
+    }
+
+    #[test]
+    fn read_only_tokens_only_read() {
+        let read = scopes("read");
+        assert!(read.permits(ApiAccess::Read(1)));
+        assert!(!read.permits(ApiAccess::Write(1)));
+        assert!(!read.permits(ApiAccess::WriteMany));
+        assert!(!read.permits(ApiAccess::History));
+    }
+
+    #[test]
+    fn write_tokens_read_and_write_any_record() {
+        let write = scopes("write");
+        assert!(write.permits(ApiAccess::Read(1)));
+        assert!(write.permits(ApiAccess::Write(1)));
+        assert!(write.permits(ApiAccess::WriteMany));
+        assert!(!write.permits(ApiAccess::History));
+    }
+
+    #[test]
+    fn record_scopes_limit_reads_and_writes() {
+        let limited = scopes("write record:1");
+        assert!(limited.permits(ApiAccess::Read(1)));
+        assert!(limited.permits(ApiAccess::Write(1)));
+        assert!(!limited.permits(ApiAccess::Read(2)));
+        assert!(!limited.permits(ApiAccess::Write(2)));
+        // The batch route checks each record once it knows them
+        assert!(limited.permits(ApiAccess::WriteMany));
+        assert!(!scopes("read record:1").permits(ApiAccess::Write(1)));
+    }
+
+    #[test]
+    fn history_needs_an_unlimited_token() {
+        assert!(scopes("history").permits(ApiAccess::History));
+        assert!(scopes("write history").permits(ApiAccess::History));
+        assert!(!scopes("history record:1").permits(ApiAccess::History));
+    }
+
+    #[cfg(feature = "ssr")]
+    #[test]
+    fn routes_need_the_matching_access() {
+        use actix_web::http::Method;
+
+        assert_eq!(required_access(&Method::GET, "/api/fields/4"), Some(ApiAccess::Read(4)));
+        assert_eq!(required_access(&Method::HEAD, "/api/fields/4"), Some(ApiAccess::Read(4)));
+        assert_eq!(required_access(&Method::PUT, "/api/fields/4"), Some(ApiAccess::Write(4)));
+        assert_eq!(required_access(&Method::PATCH, "/api/fields/4"), Some(ApiAccess::Write(4)));
+        assert_eq!(required_access(&Method::DELETE, "/api/fields/4"), None);
+        assert_eq!(required_access(&Method::POST, "/api/fields/batch"), Some(ApiAccess::WriteMany));
+        assert_eq!(required_access(&Method::GET, "/api/fields/batch"), None);
+        assert_eq!(
+            required_access(&Method::GET, "/api/history/export.jsonl"),
+            Some(ApiAccess::History)
+        );
+        assert_eq!(required_access(&Method::GET, "/api/attachments/4"), None);
+        assert_eq!(required_access(&Method::GET, "/api/fields/4/print"), None);
+    }
+}
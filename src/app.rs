@@ -1,15 +1,39 @@
 use leptos::prelude::*;
-use leptos_meta::{provide_meta_context, Stylesheet, Title};
+use leptos_meta::{provide_meta_context, Meta, Stylesheet, Title};
 use leptos_router::{
-    components::{Route, Router, Routes},
-    StaticSegment, WildcardSegment,
+    components::{Outlet, ParentRoute, Route, Router, Routes, A},
+    hooks::{use_params_map, use_query_map},
+    path, StaticSegment, WildcardSegment,
 };
+use crate::attachments::Attachments;
+use crate::auth::{UserMenu, UserSession};
+use crate::change_requests::ChangeRequestQueue;
+use crate::dashboard::Dashboard;
+use crate::demo::DemoKnobs;
 use crate::field_editor::FieldEditor;
+use crate::field_schema::FieldSchemaEditor;
+use crate::history::RecordVersions;
+use crate::impersonation::{Impersonation, ImpersonationBanner};
+use crate::inbox::{Inbox, InboxLink};
+use crate::protocol::{ProtocolStatus, ReloadBanner, BUILD_ID};
+use crate::record_list::RecordList;
+use crate::reports::Reports;
+use crate::store::FieldEditorStore;
+use crate::toast::{Toaster, Toasts};
+use crate::trash::Trash;
 
 #[component]
 pub fn App() -> impl IntoView {
     // Provides context that manages stylesheets, titles, meta tags, etc.
     provide_meta_context();
+    // Makes the signed-in user available to every component
+    UserSession::provide();
+    // Records, presence and notifications shared by all views
+    FieldEditorStore::provide();
+    // Notices when a deploy leaves this page talking to an incompatible server
+    ProtocolStatus::provide();
+    // Short messages about saves and other things the user just did
+    Toasts::provide();
 
     view! {
         // injects a stylesheet into the document <head>
@@ -18,25 +42,197 @@ pub fn App() -> impl IntoView {
 
         // sets the document title
         <Title text="Field Editor - Collaborative Editing"/>
+        // The build that rendered this page
+        <Meta name="field-editor-build" content=BUILD_ID/>
 
         // content for this welcome page
         <Router>
+            <nav class="top-nav">
+                <A href="/">"Editor"</A>
+                <A href="/records">"Records"</A>
+                <A href="/trash">"Trash"</A>
+                <A href="/approvals">"Approvals"</A>
+                <A href="/dashboard">"Dashboard"</A>
+                <A href="/fields">"Fields"</A>
+                <A href="/admin">"Admin"</A>
+                <InboxLink/>
+                <UserMenu/>
+            </nav>
+            <ReloadBanner/>
+            <ImpersonationBanner/>
             <main>
                 <Routes fallback=move || "Not found.">
                     <Route path=StaticSegment("") view=HomePage/>
+                    <ParentRoute path=StaticSegment("records") view=RecordsPage>
+                        <Route path=path!(":id") view=EditRecordPage/>
+                        <Route path=StaticSegment("") view=|| ()/>
+                    </ParentRoute>
+                    <Route path=StaticSegment("trash") view=TrashPage/>
+                    <Route path=StaticSegment("approvals") view=ApprovalsPage/>
+                    <Route path=StaticSegment("dashboard") view=DashboardPage/>
+                    <Route path=StaticSegment("fields") view=FieldsPage/>
+                    <Route path=StaticSegment("admin") view=AdminPage/>
+                    <Route path=StaticSegment("inbox") view=InboxPage/>
+                    <Route path=StaticSegment("embed") view=EmbedPage/>
                     <Route path=WildcardSegment("any") view=NotFound/>
                 </Routes>
             </main>
+            <Toaster/>
         </Router>
     }
 }
 
+// Demo knobs from the page URL, e.g. `?latency=2000&conflict_rate=0.5`
+fn use_demo_knobs() -> impl Fn() -> Option<DemoKnobs> + Copy {
+    let query = use_query_map();
+    move || query.with(|q| DemoKnobs::from_query(q.get("latency"), q.get("conflict_rate")))
+}
+
 /// Renders the home page of your application.
 #[component]
 fn HomePage() -> impl IntoView {
+    let demo = use_demo_knobs();
+
+    view! {
+        <div class="container">
+            {move || {
+                let demo = demo();
+                view! { <FieldEditor demo=demo/> }
+            }}
+        </div>
+    }
+}
+
+/// Renders the list of records and who is editing them, next to the selected record.
+#[component]
+fn RecordsPage() -> impl IntoView {
+    view! {
+        <div class="container records-page">
+            <RecordList/>
+            <Outlet/>
+        </div>
+    }
+}
+
+/// Renders the editor for the record named in the URL.
+#[component]
+fn EditRecordPage() -> impl IntoView {
+    let params = use_params_map();
+    let id = move || params.with(|p| p.get("id").and_then(|id| id.parse::<i64>().ok()));
+    let demo = use_demo_knobs();
+
+    // Each record gets an editor of its own: switching records drops the old
+    // one along with whatever it was still loading, so a slow response for the
+    // previous record can't land in the next one's form
+    view! {
+        {move || match id() {
+            Some(id) => {
+                let demo = demo();
+                view! {
+                    <FieldEditor id=id demo=demo/>
+                    <a class="print-link" href=format!("/records/{}/print", id) target="_blank" rel="external">
+                        "Print view"
+                    </a>
+                    <Attachments record_id=id/>
+                    <RecordVersions record_id=id/>
+                }
+                .into_any()
+            }
+            None => view! { <h1>"Not Found"</h1> }.into_any(),
+        }}
+    }
+}
+
+// Records shown side by side on the embed page when the URL doesn't name any
+const EMBED_DEFAULT_IDS: [i64; 3] = [1, 2, 3];
+
+/// Renders several editors on one page, for checking that embedded editors
+/// stay independent. Takes the record ids from the URL, e.g. `/embed?ids=1,4,7`.
+#[component]
+fn EmbedPage() -> impl IntoView {
+    let query = use_query_map();
+    let ids = move || {
+        let ids: Vec<i64> = query.with(|q| {
+            q.get("ids")
+                .map(|ids| ids.split(',').filter_map(|id| id.trim().parse().ok()).collect())
+                .unwrap_or_default()
+        });
+        if ids.is_empty() {
+            EMBED_DEFAULT_IDS.to_vec()
+        } else {
+            ids
+        }
+    };
+    let demo = use_demo_knobs();
+
+    view! {
+        <div class="container embed-page">
+            {move || {
+                let demo = demo();
+                ids().into_iter().map(|id| view! { <FieldEditor id=id demo=demo/> }).collect_view()
+            }}
+        </div>
+    }
+}
+
+/// Renders the trash with soft-deleted records.
+#[component]
+fn TrashPage() -> impl IntoView {
+    view! {
+        <div class="container">
+            <Trash/>
+        </div>
+    }
+}
+
+/// Renders the change requests waiting for approval.
+#[component]
+fn ApprovalsPage() -> impl IntoView {
+    view! {
+        <div class="container">
+            <ChangeRequestQueue/>
+        </div>
+    }
+}
+
+/// Renders saves and conflicts over time.
+#[component]
+fn DashboardPage() -> impl IntoView {
+    view! {
+        <div class="container">
+            <Dashboard/>
+        </div>
+    }
+}
+
+/// Renders the fields records have, for changing them.
+#[component]
+fn FieldsPage() -> impl IntoView {
+    view! {
+        <div class="container">
+            <FieldSchemaEditor/>
+        </div>
+    }
+}
+
+/// Renders the admin panel.
+#[component]
+fn AdminPage() -> impl IntoView {
+    view! {
+        <div class="container">
+            <h1>"Admin"</h1>
+            <Reports/>
+            <Impersonation/>
+        </div>
+    }
+}
+
+/// Renders the notifications inbox.
+#[component]
+fn InboxPage() -> impl IntoView {
     view! {
         <div class="container">
-            <FieldEditor/>
+            <Inbox/>
         </div>
     }
 }
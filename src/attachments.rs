@@ -0,0 +1,729 @@
+use crate::auth::use_user_session;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Files attached to a record. Uploads and downloads go through plain HTTP
+// routes so the contents can be streamed; the bytes live in the configured
+// `BlobStore` under the attachment's id, and only the metadata is in the database.
+// Attaching or replacing a file makes a new version of the record, and every
+// attachment is linked to the record versions that had it, so going back to an
+// earlier version brings back the files as they were then too.
+
+// Largest file accepted for upload
+pub const MAX_ATTACHMENT_BYTES: u64 = 25 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct Attachment {
+    pub id: String,
+    pub record_id: i64,
+    pub filename: String,
+    // Worked out from the contents, not taken from the uploader
+    pub content_type: String,
+    pub size: i64,
+    pub uploaded_by: String,
+    pub uploaded_at: i64,
+    // In pixels, for images whose header could be read
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    // Replacing a file adds a new attachment in the same slot, the id of the
+    // first version, with the next slot version
+    pub slot: String,
+    pub slot_version: i64,
+}
+
+impl Attachment {
+    pub fn download_url(&self) -> String {
+        format!("/api/attachments/{}", self.id)
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+
+    // A copy scaled down to fit in a `size` by `size` square; see `THUMBNAIL_SIZES`
+    pub fn thumbnail_url(&self, size: u32) -> String {
+        format!("/api/attachments/{}/thumbnail?size={}", self.id, size)
+    }
+}
+
+// Thumbnail size shown for image attachments in the editor
+const PREVIEW_SIZE: u32 = 200;
+
+// A byte count the way people read them
+pub fn format_size(bytes: i64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub use server::{download_attachment, upload_attachment};
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{Attachment, MAX_ATTACHMENT_BYTES};
+    use crate::auth::SESSION_COOKIE;
+    use crate::blobs::BlobStore;
+    use crate::change_requests::needs_approval;
+    use crate::db::{append_history, DbManager};
+    use crate::events::FieldEvent;
+    use crate::policy::{allowed, denial, Action, ResourceKind};
+    use crate::rate_limit::RateLimiter;
+    use crate::rest::{authorized_user, error, internal_error, session_id, too_many_requests};
+    use crate::scan::{quarantine, ScanVerdict, VirusScanner};
+    use crate::tenant::TenantDb;
+    use crate::thumbnails::image_dimensions;
+    use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+    use actix_web::http::StatusCode;
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use std::path::Path;
+    use tokio::io::AsyncWriteExt;
+
+    // How much of the start of a file content sniffing looks at
+    const SNIFF_LEN: usize = 512;
+
+    // Content types recognized by the bytes a file starts with
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+
+    // The content type of a file from its first bytes. Anything unrecognized
+    // that isn't plain text is served as an opaque download.
+    pub(crate) fn sniff_content_type(head: &[u8]) -> &'static str {
+        if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+            return content_type;
+        }
+        if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+            return "image/webp";
+        }
+        let text = match std::str::from_utf8(head) {
+            Ok(_) => true,
+            // A multi-byte character cut off at the end of the sniffed part
+            Err(e) => e.error_len().is_none(),
+        };
+        if text && !head.contains(&0) {
+            "text/plain; charset=utf-8"
+        } else {
+            "application/octet-stream"
+        }
+    }
+
+    // Only the last path component, without control characters, and not too long
+    fn clean_filename(name: &str) -> String {
+        let name: String = name
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(255)
+            .collect();
+        match name.trim() {
+            "" => "attachment".to_string(),
+            name => name.to_string(),
+        }
+    }
+
+    impl DbManager {
+        // Attach a stored file to its record as a new record version, in place of
+        // the attachment `replaces` if given. Returns the attachment as saved, or
+        // None if the record is gone or moved past `expected_version`, or
+        // `replaces` isn't one of its attachments any more.
+        #[tracing::instrument(skip_all, fields(record_id = attachment.record_id, attachment = %attachment.id, expected_version))]
+        pub async fn add_attachment(
+            &self,
+            attachment: &Attachment,
+            replaces: Option<&str>,
+            expected_version: i64,
+        ) -> Result<Option<Attachment>, sqlx::Error> {
+            let added = self
+                .retrying("add_attachment", || self.add_attachment_in_tx(attachment, replaces, expected_version))
+                .await?;
+            if added.is_some() {
+                self.forget_cached(attachment.record_id).await;
+            }
+            Ok(added)
+        }
+
+        async fn add_attachment_in_tx(
+            &self,
+            attachment: &Attachment,
+            replaces: Option<&str>,
+            expected_version: i64,
+        ) -> Result<Option<Attachment>, sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            let Some(record) = self.scope(&mut tx, attachment.record_id).await? else {
+                return Ok(None);
+            };
+            let mut attachment = attachment.clone();
+            if let Some(replaced) = replaces {
+                let slot: Option<(String, i64)> = sqlx::query_as(
+                    r#"
+                    SELECT a.slot, a.slot_version FROM attachments a
+                    JOIN attachment_links l ON l.attachment_id = a.id
+                    WHERE a.id = ? AND l.record_id = ? AND l.to_version IS NULL
+                    "#,
+                )
+                .bind(replaced)
+                .bind(attachment.record_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+                let Some((slot, slot_version)) = slot else {
+                    return Ok(None);
+                };
+                attachment.slot = slot;
+                attachment.slot_version = slot_version + 1;
+            }
+
+            let version: Option<i64> = sqlx::query_scalar(
+                r#"
+                UPDATE fields SET version = version + 1, updated_by = ?, updated_at = ?
+                WHERE id = ? AND version = ? AND deleted_at IS NULL
+                RETURNING version
+                "#,
+            )
+            .bind(&attachment.uploaded_by)
+            .bind(attachment.uploaded_at)
+            .bind(attachment.record_id)
+            .bind(expected_version)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(version) = version else {
+                return Ok(None);
+            };
+
+            sqlx::query(
+                r#"
+                INSERT INTO attachments
+                    (id, record_id, filename, content_type, size, uploaded_by, uploaded_at, width, height, slot, slot_version)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&attachment.id)
+            .bind(attachment.record_id)
+            .bind(&attachment.filename)
+            .bind(&attachment.content_type)
+            .bind(attachment.size)
+            .bind(&attachment.uploaded_by)
+            .bind(attachment.uploaded_at)
+            .bind(attachment.width)
+            .bind(attachment.height)
+            .bind(&attachment.slot)
+            .bind(attachment.slot_version)
+            .execute(&mut *tx)
+            .await?;
+            if let Some(replaced) = replaces {
+                sqlx::query("UPDATE attachment_links SET to_version = ? WHERE attachment_id = ? AND to_version IS NULL")
+                    .bind(version)
+                    .bind(replaced)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("INSERT INTO attachment_links (record_id, attachment_id, from_version) VALUES (?, ?, ?)")
+                .bind(attachment.record_id)
+                .bind(&attachment.id)
+                .bind(version)
+                .execute(&mut *tx)
+                .await?;
+
+            let event = match replaces {
+                Some(replaced) => FieldEvent::AttachmentReplaced {
+                    attachment_id: attachment.id.clone(),
+                    replaced: replaced.to_string(),
+                },
+                None => FieldEvent::AttachmentAdded {
+                    attachment_id: attachment.id.clone(),
+                },
+            };
+            append_history(&mut tx, record, &event, None, attachment.uploaded_at).await?;
+            tx.commit().await?;
+            Ok(Some(attachment))
+        }
+
+        pub async fn attachment(&self, id: &str) -> Result<Option<Attachment>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT a.* FROM attachments a
+                JOIN fields f ON f.id = a.record_id
+                WHERE a.id = ? AND f.tenant_id = ?
+                "#,
+            )
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(self.pool())
+            .await
+        }
+
+        // A record's attachments, oldest first
+        pub async fn list_attachments(&self, record_id: i64) -> Result<Vec<Attachment>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT a.* FROM attachments a
+                JOIN attachment_links l ON l.attachment_id = a.id
+                JOIN fields f ON f.id = l.record_id
+                WHERE l.record_id = ? AND f.tenant_id = ? AND l.to_version IS NULL
+                ORDER BY l.from_version, a.id
+                "#,
+            )
+            .bind(record_id)
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+
+        // The attachments a record had at one of its versions
+        pub async fn attachments_at_version(&self, record_id: i64, version: i64) -> Result<Vec<Attachment>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT a.* FROM attachments a
+                JOIN attachment_links l ON l.attachment_id = a.id
+                JOIN fields f ON f.id = l.record_id
+                WHERE l.record_id = ? AND f.tenant_id = ?
+                AND l.from_version <= ? AND (l.to_version IS NULL OR l.to_version > ?)
+                ORDER BY l.from_version, a.id
+                "#,
+            )
+            .bind(record_id)
+            .bind(self.tenant().as_str())
+            .bind(version)
+            .bind(version)
+            .fetch_all(self.pool())
+            .await
+        }
+    }
+
+    // Write the request body to `path` as it arrives; returns its size and first bytes
+    async fn spool(mut payload: web::Payload, path: &Path) -> Result<(u64, Vec<u8>), HttpResponse> {
+        let write_failed = |e: std::io::Error| {
+            tracing::error!(error = %e, "Could not spool upload");
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+        };
+        let mut file = tokio::fs::File::create(path).await.map_err(write_failed)?;
+        let mut size = 0u64;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|_| error(StatusCode::BAD_REQUEST, "The upload was interrupted"))?;
+            size += chunk.len() as u64;
+            if size > MAX_ATTACHMENT_BYTES {
+                return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "The file is too large"));
+            }
+            let wanted = SNIFF_LEN.saturating_sub(head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..wanted]);
+            file.write_all(&chunk).await.map_err(write_failed)?;
+        }
+        file.flush().await.map_err(write_failed)?;
+        Ok((size, head))
+    }
+
+    // Refuse an upload the scanner flags, moving it to quarantine, or that can't
+    // be scanned at all
+    async fn scan_upload(
+        scanner: &dyn VirusScanner,
+        path: &Path,
+        id: &str,
+        filename: &str,
+        user: &str,
+    ) -> Result<(), HttpResponse> {
+        match scanner.scan(path).await {
+            Ok(ScanVerdict::Clean) => Ok(()),
+            Ok(ScanVerdict::Infected(signature)) => {
+                match quarantine(path, id).await {
+                    Ok(kept) => tracing::warn!(
+                        %signature, filename, user, quarantined = %kept.display(),
+                        "Upload flagged by the virus scanner"
+                    ),
+                    Err(e) => tracing::error!(
+                        %signature, filename, user, error = %e,
+                        "Upload flagged by the virus scanner and could not be quarantined"
+                    ),
+                }
+                Err(error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &format!("The virus scanner flagged this file ({}), so it was not attached", signature),
+                ))
+            }
+            Err(e) => {
+                tracing::error!(error = %e, scanner = scanner.name(), "Could not scan upload");
+                Err(error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The file could not be checked for viruses; try again later",
+                ))
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct UploadQuery {
+        filename: String,
+        // The record version the uploader last saw; like a save, the upload
+        // is refused if the record moved on since
+        expected_version: i64,
+        // The id of an attachment this upload is a new version of
+        replaces: Option<String>,
+    }
+
+    // Attach the request body to a record as a file named in `?filename=`,
+    // or as a new version of the attachment in `?replaces=`, checked against
+    // the record version in `?expected_version=`.
+    // Browsers, which send the session cookie, must also send the session's
+    // CSRF token in X-CSRF-Token.
+    #[actix_web::post("/api/fields/{id}/attachments")]
+    pub async fn upload_attachment(
+        db: TenantDb,
+        store: web::Data<dyn BlobStore>,
+        req: HttpRequest,
+        record_id: web::Path<i64>,
+        query: web::Query<UploadQuery>,
+        payload: web::Payload,
+    ) -> HttpResponse {
+        let record_id = record_id.into_inner();
+        let Some(session_id) = session_id(&req) else {
+            return error(StatusCode::UNAUTHORIZED, "You must be logged in to do that");
+        };
+        let user = match db.session_user(&session_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return error(StatusCode::UNAUTHORIZED, "You must be logged in to do that"),
+            Err(e) => return internal_error(e),
+        };
+        if !allowed(&db, &user, Action::Edit, ResourceKind::Record(record_id)).await {
+            return error(StatusCode::FORBIDDEN, &denial(Action::Edit, ResourceKind::Record(record_id)));
+        }
+        if req.cookie(SESSION_COOKIE).is_some() {
+            let sent = req.headers().get("X-CSRF-Token").and_then(|v| v.to_str().ok());
+            match db.csrf_token(&session_id).await {
+                Ok(Some(expected)) if sent == Some(expected.as_str()) => {}
+                Ok(_) => return error(StatusCode::FORBIDDEN, "Invalid CSRF token"),
+                Err(e) => return internal_error(e),
+            }
+        }
+        if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+            if let Err(retry_after_secs) = limiter.check(&format!("session:{}", session_id)) {
+                return too_many_requests(retry_after_secs);
+            }
+        }
+        match db.get_fields(record_id).await {
+            Ok(_) => {}
+            Err(sqlx::Error::RowNotFound) => return error(StatusCode::NOT_FOUND, "No such record"),
+            Err(e) => return internal_error(e),
+        }
+        match db.is_expired(record_id).await {
+            Ok(false) => {}
+            Ok(true) => return error(StatusCode::CONFLICT, "The record has expired and can no longer be changed"),
+            Err(e) => return internal_error(e),
+        }
+        // Change requests carry values only, so a file can't wait for approval
+        if needs_approval(&db, &user, record_id).await {
+            return error(
+                StatusCode::FORBIDDEN,
+                "Your changes need approval, so you can't attach files to this record",
+            );
+        }
+
+        let id = db.new_id();
+        let spool_path = std::env::temp_dir().join(format!("field-editor-upload-{}", id));
+        let filename = clean_filename(&query.filename);
+        let scanner = req.app_data::<web::Data<dyn VirusScanner>>();
+        let stored = async {
+            let (size, head) = spool(payload, &spool_path).await?;
+            if let Some(scanner) = scanner {
+                scan_upload(&***scanner, &spool_path, &id, &filename, &user.name).await?;
+            }
+            let content_type = sniff_content_type(&head);
+            let dimensions = match content_type.starts_with("image/") {
+                true => image_dimensions(&spool_path).await,
+                false => None,
+            };
+            store.put(&id, &spool_path, content_type).await.map_err(|e| {
+                tracing::error!(error = %e, store = store.name(), "Could not store attachment");
+                error(StatusCode::BAD_GATEWAY, "Could not store the file")
+            })?;
+            Ok::<_, HttpResponse>((size, content_type, dimensions))
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&spool_path).await;
+        let (size, content_type, dimensions) = match stored {
+            Ok(stored) => stored,
+            Err(response) => return response,
+        };
+
+        let attachment = Attachment {
+            id: id.clone(),
+            record_id,
+            filename,
+            content_type: content_type.to_string(),
+            size: size as i64,
+            uploaded_by: user.name,
+            uploaded_at: db.now(),
+            width: dimensions.map(|(width, _)| width as i64),
+            height: dimensions.map(|(_, height)| height as i64),
+            slot: id,
+            slot_version: 1,
+        };
+        let added = db
+            .add_attachment(&attachment, query.replaces.as_deref(), query.expected_version)
+            .await;
+        let attachment = match added {
+            Ok(Some(attachment)) => attachment,
+            Ok(None) => {
+                let _ = store.delete(&attachment.id).await;
+                return error(
+                    StatusCode::CONFLICT,
+                    "The record or the file being replaced changed meanwhile; reload and try again",
+                );
+            }
+            Err(e) => {
+                let _ = store.delete(&attachment.id).await;
+                return internal_error(e);
+            }
+        };
+        tracing::info!(record_id, attachment = %attachment.id, size, content_type, "Attachment uploaded");
+        HttpResponse::Created()
+            .insert_header((header::LOCATION, attachment.download_url()))
+            .json(attachment)
+    }
+
+    // Stream an attachment's contents. Images are shown in the browser; anything
+    // else is offered as a download so it can't run as part of this site.
+    #[actix_web::get("/api/attachments/{id}")]
+    pub async fn download_attachment(
+        db: TenantDb,
+        req: HttpRequest,
+        store: web::Data<dyn BlobStore>,
+        id: web::Path<String>,
+    ) -> HttpResponse {
+        let attachment = match db.attachment(&id).await {
+            Ok(Some(attachment)) => attachment,
+            Ok(None) => return error(StatusCode::NOT_FOUND, "No such attachment"),
+            Err(e) => return internal_error(e),
+        };
+        let record = ResourceKind::Record(attachment.record_id);
+        if let Err(response) = authorized_user(&db, &req, Action::View, record).await {
+            return response;
+        }
+        let contents = match store.get(&attachment.id).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                tracing::error!(error = %e, store = store.name(), attachment = %attachment.id, "Could not read attachment");
+                return error(StatusCode::BAD_GATEWAY, "Could not read the file");
+            }
+        };
+        let disposition = match attachment.is_image() {
+            true => DispositionType::Inline,
+            false => DispositionType::Attachment,
+        };
+        HttpResponse::Ok()
+            .content_type(attachment.content_type.as_str())
+            .insert_header((header::CONTENT_LENGTH, attachment.size as u64))
+            .insert_header(ContentDisposition {
+                disposition,
+                parameters: vec![DispositionParam::Filename(attachment.filename)],
+            })
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .streaming(contents)
+    }
+}
+
+// The files attached to a record
+#[server(ListAttachments)]
+pub async fn list_attachments(record_id: i64) -> Result<Vec<Attachment>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(record_id)).await?;
+    db.list_attachments(record_id).await.map_err(db_error)
+}
+
+// Send the file picked in a file input to the upload route, as a new version
+// of the attachment `replaces` if given
+#[cfg(feature = "hydrate")]
+async fn upload(
+    record_id: i64,
+    expected_version: i64,
+    file: web_sys::File,
+    replaces: Option<String>,
+    csrf_token: String,
+) -> Result<(), String> {
+    use wasm_bindgen::JsCast;
+
+    let init = web_sys::RequestInit::new();
+    init.set_method("POST");
+    init.set_body(&file);
+    let mut url = format!(
+        "/api/fields/{}/attachments?filename={}&expected_version={}",
+        record_id,
+        String::from(js_sys::encode_uri_component(&file.name())),
+        expected_version
+    );
+    if let Some(replaces) = replaces {
+        url.push_str(&format!("&replaces={}", String::from(js_sys::encode_uri_component(&replaces))));
+    }
+    let request = web_sys::Request::new_with_str_and_init(&url, &init).map_err(|e| format!("{:?}", e))?;
+    request
+        .headers()
+        .set("X-CSRF-Token", &csrf_token)
+        .map_err(|e| format!("{:?}", e))?;
+    let response = wasm_bindgen_futures::JsFuture::from(window().fetch_with_request(&request))
+        .await
+        .map_err(|_| "The upload failed; check your connection".to_string())?
+        .dyn_into::<web_sys::Response>()
+        .map_err(|e| format!("{:?}", e))?;
+    if response.ok() {
+        return Ok(());
+    }
+    // Error responses carry `{"error": "..."}`
+    let body = match response.json() {
+        Ok(json) => wasm_bindgen_futures::JsFuture::from(json).await.ok(),
+        Err(_) => None,
+    };
+    let message = body
+        .and_then(|body| js_sys::Reflect::get(&body, &"error".into()).ok())
+        .and_then(|message| message.as_string());
+    Err(message.unwrap_or_else(|| format!("The upload failed ({})", response.status())))
+}
+
+/// Lists the files attached to a record, with an upload button for signed-in users.
+/// Picking "Replace" on a file makes the next upload a new version of it.
+#[component]
+pub fn Attachments(record_id: i64) -> impl IntoView {
+    let session = use_user_session();
+    #[cfg(feature = "hydrate")]
+    let store = crate::store::use_store();
+    let file_input = NodeRef::<leptos::html::Input>::new();
+    let uploading = RwSignal::new(false);
+    let upload_error = RwSignal::new(None::<String>);
+    // The attachment the next upload replaces, with its file name
+    let replacing = RwSignal::new(None::<(String, String)>);
+    let refresh = RwSignal::new(0u32);
+    let attachments = Resource::new(move || refresh.get(), move |_| list_attachments(record_id));
+    #[cfg(feature = "hydrate")]
+    let tasks = crate::tasks::Tasks::new();
+
+    // Files attached or brought back elsewhere show up here too
+    #[cfg(feature = "hydrate")]
+    {
+        use crate::changefeed::{subscribe, LiveUpdate};
+
+        let subscription = subscribe(move |update| match update {
+            LiveUpdate::Change(change) if change.record_id == record_id && change.event.changes_state() => {
+                refresh.update(|n| *n += 1)
+            }
+            LiveUpdate::Resync | LiveUpdate::Reconnected => refresh.update(|n| *n += 1),
+            _ => {}
+        });
+        on_cleanup(move || drop(subscription));
+    }
+
+    let on_upload = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(file) = file_input
+                .get_untracked()
+                .and_then(|input| input.files())
+                .and_then(|files| files.get(0))
+            else {
+                upload_error.set(Some("Choose a file to attach".to_string()));
+                return;
+            };
+            let replaces = replacing.get_untracked().map(|(id, _)| id);
+            let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+            // The version the editor loaded, which the upload must still find
+            let Some(expected_version) = store.confirmed_version(record_id) else {
+                upload_error.set(Some("Wait for the record to load before attaching files".to_string()));
+                return;
+            };
+            uploading.set(true);
+            upload_error.set(None);
+            tasks.spawn(async move {
+                let result = upload(record_id, expected_version, file, replaces, csrf_token).await;
+                // The upload made a new version; pick it up for the next one
+                if result.is_ok() {
+                    if let Ok(fields) = crate::field_editor::get_fields(record_id, None).await {
+                        store.put_record(fields);
+                    }
+                }
+                if let Some(input) = file_input.get_untracked() {
+                    input.set_value("");
+                }
+                if result.is_ok() {
+                    replacing.set(None);
+                }
+                upload_error.set(result.err());
+                uploading.set(false);
+                refresh.update(|n| *n += 1);
+            });
+        }
+    };
+
+    view! {
+        <div class="attachments">
+            <h2>"Attachments"</h2>
+            <Suspense fallback=|| view! { <p>"Loading attachments..."</p> }>
+                {move || attachments.get().map(|result| match result {
+                    Err(e) => view! { <div class="error">"Could not load attachments: " {e.to_string()}</div> }.into_any(),
+                    Ok(list) if list.is_empty() => view! { <p>"No attachments."</p> }.into_any(),
+                    Ok(list) => view! {
+                        <ul>
+                            {list.into_iter().map(|attachment| {
+                                let replace = (attachment.id.clone(), attachment.filename.clone());
+                                view! {
+                                    <li>
+                                        {attachment.is_image().then(|| view! {
+                                            <a href=attachment.download_url() class="attachment-preview">
+                                                <img src=attachment.thumbnail_url(PREVIEW_SIZE) alt=attachment.filename.clone() loading="lazy"/>
+                                            </a>
+                                        })}
+                                        <a href=attachment.download_url()>{attachment.filename.clone()}</a>
+                                        <span class="attachment-meta">
+                                            {(attachment.slot_version > 1).then(|| format!("version {}, ", attachment.slot_version))}
+                                            {attachment.width.zip(attachment.height).map(|(width, height)| format!("{} × {}, ", width, height))}
+                                            {format_size(attachment.size)} ", " {attachment.uploaded_by.clone()}
+                                        </span>
+                                        <button
+                                            class="link"
+                                            on:click=move |_| replacing.set(Some(replace.clone()))
+                                            disabled=move || session.user.get().flatten().is_none()
+                                        >
+                                            "Replace"
+                                        </button>
+                                    </li>
+                                }
+                            }).collect_view()}
+                        </ul>
+                    }.into_any(),
+                })}
+            </Suspense>
+            {move || replacing.get().map(|(_, filename)| view! {
+                <p class="attachment-replacing">
+                    "The next upload replaces " <strong>{filename}</strong> ". "
+                    <button class="link" on:click=move |_| replacing.set(None)>"Cancel"</button>
+                </p>
+            })}
+            <input type="file" node_ref=file_input/>
+            <button
+                on:click=on_upload
+                disabled=move || uploading.get() || session.user.get().flatten().is_none()
+            >
+                {move || match (uploading.get(), replacing.with(Option::is_some)) {
+                    (true, _) => "Uploading...",
+                    (false, true) => "Replace",
+                    (false, false) => "Attach",
+                }}
+            </button>
+            {move || upload_error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+        </div>
+    }
+}
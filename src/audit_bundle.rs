@@ -0,0 +1,239 @@
+use crate::db::{DbManager, HistoryRow, HISTORY_COLUMNS};
+use crate::history::HistoryEntry;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+
+// Audit bundles hand auditors a date range of a tenant's audit log together
+// with the state each record changed in that range was left in, as one file
+// they can check without access to the server. The file is gzip-compressed
+// JSON holding the contents as text and an Ed25519 signature over exactly that
+// text, made with the key in FIELD_EDITOR_AUDIT_SIGNING_KEY. The signing key's
+// public half is in the file too, but a bundle is only authentic if it
+// verifies against a public key the auditor got from the operator beforehand.
+
+pub const BUNDLE_FORMAT: &str = "field-editor-audit-bundle/1";
+
+// Audit log entries read per query while assembling a bundle
+const BUNDLE_PAGE_SIZE: i64 = 500;
+
+// What a bundle covers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub format: String,
+    pub tenant: String,
+    // Entries changed at or after `from` and before `to`, in milliseconds since the epoch
+    pub from: i64,
+    pub to: i64,
+    pub created_at: i64,
+    pub entries: usize,
+    pub records: usize,
+}
+
+// A record as its last entry in the range left it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordSnapshot {
+    pub record_id: i64,
+    pub version: i64,
+    pub at: i64,
+    pub values: BTreeMap<String, String>,
+}
+
+// What is signed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleContents {
+    pub manifest: BundleManifest,
+    pub entries: Vec<HistoryEntry>,
+    pub snapshots: Vec<RecordSnapshot>,
+}
+
+// The file: the contents as JSON text, and the signature over that text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedBundle {
+    pub format: String,
+    // Base64 of the raw Ed25519 public key and signature
+    pub public_key: String,
+    pub signature: String,
+    pub contents: String,
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+    Database(sqlx::Error),
+    Io(std::io::Error),
+    Format(String),
+    Key(String),
+    // The signature doesn't match the contents, or not the expected key
+    BadSignature,
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Database(e) => write!(f, "Database error: {}", e),
+            BundleError::Io(e) => write!(f, "Could not read or write the bundle: {}", e),
+            BundleError::Format(e) => write!(f, "Not a valid audit bundle: {}", e),
+            BundleError::Key(e) => write!(f, "Signing key unusable: {}", e),
+            BundleError::BadSignature => write!(f, "The signature does not match; the bundle is not authentic"),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl From<sqlx::Error> for BundleError {
+    fn from(e: sqlx::Error) -> Self {
+        BundleError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for BundleError {
+    fn from(e: std::io::Error) -> Self {
+        BundleError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BundleError {
+    fn from(e: serde_json::Error) -> Self {
+        BundleError::Format(e.to_string())
+    }
+}
+
+// Signs bundles with an Ed25519 key kept as a PKCS#8 file
+pub struct BundleSigner {
+    key: Ed25519KeyPair,
+}
+
+impl BundleSigner {
+    pub fn from_pkcs8(der: &[u8]) -> Result<Self, BundleError> {
+        Ed25519KeyPair::from_pkcs8(der)
+            .map(|key| BundleSigner { key })
+            .map_err(|e| BundleError::Key(e.to_string()))
+    }
+
+    // The key in the file FIELD_EDITOR_AUDIT_SIGNING_KEY names
+    pub fn from_env() -> Result<Self, BundleError> {
+        let path = std::env::var("FIELD_EDITOR_AUDIT_SIGNING_KEY")
+            .map_err(|_| BundleError::Key("FIELD_EDITOR_AUDIT_SIGNING_KEY is not set".to_string()))?;
+        Self::from_pkcs8(&std::fs::read(&path)?)
+    }
+
+    // Write a new key to `path` as PKCS#8; returns its public key in base64
+    pub fn generate(path: &str) -> Result<String, BundleError> {
+        let rng = ring::rand::SystemRandom::new();
+        let der = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|e| BundleError::Key(e.to_string()))?;
+        std::fs::write(path, der.as_ref())?;
+        Ok(Self::from_pkcs8(der.as_ref())?.public_key())
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key.public_key().as_ref())
+    }
+
+    pub fn sign(&self, contents: &BundleContents) -> Result<SignedBundle, BundleError> {
+        let contents = serde_json::to_string(contents)?;
+        Ok(SignedBundle {
+            format: BUNDLE_FORMAT.to_string(),
+            public_key: self.public_key(),
+            signature: BASE64.encode(self.key.sign(contents.as_bytes()).as_ref()),
+            contents,
+        })
+    }
+}
+
+impl SignedBundle {
+    // The contents, if the signature is good for them. Checked against
+    // `public_key` (base64) if given, else against the key in the bundle,
+    // which only shows the file wasn't damaged, not who made it.
+    pub fn verify(&self, public_key: Option<&str>) -> Result<BundleContents, BundleError> {
+        if self.format != BUNDLE_FORMAT {
+            return Err(BundleError::Format(format!("Unknown format {:?}", self.format)));
+        }
+        let decode = |text: &str| BASE64.decode(text.trim()).map_err(|e| BundleError::Format(e.to_string()));
+        let key = decode(public_key.unwrap_or(&self.public_key))?;
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(self.contents.as_bytes(), &decode(&self.signature)?)
+            .map_err(|_| BundleError::BadSignature)?;
+        Ok(serde_json::from_str(&self.contents)?)
+    }
+}
+
+pub fn write_bundle(path: &str, bundle: &SignedBundle) -> Result<(), BundleError> {
+    let mut encoder = GzEncoder::new(std::fs::File::create(path)?, flate2::Compression::default());
+    encoder.write_all(&serde_json::to_vec(bundle)?)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+pub fn read_bundle(path: &str) -> Result<SignedBundle, BundleError> {
+    let mut json = Vec::new();
+    GzDecoder::new(std::fs::File::open(path)?).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+impl DbManager {
+    // The tenant's audit log entries changed in [from, to), and a snapshot of
+    // every record they are about as of its last entry among them
+    pub async fn audit_bundle(&self, from: i64, to: i64) -> Result<BundleContents, sqlx::Error> {
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let mut after = 0;
+        loop {
+            let page: Vec<HistoryEntry> = sqlx::query_as::<_, HistoryRow>(&format!(
+                r#"
+                SELECT {} FROM fields_history
+                WHERE tenant_id = ? AND changed_at >= ? AND changed_at < ? AND id > ?
+                ORDER BY id LIMIT ?
+                "#,
+                HISTORY_COLUMNS
+            ))
+            .bind(self.tenant().as_str())
+            .bind(from)
+            .bind(to)
+            .bind(after)
+            .bind(BUNDLE_PAGE_SIZE)
+            .fetch_all(self.pool())
+            .await?
+            .into_iter()
+            .map(HistoryEntry::from)
+            .collect();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = last.event.id;
+            entries.extend(page);
+        }
+
+        let mut snapshots: BTreeMap<i64, RecordSnapshot> = BTreeMap::new();
+        for entry in entries.iter().filter(|entry| entry.event.event.changes_state()) {
+            snapshots.insert(
+                entry.event.record_id,
+                RecordSnapshot {
+                    record_id: entry.event.record_id,
+                    version: entry.event.version,
+                    at: entry.event.occurred_at,
+                    values: entry.values.clone(),
+                },
+            );
+        }
+
+        Ok(BundleContents {
+            manifest: BundleManifest {
+                format: BUNDLE_FORMAT.to_string(),
+                tenant: self.tenant().as_str().to_string(),
+                from,
+                to,
+                created_at: self.now(),
+                entries: entries.len(),
+                records: snapshots.len(),
+            },
+            entries,
+            snapshots: snapshots.into_values().collect(),
+        })
+    }
+}
@@ -0,0 +1,426 @@
+use crate::tasks::Tasks;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// A signed-in user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct User {
+    pub name: String,
+    // Set while an admin acts as this user: which admin, and until when
+    #[serde(default)]
+    #[cfg_attr(feature = "ssr", sqlx(default))]
+    pub impersonated_by: Option<String>,
+    #[serde(default)]
+    #[cfg_attr(feature = "ssr", sqlx(default))]
+    pub impersonation_ends_at: Option<i64>,
+}
+
+impl User {
+    // The user signed in as themselves
+    pub fn named(name: impl Into<String>) -> Self {
+        User {
+            name: name.into(),
+            impersonated_by: None,
+            impersonation_ends_at: None,
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::User;
+    use crate::tenant::TenantId;
+    use crate::db::DbManager;
+    use crate::policy::{allowed, denial, Action, ResourceKind};
+    use actix_web::http::header::{HeaderValue, SET_COOKIE};
+    use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+    use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+    use leptos::prelude::ServerFnError;
+    use std::sync::OnceLock;
+
+    pub const SESSION_COOKIE: &str = "field_editor_session";
+    const SESSION_TTL_MILLIS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+    impl DbManager {
+        // Check a user's password. Accounts are only made with `create_user`,
+        // never by signing in, so nobody can claim a name (an admin's, say)
+        // that hasn't been set up yet. A user belongs to the tenant they were
+        // created for and can't sign in to others.
+        pub async fn authenticate(&self, name: &str, password: &str) -> Result<Option<User>, sqlx::Error> {
+            let stored: Option<(String, String)> =
+                sqlx::query_as("SELECT password_hash, tenant_id FROM users WHERE name = ?")
+                    .bind(name)
+                    .fetch_optional(self.pool())
+                    .await?;
+
+            match stored {
+                Some((hash, tenant)) if tenant == self.tenant().as_str() => {
+                    let valid = PasswordHash::new(&hash)
+                        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+                        .unwrap_or(false);
+                    Ok(valid.then(|| User::named(name)))
+                }
+                _ => Ok(None),
+            }
+        }
+
+        // Set up an account in this manager's tenant, from the command line.
+        // Returns false if the name is taken, in any tenant.
+        pub async fn create_user(&self, name: &str, password: &str) -> Result<bool, sqlx::Error> {
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|e| sqlx::Error::Protocol(e.to_string()))?
+                .to_string();
+            let created = sqlx::query(
+                "INSERT INTO users (name, password_hash, tenant_id) VALUES (?, ?, ?) ON CONFLICT (name) DO NOTHING",
+            )
+            .bind(name)
+            .bind(hash)
+            .bind(self.tenant().as_str())
+            .execute(self.pool())
+            .await?;
+            Ok(created.rows_affected() > 0)
+        }
+
+        // The tenant a user registered with; None for names nobody has registered
+        pub async fn user_tenant(&self, name: &str) -> Result<Option<TenantId>, sqlx::Error> {
+            let tenant: Option<String> = sqlx::query_scalar("SELECT tenant_id FROM users WHERE name = ?")
+                .bind(name)
+                .fetch_optional(self.pool())
+                .await?;
+            Ok(tenant.and_then(|id| TenantId::parse(&id)))
+        }
+
+        // A session for the user with this manager's tenant
+        pub async fn create_session(&self, user: &User) -> Result<String, sqlx::Error> {
+            let session_id = self.new_id();
+            let now = self.now();
+            sqlx::query(
+                r#"
+                INSERT INTO sessions (id, user_name, created_at, expires_at, csrf_token, tenant_id)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&session_id)
+            .bind(&user.name)
+            .bind(now)
+            .bind(now + SESSION_TTL_MILLIS)
+            .bind(self.new_id())
+            .bind(self.tenant().as_str())
+            .execute(self.pool())
+            .await?;
+            Ok(session_id)
+        }
+
+        // The user of a live session with this manager's tenant; the impersonated
+        // user for a session an admin started to act as someone else
+        pub async fn session_user(&self, session_id: &str) -> Result<Option<User>, sqlx::Error> {
+            sqlx::query_as::<_, User>(
+                r#"
+                SELECT user_name AS name, impersonated_by,
+                       CASE WHEN impersonated_by IS NOT NULL THEN expires_at END AS impersonation_ends_at
+                FROM sessions WHERE id = ? AND tenant_id = ? AND expires_at > ?
+                "#,
+            )
+            .bind(session_id)
+            .bind(self.tenant().as_str())
+            .bind(self.now())
+            .fetch_optional(self.pool())
+            .await
+        }
+
+        // The CSRF token of a live session. Sessions created before tokens
+        // existed get one on first use.
+        pub async fn csrf_token(&self, session_id: &str) -> Result<Option<String>, sqlx::Error> {
+            let stored: Option<Option<String>> = sqlx::query_scalar(
+                "SELECT csrf_token FROM sessions WHERE id = ? AND expires_at > ?",
+            )
+            .bind(session_id)
+            .bind(self.now())
+            .fetch_optional(self.pool())
+            .await?;
+
+            match stored {
+                None => Ok(None),
+                Some(Some(token)) => Ok(Some(token)),
+                Some(None) => {
+                    let token = self.new_id();
+                    sqlx::query("UPDATE sessions SET csrf_token = COALESCE(csrf_token, ?) WHERE id = ?")
+                        .bind(&token)
+                        .bind(session_id)
+                        .execute(self.pool())
+                        .await?;
+                    sqlx::query_scalar("SELECT csrf_token FROM sessions WHERE id = ?")
+                        .bind(session_id)
+                        .fetch_optional(self.pool())
+                        .await
+                }
+            }
+        }
+
+        pub async fn delete_session(&self, session_id: &str) -> Result<(), sqlx::Error> {
+            sqlx::query("DELETE FROM sessions WHERE id = ?")
+                .bind(session_id)
+                .execute(self.pool())
+                .await?;
+            Ok(())
+        }
+    }
+
+    // The session id from the request cookie, if any
+    pub async fn session_cookie() -> Result<Option<String>, ServerFnError> {
+        let req = leptos_actix::extract::<actix_web::HttpRequest>().await?;
+        Ok(req.cookie(SESSION_COOKIE).map(|c| c.value().to_string()))
+    }
+
+    // The user behind the current request, if signed in
+    pub async fn session_user(db: &DbManager) -> Result<Option<User>, ServerFnError> {
+        let Some(session_id) = session_cookie().await? else {
+            return Ok(None);
+        };
+        db.session_user(&session_id)
+            .await
+            .map_err(ServerFnError::new)
+    }
+
+    // Guard for mutations: fails unless the request comes from a signed-in user
+    pub async fn require_user(db: &DbManager) -> Result<User, ServerFnError> {
+        session_user(db)
+            .await?
+            .ok_or_else(|| ServerFnError::new("You must be logged in to do that"))
+    }
+
+    // The users with the admin role, from FIELD_EDITOR_ADMINS
+    // (comma-separated names); nobody by default
+    pub fn admins() -> &'static [String] {
+        static ADMINS: OnceLock<Vec<String>> = OnceLock::new();
+        ADMINS.get_or_init(|| {
+            std::env::var("FIELD_EDITOR_ADMINS")
+                .map(|v| v.split(',').map(str::trim).filter(|n| !n.is_empty()).map(String::from).collect())
+                .unwrap_or_default()
+        })
+    }
+
+    // Guard for what only admins may see whatever the policy says, such as the
+    // whole audit log
+    pub async fn require_admin(db: &DbManager) -> Result<User, ServerFnError> {
+        let user = require_user(db).await?;
+        match admins().contains(&user.name) {
+            true => Ok(user),
+            false => Err(ServerFnError::new("Only admins may do that")),
+        }
+    }
+
+    // Guard for everything the authorization policy decides on: fails unless the
+    // request comes from a signed-in user the policy lets do `action` to `kind`
+    pub async fn authorize(db: &DbManager, action: Action, kind: ResourceKind) -> Result<User, ServerFnError> {
+        let user = require_user(db).await?;
+        match allowed(db, &user, action, kind).await {
+            true => Ok(user),
+            false => Err(ServerFnError::new(denial(action, kind))),
+        }
+    }
+
+    // Guard for mutations: fails unless the request carries the CSRF token of
+    // its session, which a cross-site form or script has no way to read
+    pub async fn require_csrf(db: &DbManager, token: &str) -> Result<(), ServerFnError> {
+        let expected = match session_cookie().await? {
+            Some(session_id) => db.csrf_token(&session_id).await.map_err(ServerFnError::new)?,
+            None => None,
+        };
+        match expected {
+            Some(expected) if constant_time_eq(expected.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(ServerFnError::new(
+                "Invalid or missing CSRF token; reload the page and try again",
+            )),
+        }
+    }
+
+    // Compare secrets without leaking how long a matching prefix is
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    pub fn set_session_cookie(value: &str, max_age_secs: i64) {
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+            SESSION_COOKIE, value, max_age_secs
+        );
+        let response = leptos::prelude::expect_context::<leptos_actix::ResponseOptions>();
+        response.append_header(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie).expect("Session cookie is a valid header"),
+        );
+    }
+
+    pub const SESSION_TTL_SECS: i64 = SESSION_TTL_MILLIS / 1000;
+}
+
+#[server(Login)]
+pub async fn login(name: String, password: String) -> Result<User, ServerFnError> {
+    use crate::field_editor::{db_error, open_db};
+    use crate::tenant::{tenant_source, TenantSource};
+
+    let name = name.trim().to_string();
+    if name.is_empty() || password.is_empty() {
+        return Err(ServerFnError::new("Enter a name and password"));
+    }
+
+    let db = open_db().await?;
+    // Before signing in there is no session to say which tenant the user is for
+    let db = match tenant_source() {
+        TenantSource::Session => {
+            let tenant = db.user_tenant(&name).await.map_err(db_error)?.unwrap_or_default();
+            db.with_tenant(tenant)
+        }
+        _ => db,
+    };
+    let user = db
+        .authenticate(&name, &password)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ServerFnError::new("Wrong name or password"))?;
+
+    let session_id = db.create_session(&user).await.map_err(db_error)?;
+    set_session_cookie(&session_id, SESSION_TTL_SECS);
+    Ok(user)
+}
+
+#[server(Logout)]
+pub async fn logout() -> Result<(), ServerFnError> {
+    use crate::field_editor::{db_error, open_db};
+
+    if let Some(session_id) = session_cookie().await? {
+        let db = open_db().await?;
+        db.delete_session(&session_id).await.map_err(db_error)?;
+    }
+    set_session_cookie("", 0);
+    Ok(())
+}
+
+#[server(CurrentUser)]
+pub async fn current_user() -> Result<Option<User>, ServerFnError> {
+    use crate::field_editor::open_db;
+
+    let db = open_db().await?;
+    session_user(&db).await
+}
+
+// The token mutations must send back to prove they come from this app's pages
+#[server(CsrfToken)]
+pub async fn csrf_token() -> Result<Option<String>, ServerFnError> {
+    use crate::field_editor::{db_error, open_db};
+
+    let Some(session_id) = session_cookie().await? else {
+        return Ok(None);
+    };
+    let db = open_db().await?;
+    db.csrf_token(&session_id).await.map_err(db_error)
+}
+
+// The signed-in user, shared with the rest of the UI via context
+#[derive(Clone, Copy)]
+pub struct UserSession {
+    pub user: Resource<Option<User>>,
+    // The session's CSRF token, to embed in forms that change data
+    pub csrf_token: Resource<Option<String>>,
+    refresh: RwSignal<()>,
+}
+
+impl UserSession {
+    pub fn provide() -> Self {
+        let refresh = RwSignal::new(());
+        let user = Resource::new(
+            move || refresh.get(),
+            |_| async move { current_user().await.ok().flatten() },
+        );
+        let csrf_token = Resource::new(
+            move || refresh.get(),
+            |_| async move { csrf_token().await.ok().flatten() },
+        );
+        let session = UserSession {
+            user,
+            csrf_token,
+            refresh,
+        };
+        provide_context(session);
+        session
+    }
+
+    // Re-read the session after logging in or out
+    pub fn refresh(&self) {
+        self.refresh.set(());
+    }
+}
+
+pub fn use_user_session() -> UserSession {
+    expect_context::<UserSession>()
+}
+
+/// Shows who is signed in, with a login form or logout button.
+#[component]
+pub fn UserMenu() -> impl IntoView {
+    let session = use_user_session();
+    let name = RwSignal::new(String::new());
+    let password = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let tasks = Tasks::new();
+
+    let on_login = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        tasks.spawn(async move {
+            match login(name.get_untracked(), password.get_untracked()).await {
+                Ok(_) => {
+                    error.set(None);
+                    password.set(String::new());
+                    session.refresh();
+                }
+                Err(e) => error.set(Some(e.to_string())),
+            }
+        });
+    };
+
+    let on_logout = move |_| {
+        tasks.spawn(async move {
+            let _ = logout().await;
+            session.refresh();
+        });
+    };
+
+    view! {
+        <div class="user-menu">
+            <Suspense fallback=|| ()>
+                {move || session.user.get().map(|user| match user {
+                    Some(user) => view! {
+                        <span>"Signed in as " <strong>{user.name}</strong></span>
+                        <button class="link" on:click=on_logout>"Log out"</button>
+                    }.into_any(),
+                    None => view! {
+                        <form class="login-form" on:submit=on_login>
+                            <input
+                                type="text"
+                                placeholder="Name"
+                                prop:value=name
+                                on:input=move |ev| name.set(event_target_value(&ev))
+                            />
+                            <input
+                                type="password"
+                                placeholder="Password"
+                                prop:value=password
+                                on:input=move |ev| password.set(event_target_value(&ev))
+                            />
+                            <button type="submit">"Log in"</button>
+                            {move || error.get().map(|e| view! { <span class="error">{e}</span> })}
+                        </form>
+                    }.into_any(),
+                })}
+            </Suspense>
+        </div>
+    }
+}
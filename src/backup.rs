@@ -0,0 +1,321 @@
+use crate::db::{ensure_field_definitions, DbManager, FieldValues, HistoryRow, HISTORY_COLUMNS};
+use crate::events::FieldEvent;
+use crate::history::HistoryEntry;
+use crate::integrity::record_checksum;
+use crate::tenant::{TenantId, DEFAULT_TENANT};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::fmt;
+
+// Full backups are a copy of the whole database file; incremental ones only
+// hold the audit log entries written since the previous backup of either kind.
+// Each backup leaves a marker saying which audit log entry it ends with, so the
+// next incremental one starts right after it. Restoring puts a full backup in
+// place and replays the incremental ones taken after it, in order, which brings
+// every record to the state of the last one. Only records and their audit log
+// are replayed; accounts, sessions and the like stay as of the full backup.
+// Backups cover every tenant.
+
+// Audit log entries read per query while taking an incremental backup
+const BACKUP_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupKind {
+    Full,
+    Incremental,
+}
+
+impl BackupKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            BackupKind::Full => "full",
+            BackupKind::Incremental => "incremental",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupMarker {
+    pub kind: BackupKind,
+    // The last audit log entry the backup covers
+    pub through: i64,
+    pub created_at: i64,
+}
+
+// The audit log entries after `since` up to and including `through`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IncrementalBackup {
+    pub since: i64,
+    pub through: i64,
+    pub created_at: i64,
+    pub entries: Vec<BackupEntry>,
+}
+
+// An audit log entry and the tenant whose record it's about
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupEntry {
+    // Backups from before there were tenants only have the default one
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Database(sqlx::Error),
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    // The backup doesn't continue where the database ends; one in between is missing
+    Gap { expected_since: i64, since: i64 },
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Database(e) => write!(f, "Database error: {}", e),
+            BackupError::Io(e) => write!(f, "Could not read or write the backup: {}", e),
+            BackupError::Format(e) => write!(f, "Not a valid backup: {}", e),
+            BackupError::Gap { expected_since, since } => write!(
+                f,
+                "The backup starts after change {} but the database ends at change {}",
+                since, expected_since
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<sqlx::Error> for BackupError {
+    fn from(e: sqlx::Error) -> Self {
+        BackupError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(e: serde_json::Error) -> Self {
+        BackupError::Format(e)
+    }
+}
+
+pub fn write_incremental(path: &str, backup: &IncrementalBackup) -> Result<(), BackupError> {
+    std::fs::write(path, serde_json::to_vec(backup)?)?;
+    Ok(())
+}
+
+pub fn read_incremental(path: &str) -> Result<IncrementalBackup, BackupError> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+// Put a full backup in place of the database at `db_path`. Only while no
+// server is using that database.
+pub fn restore_full(backup_path: &str, db_path: &str) -> Result<(), BackupError> {
+    for suffix in ["-wal", "-shm"] {
+        match std::fs::remove_file(format!("{}{}", db_path, suffix)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    std::fs::copy(backup_path, db_path)?;
+    Ok(())
+}
+
+impl DbManager {
+    async fn mark_backup(&self, kind: BackupKind, through: i64) -> Result<BackupMarker, sqlx::Error> {
+        let created_at = self.now();
+        sqlx::query("INSERT INTO backup_markers (kind, through_change_id, created_at) VALUES (?, ?, ?)")
+            .bind(kind.as_str())
+            .bind(through)
+            .bind(created_at)
+            .execute(self.pool())
+            .await?;
+        Ok(BackupMarker {
+            kind,
+            through,
+            created_at,
+        })
+    }
+
+    // Where the next incremental backup starts: the end of the latest backup, or
+    // the start of the audit log if there never was one
+    pub async fn last_backup_through(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COALESCE(MAX(through_change_id), 0) FROM backup_markers")
+            .fetch_one(self.pool())
+            .await
+    }
+
+    // Copy the whole database into a new file at `path`
+    #[tracing::instrument(skip(self))]
+    pub async fn backup_full(&self, path: &str) -> Result<BackupMarker, sqlx::Error> {
+        sqlx::query("VACUUM INTO ?").bind(path).execute(self.pool()).await?;
+        // Saves may land while the copy is made, so ask the copy how far it got
+        let copy = SqlitePool::connect(&format!("sqlite:{}?mode=ro", path)).await?;
+        let through: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM fields_history")
+            .fetch_one(&copy)
+            .await?;
+        copy.close().await;
+        let marker = self.mark_backup(BackupKind::Full, through).await?;
+        tracing::info!(through, "Full backup written");
+        Ok(marker)
+    }
+
+    // The audit log entries written since the last backup
+    #[tracing::instrument(skip(self))]
+    pub async fn backup_incremental(&self) -> Result<IncrementalBackup, sqlx::Error> {
+        let since = self.last_backup_through().await?;
+        let up_to = self.latest_change_id().await?.max(since);
+        let mut entries: Vec<BackupEntry> = Vec::new();
+        loop {
+            let after = entries.last().map_or(since, |entry| entry.entry.event.id);
+            let page = sqlx::query_as::<_, HistoryRow>(&format!(
+                "SELECT {} FROM fields_history WHERE id > ? AND id <= ? ORDER BY id LIMIT ?",
+                HISTORY_COLUMNS
+            ))
+            .bind(after)
+            .bind(up_to)
+            .bind(BACKUP_PAGE_SIZE)
+            .fetch_all(self.pool())
+            .await?;
+            if page.is_empty() {
+                break;
+            }
+            entries.extend(page.into_iter().map(|row| BackupEntry {
+                tenant_id: row.tenant_id.clone(),
+                entry: HistoryEntry::from(row),
+            }));
+        }
+        let marker = self.mark_backup(BackupKind::Incremental, up_to).await?;
+        tracing::info!(since, through = up_to, entries = entries.len(), "Incremental backup taken");
+        Ok(IncrementalBackup {
+            since,
+            through: up_to,
+            created_at: marker.created_at,
+            entries,
+        })
+    }
+
+    // Replay an incremental backup onto this database, which must end exactly
+    // where the backup starts. Records are set to their state after each entry.
+    #[tracing::instrument(skip_all, fields(since = backup.since, through = backup.through))]
+    pub async fn apply_incremental(&self, backup: &IncrementalBackup) -> Result<(), BackupError> {
+        let mut tx = self.pool().begin().await?;
+        let latest: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM fields_history")
+            .fetch_one(&mut *tx)
+            .await?;
+        if latest != backup.since {
+            return Err(BackupError::Gap {
+                expected_since: latest,
+                since: backup.since,
+            });
+        }
+        for BackupEntry { tenant_id, entry } in &backup.entries {
+            let values = FieldValues(entry.values.clone());
+            let envelope = &entry.event;
+            let checksum = record_checksum(envelope.record_id, envelope.version, &values);
+            if envelope.event.changes_state() {
+                let deleted_at = matches!(envelope.event, FieldEvent::Deleted).then_some(envelope.occurred_at);
+                sqlx::query(
+                    r#"
+                    INSERT INTO fields (id, version, updated_by, updated_at, deleted_at, checksum, tenant_id)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT(id) DO UPDATE SET
+                        version = excluded.version, updated_by = excluded.updated_by,
+                        updated_at = excluded.updated_at, deleted_at = excluded.deleted_at,
+                        checksum = excluded.checksum
+                    "#,
+                )
+                .bind(envelope.record_id)
+                .bind(envelope.version)
+                .bind(&envelope.actor)
+                .bind(envelope.occurred_at)
+                .bind(deleted_at)
+                .bind(&checksum)
+                .bind(tenant_id)
+                .execute(&mut *tx)
+                .await?;
+                restore_values(&mut tx, tenant_id, envelope.record_id, envelope.version, &values).await?;
+            }
+            sqlx::query(
+                r#"
+                INSERT INTO fields_history
+                    (id, record_id, version, change_type, event, field_values, changed_at, changed_by, checksum, tenant_id)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(envelope.id)
+            .bind(envelope.record_id)
+            .bind(envelope.version)
+            .bind(envelope.event.kind())
+            .bind(serde_json::to_string(&envelope.event)?)
+            .bind(serde_json::to_string(&entry.values)?)
+            .bind(envelope.occurred_at)
+            .bind(&envelope.actor)
+            .bind(&checksum)
+            .bind(tenant_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        self.mark_backup(BackupKind::Incremental, backup.through).await?;
+        tracing::info!(entries = backup.entries.len(), "Incremental backup replayed");
+        Ok(())
+    }
+}
+
+// Set a record's values to those of a backup entry. Values that didn't change
+// keep the version that last changed them, and fields the tenant doesn't define
+// (renamed or added after the full backup) are defined again, at the end.
+async fn restore_values(
+    conn: &mut SqliteConnection,
+    tenant_id: &str,
+    record_id: i64,
+    version: i64,
+    values: &FieldValues,
+) -> Result<(), sqlx::Error> {
+    let tenant = TenantId::parse(tenant_id).unwrap_or_default();
+    ensure_field_definitions(&mut *conn, &tenant).await?;
+    for name in values.names() {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO field_definitions (tenant_id, name, position)
+            SELECT ?1, ?2, COALESCE(MAX(position), -1) + 1 FROM field_definitions WHERE tenant_id = ?1
+            "#,
+        )
+        .bind(tenant.as_str())
+        .bind(name)
+        .execute(&mut *conn)
+        .await?;
+    }
+    let json = serde_json::to_string(values).expect("FieldValues serialize");
+    sqlx::query("DELETE FROM field_values WHERE record_id = ? AND field_name NOT IN (SELECT key FROM json_each(?))")
+        .bind(record_id)
+        .bind(&json)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO field_values (record_id, field_name, value, version)
+        SELECT ?, key, value, ? FROM json_each(?) WHERE true
+        ON CONFLICT (record_id, field_name) DO UPDATE SET value = excluded.value, version = excluded.version
+        WHERE value IS NOT excluded.value
+        "#,
+    )
+    .bind(record_id)
+    .bind(version)
+    .bind(&json)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
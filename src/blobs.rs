@@ -0,0 +1,304 @@
+use actix_web::web::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+
+// Where attachment contents live. The database only keeps a key per
+// attachment; the bytes go to whichever store is configured, and are streamed
+// in and out rather than held in memory.
+
+// An attachment's contents, a chunk at a time
+pub type BlobStream = BoxStream<'static, Result<Bytes, std::io::Error>>;
+
+#[derive(Debug)]
+pub enum BlobError {
+    NotFound,
+    Io(std::io::Error),
+    // The remote store refused or couldn't be reached
+    Backend(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::NotFound => write!(f, "No such blob"),
+            BlobError::Io(e) => write!(f, "Blob I/O failed: {}", e),
+            BlobError::Backend(message) => write!(f, "Blob store failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<std::io::Error> for BlobError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => BlobError::NotFound,
+            _ => BlobError::Io(e),
+        }
+    }
+}
+
+pub trait BlobStore: Send + Sync {
+    // For logs
+    fn name(&self) -> &'static str;
+
+    // Store the contents of the local file at `path` under `key`
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path, content_type: &'a str) -> BoxFuture<'a, Result<(), BlobError>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<BlobStream, BlobError>>;
+
+    // Remove a blob; removing one that isn't there is not an error
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), BlobError>>;
+}
+
+// Keys become file names and URL paths, so only plain ones are accepted
+fn check_key(key: &str) -> Result<&str, BlobError> {
+    let plain = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match plain {
+        true => Ok(key),
+        false => Err(BlobError::Backend(format!("Invalid blob key {:?}", key))),
+    }
+}
+
+// One file per blob in a directory on this machine
+pub struct LocalDirStore {
+    root: PathBuf,
+}
+
+impl LocalDirStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalDirStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+        Ok(self.root.join(check_key(key)?))
+    }
+}
+
+impl BlobStore for LocalDirStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path, _content_type: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+        Box::pin(async move {
+            let target = self.path(key)?;
+            tokio::fs::create_dir_all(&self.root).await?;
+            tokio::fs::copy(path, target).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<BlobStream, BlobError>> {
+        Box::pin(async move {
+            let file = tokio::fs::File::open(self.path(key)?).await?;
+            Ok(ReaderStream::new(file).boxed())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)?).await.map_err(BlobError::from) {
+                Err(BlobError::NotFound) => Ok(()),
+                result => result,
+            }
+        })
+    }
+}
+
+// Blobs as files in a collection on a WebDAV server, e.g. Nextcloud
+pub struct WebDavStore {
+    client: reqwest::Client,
+    base_url: String,
+    credentials: Option<(String, String)>,
+}
+
+impl WebDavStore {
+    pub fn new(base_url: &str, credentials: Option<(String, String)>) -> Self {
+        WebDavStore {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credentials,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, key: &str) -> Result<reqwest::RequestBuilder, BlobError> {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.base_url, check_key(key)?));
+        Ok(match &self.credentials {
+            Some((user, password)) => request.basic_auth(user, Some(password)),
+            None => request,
+        })
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, BlobError> {
+        let response = request.send().await.map_err(|e| BlobError::Backend(e.to_string()))?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Err(BlobError::NotFound),
+            status if status.is_success() => Ok(response),
+            status => Err(BlobError::Backend(format!("WebDAV server answered {}", status))),
+        }
+    }
+}
+
+impl BlobStore for WebDavStore {
+    fn name(&self) -> &'static str {
+        "webdav"
+    }
+
+    fn put<'a>(&'a self, key: &'a str, path: &'a Path, content_type: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+        Box::pin(async move {
+            let file = tokio::fs::File::open(path).await?;
+            let len = file.metadata().await?.len();
+            let request = self
+                .request(reqwest::Method::PUT, key)?
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .header(reqwest::header::CONTENT_LENGTH, len)
+                .body(reqwest::Body::wrap_stream(ReaderStream::new(file)));
+            Self::send(request).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<BlobStream, BlobError>> {
+        Box::pin(async move {
+            let response = Self::send(self.request(reqwest::Method::GET, key)?).await?;
+            Ok(response.bytes_stream().map_err(std::io::Error::other).boxed())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+        Box::pin(async move {
+            match Self::send(self.request(reqwest::Method::DELETE, key)?).await {
+                Ok(_) | Err(BlobError::NotFound) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use s3::S3Store;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::{check_key, BlobError, BlobStore, BlobStream};
+    use aws_sdk_s3::primitives::ByteStream;
+    use futures::future::BoxFuture;
+    use futures::stream::StreamExt;
+    use std::path::Path;
+    use tokio_util::io::ReaderStream;
+
+    // Blobs as objects in an S3 bucket, optionally under a key prefix
+    pub struct S3Store {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        prefix: String,
+    }
+
+    impl S3Store {
+        // Credentials and region come from the usual AWS environment variables and files
+        pub async fn new(bucket: &str, prefix: &str) -> Self {
+            let config = aws_config::load_from_env().await;
+            S3Store {
+                client: aws_sdk_s3::Client::new(&config),
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+            }
+        }
+
+        fn object_key(&self, key: &str) -> Result<String, BlobError> {
+            Ok(format!("{}{}", self.prefix, check_key(key)?))
+        }
+    }
+
+    fn backend_error(e: impl std::fmt::Display) -> BlobError {
+        BlobError::Backend(e.to_string())
+    }
+
+    impl BlobStore for S3Store {
+        fn name(&self) -> &'static str {
+            "s3"
+        }
+
+        fn put<'a>(&'a self, key: &'a str, path: &'a Path, content_type: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+            Box::pin(async move {
+                let body = ByteStream::from_path(path).await.map_err(backend_error)?;
+                self.client
+                    .put_object()
+                    .bucket(&self.bucket)
+                    .key(self.object_key(key)?)
+                    .content_type(content_type)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(backend_error)?;
+                Ok(())
+            })
+        }
+
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<BlobStream, BlobError>> {
+            Box::pin(async move {
+                let output = self
+                    .client
+                    .get_object()
+                    .bucket(&self.bucket)
+                    .key(self.object_key(key)?)
+                    .send()
+                    .await
+                    .map_err(|e| match e.as_service_error() {
+                        Some(service) if service.is_no_such_key() => BlobError::NotFound,
+                        _ => backend_error(e),
+                    })?;
+                Ok(ReaderStream::new(output.body.into_async_read()).boxed())
+            })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), BlobError>> {
+            Box::pin(async move {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .key(self.object_key(key)?)
+                    .send()
+                    .await
+                    .map_err(backend_error)?;
+                Ok(())
+            })
+        }
+    }
+}
+
+// The store named by FIELD_EDITOR_BLOB_STORE:
+// `local` (the default) keeps files in FIELD_EDITOR_BLOB_DIR;
+// `webdav` puts them under FIELD_EDITOR_WEBDAV_URL, logging in with
+// FIELD_EDITOR_WEBDAV_USER and FIELD_EDITOR_WEBDAV_PASSWORD if set;
+// `s3` (with the `s3` feature) puts them in FIELD_EDITOR_S3_BUCKET under FIELD_EDITOR_S3_PREFIX
+pub async fn blob_store_from_env() -> Result<Arc<dyn BlobStore>, BlobError> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let missing = |name: &str| BlobError::Backend(format!("{} is not set", name));
+    match var("FIELD_EDITOR_BLOB_STORE").as_deref().unwrap_or("local") {
+        "local" => {
+            let dir = var("FIELD_EDITOR_BLOB_DIR").unwrap_or_else(|| "/tmp/field-editor-blobs".to_string());
+            Ok(Arc::new(LocalDirStore::new(dir)))
+        }
+        "webdav" => {
+            let url = var("FIELD_EDITOR_WEBDAV_URL").ok_or_else(|| missing("FIELD_EDITOR_WEBDAV_URL"))?;
+            let credentials = var("FIELD_EDITOR_WEBDAV_USER")
+                .map(|user| (user, var("FIELD_EDITOR_WEBDAV_PASSWORD").unwrap_or_default()));
+            Ok(Arc::new(WebDavStore::new(&url, credentials)))
+        }
+        #[cfg(feature = "s3")]
+        "s3" => {
+            let bucket = var("FIELD_EDITOR_S3_BUCKET").ok_or_else(|| missing("FIELD_EDITOR_S3_BUCKET"))?;
+            let prefix = var("FIELD_EDITOR_S3_PREFIX").unwrap_or_default();
+            Ok(Arc::new(S3Store::new(&bucket, &prefix).await))
+        }
+        other => Err(BlobError::Backend(format!("Unknown blob store {}", other))),
+    }
+}
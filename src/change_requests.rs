@@ -0,0 +1,365 @@
+use crate::auth::use_user_session;
+use crate::db::FieldValues;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::format_timestamp;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// With FIELD_EDITOR_REQUIRE_APPROVAL set, saves by users the policy doesn't
+// allow to approve changes aren't applied right away. They are kept as change
+// requests instead, and an approver applies or rejects each one. Applying is
+// an ordinary save at the version the request was made against, so a request
+// whose record moved on since can't overwrite what happened meanwhile; it
+// stays in the queue until someone rejects it.
+
+// A save waiting for approval
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct ChangeRequest {
+    pub id: i64,
+    pub record_id: i64,
+    // The version the requester edited
+    pub base_version: i64,
+    #[cfg_attr(feature = "ssr", sqlx(rename = "field_values"))]
+    pub values: FieldValues,
+    pub requested_by: String,
+    pub requested_at: i64,
+}
+
+// What became of approving a change request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ApprovalOutcome {
+    // Saved; the record is now at `version`
+    Approved { version: i64 },
+    // The record moved past the request's version, or is in the trash
+    Conflict,
+    // Already approved or rejected, or not this tenant's
+    NotPending,
+}
+
+// Why a save that can't become a change request was refused
+pub const APPROVAL_REQUIRED: &str =
+    "Your changes need approval; save each record on its own in the editor or with PUT or PATCH";
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ApprovalOutcome, ChangeRequest};
+    use crate::auth::User;
+    use crate::db::{apply_update, DbManager, FieldValues};
+    use crate::events::FieldEvent;
+    use crate::policy::{allowed, Action, ResourceKind};
+    use std::sync::OnceLock;
+
+    // Whether saves need approval, from FIELD_EDITOR_REQUIRE_APPROVAL
+    pub fn approval_required() -> bool {
+        static REQUIRED: OnceLock<bool> = OnceLock::new();
+        *REQUIRED.get_or_init(|| {
+            std::env::var("FIELD_EDITOR_REQUIRE_APPROVAL").is_ok_and(|v| !v.is_empty() && v != "0" && v != "false")
+        })
+    }
+
+    // Whether `user`'s saves to a record have to go through a change request
+    pub async fn needs_approval(db: &DbManager, user: &User, id: i64) -> bool {
+        approval_required() && !allowed(db, user, Action::Approve, ResourceKind::Record(id)).await
+    }
+
+    impl DbManager {
+        // Keep a save for approval; returns the request's id. A retry with the
+        // same idempotency key gets the first attempt's request back.
+        #[tracing::instrument(skip(self, values), fields(tenant = %self.tenant()))]
+        pub async fn request_change(
+            &self,
+            id: i64,
+            user: &str,
+            values: &FieldValues,
+            base_version: i64,
+            idempotency_key: Option<&str>,
+        ) -> Result<i64, sqlx::Error> {
+            // Sealed like the values in the audit log, if keys are installed
+            let stored = match crate::keys::keyring() {
+                Some(keyring) => values.0.iter().map(|(name, value)| (name.as_str(), keyring.seal_field(name, value))).collect(),
+                None => values.clone(),
+            };
+            let stored = serde_json::to_string(&stored).expect("FieldValues serialize");
+            self.retrying("request_change", || async {
+                let mut tx = self.pool().begin().await?;
+                let inserted = sqlx::query(
+                    r#"
+                    INSERT INTO field_change_requests
+                        (tenant_id, record_id, base_version, field_values, requested_by, requested_at, idempotency_key)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (requested_by, idempotency_key) DO NOTHING
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(id)
+                .bind(base_version)
+                .bind(&stored)
+                .bind(user)
+                .bind(self.now())
+                .bind(idempotency_key)
+                .execute(&mut *tx)
+                .await?;
+                let request_id = match inserted.rows_affected() {
+                    0 => {
+                        sqlx::query_scalar(
+                            "SELECT id FROM field_change_requests WHERE requested_by = ? AND idempotency_key = ?",
+                        )
+                        .bind(user)
+                        .bind(idempotency_key)
+                        .fetch_one(&mut *tx)
+                        .await?
+                    }
+                    _ => inserted.last_insert_rowid(),
+                };
+                tx.commit().await?;
+                Ok(request_id)
+            })
+            .await
+        }
+
+        // The tenant's change requests still waiting, oldest first
+        pub async fn pending_change_requests(&self) -> Result<Vec<ChangeRequest>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT id, record_id, base_version, field_values, requested_by, requested_at
+                FROM field_change_requests
+                WHERE tenant_id = ? AND status = 'pending'
+                ORDER BY id
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+
+        // Apply a change request as a save by its requester, if the record is
+        // still at the version it was made against
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn approve_change_request(&self, request_id: i64, approver: &str) -> Result<ApprovalOutcome, sqlx::Error> {
+            let outcome = self
+                .retrying("approve_change_request", || self.approve_change_request_in_tx(request_id, approver))
+                .await?;
+            if let Some(record_id) = outcome.1 {
+                self.forget_cached(record_id).await;
+            }
+            Ok(outcome.0)
+        }
+
+        async fn approve_change_request_in_tx(
+            &self,
+            request_id: i64,
+            approver: &str,
+        ) -> Result<(ApprovalOutcome, Option<i64>), sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            let request: Option<ChangeRequest> = sqlx::query_as(
+                r#"
+                SELECT id, record_id, base_version, field_values, requested_by, requested_at
+                FROM field_change_requests
+                WHERE id = ? AND tenant_id = ? AND status = 'pending'
+                "#,
+            )
+            .bind(request_id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(request) = request else {
+                return Ok((ApprovalOutcome::NotPending, None));
+            };
+            let Some(record) = self.scope(&mut tx, request.record_id).await? else {
+                return Ok((ApprovalOutcome::Conflict, None));
+            };
+            let live: Option<i64> = sqlx::query_scalar("SELECT version FROM fields WHERE id = ? AND deleted_at IS NULL")
+                .bind(request.record_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+            let now = self.now();
+            let applied = live == Some(request.base_version)
+                && apply_update(
+                    &mut tx,
+                    record,
+                    &request.requested_by,
+                    &request.values,
+                    request.base_version,
+                    &FieldEvent::Updated,
+                    now,
+                )
+                .await?;
+            if !applied {
+                return Ok((ApprovalOutcome::Conflict, None));
+            }
+            sqlx::query("UPDATE field_change_requests SET status = 'approved', decided_by = ?, decided_at = ? WHERE id = ?")
+                .bind(approver)
+                .bind(now)
+                .bind(request_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Ok((
+                ApprovalOutcome::Approved {
+                    version: request.base_version + 1,
+                },
+                Some(request.record_id),
+            ))
+        }
+
+        // Turn a change request down; returns false if it wasn't pending
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn reject_change_request(&self, request_id: i64, approver: &str) -> Result<bool, sqlx::Error> {
+            let result = sqlx::query(
+                r#"
+                UPDATE field_change_requests SET status = 'rejected', decided_by = ?, decided_at = ?
+                WHERE id = ? AND tenant_id = ? AND status = 'pending'
+                "#,
+            )
+            .bind(approver)
+            .bind(self.now())
+            .bind(request_id)
+            .bind(self.tenant().as_str())
+            .execute(self.pool())
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+    }
+}
+
+#[server(ListChangeRequests)]
+pub async fn list_change_requests() -> Result<Vec<ChangeRequest>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::Approve, ResourceKind::Records).await?;
+    db.pending_change_requests().await.map_err(db_error)
+}
+
+#[server(ApproveChangeRequest)]
+pub async fn approve_change_request(
+    request_id: i64,
+    csrf_token: String,
+) -> Result<ApprovalOutcome, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Approve, ResourceKind::Records).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .approve_change_request(request_id, &user.name)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+#[server(RejectChangeRequest)]
+pub async fn reject_change_request(request_id: i64, csrf_token: String) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Approve, ResourceKind::Records).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .reject_change_request(request_id, &user.name)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+/// Lists the change requests waiting for approval, for approvers to apply or
+/// reject one by one.
+#[component]
+pub fn ChangeRequestQueue() -> impl IntoView {
+    let session = use_user_session();
+    let source = RwSignal::new(());
+    let requests = Resource::new(move || (source.get(), session.user.get()), |_| list_change_requests());
+    // What became of the last approval or rejection
+    let message = RwSignal::new(None::<String>);
+    let tasks = Tasks::new();
+    let csrf_token = move || session.csrf_token.get_untracked().flatten().unwrap_or_default();
+
+    let on_approve = move |request_id: i64| {
+        let csrf_token = csrf_token();
+        tasks.spawn(async move {
+            message.set(Some(match approve_change_request(request_id, csrf_token).await {
+                Ok(ApprovalOutcome::Approved { version }) => format!("Applied as version {}.", version),
+                Ok(ApprovalOutcome::Conflict) => {
+                    "The record changed since this request was made, so it wasn't applied. Reject it and ask for it again."
+                        .to_string()
+                }
+                Ok(ApprovalOutcome::NotPending) => "Someone else already decided on this request.".to_string(),
+                Err(ServerFnError::WrappedServerError(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            }));
+            source.set(());
+        });
+    };
+    let on_reject = move |request_id: i64| {
+        let csrf_token = csrf_token();
+        tasks.spawn(async move {
+            message.set(match reject_change_request(request_id, csrf_token).await {
+                Ok(true) => Some("Rejected.".to_string()),
+                Ok(false) => Some("Someone else already decided on this request.".to_string()),
+                Err(ServerFnError::WrappedServerError(e)) => Some(e.to_string()),
+                Err(e) => Some(e.to_string()),
+            });
+            source.set(());
+        });
+    };
+
+    view! {
+        <div class="field-editor">
+            <h1>"Change requests"</h1>
+            {move || message.get().map(|m| view! { <div class="notice">{m}</div> })}
+            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
+                {move || requests.get().map(|result| match result {
+                    Err(e) => view! { <div class="error">"Error loading change requests: " {e.to_string()}</div> }.into_any(),
+                    Ok(requests) if requests.is_empty() => view! { <p>"Nothing is waiting for approval."</p> }.into_any(),
+                    Ok(requests) => view! {
+                        <ul class="record-list change-requests">
+                            {requests.into_iter().map(|request| {
+                                let request_id = request.id;
+                                let values = request
+                                    .values
+                                    .0
+                                    .iter()
+                                    .map(|(name, value)| format!("{}: {}", name, value))
+                                    .collect::<Vec<_>>()
+                                    .join(", ");
+                                view! {
+                                    <li>
+                                        <a class="record-title" href=format!("/records/{}", request.record_id)>
+                                            "Record " {request.record_id}
+                                        </a>
+                                        <span class="record-meta">
+                                            {format!(
+                                                "{} at {}, from version {}: {}",
+                                                request.requested_by,
+                                                format_timestamp(request.requested_at),
+                                                request.base_version,
+                                                values,
+                                            )}
+                                        </span>
+                                        <button on:click=move |_| on_approve(request_id)>"Approve"</button>
+                                        <button class="danger" on:click=move |_| on_reject(request_id)>"Reject"</button>
+                                    </li>
+                                }
+                            }).collect_view()}
+                        </ul>
+                    }
+                    .into_any(),
+                })}
+            </Suspense>
+        </div>
+    }
+}
@@ -0,0 +1,378 @@
+
+use crate::events::EventEnvelope;
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use crate::events::{EventEnvelope, FieldEvent};
+    use crate::db::DbManager;
+    use crate::tenant::{TenantDb, TenantId};
+    use actix_web::{web, HttpResponse};
+    use std::time::Duration;
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+    const POLL_BATCH: i64 = 100;
+    const KEEPALIVE: Duration = Duration::from_secs(15);
+
+    // Fans out record events to the SSE clients connected to this instance.
+    // Each instance tails the shared history and lock event tables on its own,
+    // so a change made through any instance reaches every client without sticky
+    // sessions.
+    #[derive(Clone)]
+    pub struct ChangeFeed {
+        sender: broadcast::Sender<(TenantId, EventEnvelope)>,
+    }
+
+    // A subscription to one tenant's events
+    pub struct TenantChanges {
+        tenant: TenantId,
+        receiver: broadcast::Receiver<(TenantId, EventEnvelope)>,
+    }
+
+    impl TenantChanges {
+        // The next event of the tenant, skipping other tenants'
+        pub async fn recv(&mut self) -> Result<EventEnvelope, RecvError> {
+            loop {
+                let (tenant, change) = self.receiver.recv().await?;
+                if tenant == self.tenant {
+                    return Ok(change);
+                }
+            }
+        }
+    }
+
+    impl ChangeFeed {
+        // Start tailing the history and lock event tables from their current end
+        pub fn spawn(db: DbManager) -> Self {
+            let (sender, _) = broadcast::channel(256);
+            let feed = ChangeFeed { sender };
+            actix_web::rt::spawn(feed.clone().tail(db));
+            feed
+        }
+
+        pub fn subscribe(&self, tenant: TenantId) -> TenantChanges {
+            TenantChanges {
+                tenant,
+                receiver: self.sender.subscribe(),
+            }
+        }
+
+        async fn tail(self, db: DbManager) {
+            let (mut cursor, mut lock_cursor) = loop {
+                match (db.latest_change_id().await, db.latest_lock_event_id().await) {
+                    (Ok(id), Ok(lock_id)) => break (id, lock_id),
+                    (Err(e), _) | (_, Err(e)) => {
+                        tracing::warn!(error = %e, "Failed to read change log position");
+                        actix_web::rt::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            };
+
+            let mut ticker = actix_web::rt::time::interval(POLL_INTERVAL);
+            loop {
+                ticker.tick().await;
+                match db.changes_after(cursor, POLL_BATCH).await {
+                    Ok(changes) => {
+                        for change in changes {
+                            cursor = change.1.id;
+                            // No receivers just means nobody is listening right now
+                            let _ = self.sender.send(change);
+                        }
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Failed to poll change log"),
+                }
+                match db.lock_events_after(lock_cursor, POLL_BATCH).await {
+                    Ok(locks) => {
+                        for lock in locks {
+                            lock_cursor = lock.1.id;
+                            let _ = self.sender.send(lock);
+                        }
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Failed to poll lock events"),
+                }
+            }
+        }
+    }
+
+    // Server-sent event stream of record changes
+    #[actix_web::get("/api/events")]
+    pub async fn events_stream(feed: web::Data<ChangeFeed>, db: TenantDb) -> HttpResponse {
+        let stream = futures::stream::unfold(feed.subscribe(db.tenant().clone()), |mut rx| async move {
+            let message = tokio::select! {
+                change = rx.recv() => match change {
+                    // Lock events are numbered apart from the audit log, so
+                    // only audit log positions are given as event ids
+                    Ok(change) if matches!(change.event, FieldEvent::LockAcquired | FieldEvent::LockReleased) => format!(
+                        "event: field-event\ndata: {}\n\n",
+                        serde_json::to_string(&change).expect("EventEnvelope serializes")
+                    ),
+                    Ok(change) => format!(
+                        "id: {}\nevent: field-event\ndata: {}\n\n",
+                        change.id,
+                        serde_json::to_string(&change).expect("EventEnvelope serializes")
+                    ),
+                    // We dropped events for this client; tell it to refetch everything
+                    Err(RecvError::Lagged(_)) => "event: resync\ndata: {}\n\n".to_string(),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = tokio::time::sleep(KEEPALIVE) => ": keepalive\n\n".to_string(),
+            };
+            Some((Ok::<_, actix_web::Error>(web::Bytes::from(message)), rx))
+        });
+
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(stream)
+    }
+}
+
+// Changes to a record newer than the version a client last saw,
+// so a client that lost its live connection can catch up on what it missed
+#[leptos::server(ChangesSince)]
+pub async fn changes_since(
+    record_id: i64,
+    version: i64,
+) -> Result<Vec<EventEnvelope>, leptos::prelude::ServerFnError> {
+    use crate::field_editor::{db_error, open_db};
+
+    let db = open_db().await?;
+    db.changes_since(record_id, version).await.map_err(db_error)
+}
+
+#[cfg(feature = "hydrate")]
+pub use client::*;
+
+#[cfg(feature = "hydrate")]
+mod client {
+    use crate::events::EventEnvelope;
+    use leptos::prelude::set_timeout;
+    use send_wrapper::SendWrapper;
+    use std::cell::{Cell, RefCell};
+    use std::rc::{Rc, Weak};
+    use std::time::Duration;
+    use wasm_bindgen::prelude::*;
+    use web_sys::{Event, EventSource, MessageEvent};
+
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    // What a live client hears from the server
+    #[derive(Clone)]
+    pub enum LiveUpdate {
+        Change(EventEnvelope),
+        // Events were dropped; the client should assume anything may have changed
+        Resync,
+        // The connection broke; a reconnect is scheduled
+        Disconnected,
+        // The connection is back after a drop; changes made meanwhile were
+        // never delivered, so the client must catch up with `changes_since`
+        Reconnected,
+    }
+
+    struct Connection {
+        source: EventSource,
+        _listeners: Vec<Closure<dyn FnMut(Event)>>,
+    }
+
+    struct Inner {
+        connection: Option<Connection>,
+        // Failed attempts since the last successful connection
+        attempt: u32,
+        reconnect_scheduled: bool,
+        closed: bool,
+        on_update: Rc<dyn Fn(LiveUpdate)>,
+    }
+
+    type Listeners = Rc<RefCell<Vec<(u64, Rc<dyn Fn(LiveUpdate)>)>>>;
+
+    // One connection to the change stream for the whole tab, however many
+    // editors and lists on the page subscribe. It reconnects with backoff
+    // whenever it drops and is closed once the last subscriber is gone.
+    struct Shared {
+        inner: Rc<RefCell<Inner>>,
+        listeners: Listeners,
+        next_listener: Cell<u64>,
+    }
+
+    impl Drop for Shared {
+        fn drop(&mut self) {
+            let mut inner = self.inner.borrow_mut();
+            inner.closed = true;
+            if let Some(connection) = inner.connection.take() {
+                connection.source.close();
+            }
+        }
+    }
+
+    thread_local! {
+        static SHARED: RefCell<Weak<Shared>> = const { RefCell::new(Weak::new()) };
+    }
+
+    // The tab's connection, opened if nobody is subscribed yet
+    fn shared() -> Rc<Shared> {
+        SHARED.with(|current| {
+            if let Some(shared) = current.borrow().upgrade() {
+                return shared;
+            }
+            let listeners: Listeners = Rc::new(RefCell::new(Vec::new()));
+            let on_update = {
+                let listeners = listeners.clone();
+                move |update: LiveUpdate| {
+                    // Copied out first so a listener may subscribe or unsubscribe
+                    let current: Vec<_> = listeners.borrow().iter().map(|(_, l)| l.clone()).collect();
+                    for listener in current {
+                        listener(update.clone());
+                    }
+                }
+            };
+            let inner = Rc::new(RefCell::new(Inner {
+                connection: None,
+                attempt: 0,
+                reconnect_scheduled: false,
+                closed: false,
+                on_update: Rc::new(on_update),
+            }));
+            connect(&inner);
+            let shared = Rc::new(Shared {
+                inner,
+                listeners,
+                next_listener: Cell::new(0),
+            });
+            *current.borrow_mut() = Rc::downgrade(&shared);
+            shared
+        })
+    }
+
+    // A listener on the tab's change stream. Stops listening when dropped.
+    pub struct ChangeSubscription {
+        shared: SendWrapper<Rc<Shared>>,
+        id: u64,
+    }
+
+    impl Drop for ChangeSubscription {
+        fn drop(&mut self) {
+            self.shared.listeners.borrow_mut().retain(|(id, _)| *id != self.id);
+        }
+    }
+
+    pub fn subscribe(on_update: impl Fn(LiveUpdate) + 'static) -> ChangeSubscription {
+        let shared = shared();
+        let id = shared.next_listener.get();
+        shared.next_listener.set(id + 1);
+        // Someone joining while the shared connection is down hears about it too
+        if shared.inner.borrow().attempt > 0 {
+            on_update(LiveUpdate::Disconnected);
+        }
+        shared.listeners.borrow_mut().push((id, Rc::new(on_update)));
+        ChangeSubscription {
+            shared: SendWrapper::new(shared),
+            id,
+        }
+    }
+
+    fn connect(inner: &Rc<RefCell<Inner>>) {
+        let Ok(source) = EventSource::new("/api/events") else {
+            schedule_reconnect(Rc::downgrade(inner));
+            return;
+        };
+
+        let listen = |event: &str, handler: Box<dyn FnMut(Event)>| {
+            let closure = Closure::wrap(handler);
+            let _ = source.add_event_listener_with_callback(event, closure.as_ref().unchecked_ref());
+            closure
+        };
+
+        let on_change = {
+            let on_update = inner.borrow().on_update.clone();
+            listen(
+                "field-event",
+                Box::new(move |ev: Event| {
+                    let change = ev
+                        .dyn_into::<MessageEvent>()
+                        .ok()
+                        .and_then(|ev| ev.data().as_string())
+                        .and_then(|data| serde_json::from_str(&data).ok());
+                    if let Some(change) = change {
+                        on_update(LiveUpdate::Change(change));
+                    }
+                }),
+            )
+        };
+        let on_resync = {
+            let on_update = inner.borrow().on_update.clone();
+            listen("resync", Box::new(move |_| on_update(LiveUpdate::Resync)))
+        };
+        let on_open = {
+            let weak = Rc::downgrade(inner);
+            listen(
+                "open",
+                Box::new(move |_| {
+                    let Some(inner) = weak.upgrade() else { return };
+                    let (reconnected, on_update) = {
+                        let mut inner = inner.borrow_mut();
+                        let reconnected = inner.attempt > 0;
+                        inner.attempt = 0;
+                        (reconnected, inner.on_update.clone())
+                    };
+                    if reconnected {
+                        on_update(LiveUpdate::Reconnected);
+                    }
+                }),
+            )
+        };
+        let on_error = {
+            let weak = Rc::downgrade(inner);
+            listen("error", Box::new(move |_| schedule_reconnect(weak.clone())))
+        };
+
+        inner.borrow_mut().connection = Some(Connection {
+            source,
+            _listeners: vec![on_change, on_resync, on_open, on_error],
+        });
+    }
+
+    // Tear down the broken connection and try again after an exponential backoff.
+    // The browser's own retry gives up on some failures and never tells us what
+    // we missed, so we always manage reconnects ourselves.
+    fn schedule_reconnect(weak: Weak<RefCell<Inner>>) {
+        let Some(inner) = weak.upgrade() else { return };
+        let (delay, on_update) = {
+            let mut inner = inner.borrow_mut();
+            if inner.closed || inner.reconnect_scheduled {
+                return;
+            }
+            inner.reconnect_scheduled = true;
+            if let Some(connection) = &inner.connection {
+                connection.source.close();
+            }
+            let delay = INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(inner.attempt))
+                .min(MAX_BACKOFF);
+            inner.attempt += 1;
+            (delay, inner.on_update.clone())
+        };
+        on_update(LiveUpdate::Disconnected);
+
+        set_timeout(
+            move || {
+                let Some(inner) = weak.upgrade() else { return };
+                {
+                    let mut state = inner.borrow_mut();
+                    state.reconnect_scheduled = false;
+                    if state.closed {
+                        return;
+                    }
+                    // Dropped here rather than in the error handler, which may
+                    // still be running inside one of these closures
+                    state.connection = None;
+                }
+                connect(&inner);
+            },
+            delay,
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::RelativeTime;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Collaborators can leave comments on a field of a record, to say why a value
+// is what it is. Comments belong to the record and field rather than to a
+// version, so they stay next to the field as its value changes.
+
+// Longest comment accepted, in characters
+pub const MAX_COMMENT_LEN: usize = 2000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct FieldComment {
+    pub id: i64,
+    pub author: String,
+    pub body: String,
+    pub created_at: i64,
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::FieldComment;
+    use crate::db::DbManager;
+
+    impl DbManager {
+        // Comment on a field of a record. Returns the comment's id, or None if
+        // the record isn't this tenant's.
+        #[tracing::instrument(skip(self, body))]
+        pub async fn add_comment(&self, id: i64, field: &str, author: &str, body: &str) -> Result<Option<i64>, sqlx::Error> {
+            self.retrying("add_comment", || async {
+                let mut tx = self.pool().begin().await?;
+                let Some(record) = self.scope(&mut tx, id).await? else {
+                    return Ok(None);
+                };
+                let added = sqlx::query(
+                    r#"
+                    INSERT INTO field_comments (tenant_id, record_id, field_name, author, body, created_at)
+                    VALUES (?, ?, ?, ?, ?, ?)
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(record.id())
+                .bind(field)
+                .bind(author)
+                .bind(body)
+                .bind(self.now())
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(Some(added.last_insert_rowid()))
+            })
+            .await
+        }
+
+        // The comments on a field of a record, oldest first
+        pub async fn comments(&self, id: i64, field: &str) -> Result<Vec<FieldComment>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT id, author, body, created_at FROM field_comments
+                WHERE record_id = ? AND field_name = ? AND tenant_id = ?
+                ORDER BY id
+                "#,
+            )
+            .bind(id)
+            .bind(field)
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+    }
+}
+
+#[server(AddComment)]
+pub async fn add_comment(
+    id: i64,
+    field: String,
+    body: String,
+    csrf_token: String,
+) -> Result<i64, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::db::FieldValues;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let body = body.trim();
+    if body.is_empty() || body.chars().count() > MAX_COMMENT_LEN {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "Comments are 1 to {} characters long",
+            MAX_COMMENT_LEN
+        ))));
+    }
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    let undefined = db
+        .undefined_fields(&FieldValues::from_iter([(field.as_str(), "")]))
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    if !undefined.is_empty() {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "There is no field named {}",
+            field
+        ))));
+    }
+    db.add_comment(id, &field, &user.name, body)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?
+        .ok_or_else(|| ServerFnError::WrappedServerError(EditorError::Other("No such record".to_string())))
+}
+
+#[server(ListComments)]
+pub async fn list_comments(id: i64, field: String) -> Result<Vec<FieldComment>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.comments(id, &field).await.map_err(db_error)
+}
+
+/// A button next to a field that opens the discussion about it: the comments
+/// left on the field so far, and a box for signed-in users to add one.
+#[component]
+pub fn FieldComments(
+    /// The record.
+    record_id: i64,
+    /// The field's name.
+    field: String,
+) -> impl IntoView {
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let open = RwSignal::new(false);
+    let added = RwSignal::new(());
+    let field = StoredValue::new(field);
+    // Only fetched once the popover is opened
+    let comments = Resource::new(
+        move || open.get().then(|| added.get()),
+        move |opened| async move {
+            match opened {
+                Some(()) => list_comments(record_id, field.get_value()).await.map(Some),
+                None => Ok(None),
+            }
+        },
+    );
+    let draft = RwSignal::new(String::new());
+    let posting = RwSignal::new(false);
+    let error = RwSignal::new(None::<String>);
+    let tasks = Tasks::new();
+
+    let on_post = move |_| {
+        error.set(None);
+        posting.set(true);
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        let body = draft.get_untracked();
+        tasks.spawn(async move {
+            let result = add_comment(record_id, field.get_value(), body, csrf_token).await;
+            posting.set(false);
+            match result {
+                Ok(_) => {
+                    draft.set(String::new());
+                    added.set(());
+                }
+                Err(ServerFnError::WrappedServerError(e)) => error.set(Some(e.to_string())),
+                Err(e) => error.set(Some(e.to_string())),
+            }
+        });
+    };
+
+    view! {
+        <span class="field-comments">
+            <button
+                class="link"
+                aria-expanded=move || open.get().to_string()
+                on:click=move |_| open.update(|open| *open = !*open)
+            >
+                "Comments"
+            </button>
+            {move || open.get().then(|| view! {
+                <div class="comment-popover">
+                    <Transition fallback=|| view! { <div>"Loading..."</div> }>
+                        {move || comments.get().map(|result| match result {
+                            Ok(Some(comments)) if comments.is_empty() => {
+                                view! { <p class="record-meta">"No comments on this field yet."</p> }.into_any()
+                            }
+                            Ok(Some(comments)) => {
+                                view! {
+                                    <ul>
+                                        {comments.into_iter().map(|comment| view! {
+                                            <li>
+                                                <div class="record-meta">
+                                                    <strong>{comment.author}</strong> " "
+                                                    <RelativeTime at=comment.created_at/>
+                                                </div>
+                                                <p>{comment.body}</p>
+                                            </li>
+                                        }).collect_view()}
+                                    </ul>
+                                }
+                                .into_any()
+                            }
+                            Ok(None) => ().into_any(),
+                            Err(e) => view! { <div class="error">"Error loading comments: " {e.to_string()}</div> }.into_any(),
+                        })}
+                    </Transition>
+                    {move || signed_in().then(|| view! {
+                        <textarea
+                            maxlength=MAX_COMMENT_LEN
+                            placeholder="Why is this value what it is?"
+                            prop:value=draft
+                            on:input=move |ev| draft.set(event_target_value(&ev))
+                        ></textarea>
+                        <button
+                            on:click=on_post
+                            disabled=move || posting.get() || draft.with(|d| d.trim().is_empty())
+                        >
+                            "Comment"
+                        </button>
+                    })}
+                    {move || error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+                </div>
+            })}
+        </span>
+    }
+    .into_any()
+}
@@ -0,0 +1,259 @@
+use crate::auth::use_user_session;
+use crate::events::{EventEnvelope, FieldEvent};
+use crate::timestamp::{format_time_of_day, format_timestamp};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// The audit log entries around a rejected save: from the save that produced the
+// version the editor had loaded up to the conflict itself, plus when each
+// person involved opened the record
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConflictTimeline {
+    pub conflict: EventEnvelope,
+    // Oldest first, ending with the conflict
+    pub steps: Vec<EventEnvelope>,
+}
+
+impl ConflictTimeline {
+    // The version the rejected save was based on
+    pub fn expected_version(&self) -> i64 {
+        match self.conflict.event {
+            FieldEvent::ConflictDetected { expected_version } => expected_version,
+            _ => self.conflict.version,
+        }
+    }
+
+    // The saves that got in between loading and saving
+    pub fn competing_saves(&self) -> impl Iterator<Item = &EventEnvelope> {
+        let expected = self.expected_version();
+        self.steps
+            .iter()
+            .filter(move |step| step.event.changes_state() && step.version > expected)
+    }
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::ConflictTimeline;
+    use crate::db::{DbManager, HistoryRow, HISTORY_COLUMNS};
+    use crate::events::{EventEnvelope, FieldEvent};
+
+    // How far back to look for the start of a conflict
+    const TIMELINE_SCAN_LIMIT: i64 = 200;
+
+    impl DbManager {
+        // The story behind the user's latest rejected save on a record, if they have one
+        pub async fn conflict_timeline(&self, record_id: i64, user: &str) -> Result<Option<ConflictTimeline>, sqlx::Error> {
+            let conflict = sqlx::query_as::<_, HistoryRow>(&format!(
+                r#"
+                SELECT {} FROM fields_history
+                WHERE record_id = ? AND tenant_id = ? AND changed_by = ? AND change_type = 'conflict_detected'
+                ORDER BY id DESC LIMIT 1
+                "#,
+                HISTORY_COLUMNS
+            ))
+            .bind(record_id)
+            .bind(self.tenant().as_str())
+            .bind(user)
+            .fetch_optional(self.pool())
+            .await?;
+            let Some(conflict) = conflict.map(EventEnvelope::from) else {
+                return Ok(None);
+            };
+            let expected_version = match conflict.event {
+                FieldEvent::ConflictDetected { expected_version } => expected_version,
+                _ => conflict.version,
+            };
+
+            // Newest first
+            let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+                "SELECT {} FROM fields_history WHERE record_id = ? AND id < ? ORDER BY id DESC LIMIT ?",
+                HISTORY_COLUMNS
+            ))
+            .bind(record_id)
+            .bind(conflict.id)
+            .bind(TIMELINE_SCAN_LIMIT)
+            .fetch_all(self.pool())
+            .await?;
+            // Walk back to the save that produced the version the editor loaded
+            let mut steps = vec![conflict.clone()];
+            for entry in rows.into_iter().map(EventEnvelope::from) {
+                let loaded = entry.event.changes_state() && entry.version == expected_version;
+                steps.push(entry);
+                if loaded {
+                    break;
+                }
+            }
+
+            steps.sort_by_key(|step| step.id);
+
+            // Everyone who opened the record in that time, and for those who
+            // saved or conflicted without opening it then, when they did before.
+            // Opening isn't in the audit log, so these come from the lock events.
+            let start = steps.first().map_or(conflict.occurred_at, |step| step.occurred_at);
+            let mut missing: Vec<String> = Vec::new();
+            for step in &steps {
+                if !(step.event.changes_state() || step.id == conflict.id) {
+                    continue;
+                }
+                if let Some(actor) = &step.actor {
+                    if !missing.contains(actor) {
+                        missing.push(actor.clone());
+                    }
+                }
+            }
+            let opened = self
+                .record_opened_before(record_id, conflict.occurred_at, TIMELINE_SCAN_LIMIT)
+                .await?;
+            for entry in opened {
+                let Some(actor) = entry.actor.clone() else {
+                    continue;
+                };
+                let position = missing.iter().position(|m| *m == actor);
+                if entry.occurred_at >= start {
+                    missing.retain(|m| *m != actor);
+                    steps.push(entry);
+                } else if let Some(pos) = position {
+                    missing.remove(pos);
+                    steps.push(entry);
+                } else if missing.is_empty() {
+                    break;
+                }
+            }
+
+            // Audit log entries are already in order; lock events go between them
+            // by time, and the conflict stays last
+            steps.sort_by_key(|step| (step.occurred_at, *step == conflict));
+            Ok(Some(ConflictTimeline { conflict, steps }))
+        }
+    }
+}
+
+// The timeline of the signed-in user's most recent conflict on a record
+#[server(ExplainConflict)]
+pub async fn explain_conflict(record_id: i64) -> Result<Option<ConflictTimeline>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    let user = authorize(&db, Action::View, ResourceKind::Record(record_id)).await?;
+    db.conflict_timeline(record_id, &user.name).await.map_err(db_error)
+}
+
+// One line of the timeline, told from the point of view of `me`
+pub(crate) fn describe(step: &EventEnvelope, me: Option<&str>) -> String {
+    let mine = step.actor.is_some() && step.actor.as_deref() == me;
+    let who = match (&step.actor, mine) {
+        (_, true) => "You".to_string(),
+        (Some(actor), false) => actor.clone(),
+        (None, false) => "Someone".to_string(),
+    };
+    match &step.event {
+        FieldEvent::Created => format!("{} created the record (version {})", who, step.version),
+        FieldEvent::Updated => format!("{} saved version {}", who, step.version),
+        FieldEvent::Merged { base_version } => format!(
+            "{} saved version {}, merging edits made on version {} with the changes since",
+            who, step.version, base_version
+        ),
+        FieldEvent::Overwritten { expected_version } => format!(
+            "{} saved version {} over the changes made since version {}",
+            who, step.version, expected_version
+        ),
+        FieldEvent::Deleted => format!("{} moved the record to the trash", who),
+        FieldEvent::Restored => format!("{} restored the record as version {}", who, step.version),
+        FieldEvent::LockAcquired => format!("{} opened the record at version {}", who, step.version),
+        FieldEvent::LockReleased => format!("{} closed the record", who),
+        FieldEvent::AttachmentAdded { .. } => format!("{} attached a file (version {})", who, step.version),
+        FieldEvent::AttachmentReplaced { .. } => {
+            format!("{} replaced an attached file (version {})", who, step.version)
+        }
+        FieldEvent::Reverted { to_version } => format!(
+            "{} brought back version {} as version {}",
+            who, to_version, step.version
+        ),
+        FieldEvent::ExpirySet { expires_at: Some(expires_at) } => {
+            format!("{} set the record to expire on {}", who, format_timestamp(*expires_at))
+        }
+        FieldEvent::ExpirySet { expires_at: None } => format!("{} removed the record's expiry date", who),
+        FieldEvent::Archived => "The record expired and was moved to the trash".to_string(),
+        FieldEvent::ReviewBySet { review_by: Some(review_by) } => {
+            format!("{} asked for the record to be reviewed by {}", who, format_timestamp(*review_by))
+        }
+        FieldEvent::ReviewBySet { review_by: None } => format!("{} removed the record's review date", who),
+        FieldEvent::ReviewDue { review_by, .. } => {
+            format!("The record was due for review on {}", format_timestamp(*review_by))
+        }
+        FieldEvent::ReviewSnoozed { until } => {
+            format!("{} snoozed the review reminders until {}", who, format_timestamp(*until))
+        }
+        FieldEvent::ConflictDetected { expected_version } => format!(
+            "{} tried to save changes to version {}, but the record was already at version {}; the save was rejected",
+            who, expected_version, step.version
+        ),
+        FieldEvent::Unknown => format!("{} did something this version doesn't know about", who),
+    }
+}
+
+/// Explains the user's latest save conflict on a record as a timeline of who
+/// opened and saved it when, taken from the audit log.
+#[component]
+pub fn ConflictExplainer(
+    /// The record the conflict happened on.
+    record_id: i64,
+) -> impl IntoView {
+    let session = use_user_session();
+    let timeline = Resource::new(|| (), move |_| explain_conflict(record_id));
+
+    view! {
+        <div class="conflict-explainer">
+            <h2>"What happened?"</h2>
+            <Suspense fallback=|| view! { <p>"Loading the audit log..."</p> }>
+                {move || timeline.get().map(|result| match result {
+                    Err(e) => view! { <p class="error">"Could not load the audit log: " {e.to_string()}</p> }.into_any(),
+                    Ok(None) => view! { <p>"No rejected save of yours was found for this record."</p> }.into_any(),
+                    Ok(Some(timeline)) => {
+                        let me = session.user.get().flatten().map(|u| u.name);
+                        let expected = timeline.expected_version();
+                        let others: Vec<String> = timeline
+                            .competing_saves()
+                            .filter_map(|save| save.actor.clone())
+                            .filter(|actor| Some(actor) != me.as_ref())
+                            .fold(Vec::new(), |mut names, name| {
+                                if !names.contains(&name) {
+                                    names.push(name);
+                                }
+                                names
+                            });
+                        let others = if others.is_empty() { "Someone".to_string() } else { others.join(", ") };
+                        view! {
+                            <p>
+                                "Your editor had loaded version " {expected} ". While you were editing, "
+                                {others} " saved newer versions. Every save says which version it started from; "
+                                "yours said version " {expected} ", so the server refused it rather than "
+                                "silently overwrite changes you had never seen. This is optimistic concurrency control."
+                            </p>
+                            <ol class="conflict-timeline">
+                                {timeline.steps.iter().map(|step| {
+                                    let mine = step.actor.is_some() && step.actor == me;
+                                    let class = match (&step.event, mine) {
+                                        (FieldEvent::ConflictDetected { .. }, _) => "conflict mine",
+                                        (_, true) => "mine",
+                                        (_, false) => "theirs",
+                                    };
+                                    view! {
+                                        <li class=class>
+                                            <span class="when">{format_time_of_day(step.occurred_at)}</span>
+                                            <span class="what">{describe(step, me.as_deref())}</span>
+                                        </li>
+                                    }
+                                }).collect_view()}
+                            </ol>
+                        }.into_any()
+                    }
+                })}
+            </Suspense>
+        </div>
+    }
+}
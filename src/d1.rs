@@ -0,0 +1,146 @@
+use crate::db::FieldValues;
+use crate::storage::{
+    is_version_conflict, load_statement, now_millis, save_if_version_statements, RecordStorage, SqlArg,
+    StorageError, StoredRecord,
+};
+use futures::future::BoxFuture;
+use js_sys::{Array, Promise, Reflect};
+use send_wrapper::SendWrapper;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+// Records in Cloudflare D1, through the database binding a Worker gets in its
+// environment. D1 has no interactive transactions either, but runs a batch as
+// one: a save is the same version check, write and audit log entry as for
+// libSQL, sent as one batch. The rest of the editor, the actix server and its
+// sqlx pool included, doesn't build for Workers, so this covers records only.
+
+#[wasm_bindgen]
+extern "C" {
+    // The binding, as `env.DB` or whatever wrangler.toml names it
+    pub type D1Database;
+
+    #[wasm_bindgen(method)]
+    fn prepare(this: &D1Database, query: &str) -> D1PreparedStatement;
+
+    #[wasm_bindgen(method, catch)]
+    fn batch(this: &D1Database, statements: Array) -> Result<Promise, JsValue>;
+
+    type D1PreparedStatement;
+
+    #[wasm_bindgen(method, variadic)]
+    fn bind(this: &D1PreparedStatement, values: Box<[JsValue]>) -> D1PreparedStatement;
+
+    // Resolves to `{ results: [row, ...], meta: {...} }`, rows as objects by column name
+    #[wasm_bindgen(method, catch)]
+    fn all(this: &D1PreparedStatement) -> Result<Promise, JsValue>;
+}
+
+// Workers run on one thread; JS values only have to satisfy the trait's bounds
+struct SendFuture<F>(SendWrapper<Pin<Box<F>>>);
+
+impl<F: Future> Future for SendFuture<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+fn send<'a, T>(future: impl Future<Output = T> + 'a) -> BoxFuture<'a, T> {
+    Box::pin(SendFuture(SendWrapper::new(Box::pin(future))))
+}
+
+fn js_error(e: JsValue) -> StorageError {
+    let message = Reflect::get(&e, &"message".into())
+        .ok()
+        .and_then(|m| m.as_string())
+        .or_else(|| e.as_string())
+        .unwrap_or_else(|| format!("{:?}", e));
+    StorageError::Remote(message)
+}
+
+// D1 takes numbers as JS numbers, exact up to 2^53
+fn js_arg(arg: SqlArg) -> JsValue {
+    match arg {
+        SqlArg::Integer(value) => JsValue::from_f64(value as f64),
+        SqlArg::Text(value) => JsValue::from_str(&value),
+    }
+}
+
+pub struct D1Storage {
+    db: SendWrapper<D1Database>,
+}
+
+impl D1Storage {
+    // Use the binding the Worker was handed, e.g. `env.DB`
+    pub fn new(db: D1Database) -> Self {
+        D1Storage { db: SendWrapper::new(db) }
+    }
+
+    fn statement(&self, (sql, args): (String, Vec<SqlArg>)) -> D1PreparedStatement {
+        self.db
+            .prepare(&sql)
+            .bind(args.into_iter().map(js_arg).collect::<Vec<_>>().into_boxed_slice())
+    }
+}
+
+impl RecordStorage for D1Storage {
+    fn name(&self) -> &'static str {
+        "d1"
+    }
+
+    fn load(&self, tenant: &str, id: i64) -> BoxFuture<'_, Result<Option<StoredRecord>, StorageError>> {
+        let statement = load_statement(tenant, id);
+        send(async move {
+            let result = JsFuture::from(self.statement(statement).all().map_err(js_error)?)
+                .await
+                .map_err(js_error)?;
+            let rows = Reflect::get(&result, &"results".into()).map_err(js_error)?;
+            let row = Array::from(&rows).get(0);
+            if row.is_undefined() {
+                return Ok(None);
+            }
+            let version = Reflect::get(&row, &"version".into()).ok().and_then(|v| v.as_f64());
+            let values = Reflect::get(&row, &"field_values".into())
+                .ok()
+                .and_then(|v| v.as_string())
+                .map(|json| serde_json::from_str::<FieldValues>(&json));
+            match (version, values) {
+                (Some(version), Some(Ok(values))) => Ok(Some(StoredRecord {
+                    id,
+                    version: version as i64,
+                    values,
+                })),
+                _ => Err(StorageError::Remote(format!("Record {} came back malformed", id))),
+            }
+        })
+    }
+
+    fn save_if_version<'a>(
+        &'a self,
+        tenant: &'a str,
+        id: i64,
+        user: &'a str,
+        values: &'a FieldValues,
+        expected_version: i64,
+    ) -> BoxFuture<'a, Result<bool, StorageError>> {
+        let statements = save_if_version_statements(tenant, id, user, values, expected_version, now_millis());
+        send(async move {
+            let batch: Array = statements.into_iter().map(|s| JsValue::from(self.statement(s))).collect();
+            let outcome = match self.db.batch(batch) {
+                Ok(promise) => JsFuture::from(promise).await.map_err(js_error),
+                Err(e) => Err(js_error(e)),
+            };
+            match outcome {
+                Ok(_) => Ok(true),
+                // Someone else saved first
+                Err(StorageError::Remote(message)) if is_version_conflict(&message) => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
@@ -0,0 +1,255 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Longest period the dashboard covers
+pub const MAX_DASHBOARD_DAYS: u32 = 365;
+
+// Saves and conflicts across the tenant's records on one UTC day
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct DailyActivity {
+    // YYYY-MM-DD
+    pub day: String,
+    pub saves: i64,
+    pub conflicts: i64,
+}
+
+// Saves and conflicts on one record over the whole period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct RecordActivity {
+    pub record_id: i64,
+    pub title: String,
+    pub saves: i64,
+    pub conflicts: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivitySummary {
+    // Days with any activity, oldest first
+    pub days: Vec<DailyActivity>,
+    // The records with the most conflicts, then saves
+    pub busiest: Vec<RecordActivity>,
+}
+
+// One record's saves, conflicts and distinct editors so far today (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct RecordActivityToday {
+    pub saves: i64,
+    pub conflicts: i64,
+    pub editors: i64,
+}
+
+impl RecordActivityToday {
+    // E.g. "3 people edited this today (7 saves, 1 conflict)"; None before the first save
+    pub fn describe(&self) -> Option<String> {
+        if self.saves == 0 {
+            return None;
+        }
+        let plural = |n: i64, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+        let mut counts = vec![plural(self.saves, "save", "saves")];
+        if self.conflicts > 0 {
+            counts.push(plural(self.conflicts, "conflict", "conflicts"));
+        }
+        Some(format!(
+            "{} edited this today ({})",
+            plural(self.editors, "person", "people"),
+            counts.join(", ")
+        ))
+    }
+
+    // Worth coordinating: more than one editor, or saves that collided
+    pub fn is_busy(&self) -> bool {
+        self.editors > 1 || self.conflicts > 0
+    }
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ActivitySummary, DailyActivity, RecordActivity, RecordActivityToday};
+    use crate::db::{reveal_title, DbManager, TITLE_EXPR};
+
+    // Records listed as the most contended
+    const BUSIEST_RECORDS: i64 = 10;
+
+    impl DbManager {
+        // The tenant's activity over the last `days` UTC days, today included,
+        // from the daily summary rather than the audit log
+        pub async fn activity_summary(&self, days: u32) -> Result<ActivitySummary, sqlx::Error> {
+            let since = format!("-{} days", days.saturating_sub(1));
+            let days: Vec<DailyActivity> = sqlx::query_as(
+                r#"
+                SELECT day, SUM(saves) AS saves, SUM(conflicts) AS conflicts
+                FROM daily_record_activity
+                WHERE tenant_id = ? AND day >= date(? / 1000, 'unixepoch', ?)
+                GROUP BY day ORDER BY day
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .bind(self.now())
+            .bind(&since)
+            .fetch_all(self.pool())
+            .await?;
+            let busiest: Vec<RecordActivity> = sqlx::query_as(&format!(
+                r#"
+                SELECT a.record_id, {} AS title, SUM(a.saves) AS saves, SUM(a.conflicts) AS conflicts
+                FROM daily_record_activity a JOIN fields ON fields.id = a.record_id
+                WHERE a.tenant_id = ? AND a.day >= date(? / 1000, 'unixepoch', ?)
+                GROUP BY a.record_id
+                ORDER BY conflicts DESC, saves DESC, a.record_id
+                LIMIT ?
+                "#,
+                TITLE_EXPR
+            ))
+            .bind(self.tenant().as_str())
+            .bind(self.now())
+            .bind(&since)
+            .bind(BUSIEST_RECORDS)
+            .fetch_all(self.pool())
+            .await?;
+            let busiest = busiest
+                .into_iter()
+                .map(|record| {
+                    Ok(RecordActivity {
+                        title: reveal_title(record.title)?,
+                        ..record
+                    })
+                })
+                .collect::<Result<_, sqlx::Error>>()?;
+            Ok(ActivitySummary { days, busiest })
+        }
+
+        // A record's activity so far today, from the daily summaries
+        pub async fn record_activity_today(&self, id: i64) -> Result<RecordActivityToday, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT
+                    COALESCE(a.saves, 0) AS saves,
+                    COALESCE(a.conflicts, 0) AS conflicts,
+                    (
+                        SELECT COUNT(*) FROM daily_record_editors e
+                        WHERE e.record_id = t.record_id AND e.tenant_id = t.tenant_id AND e.day = t.day
+                    ) AS editors
+                FROM (SELECT ? AS record_id, ? AS tenant_id, date(? / 1000, 'unixepoch') AS day) t
+                LEFT JOIN daily_record_activity a
+                    ON a.record_id = t.record_id AND a.tenant_id = t.tenant_id AND a.day = t.day
+                "#,
+            )
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .bind(self.now())
+            .fetch_one(self.pool())
+            .await
+        }
+    }
+}
+
+#[server(GetActivitySummary)]
+pub async fn get_activity_summary(days: u32) -> Result<ActivitySummary, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.activity_summary(days.clamp(1, MAX_DASHBOARD_DAYS)).await.map_err(db_error)
+}
+
+#[server(GetRecordActivityToday)]
+pub async fn get_record_activity_today(id: i64) -> Result<RecordActivityToday, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.record_activity_today(id).await.map_err(db_error)
+}
+
+/// Says how many people saved a record today, so editors know when others are
+/// working on it too.
+#[component]
+pub fn ActivityToday(
+    /// The record.
+    record_id: i64,
+    /// The version the editor shows; counted again whenever it changes.
+    version: RwSignal<i64>,
+) -> impl IntoView {
+    let activity = Resource::new(move || version.get(), move |_| get_record_activity_today(record_id));
+
+    view! {
+        <Transition fallback=|| ()>
+            {move || activity.get().and_then(Result::ok).and_then(|activity| {
+                let text = activity.describe()?;
+                Some(view! {
+                    <p class="activity-today" class:busy=activity.is_busy()>{text}</p>
+                })
+            })}
+        </Transition>
+    }
+}
+
+/// Shows how many saves and conflicts there were per day, and which records
+/// were the most contended.
+#[component]
+pub fn Dashboard() -> impl IntoView {
+    let days = RwSignal::new(30u32);
+    let summary = Resource::new(move || days.get(), get_activity_summary);
+
+    view! {
+        <div class="field-editor dashboard">
+            <h1>"Dashboard"</h1>
+            <select on:change=move |ev| {
+                if let Ok(value) = event_target_value(&ev).parse() {
+                    days.set(value);
+                }
+            }>
+                {[7u32, 30, 90, 365].into_iter().map(|n| view! {
+                    <option value=n.to_string() selected=move || days.get() == n>{format!("Last {} days", n)}</option>
+                }).collect_view()}
+            </select>
+
+            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
+                {move || summary.get().map(|result| match result {
+                    Err(e) => view! { <div class="error">"Error loading activity: " {e.to_string()}</div> }.into_any(),
+                    Ok(summary) if summary.days.is_empty() => view! { <p>"No saves or conflicts in this period."</p> }.into_any(),
+                    Ok(summary) => {
+                        let (saves, conflicts) = summary
+                            .days
+                            .iter()
+                            .fold((0, 0), |(s, c), day| (s + day.saves, c + day.conflicts));
+                        view! {
+                            <p class="record-meta">{format!("{} saves, {} conflicts", saves, conflicts)}</p>
+                            <table class="report-table">
+                                <thead>
+                                    <tr><th>"Day"</th><th>"Saves"</th><th>"Conflicts"</th></tr>
+                                </thead>
+                                <tbody>
+                                    {summary.days.into_iter().map(|day| view! {
+                                        <tr><td>{day.day}</td><td>{day.saves}</td><td>{day.conflicts}</td></tr>
+                                    }).collect_view()}
+                                </tbody>
+                            </table>
+                            <h2>"Most contended records"</h2>
+                            <ul class="record-list">
+                                {summary.busiest.into_iter().map(|record| view! {
+                                    <li>
+                                        <a class="record-title" href=format!("/records/{}", record.record_id)>
+                                            {record.title}
+                                        </a>
+                                        <span class="record-meta">
+                                            {format!("{} saves, {} conflicts", record.saves, record.conflicts)}
+                                        </span>
+                                    </li>
+                                }).collect_view()}
+                            </ul>
+                        }
+                        .into_any()
+                    }
+                })}
+            </Suspense>
+        </div>
+    }
+}
@@ -0,0 +1,195 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Fake but plausible records for load tests and demo environments: a title,
+// a person, a date and a few paragraphs of text, in the tenant's fields in that
+// order, with more paragraphs for any fields after the fourth. Nothing is derived from real
+// data, and the same seed always produces the same records.
+
+// Most records one call may generate
+pub const MAX_GENERATED: u32 = 10_000;
+
+// What a generator run added
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratedData {
+    pub count: u32,
+    // Pass again to generate the same records
+    pub seed: u64,
+    pub first_id: Option<i64>,
+    pub last_id: Option<i64>,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::GeneratedData;
+    use crate::db::{append_history, write_values, DbManager, FieldValues};
+    use crate::events::FieldEvent;
+    use crate::timestamp::format_timestamp;
+
+    const FIRST_NAMES: &[&str] = &[
+        "Ada", "Bernd", "Chiara", "Dmitri", "Elif", "Farah", "Goran", "Hana", "Ines", "Jonas", "Kemal", "Lena",
+        "Mateo", "Nadia", "Oskar", "Priya", "Quentin", "Rosa", "Sven", "Tomoko", "Umar", "Vera", "Wen", "Yusuf",
+    ];
+    const LAST_NAMES: &[&str] = &[
+        "Albrecht", "Bianchi", "Castillo", "Dubois", "Eriksen", "Fischer", "García", "Hoffmann", "Ivanova", "Jansen",
+        "Kowalski", "Lindqvist", "Moreau", "Nakamura", "Okafor", "Petrov", "Rossi", "Schmidt", "Tanaka", "Varga",
+    ];
+    const TOPICS: &[&str] = &[
+        "Quarterly review", "Site inspection", "Budget proposal", "Incident report", "Supplier contract",
+        "Onboarding plan", "Maintenance log", "Customer feedback", "Release checklist", "Training schedule",
+    ];
+    const SUBJECTS: &[&str] = &[
+        "The team", "The customer", "Our supplier", "The auditor", "The night shift", "Management", "The vendor",
+    ];
+    const VERBS: &[&str] = &[
+        "reviewed", "approved", "questioned", "postponed", "updated", "rejected", "signed off on", "revisited",
+    ];
+    const OBJECTS: &[&str] = &[
+        "the revised figures", "the delivery timeline", "the safety measures", "the draft agreement",
+        "last month's numbers", "the open issues", "the staffing plan", "the test results",
+    ];
+    const ENDINGS: &[&str] = &[
+        "before the deadline", "after a short discussion", "pending further checks", "with minor changes",
+        "for the second time", "without objections", "as agreed in the last meeting",
+    ];
+
+    // Dates fall within the ten years before this point (2025-01-01)
+    const LATEST_DATE_MILLIS: i64 = 1_735_689_600_000;
+    const DAY_MILLIS: i64 = 86_400_000;
+
+    // A small deterministic generator (SplitMix64); good enough for fake data
+    pub struct FakeData(u64);
+
+    impl FakeData {
+        pub fn new(seed: u64) -> Self {
+            FakeData(seed)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick(&mut self, words: &[&'static str]) -> &'static str {
+            words[self.below(words.len() as u64) as usize]
+        }
+
+        fn name(&mut self) -> String {
+            format!("{} {}", self.pick(FIRST_NAMES), self.pick(LAST_NAMES))
+        }
+
+        fn date(&mut self) -> String {
+            let days_back = self.below(10 * 365) as i64;
+            format_timestamp(LATEST_DATE_MILLIS - days_back * DAY_MILLIS)[..10].to_string()
+        }
+
+        fn sentence(&mut self) -> String {
+            format!(
+                "{} {} {} {}.",
+                self.pick(SUBJECTS),
+                self.pick(VERBS),
+                self.pick(OBJECTS),
+                self.pick(ENDINGS)
+            )
+        }
+
+        // A few paragraphs of a few sentences each
+        fn long_text(&mut self) -> String {
+            let paragraphs = 1 + self.below(3);
+            (0..paragraphs)
+                .map(|_| {
+                    let sentences = 2 + self.below(5);
+                    (0..sentences).map(|_| self.sentence()).collect::<Vec<_>>().join(" ")
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        }
+
+        // Values for the fields with these names, in the order they are defined
+        pub fn record(&mut self, fields: &[String]) -> FieldValues {
+            let person = self.name();
+            let mut values = FieldValues::default();
+            for (i, name) in fields.iter().enumerate() {
+                let value = match i {
+                    0 => format!("{} for {}", self.pick(TOPICS), person),
+                    1 => self.name(),
+                    2 => self.date(),
+                    _ => self.long_text(),
+                };
+                values.set(name.clone(), value);
+            }
+            values
+        }
+    }
+
+    // Attribution of generated records in the audit log
+    const GENERATOR_ACTOR: &str = "generator";
+
+    impl DbManager {
+        // Insert `count` generated records in one transaction
+        #[tracing::instrument(skip(self))]
+        pub async fn generate_records(&self, count: u32, seed: u64) -> Result<GeneratedData, sqlx::Error> {
+            let mut fake = FakeData::new(seed);
+            let fields: Vec<String> = self.field_definitions().await?.into_iter().map(|d| d.name).collect();
+            let now = self.now();
+            let mut tx = self.pool().begin().await?;
+            let mut ids = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let values = fake.record(&fields);
+                let id: i64 = sqlx::query_scalar(
+                    r#"
+                    INSERT INTO fields (version, updated_by, updated_at, tenant_id)
+                    VALUES (1, ?, ?, ?)
+                    RETURNING id
+                    "#,
+                )
+                .bind(GENERATOR_ACTOR)
+                .bind(now)
+                .bind(self.tenant().as_str())
+                .fetch_one(&mut *tx)
+                .await?;
+                let record = self.scope(&mut tx, id).await?.expect("The record was just inserted");
+                write_values(&mut tx, record, &values).await?;
+                append_history(&mut tx, record, &FieldEvent::Created, None, now).await?;
+                ids.push(id);
+            }
+            tx.commit().await?;
+            Ok(GeneratedData {
+                count,
+                seed,
+                first_id: ids.first().copied(),
+                last_id: ids.last().copied(),
+            })
+        }
+    }
+}
+
+// Add fake records, for demo and load test environments only
+#[server(GenerateDemoData)]
+pub async fn generate_demo_data(count: u32, seed: Option<u64>) -> Result<GeneratedData, ServerFnError> {
+    use crate::demo::demo_enabled;
+    use crate::field_editor::{db_error, open_db};
+
+    if !demo_enabled() {
+        let response = expect_context::<leptos_actix::ResponseOptions>();
+        response.set_status(actix_web::http::StatusCode::NOT_FOUND);
+        return Err(ServerFnError::new("Generating data is only available in demo mode"));
+    }
+    if count > MAX_GENERATED {
+        return Err(ServerFnError::new(format!("Generate at most {} records at a time", MAX_GENERATED)));
+    }
+    let seed = seed.unwrap_or_else(|| uuid::Uuid::new_v4().as_u64_pair().0);
+    let db = open_db().await?;
+    db.generate_records(count, seed).await.map_err(db_error)
+}
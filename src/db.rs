@@ -1,141 +1,2569 @@
-use sqlx::{FromRow, Pool, Sqlite, SqlitePool};
+#[cfg(feature = "ssr")]
+use sqlx::{FromRow, Pool, Sqlite, SqliteConnection, SqlitePool};
+#[cfg(feature = "ssr")]
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+#[cfg(feature = "ssr")]
+use std::str::FromStr;
+#[cfg(feature = "ssr")]
 use std::sync::Arc;
+#[cfg(feature = "ssr")]
+use std::time::Duration;
+#[cfg(feature = "ssr")]
+use crate::providers::{Clock, IdGenerator, SystemClock, UuidGenerator};
+#[cfg(feature = "ssr")]
+use crate::keys::KeyProvider;
+#[cfg(feature = "ssr")]
+use crate::record_cache::{cache_metrics, RecordCache};
+#[cfg(feature = "ssr")]
+use crate::retry::RetryPolicy;
+#[cfg(feature = "ssr")]
+use crate::tenant::{ScopedRecord, TenantId};
+#[cfg(feature = "ssr")]
+use crate::timestamp::{format_rfc3339, parse_rfc3339};
+#[cfg(feature = "ssr")]
+use std::future::Future;
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+#[cfg(feature = "ssr")]
+use crate::events::{EventEnvelope, FieldEvent, EVENT_SCHEMA_VERSION};
+#[cfg(feature = "ssr")]
+use crate::integrity::record_checksum;
+#[cfg(feature = "ssr")]
+use crate::merge::{merge_into_current, MergeResult};
+#[cfg(feature = "ssr")]
+use crate::webhooks::{enqueue_webhook_deliveries, WebhookPayload};
+
+// The audit log change types that count as saves in the activity summary
+#[cfg(feature = "ssr")]
+macro_rules! save_kinds_sql {
+    () => {
+        "('updated', 'merged', 'overwritten', 'reverted')"
+    };
+}
 
 // Our data model
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(FromRow, utoipa::ToSchema, async_graphql::SimpleObject))]
 pub struct Fields {
     pub id: i64,
-    pub field1: String,
-    pub field2: String,
-    pub field3: String,
-    pub field4: String,
+    // Serialized next to the other members, so a record reads `{"id": 1, "field1": ..., "version": 2}`
+    #[serde(flatten)]
+    #[cfg_attr(feature = "ssr", sqlx(rename = "field_values"))]
+    pub values: FieldValues,
+    pub version: i64,
+    // Who saved the current version, and when
+    pub updated_by: Option<String>,
+    pub updated_at: Option<i64>,
+}
+
+// The values of a record by field name. Which names a record has is up to the
+// field definitions of its tenant; a save may leave out fields it doesn't change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+#[cfg_attr(feature = "ssr", derive(utoipa::ToSchema))]
+pub struct FieldValues(pub BTreeMap<String, String>);
+
+impl FieldValues {
+    // The value of a field; empty if it has none
+    pub fn get(&self, name: &str) -> &str {
+        self.0.get(name).map(String::as_str).unwrap_or_default()
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    // These values with `changes` saved over them
+    pub fn updated_with(&self, changes: &FieldValues) -> FieldValues {
+        let mut updated = self.clone();
+        updated.0.extend(changes.0.clone());
+        updated
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for FieldValues {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        FieldValues(iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+// Values are read from the database as the JSON object built by `FIELD_VALUES_COLUMN`
+#[cfg(feature = "ssr")]
+impl sqlx::Type<Sqlite> for FieldValues {
+    fn type_info() -> sqlx::sqlite::SqliteTypeInfo {
+        <String as sqlx::Type<Sqlite>>::type_info()
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl<'r> sqlx::Decode<'r, Sqlite> for FieldValues {
+    fn decode(value: sqlx::sqlite::SqliteValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let json = <&str as sqlx::Decode<Sqlite>>::decode(value)?;
+        FieldValues::from_stored(json)
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl FieldValues {
+    // Values from their stored JSON, as in `field_values` of the audit log,
+    // decrypted where they were encrypted at rest
+    pub(crate) fn from_stored(json: &str) -> Result<Self, sqlx::error::BoxDynError> {
+        let FieldValues(values) = serde_json::from_str(json)?;
+        values
+            .into_iter()
+            .map(|(name, value)| Ok((name, crate::keys::reveal(&value)?)))
+            .collect::<Result<_, crate::keys::KeyError>>()
+            .map(FieldValues)
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(feature = "ssr")]
+async_graphql::scalar!(FieldValues, "FieldValues", "A record's values as an object of field names to strings");
+
+// One record's part in a save of several records at once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(utoipa::ToSchema))]
+pub struct RecordChange {
+    pub id: i64,
+    pub values: FieldValues,
+    // The version the editor loaded; in `update_many` the whole save fails if
+    // the record has moved on, in `update_each` only this record's part
+    #[serde(default)]
+    pub expected_version: i64,
+    // When the caller loaded the record, in RFC 3339; takes the place of
+    // `expected_version` where the server runs in timestamp mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_updated_at: Option<String>,
+}
+
+// How one record of a batch save fared, in `update_each`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(utoipa::ToSchema))]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RecordUpdateResult {
+    Saved { id: i64, version: i64 },
+    // Moved past the expected version; no current version if it's deleted or not there
+    Conflict { id: i64, current_version: Option<i64> },
+}
+
+// What a save does when the record has moved past the version it was based on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    // Refuse it, so the user can look at what changed
+    #[default]
+    Reject,
+    // Save it anyway, replacing the changes made meanwhile
+    LastWriterWins,
+    // Combine it with the changes made meanwhile, unless both changed the same field
+    Merge,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "reject" => Ok(ConflictPolicy::Reject),
+            "lastwriterwins" | "lww" => Ok(ConflictPolicy::LastWriterWins),
+            "merge" => Ok(ConflictPolicy::Merge),
+            other => Err(format!("Unknown conflict policy {}", other)),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl ConflictPolicy {
+    // FIELD_EDITOR_CONFLICT_POLICY (`reject`, `last-writer-wins` or `merge`), or rejecting
+    pub fn from_env() -> Self {
+        std::env::var("FIELD_EDITOR_CONFLICT_POLICY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+
+    // How much of others' work a policy may lose: rejecting loses nothing,
+    // merging only what can't be combined, last writer wins all of it
+    fn leniency(self) -> u8 {
+        match self {
+            ConflictPolicy::Reject => 0,
+            ConflictPolicy::Merge => 1,
+            ConflictPolicy::LastWriterWins => 2,
+        }
+    }
+
+    // The policy a save asked for, unless it is more lenient than `limit`,
+    // the server's; a client may be stricter, but never looser
+    pub fn capped_at(self, limit: ConflictPolicy) -> Self {
+        if self.leniency() <= limit.leniency() {
+            self
+        } else {
+            limit
+        }
+    }
+}
+
+// A row in the record list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(FromRow))]
+pub struct RecordSummary {
+    pub id: i64,
+    pub title: String,
     pub version: i64,
+    pub deleted_at: Option<i64>,
+}
+
+// The database file every part of the server shares
+#[cfg(feature = "ssr")]
+pub const DB_PATH: &str = "/tmp/fields.db";
+
+#[cfg(feature = "ssr")]
+static POOL: tokio::sync::OnceCell<SqlitePool> = tokio::sync::OnceCell::const_new();
+
+// How every connection in the pool is set up
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    // WAL lets readers carry on while someone writes
+    pub journal_mode: SqliteJournalMode,
+    // NORMAL is safe with WAL and avoids a sync on every commit
+    pub synchronous: SqliteSynchronous,
+    // How long a writer waits for the lock before giving up with SQLITE_BUSY
+    pub busy_timeout: Duration,
+}
+
+#[cfg(feature = "ssr")]
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Normal,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl DbConfig {
+    // The defaults, overridden by FIELD_EDITOR_DB_JOURNAL_MODE (e.g. `wal`, `delete`),
+    // FIELD_EDITOR_DB_SYNCHRONOUS (`off`, `normal`, `full`, `extra`) and
+    // FIELD_EDITOR_DB_BUSY_TIMEOUT_MS where set
+    pub fn from_env() -> Self {
+        let defaults = DbConfig::default();
+        let var = |name: &str| std::env::var(name).ok();
+        DbConfig {
+            journal_mode: var("FIELD_EDITOR_DB_JOURNAL_MODE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.journal_mode),
+            synchronous: var("FIELD_EDITOR_DB_SYNCHRONOUS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.synchronous),
+            busy_timeout: var("FIELD_EDITOR_DB_BUSY_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.busy_timeout),
+        }
+    }
+}
+
+// What clients send back to show which state of a record they last saw
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConcurrencyMode {
+    // The integer version, e.g. `"7"`
+    #[default]
+    Version,
+    // When the record was last saved, as RFC 3339, e.g. `"2024-05-01T12:30:00.250Z"`;
+    // for clients of existing tables that keep a timestamp but no version
+    Timestamp,
+}
+
+#[cfg(feature = "ssr")]
+impl std::str::FromStr for ConcurrencyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "version" => Ok(ConcurrencyMode::Version),
+            "timestamp" => Ok(ConcurrencyMode::Timestamp),
+            other => Err(format!("Unknown concurrency mode {}", other)),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl ConcurrencyMode {
+    // FIELD_EDITOR_CONCURRENCY_MODE (`version` or `timestamp`), or versions
+    pub fn from_env() -> Self {
+        std::env::var("FIELD_EDITOR_CONCURRENCY_MODE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+// The process-wide pool on `DB_PATH`. The first call connects and creates the
+// tables; calls racing it wait for that instead of doing the same.
+// FIELD_EDITOR_DB_MAX_CONNECTIONS and FIELD_EDITOR_DB_ACQUIRE_TIMEOUT_MS size it.
+#[cfg(feature = "ssr")]
+pub async fn get_pool() -> Result<SqlitePool, sqlx::Error> {
+    POOL.get_or_try_init(|| async {
+        let var = |name: &str| std::env::var(name).ok();
+        let mut builder = DbManager::builder().config(DbConfig::from_env());
+        if let Some(max) = var("FIELD_EDITOR_DB_MAX_CONNECTIONS").and_then(|v| v.parse().ok()) {
+            builder = builder.max_connections(max);
+        }
+        if let Some(millis) = var("FIELD_EDITOR_DB_ACQUIRE_TIMEOUT_MS").and_then(|v| v.parse().ok()) {
+            builder = builder.acquire_timeout(Duration::from_millis(millis));
+        }
+        let db = builder.build().await?;
+        Ok(db.pool().clone())
+    })
+    .await
+    .cloned()
 }
 
 // Database connection manager
+#[cfg(feature = "ssr")]
+#[derive(Clone)]
 pub struct DbManager {
-    connection_string: String,
-    pool: Option<Arc<Pool<Sqlite>>>,
+    pool: SqlitePool,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdGenerator>,
+    keys: Option<Arc<dyn KeyProvider>>,
+    cache: Option<Arc<dyn RecordCache>>,
+    retry: RetryPolicy,
+    concurrency: ConcurrencyMode,
+    conflicts: ConflictPolicy,
+    tenant: TenantId,
 }
 
+// Where a `DbManager` gets its connections: a pool it opens itself, on the
+// database at `url` (`DB_PATH` unless set), or one handed to it with `with_pool`
+#[cfg(feature = "ssr")]
+#[derive(Debug, Default)]
+pub struct DbManagerBuilder {
+    url: Option<String>,
+    pool: Option<SqlitePool>,
+    config: DbConfig,
+    max_connections: Option<u32>,
+    acquire_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "ssr")]
+impl DbManagerBuilder {
+    pub fn url(mut self, connection_string: &str) -> Self {
+        self.url = Some(connection_string.to_string());
+        self
+    }
+
+    // Use an existing pool instead of opening one. The caller keeps owning it:
+    // the settings below only apply to pools the builder opens, so set them on
+    // the pool instead, and it's up to the caller when to close it.
+    pub fn with_pool(mut self, pool: SqlitePool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    // Pragmas for the connections of an opened pool
+    pub fn config(mut self, config: DbConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // The most connections an opened pool keeps at once (sqlx's default is 10)
+    pub fn max_connections(mut self, max: u32) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    // How long waiting for a free connection may take before failing with `PoolTimedOut`
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = Some(timeout);
+        self
+    }
+
+    // Open the pool unless one was given, then create or update the tables in
+    // its database
+    pub async fn build(self) -> Result<DbManager, sqlx::Error> {
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => {
+                let url = self.url.unwrap_or_else(|| format!("sqlite:{}", DB_PATH));
+                let options = SqliteConnectOptions::from_str(&url)?
+                    .journal_mode(self.config.journal_mode)
+                    .synchronous(self.config.synchronous)
+                    .busy_timeout(self.config.busy_timeout);
+                let mut pool_options = SqlitePoolOptions::new();
+                if let Some(max) = self.max_connections {
+                    pool_options = pool_options.max_connections(max);
+                }
+                if let Some(timeout) = self.acquire_timeout {
+                    pool_options = pool_options.acquire_timeout(timeout);
+                }
+                pool_options.connect_with(options).await?
+            }
+        };
+        let db = DbManager::from_pool(pool);
+        db.create_schema().await?;
+        Ok(db)
+    }
+}
+
+#[cfg(feature = "ssr")]
 impl DbManager {
-    pub fn new(connection_string: &str) -> Self {
+    // Open a pool on the database and create or update its tables, so a
+    // `DbManager` is always ready to use
+    pub async fn connect(connection_string: &str) -> Result<Self, sqlx::Error> {
+        Self::connect_with(connection_string, &DbConfig::default()).await
+    }
+
+    // `connect`, with the pragmas from `config` applied to every connection
+    pub async fn connect_with(connection_string: &str, config: &DbConfig) -> Result<Self, sqlx::Error> {
+        DbManager::builder()
+            .url(connection_string)
+            .config(config.clone())
+            .build()
+            .await
+    }
+
+    // Set up a `DbManager` step by step, e.g. on a pool the caller already has
+    pub fn builder() -> DbManagerBuilder {
+        DbManagerBuilder::default()
+    }
+
+    // Use a pool whose database already has its tables, e.g. from `get_pool`
+    pub fn from_pool(pool: SqlitePool) -> Self {
         DbManager {
-            connection_string: connection_string.to_string(),
-            pool: None,
+            pool,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(UuidGenerator),
+            keys: None,
+            cache: None,
+            retry: RetryPolicy::default(),
+            concurrency: ConcurrencyMode::default(),
+            conflicts: ConflictPolicy::default(),
+            tenant: TenantId::default(),
+        }
+    }
+
+    // Replace the clock used for all timestamps, e.g. with a `FixedClock` in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Replace the generator used for new ids, e.g. with a `SequentialIdGenerator` in tests
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    // Set where the keys for encrypting values at rest come from
+    pub fn with_key_provider(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    // The configured key provider; None when values are stored in plain text
+    pub fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.keys.as_deref()
+    }
+
+    // Serve `get_fields` from this cache when it has the record
+    pub fn with_record_cache(mut self, cache: Arc<dyn RecordCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Drop a record from the caches after changing it
+    pub(crate) async fn forget_cached(&self, id: i64) {
+        crate::print::print_cache().forget(self.tenant(), id);
+        if let Some(cache) = &self.cache {
+            cache.invalidate(self.tenant(), id).await;
+        }
+    }
+
+    // Set how operations are retried after transient errors such as SQLITE_BUSY
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Set whether clients see versions or timestamps as their concurrency token
+    pub fn with_concurrency_mode(mut self, concurrency: ConcurrencyMode) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn concurrency_mode(&self) -> ConcurrencyMode {
+        self.concurrency
+    }
+
+    // Work for `tenant`: only its records are seen, and new ones belong to it
+    pub fn with_tenant(mut self, tenant: TenantId) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    pub fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    // Set what `update_fields` does with saves against an older version
+    pub fn with_conflict_policy(mut self, conflicts: ConflictPolicy) -> Self {
+        self.conflicts = conflicts;
+        self
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflicts
+    }
+
+    // The token standing for the state of `fields` the caller is looking at
+    pub fn concurrency_token(&self, fields: &Fields) -> String {
+        match self.concurrency {
+            ConcurrencyMode::Version => fields.version.to_string(),
+            ConcurrencyMode::Timestamp => format_rfc3339(fields.updated_at.unwrap_or(0)),
+        }
+    }
+
+    // The version to save against for a token a client sent back, given the
+    // record as it is now. A version is passed through for the save to check;
+    // a timestamp is only good if it's the current one, so an older one comes
+    // back as None. Err says why the token isn't one at all.
+    pub fn expected_version(&self, current: &Fields, token: &str) -> Result<Option<i64>, String> {
+        match self.concurrency {
+            ConcurrencyMode::Version => token
+                .parse()
+                .map(Some)
+                .map_err(|_| "Not a version of this record".to_string()),
+            ConcurrencyMode::Timestamp => {
+                let updated_at = parse_rfc3339(token).ok_or_else(|| "Not an RFC 3339 timestamp".to_string())?;
+                Ok((updated_at == current.updated_at.unwrap_or(0)).then_some(current.version))
+            }
+        }
+    }
+
+    // `expected_version` for the record with this id as it is now, for saves
+    // that carry their token next to the values. Gone records and stale tokens
+    // give a version no record is at, so the save reports its usual conflict.
+    pub async fn expected_version_of(&self, id: i64, token: &str) -> Result<Result<i64, String>, sqlx::Error> {
+        if self.concurrency == ConcurrencyMode::Version {
+            return Ok(token.parse().map_err(|_| "Not a version of this record".to_string()));
+        }
+        if parse_rfc3339(token).is_none() {
+            return Ok(Err("Not an RFC 3339 timestamp".to_string()));
         }
+        match self.get_fields(id).await {
+            Ok(current) => Ok(self.expected_version(&current, token).map(|version| version.unwrap_or(0))),
+            Err(sqlx::Error::RowNotFound) => Ok(Ok(0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Run one database operation under the retry policy. `attempt` must do the
+    // whole operation, transaction included, so a retry starts from scratch.
+    pub(crate) async fn retrying<T, F, Fut>(&self, operation: &'static str, attempt: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        self.retry.run(operation, attempt).await
+    }
+
+    pub fn now(&self) -> i64 {
+        self.clock.now_millis()
+    }
+
+    pub fn new_id(&self) -> String {
+        self.ids.new_id()
     }
 
-    // Initialize the database and create tables if they don't exist
-    pub async fn initialize(&mut self) -> Result<(), sqlx::Error> {
-        // Create a connection pool
-        let pool = SqlitePool::connect(&self.connection_string).await?;
+    // Create tables if they don't exist and bring older ones up to date
+    async fn create_schema(&self) -> Result<(), sqlx::Error> {
+        let pool = self.pool();
 
         // Create our fields table with a version column for concurrency control
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS fields (
                 id INTEGER PRIMARY KEY,
-                field1 TEXT NOT NULL,
-                field2 TEXT NOT NULL,
-                field3 TEXT NOT NULL,
-                field4 TEXT NOT NULL,
                 version INTEGER NOT NULL DEFAULT 1
             )
             "#,
         )
-        .execute(&pool)
+        .execute(pool)
+        .await?;
+
+        // Every change to a record is appended here; other instances tail
+        // this table to learn about changes they didn't make themselves
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS fields_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                record_id INTEGER NOT NULL,
+                version INTEGER NOT NULL,
+                change_type TEXT NOT NULL,
+                field_values TEXT NOT NULL,
+                changed_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
         .await?;
 
-        // Insert default data if the table is empty
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM fields")
-            .fetch_one(&pool)
+        // Soft-deleted records keep their data until restored
+        add_column_if_missing(pool, "fields", "deleted_at", "INTEGER").await?;
+        // Attribution of the latest change
+        add_column_if_missing(pool, "fields", "updated_by", "TEXT").await?;
+        add_column_if_missing(pool, "fields", "updated_at", "INTEGER").await?;
+        add_column_if_missing(pool, "fields_history", "changed_by", "TEXT").await?;
+        // The structured event as JSON; change_type mirrors its tag for filtering
+        add_column_if_missing(pool, "fields_history", "event", "TEXT").await?;
+        // SHA-256 of id, version and values, for `verify_integrity`
+        add_column_if_missing(pool, "fields", "checksum", "TEXT").await?;
+        add_column_if_missing(pool, "fields_history", "checksum", "TEXT").await?;
+        // Records belong to a tenant, and so do their history entries
+        add_column_if_missing(pool, "fields", "tenant_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        add_column_if_missing(pool, "fields_history", "tenant_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS fields_tenant ON fields (tenant_id, id)")
+            .execute(pool)
             .await?;
+        // When the record stops accepting changes; never if NULL
+        add_column_if_missing(pool, "fields", "expires_at", "INTEGER").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS fields_expires_at ON fields (expires_at) WHERE expires_at IS NOT NULL")
+            .execute(pool)
+            .await?;
+        // When someone should look at the record again; never if NULL
+        add_column_if_missing(pool, "fields", "review_by", "INTEGER").await?;
+        // No review reminders until then
+        add_column_if_missing(pool, "fields", "review_snoozed_until", "INTEGER").await?;
+        // When the last review reminder went out
+        add_column_if_missing(pool, "fields", "review_reminded_at", "INTEGER").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS fields_review_by ON fields (review_by) WHERE review_by IS NOT NULL")
+            .execute(pool)
+            .await?;
+        for index in HISTORY_INDEXES {
+            sqlx::query(index).execute(pool).await?;
+        }
+
+        // Which fields the records of each tenant have, in the order editors show them
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS field_definitions (
+                tenant_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                PRIMARY KEY (tenant_id, name)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        // How editors present each field; NULL means the default derived from the name, or none
+        add_column_if_missing(pool, "field_definitions", "label", "TEXT").await?;
+        add_column_if_missing(pool, "field_definitions", "placeholder", "TEXT").await?;
+        add_column_if_missing(pool, "field_definitions", "help", "TEXT").await?;
+        add_column_if_missing(pool, "field_definitions", "required", "INTEGER NOT NULL DEFAULT 0").await?;
+
+        // One row per record and field; `version` is the record version that last changed the value
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS field_values (
+                record_id INTEGER NOT NULL,
+                field_name TEXT NOT NULL,
+                value TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                PRIMARY KEY (record_id, field_name)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        ensure_field_definitions(&mut *pool.acquire().await?, &TenantId::default()).await?;
+        migrate_fixed_fields(pool).await?;
 
-        if count == 0 {
+        // Full-text index over each record's values, kept in step with them by triggers
+        let fts_exists: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'records_fts'",
+        )
+        .fetch_one(pool)
+        .await?;
+        sqlx::query("CREATE VIRTUAL TABLE IF NOT EXISTS records_fts USING fts5(content)")
+            .execute(pool)
+            .await?;
+        // The first triggers indexed encrypted values too; replace them and
+        // index again without
+        let old_triggers: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'trigger' AND name = 'field_values_fts_insert'",
+        )
+        .fetch_one(pool)
+        .await?;
+        if old_triggers {
+            for name in ["field_values_fts_insert", "field_values_fts_delete", "field_values_fts_update"] {
+                sqlx::query(&format!("DROP TRIGGER IF EXISTS {}", name)).execute(pool).await?;
+            }
+            sqlx::query("DELETE FROM records_fts").execute(pool).await?;
+        }
+        for trigger in FTS_TRIGGERS {
+            sqlx::query(trigger).execute(pool).await?;
+        }
+        sqlx::query(UPDATED_AT_TRIGGER).execute(pool).await?;
+        if !fts_exists || old_triggers {
+            // Index whatever the table held before the index existed
             sqlx::query(
                 r#"
-                INSERT INTO fields (id, field1, field2, field3, field4, version)
-                VALUES (1, ?, ?, ?, ?, 1)
+                INSERT INTO records_fts (rowid, content)
+                SELECT record_id, group_concat(value, ' ') FROM field_values
+                WHERE value NOT LIKE 'enc:v1:%'
+                GROUP BY record_id
                 "#,
             )
-            .bind("Default value 1")
-            .bind("Default value 2")
-            .bind("Default value 3")
-            .bind("Default value 4")
-            .execute(&pool)
+            .execute(pool)
             .await?;
         }
 
-        self.pool = Some(Arc::new(pool));
-        Ok(())
-    }
-
-    // Get all field values with their current version
-    pub async fn get_fields(&self) -> Result<Fields, sqlx::Error> {
-        dbg!("Fetching fields");
-        let pool = self.pool.as_ref().expect("Database not initialized");
-        
-        // Fetch fields using query_as instead of the macro
-        let fields = sqlx::query_as::<_, Fields>(
-            "SELECT id, field1, field2, field3, field4, version FROM fields WHERE id = 1"
+        // Saves and conflicts per record and UTC day, so dashboards don't have to
+        // aggregate the whole audit log
+        let activity_exists: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'daily_record_activity'",
         )
-        .fetch_one(pool.as_ref())
+        .fetch_one(pool)
         .await?;
-        dbg!(&fields);
-        Ok(fields)
-    }
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS daily_record_activity (
+                tenant_id TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                day TEXT NOT NULL,
+                saves INTEGER NOT NULL,
+                conflicts INTEGER NOT NULL,
+                PRIMARY KEY (record_id, day)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS daily_record_activity_day ON daily_record_activity (tenant_id, day)")
+            .execute(pool)
+            .await?;
+        sqlx::query(ACTIVITY_TRIGGER).execute(pool).await?;
+        if !activity_exists {
+            // Summarize the log written before the summary existed
+            sqlx::query(concat!(
+                r#"
+                INSERT INTO daily_record_activity (tenant_id, record_id, day, saves, conflicts)
+                SELECT tenant_id, record_id, date(changed_at / 1000, 'unixepoch') AS day,
+                       SUM(change_type <> 'conflict_detected'), SUM(change_type = 'conflict_detected')
+                FROM fields_history
+                WHERE change_type IN "#,
+                save_kinds_sql!(),
+                r#" OR change_type = 'conflict_detected'
+                GROUP BY record_id, day
+                "#
+            ))
+            .execute(pool)
+            .await?;
+        }
 
-    // Update fields with optimistic concurrency control
-    pub async fn update_fields(
-        &self, 
-        field1: &str, 
-        field2: &str, 
-        field3: &str, 
-        field4: &str, 
-        expected_version: i64
-    ) -> Result<bool, sqlx::Error> {
-        dbg!(format!("Updating fields with version: {}", expected_version));
-        let pool = self.pool.as_ref().expect("Database not initialized");
-        
-        // Start a transaction
-        let mut tx = pool.begin().await?;
-        
-        // First check if the version matches
-        let current_version: Option<i64> = sqlx::query_scalar(
-            "SELECT version FROM fields WHERE id = 1 AND version = ?"
+        // Who saved each record per UTC day, next to the counts above, so an
+        // editor can be told how many people are working on a record
+        let editors_exist: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'daily_record_editors'",
         )
-        .bind(expected_version)
-        .fetch_optional(&mut *tx)
+        .fetch_one(pool)
         .await?;
-        
-        // If the version doesn't match, someone else has updated the record
-        if current_version.is_none() {
-            tx.rollback().await?;
-            return Ok(false); // Concurrency conflict
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS daily_record_editors (
+                tenant_id TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                day TEXT NOT NULL,
+                editor TEXT NOT NULL,
+                PRIMARY KEY (record_id, day, editor)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query(EDITORS_TRIGGER).execute(pool).await?;
+        if !editors_exist {
+            sqlx::query(concat!(
+                r#"
+                INSERT OR IGNORE INTO daily_record_editors (tenant_id, record_id, day, editor)
+                SELECT tenant_id, record_id, date(changed_at / 1000, 'unixepoch'), changed_by
+                FROM fields_history
+                WHERE changed_by IS NOT NULL AND change_type IN "#,
+                save_kinds_sql!(),
+            ))
+            .execute(pool)
+            .await?;
         }
-        
-        // Update the fields and increment the version
-        let result = sqlx::query(
-            r#"
-            UPDATE fields
-            SET field1 = ?, field2 = ?, field3 = ?, field4 = ?, version = version + 1
-            WHERE id = 1 AND version = ?
-            "#
-        )
-        .bind(field1)
-        .bind(field2)
-        .bind(field3)
-        .bind(field4)
-        .bind(expected_version)
-        .execute(&mut *tx)
+
+        // Insert default data on first start; a first record in the trash stays there
+        let defaults: FieldValues = DEFAULT_FIELDS
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, format!("Default value {}", i + 1)))
+            .collect();
+        match self.get_or_create_fields(1, &defaults).await {
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        // Accounts and cookie sessions for attributing edits
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
         .await?;
-        
-        // Commit the transaction
-        tx.commit().await?;
-        
-        // Check if the update was successful
-        Ok(result.rows_affected() > 0)
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                user_name TEXT NOT NULL REFERENCES users(name),
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        // Secret echoed back by the UI on every mutation to prove it came from our own page
+        add_column_if_missing(pool, "sessions", "csrf_token", "TEXT").await?;
+        // Users sign in to the tenant they were registered with
+        add_column_if_missing(pool, "users", "tenant_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        add_column_if_missing(pool, "sessions", "tenant_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        // Sessions an admin started to act as another user: the admin, and the
+        // admin's own session to go back to
+        add_column_if_missing(pool, "sessions", "impersonated_by", "TEXT").await?;
+        add_column_if_missing(pool, "sessions", "impersonator_session", "TEXT").await?;
+        // Everything done while impersonating, and when it started and ended
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS impersonation_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                tenant_id TEXT NOT NULL,
+                admin TEXT NOT NULL,
+                user_name TEXT NOT NULL,
+                event TEXT NOT NULL,
+                detail TEXT NOT NULL DEFAULT '',
+                at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS impersonation_log_tenant ON impersonation_log (tenant_id, id)")
+            .execute(pool)
+            .await?;
+        // API tokens, by the SHA-256 of the secret, with their scopes as words
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS api_tokens (
+                id TEXT PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                tenant_id TEXT NOT NULL,
+                user_name TEXT NOT NULL,
+                name TEXT NOT NULL DEFAULT '',
+                scopes TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_used_at INTEGER,
+                revoked_at INTEGER
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Who has which record open in an editor, kept alive by heartbeats
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS presence (
+                editor_id TEXT PRIMARY KEY,
+                record_id INTEGER NOT NULL,
+                user_name TEXT NOT NULL,
+                last_seen INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        // When the editor opened the record; the earliest live editor holds the lock
+        add_column_if_missing(pool, "presence", "joined_at", "INTEGER").await?;
+
+        // Editors opening and closing records, for the change feed. Kept apart
+        // from the audit log, since they don't change the record.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lock_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                record_id INTEGER NOT NULL,
+                version INTEGER NOT NULL,
+                change_type TEXT NOT NULL,
+                event TEXT NOT NULL,
+                changed_at INTEGER NOT NULL,
+                changed_by TEXT,
+                tenant_id TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS lock_events_record ON lock_events (record_id, changed_at)")
+            .execute(pool)
+            .await?;
+
+        // Saves already applied, by client-chosen key, so retries aren't applied twice
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS processed_requests (
+                user_name TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                result INTEGER,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (user_name, idempotency_key)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Endpoints notified of changes; inactive ones are kept so their deliveries stay readable
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS webhooks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL UNIQUE,
+                active INTEGER NOT NULL DEFAULT 1,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // One row per change and webhook: the outbox the delivery job works through,
+        // and afterwards the log of what was sent and how the endpoint answered
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                webhook_id INTEGER NOT NULL REFERENCES webhooks(id),
+                record_id INTEGER NOT NULL,
+                payload TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_status_code INTEGER,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                delivered_at INTEGER
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Leases coordinate which server instance owns background work
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS leases (
+                name TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Where each backup ended in the audit log, so the next incremental one starts there
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS backup_markers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                through_change_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Files attached to records; their contents are in the blob store under the same id
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS attachments (
+                id TEXT PRIMARY KEY,
+                record_id INTEGER NOT NULL,
+                filename TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                uploaded_by TEXT NOT NULL,
+                uploaded_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS attachments_record ON attachments (record_id)")
+            .execute(pool)
+            .await?;
+        add_column_if_missing(pool, "attachments", "width", "INTEGER").await?;
+        add_column_if_missing(pool, "attachments", "height", "INTEGER").await?;
+        // Every version of a file shares the id of the first one as its slot
+        add_column_if_missing(pool, "attachments", "slot", "TEXT").await?;
+        add_column_if_missing(pool, "attachments", "slot_version", "INTEGER NOT NULL DEFAULT 1").await?;
+        sqlx::query("UPDATE attachments SET slot = id WHERE slot IS NULL")
+            .execute(pool)
+            .await?;
+
+        // Which attachments a record had at which of its versions: from
+        // `from_version` up to, but not including, `to_version`
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS attachment_links (
+                record_id INTEGER NOT NULL,
+                attachment_id TEXT NOT NULL,
+                from_version INTEGER NOT NULL,
+                to_version INTEGER,
+                PRIMARY KEY (attachment_id, from_version)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS attachment_links_record ON attachment_links (record_id, to_version)")
+            .execute(pool)
+            .await?;
+        // Attachments from before versioning belong to every version
+        sqlx::query(
+            r#"
+            INSERT INTO attachment_links (record_id, attachment_id, from_version)
+            SELECT record_id, id, 0 FROM attachments
+            WHERE id NOT IN (SELECT attachment_id FROM attachment_links)
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        // Saves waiting for an approver, when saves need approval; values are
+        // stored like the audit log's
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS field_change_requests (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                tenant_id TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                base_version INTEGER NOT NULL,
+                field_values TEXT NOT NULL,
+                requested_by TEXT NOT NULL,
+                requested_at INTEGER NOT NULL,
+                idempotency_key TEXT,
+                status TEXT NOT NULL DEFAULT 'pending',
+                decided_by TEXT,
+                decided_at INTEGER,
+                UNIQUE (requested_by, idempotency_key)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS field_change_requests_pending ON field_change_requests (tenant_id, status)",
+        )
+        .execute(pool)
+        .await?;
+
+        // Changes saved to take effect later. Status is pending until published,
+        // failed if they conflicted when due, or cancelled.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS scheduled_changes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                tenant_id TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                base_version INTEGER NOT NULL,
+                field_values TEXT NOT NULL,
+                scheduled_by TEXT NOT NULL,
+                scheduled_at INTEGER NOT NULL,
+                publish_at INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                published_at INTEGER,
+                cancelled_by TEXT
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS scheduled_changes_due ON scheduled_changes (status, publish_at)")
+            .execute(pool)
+            .await?;
+
+        // What collaborators said about a field of a record
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS field_comments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                tenant_id TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                field_name TEXT NOT NULL,
+                author TEXT NOT NULL,
+                body TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS field_comments_field ON field_comments (record_id, field_name)")
+            .execute(pool)
+            .await?;
+
+        // How far into the audit log change notifications have been sent
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notifier_position (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                through_change_id INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Access the underlying pool for modules that run their own queries
+    pub(crate) fn pool(&self) -> &Pool<Sqlite> {
+        &self.pool
+    }
+
+    // Check that the database still answers
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(self.pool()).await?;
+        Ok(())
+    }
+
+    // Wait for checked-out connections to come back, then close them all.
+    // Shared by all clones; anything still using the pool afterwards gets `PoolClosed`.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    // Get all field values of a record with their current version
+    #[tracing::instrument(skip_all, fields(record_id = id))]
+    pub async fn get_fields(&self, id: i64) -> Result<Fields, sqlx::Error> {
+        if let Some(cache) = &self.cache {
+            // Only the current version will do; looking it up is cheap next to gathering the values
+            let version: Option<i64> =
+                sqlx::query_scalar("SELECT version FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL")
+                    .bind(id)
+                    .bind(self.tenant().as_str())
+                    .fetch_optional(self.pool())
+                    .await?;
+            let Some(version) = version else {
+                return Err(sqlx::Error::RowNotFound);
+            };
+            let cached = cache.get(self.tenant(), id, version).await;
+            cache_metrics().record(cached.is_some());
+            if let Some(fields) = cached {
+                tracing::debug!(version = fields.version, cache = cache.name(), "Fetched fields from cache");
+                return Ok(fields);
+            }
+        }
+        // `FIELDS_COLUMNS` spelled out, as the macro needs the query as one literal
+        let tenant = self.tenant().as_str();
+        let fields = self
+            .retrying("get_fields", || {
+                sqlx::query_as!(
+                    Fields,
+                    r#"
+                    SELECT
+                        id AS "id!",
+                        (
+                            SELECT json_group_object(d.name, COALESCE(v.value, ''))
+                            FROM field_definitions d
+                            LEFT JOIN field_values v ON v.record_id = fields.id AND v.field_name = d.name
+                            WHERE d.tenant_id = fields.tenant_id
+                        ) AS "values!: FieldValues",
+                        version, updated_by, updated_at
+                    FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL
+                    "#,
+                    id,
+                    tenant,
+                )
+                .fetch_one(self.pool())
+            })
+            .await?;
+        tracing::debug!(version = fields.version, "Fetched fields");
+        if let Some(cache) = &self.cache {
+            cache.put(self.tenant(), &fields).await;
+        }
+        Ok(fields)
+    }
+
+    // Get a record, creating it with `defaults` first if there is no record
+    // with that id. Safe to race: of several callers creating the same record,
+    // one inserts it and the others read what it inserted.
+    pub async fn get_or_create_fields(&self, id: i64, defaults: &FieldValues) -> Result<Fields, sqlx::Error> {
+        let tenant = self.tenant().as_str();
+        let mut tx = self.pool().begin().await?;
+        let inserted = sqlx::query!(
+            "INSERT INTO fields (id, version, tenant_id) VALUES (?, 1, ?) ON CONFLICT (id) DO NOTHING",
+            id,
+            tenant,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            if let Some(record) = self.scope(&mut tx, id).await? {
+                write_values(&mut tx, record, defaults).await?;
+                append_history(&mut tx, record, &FieldEvent::Created, None, self.now()).await?;
+            }
+        }
+        tx.commit().await?;
+        self.get_fields(id).await
+    }
+
+    // Update fields with optimistic concurrency control, settling a conflict
+    // by the configured `ConflictPolicy`. Returns whether the values were saved.
+    // A save retried with the same idempotency key gets the first attempt's
+    // result back instead of being applied again.
+    pub async fn update_fields(
+        &self, 
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        expected_version: i64,
+        idempotency_key: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        self.save_fields(id, user, values, expected_version, idempotency_key, self.conflicts)
+            .await
+            .map(|result| result.is_saved())
+    }
+
+    // `update_fields` with the conflict policy chosen by the caller
+    pub async fn update_fields_with_policy(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        expected_version: i64,
+        idempotency_key: Option<&str>,
+        policy: ConflictPolicy,
+    ) -> Result<MergeResult, sqlx::Error> {
+        self.save_fields(id, user, values, expected_version, idempotency_key, policy)
+            .await
+    }
+
+    // Like `update_fields`, but a save against an older version is merged with
+    // what was saved since instead of rejected, unless both changed the same field
+    pub async fn update_fields_merging(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        expected_version: i64,
+        idempotency_key: Option<&str>,
+    ) -> Result<MergeResult, sqlx::Error> {
+        self.save_fields(id, user, values, expected_version, idempotency_key, ConflictPolicy::Merge)
+            .await
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(record_id = id, user = %user, expected_version = expected_version, policy = ?policy)
+    )]
+    async fn save_fields(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        expected_version: i64,
+        idempotency_key: Option<&str>,
+        policy: ConflictPolicy,
+    ) -> Result<MergeResult, sqlx::Error> {
+        let result = self
+            .retrying("save_fields", || {
+                self.save_fields_in_tx(id, user, values, expected_version, idempotency_key, policy)
+            })
+            .await;
+        match &result {
+            Ok(MergeResult::Saved) => tracing::debug!("Saved"),
+            Ok(MergeResult::Merged { version }) => tracing::info!(version, "Merged with changes saved meanwhile"),
+            Ok(MergeResult::Overwritten { version }) => tracing::info!(version, "Overwrote changes saved meanwhile"),
+            Ok(MergeResult::Conflict { fields }) => tracing::info!(?fields, "Rejected: version conflict"),
+            Err(e) => tracing::warn!(error = %e, "Save failed"),
+        }
+        if matches!(result, Ok(ref saved) if saved.is_saved()) {
+            self.forget_cached(id).await;
+        }
+        result
+    }
+
+    async fn save_fields_in_tx(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        expected_version: i64,
+        idempotency_key: Option<&str>,
+        policy: ConflictPolicy,
+    ) -> Result<MergeResult, sqlx::Error> {
+        // Start a transaction
+        let mut tx = self.pool().begin().await?;
+        let Some(record) = self.scope(&mut tx, id).await? else {
+            return Ok(MergeResult::Conflict { fields: Vec::new() });
+        };
+
+        if let Some(key) = idempotency_key {
+            if let Some(saved) = claim_idempotency_key(&mut tx, user, key, id, self.now()).await? {
+                tx.rollback().await?;
+                return Ok(match saved {
+                    true => MergeResult::Saved,
+                    false => MergeResult::Conflict { fields: Vec::new() },
+                });
+            }
+        }
+        
+        // First check if the version matches
+        let current_version: Option<i64> = sqlx::query_scalar!(
+            "SELECT version FROM fields WHERE id = ? AND version = ? AND deleted_at IS NULL",
+            id,
+            expected_version,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        
+        // If the version doesn't match, someone else has updated the record
+        if current_version.is_none() {
+            let result = match policy {
+                ConflictPolicy::Reject => MergeResult::Conflict { fields: Vec::new() },
+                ConflictPolicy::LastWriterWins => {
+                    overwrite_current(&mut tx, record, user, values, expected_version, self.now()).await?
+                }
+                ConflictPolicy::Merge => {
+                    merge_into_current(&mut tx, record, user, values, expected_version, self.now()).await?
+                }
+            };
+            if !result.is_saved() {
+                // Keep a record of the rejected save in the audit log
+                let conflict = FieldEvent::ConflictDetected { expected_version };
+                append_history(&mut tx, record, &conflict, Some(user), self.now()).await?;
+            }
+            record_idempotent_result(&mut *tx, user, idempotency_key, result.is_saved()).await?;
+            tx.commit().await?;
+            return Ok(result); // Merged, overwritten, or a concurrency conflict
+        }
+        
+        // Update the fields and increment the version
+        let success =
+            apply_update(&mut tx, record, user, values, expected_version, &FieldEvent::Updated, self.now()).await?;
+        record_idempotent_result(&mut *tx, user, idempotency_key, success).await?;
+        
+        // Commit the transaction
+        tx.commit().await?;
+        
+        // Check if the update was successful
+        Ok(match success {
+            true => MergeResult::Saved,
+            false => MergeResult::Conflict { fields: Vec::new() },
+        })
+    }
+
+    // Save several records as one unit: every record is checked against its own
+    // expected version, and if any of them has moved on nothing is written.
+    // Returns the ids that conflicted, empty when everything was saved.
+    #[tracing::instrument(skip_all, fields(user = %user, records = changes.len()))]
+    pub async fn update_many(
+        &self,
+        user: &str,
+        changes: &[RecordChange],
+        idempotency_key: Option<&str>,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        self.retrying("update_many", || self.update_many_in_tx(user, changes, idempotency_key))
+            .await
+    }
+
+    async fn update_many_in_tx(
+        &self,
+        user: &str,
+        changes: &[RecordChange],
+        idempotency_key: Option<&str>,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let now = self.now();
+
+        if let (Some(key), Some(first)) = (idempotency_key, changes.first()) {
+            if let Some(saved) = claim_idempotency_key(&mut tx, user, key, first.id, now).await? {
+                tx.rollback().await?;
+                if saved {
+                    return Ok(Vec::new());
+                }
+                // The first attempt conflicted; report what conflicts now
+                return self.conflicting(changes).await;
+            }
+        }
+
+        let ids = serde_json::to_string(&changes.iter().map(|c| c.id).collect::<Vec<_>>()).expect("ids serialize");
+        let tenant = self.tenant().as_str();
+        let current: Vec<(i64, i64)> = sqlx::query!(
+            r#"
+            SELECT id AS "id!", version FROM fields
+            WHERE id IN (SELECT value FROM json_each(?)) AND tenant_id = ? AND deleted_at IS NULL
+            "#,
+            ids,
+            tenant,
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| (row.id, row.version))
+        .collect();
+        let conflicts: Vec<i64> = changes
+            .iter()
+            .filter(|c| !current.contains(&(c.id, c.expected_version)))
+            .map(|c| c.id)
+            .collect();
+
+        if !conflicts.is_empty() {
+            for change in changes.iter().filter(|c| conflicts.contains(&c.id)) {
+                let conflict = FieldEvent::ConflictDetected {
+                    expected_version: change.expected_version,
+                };
+                // Other tenants' records are conflicts too, but not ours to log
+                if let Some(record) = self.scope(&mut tx, change.id).await? {
+                    append_history(&mut tx, record, &conflict, Some(user), now).await?;
+                }
+            }
+            record_idempotent_result(&mut *tx, user, idempotency_key, false).await?;
+            tx.commit().await?;
+            return Ok(conflicts);
+        }
+
+        for change in changes {
+            // Every record was found in this tenant above
+            if let Some(record) = self.scope(&mut tx, change.id).await? {
+                apply_update(&mut tx, record, user, &change.values, change.expected_version, &FieldEvent::Updated, now)
+                    .await?;
+            }
+        }
+        record_idempotent_result(&mut *tx, user, idempotency_key, true).await?;
+        tx.commit().await?;
+        for change in changes {
+            self.forget_cached(change.id).await;
+        }
+        Ok(Vec::new())
+    }
+
+    // Save several records in one transaction, each on its own: a record still
+    // at its expected version is saved, one that has moved on is left alone
+    // and logged as a conflict. Returns a result per change, in order.
+    #[tracing::instrument(skip_all, fields(user = %user, records = changes.len()))]
+    pub async fn update_each(&self, user: &str, changes: &[RecordChange]) -> Result<Vec<RecordUpdateResult>, sqlx::Error> {
+        let results = self
+            .retrying("update_each", || self.update_each_in_tx(user, changes))
+            .await?;
+        for result in &results {
+            if let RecordUpdateResult::Saved { id, .. } = result {
+                self.forget_cached(*id).await;
+            }
+        }
+        Ok(results)
+    }
+
+    async fn update_each_in_tx(&self, user: &str, changes: &[RecordChange]) -> Result<Vec<RecordUpdateResult>, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let now = self.now();
+        let mut results = Vec::with_capacity(changes.len());
+        for change in changes {
+            let record = match self.scope(&mut tx, change.id).await? {
+                Some(record) => record,
+                None => {
+                    results.push(RecordUpdateResult::Conflict { id: change.id, current_version: None });
+                    continue;
+                }
+            };
+            let current_version = sqlx::query_scalar!(
+                "SELECT version FROM fields WHERE id = ? AND deleted_at IS NULL",
+                change.id,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            if current_version == Some(change.expected_version)
+                && apply_update(&mut tx, record, user, &change.values, change.expected_version, &FieldEvent::Updated, now)
+                    .await?
+            {
+                results.push(RecordUpdateResult::Saved { id: change.id, version: change.expected_version + 1 });
+                continue;
+            }
+            let conflict = FieldEvent::ConflictDetected {
+                expected_version: change.expected_version,
+            };
+            append_history(&mut tx, record, &conflict, Some(user), now).await?;
+            results.push(RecordUpdateResult::Conflict { id: change.id, current_version });
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    // The changes whose record is no longer at the expected version
+    async fn conflicting(&self, changes: &[RecordChange]) -> Result<Vec<i64>, sqlx::Error> {
+        let tenant = self.tenant().as_str();
+        let mut conflicts = Vec::new();
+        for change in changes {
+            let current: Option<i64> = sqlx::query_scalar!(
+                "SELECT version FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL",
+                change.id,
+                tenant,
+            )
+            .fetch_optional(self.pool())
+            .await?;
+            if current != Some(change.expected_version) {
+                conflicts.push(change.id);
+            }
+        }
+        Ok(conflicts)
+    }
+
+    // Forget idempotency keys older than the given age; clients stop retrying long before
+    pub async fn prune_processed_requests(&self, max_age_millis: i64) -> Result<u64, sqlx::Error> {
+        let cutoff = self.now() - max_age_millis;
+        let result = sqlx::query!("DELETE FROM processed_requests WHERE created_at < ?", cutoff)
+            .execute(self.pool())
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // List records, newest (highest id) first; soft-deleted records are only included on request
+    pub async fn list_records(&self, include_deleted: bool) -> Result<Vec<RecordSummary>, sqlx::Error> {
+        sqlx::query_as::<_, RecordSummary>(&format!(
+            r#"
+            SELECT id, {} AS title, version, deleted_at FROM fields
+            WHERE tenant_id = ? AND (? OR deleted_at IS NULL)
+            ORDER BY id DESC
+            "#,
+            TITLE_EXPR
+        ))
+        .bind(self.tenant().as_str())
+        .bind(include_deleted)
+        .fetch_all(self.pool())
+        .await
+        .and_then(reveal_titles)
+    }
+
+    // List only the records in the trash, most recently deleted first
+    pub async fn list_deleted(&self) -> Result<Vec<RecordSummary>, sqlx::Error> {
+        sqlx::query_as::<_, RecordSummary>(&format!(
+            r#"
+            SELECT id, {} AS title, version, deleted_at FROM fields
+            WHERE tenant_id = ? AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC
+            "#,
+            TITLE_EXPR
+        ))
+        .bind(self.tenant().as_str())
+        .fetch_all(self.pool())
+        .await
+        .and_then(reveal_titles)
+    }
+
+    // Move a record to the trash, if it is still at the version the caller saw;
+    // deleting over someone else's newer edit is a conflict like saving over it.
+    // The version is bumped so editors still holding the old version get a
+    // conflict instead of silently writing.
+    #[tracing::instrument(skip_all, fields(record_id = id, user = %user, expected_version = expected_version))]
+    pub async fn delete_record(&self, id: i64, user: &str, expected_version: i64) -> Result<bool, sqlx::Error> {
+        let deleted = self
+            .retrying("delete_record", || self.delete_record_in_tx(id, user, expected_version))
+            .await?;
+        if deleted {
+            self.forget_cached(id).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_record_in_tx(&self, id: i64, user: &str, expected_version: i64) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let Some(record) = self.scope(&mut tx, id).await? else {
+            return Ok(false);
+        };
+        let now = self.now();
+        let result = sqlx::query!(
+            r#"
+            UPDATE fields SET deleted_at = ?, version = version + 1, updated_by = ?, updated_at = ?
+            WHERE id = ? AND version = ? AND deleted_at IS NULL
+            "#,
+            now,
+            user,
+            now,
+            id,
+            expected_version,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() > 0 {
+            append_history(&mut tx, record, &FieldEvent::Deleted, None, self.now()).await?;
+        } else {
+            let conflict = FieldEvent::ConflictDetected { expected_version };
+            append_history(&mut tx, record, &conflict, Some(user), self.now()).await?;
+        }
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // The version of a record in the trash and who put it there; None if it isn't there
+    pub async fn tombstone(&self, id: i64) -> Result<Option<(i64, Option<String>)>, sqlx::Error> {
+        let tenant = self.tenant().as_str();
+        let row = sqlx::query!(
+            "SELECT version, updated_by FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NOT NULL",
+            id,
+            tenant,
+        )
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.map(|row| (row.version, row.updated_by)))
+    }
+
+    // Bring a deleted record back and save new values into it in one go, for an
+    // editor whose save found the record deleted. Only succeeds while the record
+    // is still the tombstone the editor was told about.
+    #[tracing::instrument(
+        skip_all,
+        fields(record_id = id, user = %user, tombstone_version = tombstone_version)
+    )]
+    pub async fn restore_and_update(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        tombstone_version: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let restored = self
+            .retrying("restore_and_update", || {
+                self.restore_and_update_in_tx(id, user, values, tombstone_version)
+            })
+            .await?;
+        if restored {
+            self.forget_cached(id).await;
+        }
+        Ok(restored)
+    }
+
+    async fn restore_and_update_in_tx(
+        &self,
+        id: i64,
+        user: &str,
+        values: &FieldValues,
+        tombstone_version: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let Some(record) = self.scope(&mut tx, id).await? else {
+            return Ok(false);
+        };
+        let now = self.now();
+        let restored = sqlx::query!(
+            r#"
+            UPDATE fields SET deleted_at = NULL, version = version + 1, updated_by = ?, updated_at = ?
+            WHERE id = ? AND version = ? AND deleted_at IS NOT NULL
+            "#,
+            user,
+            now,
+            id,
+            tombstone_version,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !restored {
+            return Ok(false);
+        }
+        append_history(&mut tx, record, &FieldEvent::Restored, None, now).await?;
+
+        let old_values = record_values(&mut tx, record).await?;
+        sqlx::query!(
+            "UPDATE fields SET version = version + 1, updated_by = ?, updated_at = ? WHERE id = ?",
+            user,
+            now,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        write_values(&mut tx, record, values).await?;
+        append_history(&mut tx, record, &FieldEvent::Updated, None, now).await?;
+        let new_values = record_values(&mut tx, record).await?;
+        let change = WebhookPayload::updated(id, tombstone_version + 2, user, old_values, new_values, now);
+        enqueue_webhook_deliveries(&mut *tx, &change, now).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Bring a record back from the trash
+    #[tracing::instrument(skip_all, fields(record_id = id, user = %user))]
+    pub async fn restore_record(&self, id: i64, user: &str) -> Result<bool, sqlx::Error> {
+        let restored = self.retrying("restore_record", || self.restore_record_in_tx(id, user)).await?;
+        if restored {
+            self.forget_cached(id).await;
+        }
+        Ok(restored)
+    }
+
+    async fn restore_record_in_tx(&self, id: i64, user: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let Some(record) = self.scope(&mut tx, id).await? else {
+            return Ok(false);
+        };
+        let now = self.now();
+        let result = sqlx::query!(
+            r#"
+            UPDATE fields SET deleted_at = NULL, version = version + 1, updated_by = ?, updated_at = ?
+            WHERE id = ? AND deleted_at IS NOT NULL
+            "#,
+            user,
+            now,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() > 0 {
+            append_history(&mut tx, record, &FieldEvent::Restored, None, self.now()).await?;
+        }
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Copy a record's current values into a new record at version 1. Returns
+    // the new record's id, or None if the record is missing or in the trash.
+    #[tracing::instrument(skip_all, fields(record_id = id, user = %user))]
+    pub async fn clone_record(&self, id: i64, user: &str) -> Result<Option<i64>, sqlx::Error> {
+        self.retrying("clone_record", || self.clone_record_in_tx(id, user)).await
+    }
+
+    async fn clone_record_in_tx(&self, id: i64, user: &str) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let Some(source) = self.scope(&mut tx, id).await? else {
+            return Ok(None);
+        };
+        let live = sqlx::query_scalar!("SELECT id FROM fields WHERE id = ? AND deleted_at IS NULL", id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if live.is_none() {
+            return Ok(None);
+        }
+        let values = record_values(&mut tx, source).await?;
+        let now = self.now();
+        let tenant = self.tenant().as_str();
+        let new_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO fields (version, updated_by, updated_at, tenant_id)
+            VALUES (1, ?, ?, ?)
+            RETURNING id
+            "#,
+            user,
+            now,
+            tenant,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let record = self.scope(&mut tx, new_id).await?.expect("The record was just inserted");
+        write_values(&mut tx, record, &values).await?;
+        append_history(&mut tx, record, &FieldEvent::Created, None, now).await?;
+        tx.commit().await?;
+        Ok(Some(new_id))
+    }
+
+    // Save the values and attachments a record had at an earlier version as its
+    // newest version. Fails (returns false) if the record moved past
+    // `expected_version`, is in the trash, or the audit log doesn't reach back to
+    // `version`.
+    #[tracing::instrument(skip_all, fields(record_id = id, user = %user, version, expected_version))]
+    pub async fn revert_to_version(
+        &self,
+        id: i64,
+        user: &str,
+        version: i64,
+        expected_version: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let reverted = self
+            .retrying("revert_to_version", || {
+                self.revert_to_version_in_tx(id, user, version, expected_version)
+            })
+            .await?;
+        if reverted {
+            self.forget_cached(id).await;
+        }
+        Ok(reverted)
+    }
+
+    async fn revert_to_version_in_tx(
+        &self,
+        id: i64,
+        user: &str,
+        version: i64,
+        expected_version: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        let Some(record) = self.scope(&mut tx, id).await? else {
+            return Ok(false);
+        };
+        let current: Option<i64> = sqlx::query_scalar!(
+            "SELECT version FROM fields WHERE id = ? AND version = ? AND deleted_at IS NULL",
+            id,
+            expected_version,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if current.is_none() || version >= expected_version {
+            return Ok(false);
+        }
+        let Some(values) = values_at_version(&mut *tx, id, version).await? else {
+            return Ok(false);
+        };
+        let event = FieldEvent::Reverted { to_version: version };
+        if !apply_update(&mut tx, record, user, &values, expected_version, &event, self.now()).await? {
+            return Ok(false);
+        }
+
+        // Unlink the attachments the old version didn't have and link the ones it
+        // had that are gone now
+        let new_version = expected_version + 1;
+        sqlx::query!(
+            r#"
+            UPDATE attachment_links SET to_version = ?1
+            WHERE record_id = ?2 AND to_version IS NULL AND attachment_id NOT IN (
+                SELECT attachment_id FROM attachment_links
+                WHERE record_id = ?2 AND from_version <= ?3 AND (to_version IS NULL OR to_version > ?3)
+            )
+            "#,
+            new_version,
+            id,
+            version,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO attachment_links (record_id, attachment_id, from_version)
+            SELECT record_id, attachment_id, ?1 FROM attachment_links
+            WHERE record_id = ?2 AND from_version <= ?3 AND to_version > ?3
+            "#,
+            new_version,
+            id,
+            version,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // The highest history id written so far, used as the starting point for tailing
+    pub async fn latest_change_id(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) AS "id!: i64" FROM fields_history"#)
+            .fetch_one(self.pool())
+            .await
+    }
+
+    // Events that produced versions of one record newer than the given version, oldest first
+    pub async fn changes_since(&self, record_id: i64, version: i64) -> Result<Vec<EventEnvelope>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+            "SELECT {} FROM fields_history WHERE record_id = ? AND tenant_id = ? AND version > ? ORDER BY id",
+            HISTORY_COLUMNS
+        ))
+        .bind(record_id)
+        .bind(self.tenant().as_str())
+        .bind(version)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(EventEnvelope::from)
+            .filter(|e| e.event.changes_state())
+            .collect())
+    }
+
+    // All events written after the given history id, of every tenant, oldest first
+    pub async fn changes_after(&self, after_id: i64, limit: i64) -> Result<Vec<(TenantId, EventEnvelope)>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+            "SELECT {} FROM fields_history WHERE id > ? ORDER BY id LIMIT ?",
+            HISTORY_COLUMNS
+        ))
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (TenantId::parse(&row.tenant_id).unwrap_or_default(), EventEnvelope::from(row)))
+            .collect())
+    }
+}
+
+// An audit log row as stored
+#[cfg(feature = "ssr")]
+#[derive(FromRow)]
+pub(crate) struct HistoryRow {
+    pub id: i64,
+    pub record_id: i64,
+    pub version: i64,
+    pub change_type: String,
+    pub event: Option<String>,
+    pub field_values: String,
+    pub changed_at: i64,
+    pub changed_by: Option<String>,
+    pub tenant_id: String,
+}
+
+// Columns to select for a `HistoryRow`
+#[cfg(feature = "ssr")]
+pub(crate) const HISTORY_COLUMNS: &str =
+    "id, record_id, version, change_type, event, field_values, changed_at, changed_by, tenant_id";
+
+#[cfg(feature = "ssr")]
+impl From<HistoryRow> for EventEnvelope {
+    fn from(row: HistoryRow) -> Self {
+        // Rows written before events were stored as JSON only have the change type
+        let event = row
+            .event
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_else(|| {
+                serde_json::from_value(serde_json::json!({ "type": row.change_type }))
+                    .unwrap_or(FieldEvent::Unknown)
+            });
+        EventEnvelope {
+            schema_version: EVENT_SCHEMA_VERSION,
+            id: row.id,
+            record_id: row.record_id,
+            version: row.version,
+            occurred_at: row.changed_at,
+            actor: row.changed_by,
+            event,
+        }
+    }
+}
+
+// Record an event in the audit log together with a snapshot of the record's
+// current state. The actor defaults to whoever the row says made the last change.
+// Called inside the transaction that made the change.
+#[cfg(feature = "ssr")]
+pub(crate) async fn append_history(
+    conn: &mut SqliteConnection,
+    record: ScopedRecord,
+    event: &FieldEvent,
+    actor: Option<&str>,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let id = record.id();
+    // Stamp the record with the checksum of its current version first, so the
+    // record and the audit log agree on it
+    // The log keeps the values as stored, so encrypted values stay encrypted in it
+    let row: Option<(i64, String)> =
+        sqlx::query_as(&format!("SELECT version, {} FROM fields WHERE id = ?", FIELD_VALUES_COLUMN))
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    let Some((version, stored)) = row else {
+        return Ok(());
+    };
+    let values = FieldValues::from_stored(&stored).map_err(sqlx::Error::Decode)?;
+    let checksum = record_checksum(id, version, &values);
+    sqlx::query!("UPDATE fields SET checksum = ?1 WHERE id = ?2 AND checksum IS NOT ?1", checksum, id)
+        .execute(&mut *conn)
+        .await?;
+
+    let kind = event.kind();
+    let event_json = serde_json::to_string(event).expect("FieldEvent serializes");
+    sqlx::query!(
+        r#"
+        INSERT INTO fields_history
+            (record_id, version, change_type, event, field_values, changed_at, changed_by, checksum, tenant_id)
+        SELECT id, version, ?, ?, ?, ?, COALESCE(?, updated_by), checksum, tenant_id
+        FROM fields WHERE id = ?
+        "#,
+        kind,
+        event_json,
+        stored,
+        now,
+        actor,
+        id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// A record's values as of an earlier version, from the audit log; None if the
+// log doesn't go back that far
+#[cfg(feature = "ssr")]
+pub(crate) async fn values_at_version<'e, E>(
+    executor: E,
+    id: i64,
+    version: i64,
+) -> Result<Option<FieldValues>, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let values: Option<String> = sqlx::query_scalar!(
+        "SELECT field_values FROM fields_history WHERE record_id = ? AND version = ? ORDER BY id DESC LIMIT 1",
+        id,
+        version,
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(values.and_then(|json| FieldValues::from_stored(&json).ok()))
+}
+
+// Claim an idempotency key inside the caller's transaction. The insert takes
+// the write lock, so a concurrent retry waits for us and then finds the key with
+// its result filled in. Returns that earlier result if the key was used before.
+#[cfg(feature = "ssr")]
+async fn claim_idempotency_key(
+    conn: &mut SqliteConnection,
+    user: &str,
+    key: &str,
+    record_id: i64,
+    now: i64,
+) -> Result<Option<bool>, sqlx::Error> {
+    let claimed = sqlx::query!(
+        r#"
+        INSERT INTO processed_requests (user_name, idempotency_key, record_id, created_at)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT(user_name, idempotency_key) DO NOTHING
+        "#,
+        user,
+        key,
+        record_id,
+        now,
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        > 0;
+    if claimed {
+        return Ok(None);
+    }
+    let result: Option<bool> = sqlx::query_scalar!(
+        r#"SELECT result AS "result: bool" FROM processed_requests WHERE user_name = ? AND idempotency_key = ?"#,
+        user,
+        key,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(Some(result.unwrap_or(false)))
+}
+
+// Save `values` over whatever version the record is at now, in the same
+// transaction that found it had moved past `expected_version`. A record in the
+// trash stays a conflict.
+#[cfg(feature = "ssr")]
+async fn overwrite_current(
+    conn: &mut SqliteConnection,
+    record: ScopedRecord,
+    user: &str,
+    values: &FieldValues,
+    expected_version: i64,
+    now: i64,
+) -> Result<MergeResult, sqlx::Error> {
+    let id = record.id();
+    let current: Option<i64> = sqlx::query_scalar!("SELECT version FROM fields WHERE id = ? AND deleted_at IS NULL", id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(current) = current else {
+        return Ok(MergeResult::Conflict { fields: Vec::new() });
+    };
+    let event = FieldEvent::Overwritten { expected_version };
+    match apply_update(conn, record, user, values, current, &event, now).await? {
+        true => Ok(MergeResult::Overwritten { version: current + 1 }),
+        false => Ok(MergeResult::Conflict { fields: Vec::new() }),
+    }
+}
+
+// Write new values over the version the caller saw, bumping the version and
+// recording the change as `event` in the audit log and for webhooks. Returns
+// false if the record isn't at that version. Called inside the caller's transaction.
+#[cfg(feature = "ssr")]
+pub(crate) async fn apply_update(
+    conn: &mut SqliteConnection,
+    record: ScopedRecord,
+    user: &str,
+    values: &FieldValues,
+    expected_version: i64,
+    event: &FieldEvent,
+    now: i64,
+) -> Result<bool, sqlx::Error> {
+    let id = record.id();
+    let old_values = record_values(&mut *conn, record).await?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE fields SET version = version + 1, updated_by = ?, updated_at = ?
+        WHERE id = ? AND version = ?
+        "#,
+        user,
+        now,
+        id,
+        expected_version,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    write_values(&mut *conn, record, values).await?;
+    append_history(&mut *conn, record, event, None, now).await?;
+    let new_values = record_values(&mut *conn, record).await?;
+    let change = WebhookPayload::updated(id, expected_version + 1, user, old_values, new_values, now);
+    enqueue_webhook_deliveries(&mut *conn, &change, now).await?;
+    Ok(true)
+}
+
+// Store the outcome of a save under its idempotency key, if it has one
+#[cfg(feature = "ssr")]
+async fn record_idempotent_result<'e, E>(
+    executor: E,
+    user: &str,
+    idempotency_key: Option<&str>,
+    result: bool,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    if let Some(key) = idempotency_key {
+        sqlx::query!(
+            "UPDATE processed_requests SET result = ? WHERE user_name = ? AND idempotency_key = ?",
+            result,
+            user,
+            key,
+        )
+        .execute(executor)
+        .await?;
+    }
+    Ok(())
+}
+
+// Back the audit log queries: per record, by actor, by change type and by time
+#[cfg(feature = "ssr")]
+const HISTORY_INDEXES: [&str; 4] = [
+    "CREATE INDEX IF NOT EXISTS fields_history_record ON fields_history (record_id, id)",
+    "CREATE INDEX IF NOT EXISTS fields_history_actor ON fields_history (changed_by, id)",
+    "CREATE INDEX IF NOT EXISTS fields_history_type ON fields_history (change_type, id)",
+    "CREATE INDEX IF NOT EXISTS fields_history_time ON fields_history (changed_at)",
+];
+
+// The fields a tenant starts out with
+#[cfg(feature = "ssr")]
+pub const DEFAULT_FIELDS: [&str; 4] = ["field1", "field2", "field3", "field4"];
+
+// A record's values as the JSON object `FieldValues` decodes from: every field
+// its tenant defines, empty where the record has no value for it. Selects from
+// the record's row in `fields`.
+#[cfg(any(feature = "ssr", feature = "d1"))]
+macro_rules! field_values_sql {
+    () => {
+        r#"(
+            SELECT json_group_object(d.name, COALESCE(v.value, ''))
+            FROM field_definitions d
+            LEFT JOIN field_values v ON v.record_id = fields.id AND v.field_name = d.name
+            WHERE d.tenant_id = fields.tenant_id
+        ) AS field_values"#
+    };
+}
+
+// Columns to select from `fields` for a `FieldValues`, and for `Fields`.
+// `get_fields` spells them out for its checked query; keep the two alike.
+#[cfg(any(feature = "ssr", feature = "d1"))]
+pub(crate) const FIELD_VALUES_COLUMN: &str = field_values_sql!();
+#[cfg(feature = "ssr")]
+pub(crate) const FIELDS_COLUMNS: &str = concat!("id, ", field_values_sql!(), ", version, updated_by, updated_at");
+
+// A record's title: the value of its tenant's first field
+#[cfg(feature = "ssr")]
+pub(crate) const TITLE_EXPR: &str = r#"COALESCE((
+    SELECT COALESCE(v.value, '')
+    FROM field_definitions d
+    LEFT JOIN field_values v ON v.record_id = fields.id AND v.field_name = d.name
+    WHERE d.tenant_id = fields.tenant_id
+    ORDER BY d.position LIMIT 1
+), '')"#;
+
+// Titles are selected as stored; decrypt the ones encrypted at rest
+#[cfg(feature = "ssr")]
+pub(crate) fn reveal_title(title: String) -> Result<String, sqlx::Error> {
+    crate::keys::reveal(&title).map_err(|e| sqlx::Error::Decode(e.into()))
+}
+
+#[cfg(feature = "ssr")]
+pub(crate) fn reveal_titles(records: Vec<RecordSummary>) -> Result<Vec<RecordSummary>, sqlx::Error> {
+    records
+        .into_iter()
+        .map(|record| {
+            Ok(RecordSummary {
+                title: reveal_title(record.title)?,
+                ..record
+            })
+        })
+        .collect()
+}
+
+// A record's current values, every defined field included
+#[cfg(feature = "ssr")]
+pub(crate) async fn record_values(conn: &mut SqliteConnection, record: ScopedRecord) -> Result<FieldValues, sqlx::Error> {
+    sqlx::query_scalar(&format!("SELECT {} FROM fields WHERE id = ?", FIELD_VALUES_COLUMN))
+        .bind(record.id())
+        .fetch_one(conn)
+        .await
+}
+
+// Write values into a record at its current version. Fields its tenant doesn't
+// define are ignored, and fields left out keep their values. Values that
+// changed are stamped with the record's version, and encrypted if keys are installed.
+#[cfg(feature = "ssr")]
+pub(crate) async fn write_values(
+    conn: &mut SqliteConnection,
+    record: ScopedRecord,
+    values: &FieldValues,
+) -> Result<(), sqlx::Error> {
+    let id = record.id();
+    let tenant: String = sqlx::query_scalar!("SELECT tenant_id FROM fields WHERE id = ?", id)
+        .fetch_one(&mut *conn)
+        .await?;
+    ensure_field_definitions(&mut *conn, &TenantId::parse(&tenant).unwrap_or_default()).await?;
+    // Encrypting gives a different text every time, so only seal what changed
+    // and leave unchanged values with the version that last changed them
+    let sealed;
+    let values = match crate::keys::keyring() {
+        Some(keyring) => {
+            let current = record_values(&mut *conn, record).await?;
+            sealed = values
+                .0
+                .iter()
+                .filter(|(name, value)| current.0.get(*name) != Some(*value))
+                .map(|(name, value)| (name.as_str(), keyring.seal_field(name, value)))
+                .collect::<FieldValues>();
+            &sealed
+        }
+        None => values,
+    };
+    let values = serde_json::to_string(values).expect("FieldValues serialize");
+    sqlx::query!(
+        r#"
+        INSERT INTO field_values (record_id, field_name, value, version)
+        SELECT f.id, j.key, j.value, f.version
+        FROM fields f
+        JOIN json_each(?) j
+        JOIN field_definitions d ON d.tenant_id = f.tenant_id AND d.name = j.key
+        WHERE f.id = ?
+        ON CONFLICT (record_id, field_name) DO UPDATE SET value = excluded.value, version = excluded.version
+        WHERE value IS NOT excluded.value
+        "#,
+        values,
+        id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Give a tenant the default fields if it has no field definitions yet
+#[cfg(feature = "ssr")]
+pub(crate) async fn ensure_field_definitions(conn: &mut SqliteConnection, tenant: &TenantId) -> Result<(), sqlx::Error> {
+    let tenant = tenant.as_str();
+    let names = serde_json::to_string(&DEFAULT_FIELDS).expect("names serialize");
+    sqlx::query!(
+        r#"
+        INSERT INTO field_definitions (tenant_id, name, position)
+        SELECT ?1, value, key FROM json_each(?2)
+        WHERE NOT EXISTS (SELECT 1 FROM field_definitions WHERE tenant_id = ?1)
+        "#,
+        tenant,
+        names,
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Databases from before field definitions kept four fixed columns on `fields`
+// and indexed them in `fields_fts`. Move the values into `field_values`, give
+// every tenant that has records the default fields, and drop the old columns.
+#[cfg(feature = "ssr")]
+async fn migrate_fixed_fields(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let fixed: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info('fields') WHERE name = 'field1'")
+        .fetch_one(pool)
+        .await?;
+    if !fixed {
+        return Ok(());
+    }
+    tracing::info!("Moving field values out of the fields table");
+    let mut tx = pool.begin().await?;
+    let tenants: Vec<String> = sqlx::query_scalar("SELECT DISTINCT tenant_id FROM fields")
+        .fetch_all(&mut *tx)
+        .await?;
+    for tenant in tenants {
+        ensure_field_definitions(&mut tx, &TenantId::parse(&tenant).unwrap_or_default()).await?;
+    }
+    for name in DEFAULT_FIELDS {
+        sqlx::query(&format!(
+            "INSERT OR IGNORE INTO field_values (record_id, field_name, value, version) SELECT id, ?, {}, version FROM fields",
+            name
+        ))
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    }
+    for statement in [
+        "DROP TRIGGER IF EXISTS fields_fts_insert",
+        "DROP TRIGGER IF EXISTS fields_fts_delete",
+        "DROP TRIGGER IF EXISTS fields_fts_update",
+        "DROP TABLE IF EXISTS fields_fts",
+    ] {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    for name in DEFAULT_FIELDS {
+        sqlx::query(&format!("ALTER TABLE fields DROP COLUMN {}", name))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+// Keep each record's entry in `records_fts`, all its plain text values in one
+// column, in step with `field_values`. Encrypted values (`enc:v1:`, see
+// `keys::SEALED_PREFIX`) are left out: their text means nothing to a search.
+#[cfg(feature = "ssr")]
+const FTS_TRIGGERS: [&str; 3] = [
+    r#"
+    CREATE TRIGGER IF NOT EXISTS field_values_fts_insert_plain AFTER INSERT ON field_values BEGIN
+        DELETE FROM records_fts WHERE rowid = new.record_id;
+        INSERT INTO records_fts (rowid, content)
+        SELECT new.record_id, group_concat(value, ' ') FROM field_values
+        WHERE record_id = new.record_id AND value NOT LIKE 'enc:v1:%'
+        HAVING COUNT(*) > 0;
+    END
+    "#,
+    r#"
+    CREATE TRIGGER IF NOT EXISTS field_values_fts_delete_plain AFTER DELETE ON field_values BEGIN
+        DELETE FROM records_fts WHERE rowid = old.record_id;
+        INSERT INTO records_fts (rowid, content)
+        SELECT old.record_id, group_concat(value, ' ') FROM field_values
+        WHERE record_id = old.record_id AND value NOT LIKE 'enc:v1:%'
+        HAVING COUNT(*) > 0;
+    END
+    "#,
+    r#"
+    CREATE TRIGGER IF NOT EXISTS field_values_fts_update_plain AFTER UPDATE OF value ON field_values BEGIN
+        DELETE FROM records_fts WHERE rowid = new.record_id;
+        INSERT INTO records_fts (rowid, content)
+        SELECT new.record_id, group_concat(value, ' ') FROM field_values
+        WHERE record_id = new.record_id AND value NOT LIKE 'enc:v1:%'
+        HAVING COUNT(*) > 0;
+    END
+    "#,
+];
+
+// Keeps `daily_record_activity` in step with the audit log, in the transaction
+// that writes the log entry
+#[cfg(feature = "ssr")]
+const ACTIVITY_TRIGGER: &str = concat!(
+    r#"
+    CREATE TRIGGER IF NOT EXISTS fields_history_activity AFTER INSERT ON fields_history
+    WHEN new.change_type IN "#,
+    save_kinds_sql!(),
+    r#" OR new.change_type = 'conflict_detected' BEGIN
+        INSERT INTO daily_record_activity (tenant_id, record_id, day, saves, conflicts)
+        VALUES (
+            new.tenant_id, new.record_id, date(new.changed_at / 1000, 'unixepoch'),
+            new.change_type <> 'conflict_detected', new.change_type = 'conflict_detected'
+        )
+        ON CONFLICT (record_id, day) DO UPDATE SET
+            saves = saves + excluded.saves, conflicts = conflicts + excluded.conflicts;
+    END
+    "#
+);
+
+// Keeps `daily_record_editors` in step with the audit log, like the activity trigger
+#[cfg(feature = "ssr")]
+const EDITORS_TRIGGER: &str = concat!(
+    r#"
+    CREATE TRIGGER IF NOT EXISTS fields_history_editors AFTER INSERT ON fields_history
+    WHEN new.changed_by IS NOT NULL AND new.change_type IN "#,
+    save_kinds_sql!(),
+    r#" BEGIN
+        INSERT OR IGNORE INTO daily_record_editors (tenant_id, record_id, day, editor)
+        VALUES (new.tenant_id, new.record_id, date(new.changed_at / 1000, 'unixepoch'), new.changed_by);
+    END
+    "#
+);
+
+// Every new version gets a later `updated_at` than the one before, even when
+// two saves land in the same millisecond or the clock steps back, so a
+// timestamp names a single version as surely as the version number does
+#[cfg(feature = "ssr")]
+const UPDATED_AT_TRIGGER: &str = r#"
+    CREATE TRIGGER IF NOT EXISTS fields_updated_at_increasing AFTER UPDATE OF version ON fields
+    WHEN new.version <> old.version AND new.updated_at <= old.updated_at BEGIN
+        UPDATE fields SET updated_at = old.updated_at + 1 WHERE id = new.id;
+    END
+"#;
+
+// Add a column to an existing table, for databases created by older versions
+#[cfg(feature = "ssr")]
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let exists: bool =
+        sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::auth::User;
+    use crate::providers::{FixedClock, SequentialIdGenerator};
+    use std::path::PathBuf;
+
+    // A database of its own in the temp directory, on a clock that only moves when told to
+    async fn temp_db(clock: Arc<FixedClock>) -> (PathBuf, DbManager) {
+        let path = std::env::temp_dir().join(format!("field-editor-test-{}.db", uuid::Uuid::new_v4()));
+        let db = DbManager::builder()
+            .url(&format!("sqlite:{}?mode=rwc", path.display()))
+            .build()
+            .await
+            .expect("database opens")
+            .with_clock(clock);
+        (path, db)
+    }
+
+    async fn remove(path: PathBuf, db: DbManager) {
+        db.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[actix_web::test]
+    async fn saves_in_the_same_millisecond_get_their_own_timestamps() {
+        let clock = Arc::new(FixedClock::new(1_700_000_000_000));
+        let (path, db) = temp_db(clock).await;
+        let db = db.with_concurrency_mode(ConcurrencyMode::Timestamp);
+        let name = db.field_definitions().await.unwrap()[0].name.clone();
+        let values = |value: &str| -> FieldValues { [(name.as_str(), value)].into_iter().collect() };
+
+        let created = db.get_or_create_fields(1, &values("created")).await.unwrap();
+        let expected = db.expected_version_of(1, &db.concurrency_token(&created)).await.unwrap().unwrap();
+        assert!(db.update_fields(1, "alice", &values("first"), expected, None).await.unwrap());
+        let first = db.get_fields(1).await.unwrap();
+        let first_token = db.concurrency_token(&first);
+
+        // Saved in the same millisecond as the first save
+        let expected = db.expected_version_of(1, &first_token).await.unwrap().unwrap();
+        assert!(db.update_fields(1, "bob", &values("second"), expected, None).await.unwrap());
+        let second = db.get_fields(1).await.unwrap();
+        assert!(second.updated_at > first.updated_at);
+        assert_ne!(db.concurrency_token(&second), first_token);
+
+        // So a writer still holding the first save's timestamp is turned away
+        let stale = db.expected_version_of(1, &first_token).await.unwrap().unwrap();
+        assert!(!db.update_fields(1, "carol", &values("third"), stale, None).await.unwrap());
+        assert_eq!(db.get_fields(1).await.unwrap().values, second.values);
+
+        remove(path, db).await;
+    }
+
+    #[actix_web::test]
+    async fn timestamp_tokens_must_be_rfc3339() {
+        let (path, db) = temp_db(Arc::new(FixedClock::new(1_700_000_000_000))).await;
+        let db = db.with_concurrency_mode(ConcurrencyMode::Timestamp);
+        db.get_or_create_fields(1, &FieldValues::default()).await.unwrap();
+        assert!(db.expected_version_of(1, "7").await.unwrap().is_err());
+        // A record that isn't there is a conflict, not a malformed token
+        assert_eq!(db.expected_version_of(2, "2023-11-14T22:13:20Z").await.unwrap(), Ok(0));
+        remove(path, db).await;
+    }
+
+    #[actix_web::test]
+    async fn sessions_run_on_the_injected_clock_and_ids() {
+        let clock = Arc::new(FixedClock::new(1_700_000_000_000));
+        let (path, db) = temp_db(clock.clone()).await;
+        let db = db.with_id_generator(Arc::new(SequentialIdGenerator::new("test")));
+        assert_eq!(db.now(), 1_700_000_000_000);
+
+        // Registering takes neither an id nor the time
+        assert!(db.create_user("alice", "correct horse").await.unwrap());
+        let session = db.create_session(&User::named("alice")).await.unwrap();
+        // The session id, then its CSRF token
+        assert_eq!(session, "test-1");
+        assert_eq!(db.new_id(), "test-3");
+        assert_eq!(db.session_user(&session).await.unwrap().map(|user| user.name).as_deref(), Some("alice"));
+
+        // Sessions last a week from 1_700_000_000_000
+        clock.set(1_700_000_000_000 + 7 * 24 * 60 * 60 * 1000 - 1);
+        assert!(db.session_user(&session).await.unwrap().is_some());
+        clock.advance(1);
+        assert_eq!(db.session_user(&session).await.unwrap(), None);
+
+        remove(path, db).await;
+    }
+
+    #[actix_web::test]
+    async fn saves_are_stamped_with_the_injected_clock() {
+        let clock = Arc::new(FixedClock::new(1_700_000_000_000));
+        let (path, db) = temp_db(clock.clone()).await;
+        let name = db.field_definitions().await.unwrap()[0].name.clone();
+        let values: FieldValues = [(name.as_str(), "saved")].into_iter().collect();
+
+        db.get_or_create_fields(1, &FieldValues::default()).await.unwrap();
+        clock.advance(5_000);
+        assert!(db.update_fields(1, "alice", &values, 1, None).await.unwrap());
+        let saved = db.get_fields(1).await.unwrap();
+        assert_eq!(saved.updated_at, Some(1_700_000_005_000));
+        assert_eq!(saved.updated_by.as_deref(), Some("alice"));
+
+        remove(path, db).await;
     }
 }
@@ -0,0 +1,85 @@
+use crate::db::Fields;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+use std::collections::BTreeMap;
+
+// What changed in a record since a version the client already has, so clients
+// that fall behind fetch only the fields that differ instead of the whole record
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldDelta {
+    pub id: i64,
+    // The version the client has
+    pub since: i64,
+    // The record's current version; equal to `since` when nothing changed
+    pub version: i64,
+    // Current values of the fields that differ from version `since`. All of them
+    // when the audit log no longer knows that version.
+    pub changed: BTreeMap<String, String>,
+    // Every field the record has now; the ones the client has besides were
+    // removed or renamed since
+    pub names: Vec<String>,
+    pub updated_by: Option<String>,
+    pub updated_at: Option<i64>,
+}
+
+impl FieldDelta {
+    // Bring a copy of the record at version `since` up to date
+    pub fn apply(&self, fields: &mut Fields) {
+        fields.values.0.retain(|name, _| self.names.contains(name));
+        for (name, value) in &self.changed {
+            fields.values.set(name.clone(), value.clone());
+        }
+        fields.version = self.version;
+        fields.updated_by = self.updated_by.clone();
+        fields.updated_at = self.updated_at;
+    }
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::FieldDelta;
+    use crate::db::{values_at_version, DbManager};
+    use std::collections::BTreeMap;
+
+    impl DbManager {
+        // The fields of a live record that changed after `since`
+        pub async fn delta_since(&self, id: i64, since: i64) -> Result<FieldDelta, sqlx::Error> {
+            let current = self.get_fields(id).await?;
+            let base = match current.version == since {
+                true => None,
+                false => values_at_version(self.pool(), id, since).await?,
+            };
+            let mut changed = BTreeMap::new();
+            if current.version != since {
+                for (name, value) in current.values.iter() {
+                    let old = base.as_ref().and_then(|b| b.0.get(name));
+                    if old.map(String::as_str) != Some(value) {
+                        changed.insert(name.to_string(), value.to_string());
+                    }
+                }
+            }
+            Ok(FieldDelta {
+                id,
+                since,
+                version: current.version,
+                changed,
+                names: current.values.names().map(str::to_string).collect(),
+                updated_by: current.updated_by,
+                updated_at: current.updated_at,
+            })
+        }
+    }
+}
+
+// The fields of a record that changed after the given version
+#[server(GetChangesSince)]
+pub async fn get_changes_since(id: i64, version: i64) -> Result<FieldDelta, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.delta_since(id, version).await.map_err(db_error)
+}
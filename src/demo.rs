@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+// Knobs for showing off the concurrency handling in talks: slow the server down
+// and have a bot save over the record now and then, so conflicts happen on cue.
+// Set from the page URL, e.g. `/records/1?latency=2000&conflict_rate=0.5`, and
+// only honored by servers in demo mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DemoKnobs {
+    // Extra delay added to every load and save
+    #[serde(default)]
+    pub latency_ms: u64,
+    // Chance between 0 and 1 that a save finds the record just changed by someone else
+    #[serde(default)]
+    pub conflict_rate: f64,
+}
+
+impl DemoKnobs {
+    // Read `latency` and `conflict_rate` from a page's query string; None if neither is set
+    pub fn from_query(latency: Option<String>, conflict_rate: Option<String>) -> Option<Self> {
+        let latency_ms = latency.and_then(|v| v.parse().ok());
+        let conflict_rate = conflict_rate.and_then(|v| v.parse::<f64>().ok());
+        if latency_ms.is_none() && conflict_rate.is_none() {
+            return None;
+        }
+        Some(DemoKnobs {
+            latency_ms: latency_ms.unwrap_or(0),
+            conflict_rate: conflict_rate.unwrap_or(0.0).clamp(0.0, 1.0),
+        })
+    }
+
+    // Human-readable summary for the banner
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ms latency, {:.0}% of saves conflict",
+            self.latency_ms,
+            self.conflict_rate * 100.0
+        )
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::DemoKnobs;
+    use crate::db::{DbManager, FieldValues};
+    use crate::timestamp::format_time_of_day;
+    use std::time::Duration;
+
+    // Who the injected competing saves are attributed to
+    pub const DEMO_BOT: &str = "demo-bot";
+    // Never keep a request waiting longer than this, whatever the URL says
+    const MAX_LATENCY_MS: u64 = 30_000;
+
+    // Demo knobs work in debug builds, or anywhere with FIELD_EDITOR_DEMO=1
+    pub fn demo_enabled() -> bool {
+        cfg!(debug_assertions) || std::env::var("FIELD_EDITOR_DEMO").is_ok_and(|v| v == "1")
+    }
+
+    // The knobs to act on, if the server allows them
+    fn active(knobs: Option<DemoKnobs>) -> Option<DemoKnobs> {
+        knobs.filter(|_| demo_enabled())
+    }
+
+    // Sleep for the requested latency
+    pub async fn demo_latency(knobs: Option<DemoKnobs>) {
+        if let Some(knobs) = active(knobs).filter(|k| k.latency_ms > 0) {
+            tokio::time::sleep(Duration::from_millis(knobs.latency_ms.min(MAX_LATENCY_MS))).await;
+        }
+    }
+
+    // Roll the dice and maybe save a competing change to the record, so the
+    // save about to happen finds a newer version than it expects
+    pub async fn demo_contention(db: &DbManager, knobs: Option<DemoKnobs>, id: i64) -> Result<(), sqlx::Error> {
+        let Some(knobs) = active(knobs) else {
+            return Ok(());
+        };
+        let roll = uuid::Uuid::new_v4().as_u128() as f64 / u128::MAX as f64;
+        if roll >= knobs.conflict_rate {
+            return Ok(());
+        }
+        let current = db.get_fields(id).await?;
+        // The competing change goes into the record's last field
+        let mut values = FieldValues::default();
+        if let Some(last) = current.values.names().last() {
+            values.set(last, format!("Changed by {} at {}", DEMO_BOT, format_time_of_day(db.now())));
+        }
+        db.update_fields(id, DEMO_BOT, &values, current.version, None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::str::FromStr;
+
+const TOO_MANY_REQUESTS_PREFIX: &str = "Too many requests; try again in ";
+const TOO_MANY_REQUESTS_SUFFIX: &str = "s";
+const INVALID_FIELD_PREFIX: &str = "Invalid field ";
+const INVALID_FIELD_SEPARATOR: &str = ": ";
+
+// A save was refused because of the value of one field, or because it named
+// one the tenant doesn't have
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub field: String,
+    pub message: String,
+}
+
+// Errors the mutation server functions report to the UI as values it can match on,
+// rather than as an opaque message. Travels inside `ServerFnError::WrappedServerError`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditorError {
+    // The caller hit the rate limit and should wait before trying again
+    TooManyRequests { retry_after_secs: u64 },
+    // The values can't be saved as they are; the form can point at the field
+    Invalid(ValidationError),
+    // Anything else, as a message for the user
+    Other(String),
+}
+
+// Server function errors carrying an `EditorError`
+pub type EditorServerError = leptos::prelude::ServerFnError<EditorError>;
+
+// The wire format of server function errors is this Display output,
+// parsed back by FromStr on the client, so the two must round-trip
+impl fmt::Display for EditorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditorError::TooManyRequests { retry_after_secs } => write!(
+                f,
+                "{}{}{}",
+                TOO_MANY_REQUESTS_PREFIX, retry_after_secs, TOO_MANY_REQUESTS_SUFFIX
+            ),
+            EditorError::Invalid(ValidationError { field, message }) => write!(
+                f,
+                "{}{}{}{}",
+                INVALID_FIELD_PREFIX, field, INVALID_FIELD_SEPARATOR, message
+            ),
+            EditorError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl FromStr for EditorError {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let retry_after_secs = s
+            .strip_prefix(TOO_MANY_REQUESTS_PREFIX)
+            .and_then(|rest| rest.strip_suffix(TOO_MANY_REQUESTS_SUFFIX))
+            .and_then(|secs| secs.parse().ok());
+        // Field names can't contain the separator, so the first one ends the name
+        let invalid = s
+            .strip_prefix(INVALID_FIELD_PREFIX)
+            .and_then(|rest| rest.split_once(INVALID_FIELD_SEPARATOR));
+        Ok(match (retry_after_secs, invalid) {
+            (Some(retry_after_secs), _) => EditorError::TooManyRequests { retry_after_secs },
+            (None, Some((field, message))) => EditorError::Invalid(ValidationError {
+                field: field.to_string(),
+                message: message.to_string(),
+            }),
+            (None, None) => EditorError::Other(s.to_string()),
+        })
+    }
+}
+
+// Lets mutations use `?` on the helpers that return plain `ServerFnError`s
+impl From<leptos::prelude::ServerFnError> for EditorError {
+    fn from(e: leptos::prelude::ServerFnError) -> Self {
+        match e {
+            leptos::prelude::ServerFnError::ServerError(message) => EditorError::Other(message),
+            other => EditorError::Other(other.to_string()),
+        }
+    }
+}
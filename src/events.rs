@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+// Version of the event wire format. Bump it when an existing field changes
+// meaning; adding variants or optional fields doesn't need a bump because
+// readers ignore what they don't understand.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+// Something that happened to a record. This one type is what the audit log
+// stores and what the change feed, SSE clients and other consumers receive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldEvent {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    // A save was rejected because the record had moved past the version the editor loaded
+    ConflictDetected { expected_version: i64 },
+    // A save made on top of `base_version` was combined with the changes saved since
+    Merged { base_version: i64 },
+    // A save made on top of `expected_version` replaced whatever was saved since,
+    // under the last-writer-wins policy
+    Overwritten { expected_version: i64 },
+    LockAcquired,
+    LockReleased,
+    // A file was attached
+    AttachmentAdded { attachment_id: String },
+    // A new version of an attached file took the place of `replaced`
+    AttachmentReplaced { attachment_id: String, replaced: String },
+    // The values and attachments of `to_version` were saved again as a new version
+    Reverted { to_version: i64 },
+    // The record was given an expiry date, or had it removed (None)
+    ExpirySet { expires_at: Option<i64> },
+    // The record passed its expiry date and was moved to the trash
+    Archived,
+    // The record was given a date to be reviewed by, or had it removed (None)
+    ReviewBySet { review_by: Option<i64> },
+    // The record wasn't updated by its review date; `recipients` are the users
+    // who have changed it and should be reminded
+    ReviewDue { review_by: i64, recipients: Vec<String> },
+    // Review reminders for the record were put off until `until`
+    ReviewSnoozed { until: i64 },
+    // An event type introduced by a newer server; safe to skip
+    #[serde(other)]
+    Unknown,
+}
+
+impl FieldEvent {
+    // The name used in the `type` tag and the audit log's change_type column
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FieldEvent::Created => "created",
+            FieldEvent::Updated => "updated",
+            FieldEvent::Deleted => "deleted",
+            FieldEvent::Restored => "restored",
+            FieldEvent::ConflictDetected { .. } => "conflict_detected",
+            FieldEvent::Merged { .. } => "merged",
+            FieldEvent::Overwritten { .. } => "overwritten",
+            FieldEvent::LockAcquired => "lock_acquired",
+            FieldEvent::LockReleased => "lock_released",
+            FieldEvent::AttachmentAdded { .. } => "attachment_added",
+            FieldEvent::AttachmentReplaced { .. } => "attachment_replaced",
+            FieldEvent::Reverted { .. } => "reverted",
+            FieldEvent::ExpirySet { .. } => "expiry_set",
+            FieldEvent::Archived => "archived",
+            FieldEvent::ReviewBySet { .. } => "review_by_set",
+            FieldEvent::ReviewDue { .. } => "review_due",
+            FieldEvent::ReviewSnoozed { .. } => "review_snoozed",
+            FieldEvent::Unknown => "unknown",
+        }
+    }
+
+    // Whether the event produced a new version of the record's data
+    pub fn changes_state(&self) -> bool {
+        matches!(
+            self,
+            FieldEvent::Created
+                | FieldEvent::Updated
+                | FieldEvent::Merged { .. }
+                | FieldEvent::Overwritten { .. }
+                | FieldEvent::Deleted
+                | FieldEvent::Restored
+                | FieldEvent::AttachmentAdded { .. }
+                | FieldEvent::AttachmentReplaced { .. }
+                | FieldEvent::Reverted { .. }
+                | FieldEvent::Archived
+        )
+    }
+}
+
+// An event together with where and when it happened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+    // Position in the audit log, or among lock events for those; doubles as
+    // the SSE event id
+    pub id: i64,
+    pub record_id: i64,
+    // The record's version after the event
+    pub version: i64,
+    pub occurred_at: i64,
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(flatten)]
+    pub event: FieldEvent,
+}
+
+fn default_schema_version() -> u32 {
+    EVENT_SCHEMA_VERSION
+}
@@ -0,0 +1,312 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::{format_timestamp, parse_rfc3339};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Records can be given an expiry date. Once it has passed the record can still
+// be read but no longer changed, and under the archive action the background
+// runner also moves it to the trash. Editors warn about the date as it nears.
+// A record brought back from the trash while still expired is archived again
+// on the next run, so extend or remove the date first.
+
+// What becomes of a record past its expiry date
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpiryAction {
+    // It becomes read-only
+    #[default]
+    ReadOnly,
+    // It becomes read-only and is moved to the trash
+    Archive,
+}
+
+impl std::str::FromStr for ExpiryAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "readonly" => Ok(ExpiryAction::ReadOnly),
+            "archive" => Ok(ExpiryAction::Archive),
+            other => Err(format!("Unknown expiry action {}", other)),
+        }
+    }
+}
+
+// A record's expiry date and what it means for the editor right now
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordExpiry {
+    pub expires_at: Option<i64>,
+    // The date has passed; the record can't be changed any more
+    pub expired: bool,
+    // The date is within the warning period
+    pub expiring_soon: bool,
+    pub action: ExpiryAction,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ExpiryAction, RecordExpiry};
+    use crate::db::{append_history, DbManager};
+    use crate::events::FieldEvent;
+    use crate::field_editor::db_error;
+    use crate::tenant::TenantId;
+    use leptos::prelude::ServerFnError;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    // Attribution of records archived at expiry in the audit log
+    const EXPIRY_ACTOR: &str = "expiry";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExpiryPolicy {
+        pub action: ExpiryAction,
+        // How long before the expiry date editors start warning
+        pub warning: Duration,
+    }
+
+    impl ExpiryPolicy {
+        // FIELD_EDITOR_EXPIRY_ACTION (`read-only` or `archive`), read-only by
+        // default, and FIELD_EDITOR_EXPIRY_WARNING_DAYS, 7 by default
+        pub fn from_env() -> Self {
+            let days: u64 = std::env::var("FIELD_EDITOR_EXPIRY_WARNING_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(7);
+            ExpiryPolicy {
+                action: std::env::var("FIELD_EDITOR_EXPIRY_ACTION")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_default(),
+                warning: Duration::from_secs(days * 24 * 60 * 60),
+            }
+        }
+    }
+
+    // The expiry policy for this process, read from the environment once
+    pub fn expiry_policy() -> &'static ExpiryPolicy {
+        static POLICY: OnceLock<ExpiryPolicy> = OnceLock::new();
+        POLICY.get_or_init(ExpiryPolicy::from_env)
+    }
+
+    impl DbManager {
+        // A record's expiry under `policy`; None if the record isn't this tenant's
+        pub async fn record_expiry(&self, id: i64, policy: &ExpiryPolicy) -> Result<Option<RecordExpiry>, sqlx::Error> {
+            let expires_at: Option<Option<i64>> =
+                sqlx::query_scalar("SELECT expires_at FROM fields WHERE id = ? AND tenant_id = ?")
+                    .bind(id)
+                    .bind(self.tenant().as_str())
+                    .fetch_optional(self.pool())
+                    .await?;
+            let now = self.now();
+            Ok(expires_at.map(|expires_at| RecordExpiry {
+                expires_at,
+                expired: expires_at.is_some_and(|at| at <= now),
+                expiring_soon: expires_at.is_some_and(|at| at > now && at - now <= policy.warning.as_millis() as i64),
+                action: policy.action,
+            }))
+        }
+
+        // Whether a record is past its expiry date
+        pub async fn is_expired(&self, id: i64) -> Result<bool, sqlx::Error> {
+            let expired: Option<bool> = sqlx::query_scalar(
+                "SELECT expires_at IS NOT NULL AND expires_at <= ? FROM fields WHERE id = ? AND tenant_id = ?",
+            )
+            .bind(self.now())
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(self.pool())
+            .await?;
+            Ok(expired.unwrap_or(false))
+        }
+
+        // Give a record an expiry date, or remove it with None. Works on
+        // expired records too, which is how they're made editable again.
+        // Returns false if the record isn't this tenant's.
+        #[tracing::instrument(skip(self))]
+        pub async fn set_expiry(&self, id: i64, user: &str, expires_at: Option<i64>) -> Result<bool, sqlx::Error> {
+            self.retrying("set_expiry", || async {
+                let mut tx = self.pool().begin().await?;
+                let Some(record) = self.scope(&mut tx, id).await? else {
+                    return Ok(false);
+                };
+                sqlx::query("UPDATE fields SET expires_at = ? WHERE id = ?")
+                    .bind(expires_at)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                let event = FieldEvent::ExpirySet { expires_at };
+                append_history(&mut tx, record, &event, Some(user), self.now()).await?;
+                tx.commit().await?;
+                Ok(true)
+            })
+            .await
+        }
+
+        // Move the records of every tenant that are past their expiry date to
+        // the trash. Returns how many were moved.
+        #[tracing::instrument(skip(self))]
+        pub async fn archive_expired(&self) -> Result<usize, sqlx::Error> {
+            self.retrying("archive_expired", || async {
+                let now = self.now();
+                let mut tx = self.pool().begin().await?;
+                let archived: Vec<(i64, String)> = sqlx::query_as(
+                    r#"
+                    UPDATE fields SET deleted_at = ?1, version = version + 1, updated_by = ?2, updated_at = ?1
+                    WHERE expires_at <= ?1 AND deleted_at IS NULL
+                    RETURNING id, tenant_id
+                    "#,
+                )
+                .bind(now)
+                .bind(EXPIRY_ACTOR)
+                .fetch_all(&mut *tx)
+                .await?;
+                for (id, tenant) in &archived {
+                    let db = self.clone().with_tenant(TenantId::parse(tenant).unwrap_or_default());
+                    if let Some(record) = db.scope(&mut tx, *id).await? {
+                        append_history(&mut tx, record, &FieldEvent::Archived, None, now).await?;
+                    }
+                }
+                tx.commit().await?;
+                for (id, tenant) in &archived {
+                    let db = self.clone().with_tenant(TenantId::parse(tenant).unwrap_or_default());
+                    db.forget_cached(*id).await;
+                }
+                Ok(archived.len())
+            })
+            .await
+        }
+    }
+
+    // Guard for mutations: fails if the record is past its expiry date
+    pub async fn require_unexpired(db: &DbManager, id: i64) -> Result<(), ServerFnError> {
+        match db.is_expired(id).await.map_err(db_error)? {
+            true => Err(ServerFnError::new("This record has expired and can no longer be changed")),
+            false => Ok(()),
+        }
+    }
+}
+
+#[server(GetRecordExpiry)]
+pub async fn get_record_expiry(id: i64) -> Result<Option<RecordExpiry>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.record_expiry(id, expiry_policy()).await.map_err(db_error)
+}
+
+// Set or remove (None) the expiry date of a record, in milliseconds since the epoch
+#[server(SetRecordExpiry)]
+pub async fn set_record_expiry(
+    id: i64,
+    expires_at: Option<i64>,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .set_expiry(id, &user.name, expires_at)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+/// Warns that a record is about to expire or has expired, and lets signed-in
+/// users set or remove its expiry date.
+#[component]
+pub fn ExpiryNotice(
+    /// The record.
+    id: i64,
+    /// Its expiry, as loaded by the editor.
+    expiry: Resource<Result<Option<RecordExpiry>, ServerFnError>>,
+    /// Set after the date changes, to make the editor reload.
+    refresh: RwSignal<()>,
+) -> impl IntoView {
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let date = RwSignal::new(String::new());
+    let failed = RwSignal::new(false);
+    let tasks = Tasks::new();
+
+    let current = move || expiry.get().and_then(Result::ok).flatten();
+
+    let save = move |expires_at: Option<i64>| {
+        failed.set(false);
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        tasks.spawn(async move {
+            match set_record_expiry(id, expires_at, csrf_token).await {
+                Ok(true) => {
+                    date.set(String::new());
+                    refresh.set(());
+                }
+                Ok(false) | Err(_) => failed.set(true),
+            }
+        });
+    };
+    let on_set = move |_| {
+        // A date input gives YYYY-MM-DD; the record expires at the start of that day, UTC
+        match parse_rfc3339(&format!("{}T00:00:00Z", date.get_untracked())) {
+            Some(expires_at) => save(Some(expires_at)),
+            None => failed.set(true),
+        }
+    };
+
+    view! {
+        {move || current().and_then(|e| Some((e.expires_at?, e))).map(|(at, e)| {
+            let when = format_timestamp(at);
+            let then = match e.action {
+                ExpiryAction::ReadOnly => "can no longer be changed",
+                ExpiryAction::Archive => "is moved to the trash",
+            };
+            if e.expired {
+                view! {
+                    <div class="error-message">
+                        "This record expired on " {when} " and " {then} ". "
+                        "Remove or extend the expiry date to edit it again."
+                    </div>
+                }
+                .into_any()
+            } else if e.expiring_soon {
+                view! {
+                    <div class="notice">"This record expires on " {when} "; after that it " {then} "."</div>
+                }
+                .into_any()
+            } else {
+                view! { <div class="record-meta">"Expires on " {when}</div> }.into_any()
+            }
+        })}
+
+        {move || signed_in().then(|| view! {
+            <div class="form-group">
+                <label for=format!("record-{}-expiry", id)>"Expiry date"</label>
+                <input
+                    id=format!("record-{}-expiry", id)
+                    type="date"
+                    prop:value=date
+                    on:input=move |ev| date.set(event_target_value(&ev))
+                />
+                <button on:click=on_set disabled=move || date.get().is_empty()>"Set expiry"</button>
+                {move || current().is_some_and(|e| e.expires_at.is_some()).then(|| view! {
+                    <button class="link" on:click=move |_| save(None)>"Remove expiry"</button>
+                })}
+            </div>
+        })}
+
+        {move || failed.get().then(|| view! {
+            <div class="error-message">"The expiry date could not be changed."</div>
+        })}
+    }
+}
@@ -0,0 +1,225 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// A file ready to be downloaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportFile {
+    pub filename: String,
+    pub content_type: String,
+    pub content: String,
+}
+
+// Quote a CSV value when it contains anything that would break the row
+#[cfg(feature = "ssr")]
+pub(crate) fn csv_value(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::csv_value;
+    use crate::db::{DbManager, Fields, FIELDS_COLUMNS};
+    use crate::history::HistoryEntry;
+    use serde::Serialize;
+
+    const HISTORY_PAGE_SIZE: i64 = 500;
+
+    // The JSON export
+    #[derive(Debug, Clone, Serialize)]
+    pub(super) struct JsonExport {
+        pub records: Vec<Fields>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub history: Option<Vec<HistoryEntry>>,
+    }
+
+    // One table for records and history alike: records are `record` rows,
+    // audit log entries are `history` rows with the values right after the event.
+    // There is a column for each of `fields`, followed by one for each field
+    // that only the history still has.
+    pub(super) fn to_csv(fields: &[String], records: &[Fields], history: Option<&[HistoryEntry]>) -> String {
+        let mut columns = fields.to_vec();
+        for entry in history.unwrap_or_default() {
+            for name in entry.values.keys() {
+                if !columns.contains(name) {
+                    columns.push(name.clone());
+                }
+            }
+        }
+        let mut csv = ["kind", "record_id", "version"]
+            .into_iter()
+            .chain(columns.iter().map(String::as_str))
+            .chain(["changed_by", "changed_at", "event"])
+            .map(csv_value)
+            .collect::<Vec<_>>()
+            .join(",");
+        csv.push('\n');
+        let mut row = |values: Vec<String>| {
+            let line: Vec<String> = values.iter().map(|v| csv_value(v)).collect();
+            csv.push_str(&line.join(","));
+            csv.push('\n');
+        };
+        for record in records {
+            let mut values = vec!["record".to_string(), record.id.to_string(), record.version.to_string()];
+            values.extend(columns.iter().map(|name| record.values.get(name).to_string()));
+            values.extend([
+                record.updated_by.clone().unwrap_or_default(),
+                record.updated_at.map(|at| at.to_string()).unwrap_or_default(),
+                String::new(),
+            ]);
+            row(values);
+        }
+        for entry in history.unwrap_or_default() {
+            let mut values = vec![
+                "history".to_string(),
+                entry.event.record_id.to_string(),
+                entry.event.version.to_string(),
+            ];
+            values.extend(columns.iter().map(|name| entry.values.get(name).cloned().unwrap_or_default()));
+            values.extend([
+                entry.event.actor.clone().unwrap_or_default(),
+                entry.event.occurred_at.to_string(),
+                entry.event.event.kind().to_string(),
+            ]);
+            row(values);
+        }
+        csv
+    }
+
+    impl DbManager {
+        // Every record that isn't in the trash
+        pub async fn all_fields(&self) -> Result<Vec<Fields>, sqlx::Error> {
+            sqlx::query_as::<_, Fields>(&format!(
+                "SELECT {} FROM fields WHERE tenant_id = ? AND deleted_at IS NULL ORDER BY id",
+                FIELDS_COLUMNS
+            ))
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+
+        // The whole audit log, oldest first
+        pub async fn all_history(&self) -> Result<Vec<HistoryEntry>, sqlx::Error> {
+            let up_to = self.latest_change_id().await?;
+            let mut history = Vec::new();
+            loop {
+                let after = history.last().map_or(0, |entry: &HistoryEntry| entry.event.id);
+                let page = self.history_page(after, up_to, HISTORY_PAGE_SIZE).await?;
+                if page.is_empty() {
+                    return Ok(history);
+                }
+                history.extend(page);
+            }
+        }
+    }
+}
+
+// All records, and the audit log if asked for, as a downloadable file; the
+// audit log is for admins only, as with its other exports
+#[server(ExportFields)]
+pub async fn export_fields(format: ExportFormat, include_history: bool) -> Result<ExportFile, ServerFnError> {
+    use crate::auth::{authorize, require_admin};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use server::{to_csv, JsonExport};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    if include_history {
+        require_admin(&db).await?;
+    }
+    let fields: Vec<String> = db.field_definitions().await.map_err(db_error)?.into_iter().map(|d| d.name).collect();
+    let records = db.all_fields().await.map_err(db_error)?;
+    let history = match include_history {
+        true => Some(db.all_history().await.map_err(db_error)?),
+        false => None,
+    };
+
+    Ok(match format {
+        ExportFormat::Csv => ExportFile {
+            filename: "fields.csv".to_string(),
+            content_type: "text/csv".to_string(),
+            content: to_csv(&fields, &records, history.as_deref()),
+        },
+        ExportFormat::Json => ExportFile {
+            filename: "fields.json".to_string(),
+            content_type: "application/json".to_string(),
+            content: serde_json::to_string_pretty(&JsonExport { records, history })?,
+        },
+    })
+}
+
+// Hand a file to the browser as a download
+#[cfg(feature = "hydrate")]
+pub(crate) fn download(file: &ExportFile) -> Result<(), wasm_bindgen::JsValue> {
+    use wasm_bindgen::JsCast;
+
+    let parts = js_sys::Array::of1(&wasm_bindgen::JsValue::from_str(&file.content));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(&file.content_type);
+    let blob = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+
+    let link = document()
+        .create_element("a")?
+        .dyn_into::<web_sys::HtmlAnchorElement>()?;
+    link.set_href(&url);
+    link.set_download(&file.filename);
+    link.click();
+    web_sys::Url::revoke_object_url(&url)
+}
+
+/// Downloads all records as CSV or JSON, optionally with their history.
+#[component]
+pub fn ExportButton() -> impl IntoView {
+    let format = RwSignal::new(ExportFormat::Csv);
+    let include_history = RwSignal::new(false);
+    let export = Action::new(move |&(format, include_history): &(ExportFormat, bool)| async move {
+        let file = export_fields(format, include_history).await?;
+        #[cfg(feature = "hydrate")]
+        download(&file).map_err(|e| ServerFnError::new(format!("{:?}", e)))?;
+        #[cfg(not(feature = "hydrate"))]
+        let _ = file;
+        Ok::<_, ServerFnError>(())
+    });
+
+    view! {
+        <div class="export">
+            <select on:change=move |ev| {
+                format.set(if event_target_value(&ev) == "json" { ExportFormat::Json } else { ExportFormat::Csv });
+            }>
+                <option value="csv" selected=move || format.get() == ExportFormat::Csv>"CSV"</option>
+                <option value="json" selected=move || format.get() == ExportFormat::Json>"JSON"</option>
+            </select>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=include_history
+                    on:change=move |ev| include_history.set(event_target_checked(&ev))
+                />
+                " with history"
+            </label>
+            <button
+                on:click=move |_| {
+                    export.dispatch((format.get(), include_history.get()));
+                }
+                disabled=move || export.pending().get()
+            >
+                {move || if export.pending().get() { "Exporting..." } else { "Export" }}
+            </button>
+            {move || export.value().get().and_then(Result::err).map(|e| view! {
+                <span class="error">"Export failed: " {e.to_string()}</span>
+            })}
+        </div>
+    }
+}
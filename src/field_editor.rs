@@ -1,204 +1,1333 @@
-use crate::db::{DbManager, Fields};
+#[cfg(feature = "ssr")]
+use crate::auth::{authorize, require_csrf, require_user};
+use crate::auth::use_user_session;
+#[cfg(feature = "ssr")]
+use crate::change_requests::{needs_approval, APPROVAL_REQUIRED};
+use crate::comments::FieldComments;
+use crate::conflict::ConflictExplainer;
+use crate::dashboard::ActivityToday;
+#[cfg(feature = "ssr")]
+use crate::db::{ConcurrencyMode, DbManager};
+use crate::db::{ConflictPolicy, FieldValues, Fields, RecordChange};
+use crate::demo::DemoKnobs;
+use crate::errors::{EditorError, ValidationError};
+#[cfg(feature = "ssr")]
+use crate::expiry::require_unexpired;
+#[cfg(feature = "ssr")]
+use crate::policy::{allowed, denial, Action, ResourceKind};
+use crate::expiry::{get_record_expiry, ExpiryNotice};
+#[cfg(feature = "ssr")]
+use crate::field_schema::require_valid_fields;
+use crate::field_schema::{field_label, get_field_schema, FieldDefinition};
+#[cfg(feature = "ssr")]
+use crate::merge::MergeResult;
+#[cfg(feature = "ssr")]
+use crate::rate_limit::rate_limit;
+use crate::protocol::use_protocol_status;
+use crate::records::{delete_record, DuplicateButton};
+use crate::review::ReviewNotice;
+use crate::scheduled::ScheduledChanges;
+use crate::store::{use_store, Draft, PendingSave};
+use crate::tasks::Tasks;
+use crate::timestamp::{now_millis, RelativeTime};
+use crate::toast::use_toasts;
 use leptos::prelude::*;
 use leptos::suspense::Suspense;
-use leptos::*;
+use server_fn::codec::GetUrl;
 use server_fn::error::ServerFnError;
-use wasm_bindgen_futures::spawn_local;
 
-#[server(GetFields)]
-pub async fn get_fields() -> Result<Fields, ServerFnError> {
-    let mut db = DbManager::new("sqlite:/tmp/fields.db");
-    db.initialize()
+// The shared database for a server function call, scoped to the request's tenant
+#[cfg(feature = "ssr")]
+pub(crate) async fn open_db() -> Result<DbManager, ServerFnError> {
+    // The app's `DbManager`, so calls share its record cache, keys and policies
+    let db = leptos_actix::extract::<actix_web::web::Data<DbManager>>().await?;
+    let req = leptos_actix::extract::<actix_web::HttpRequest>().await?;
+    let tenant = crate::tenant::resolve_tenant(&db, &req)
         .await
-        .map_err(|e| ServerFnError::<sqlx::Error>::ServerError(e.to_string()))?;
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    Ok(DbManager::clone(&db).with_tenant(tenant))
+}
 
-    let fields = db
-        .get_fields()
-        .await
-        .map_err(|e| ServerFnError::<sqlx::Error>::ServerError(e.to_string()))?;
+#[cfg(feature = "ssr")]
+pub(crate) fn db_error(e: sqlx::Error) -> ServerFnError {
+    ServerFnError::<sqlx::Error>::ServerError(e.to_string()).into()
+}
+
+// Sent as a GET so the browser's HTTP cache can revalidate it: the response
+// carries the concurrency token as its ETag, and an unchanged record costs a
+// 304 whose body `bodiless_not_modified` drops
+#[server(GetFields, input = GetUrl)]
+pub async fn get_fields(id: i64, demo: Option<DemoKnobs>) -> Result<Fields, ServerFnError> {
+    use actix_web::http::{header, StatusCode};
+    use crate::rest::{etag, not_modified};
+
+    crate::demo::demo_latency(demo).await;
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    let fields = db.get_fields(id).await.map_err(db_error)?;
+
+    let req = leptos_actix::extract::<actix_web::HttpRequest>().await?;
+    let response = expect_context::<leptos_actix::ResponseOptions>();
+    let etag = etag(db.concurrency_token(&fields));
+    response.insert_header(header::ETAG, header::HeaderValue::from_str(&etag)?);
+    response.insert_header(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
+    if not_modified(&req, &etag) {
+        response.set_status(StatusCode::NOT_MODIFIED);
+    }
 
     Ok(fields)
 }
 
+// What became of a save
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SaveOutcome {
+    Saved,
+    // Someone else saved a newer version first, changing other fields than the
+    // user did; both sets of changes were kept and the record is now at `version`
+    Merged { version: i64 },
+    // Someone else saved a newer version first and the user's values replaced
+    // it, as the save asked for; the record is now at `version`
+    Overwritten { version: i64 },
+    // Someone else saved a newer version first, changing some of the same
+    // fields; `fields` are those, empty if it couldn't be told
+    Conflict { fields: Vec<String> },
+    // Someone moved the record to the trash meanwhile; `version` is the deleted
+    // record's version, to pass to `restore_and_update`
+    Deleted { version: i64, deleted_by: Option<String> },
+    // Saves need approval and the user can't approve their own: the values were
+    // kept as change request `request_id` for an approver
+    PendingApproval { request_id: i64 },
+}
+
 #[server(UpdateFields)]
 pub async fn update_fields(
-    field1: String,
-    field2: String,
-    field3: String,
-    field4: String,
+    id: i64,
+    values: FieldValues,
     expected_version: i64,
-) -> Result<bool, ServerFnError> {
-    dbg!(format!(
-        "server-fn: Updating fields with version: {}",
-        expected_version
-    ));
-    let mut db = DbManager::new("sqlite:/tmp/fields.db");
-    db.initialize()
+    csrf_token: String,
+    // Chosen by the client per save; retries of the same save reuse it
+    idempotency_key: uuid::Uuid,
+    // What to do if the record moved on meanwhile; the server's policy unless
+    // given, and never more lenient than it
+    conflict_policy: Option<ConflictPolicy>,
+    demo: Option<DemoKnobs>,
+) -> Result<SaveOutcome, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    require_unexpired(&db, id).await.map_err(EditorError::from)?;
+    require_valid_fields(&db, &values).await?;
+    if needs_approval(&db, &user, id).await {
+        let request_id = db
+            .request_change(id, &user.name, &values, expected_version, Some(&idempotency_key.to_string()))
+            .await
+            .map_err(|e| EditorError::from(db_error(e)))?;
+        return Ok(SaveOutcome::PendingApproval { request_id });
+    }
+    crate::demo::demo_latency(demo).await;
+    crate::demo::demo_contention(&db, demo, id)
         .await
-        .map_err(|e| ServerFnError::<sqlx::Error>::ServerError(e.to_string()))?;
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    let result = db
+        .update_fields_with_policy(
+            id,
+            &user.name,
+            &values,
+            expected_version,
+            Some(&idempotency_key.to_string()),
+            conflict_policy.map_or(db.conflict_policy(), |asked| asked.capped_at(db.conflict_policy())),
+        )
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    let fields = match result {
+        MergeResult::Saved => return Ok(SaveOutcome::Saved),
+        MergeResult::Merged { version } => return Ok(SaveOutcome::Merged { version }),
+        MergeResult::Overwritten { version } => return Ok(SaveOutcome::Overwritten { version }),
+        MergeResult::Conflict { fields } => fields,
+    };
 
-    let success = db
-        .update_fields(&field1, &field2, &field3, &field4, expected_version)
+    // Tell a plain conflict apart from saving into a record that is gone
+    let tombstone = db.tombstone(id).await.map_err(|e| EditorError::from(db_error(e)))?;
+    Ok(match tombstone {
+        Some((version, deleted_by)) => SaveOutcome::Deleted { version, deleted_by },
+        None => SaveOutcome::Conflict { fields },
+    })
+}
+
+// Restore a record that was deleted while the user edited it and save their
+// values into it. Fails (returns false) if the record changed again meanwhile.
+#[server(RestoreAndUpdate)]
+pub async fn restore_and_update(
+    id: i64,
+    values: FieldValues,
+    tombstone_version: i64,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    require_unexpired(&db, id).await.map_err(EditorError::from)?;
+    require_valid_fields(&db, &values).await?;
+    if needs_approval(&db, &user, id).await {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(APPROVAL_REQUIRED.to_string())));
+    }
+    Ok(db
+        .restore_and_update(id, &user.name, &values, tombstone_version)
         .await
-        .map_err(|e| ServerFnError::<sqlx::Error>::ServerError(e.to_string()))?;
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+// What became of a save spanning several records
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum BatchSaveOutcome {
+    Saved,
+    // Nothing was saved because these records had moved on or were deleted
+    Conflict { ids: Vec<i64> },
+}
 
-    Ok(success)
+// Save several records in one transaction, for pages embedding more than one
+// `FieldEditor`. Each record is checked against its own expected version and
+// either all of them are saved or none is.
+#[server(UpdateManyFields)]
+pub async fn update_many_fields(
+    changes: Vec<RecordChange>,
+    csrf_token: String,
+    idempotency_key: uuid::Uuid,
+) -> Result<BatchSaveOutcome, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = require_user(&db).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    for change in &changes {
+        if !allowed(&db, &user, Action::Edit, ResourceKind::Record(change.id)).await {
+            return Err(ServerFnError::WrappedServerError(EditorError::Other(denial(
+                Action::Edit,
+                ResourceKind::Record(change.id),
+            ))));
+        }
+        if needs_approval(&db, &user, change.id).await {
+            return Err(ServerFnError::WrappedServerError(EditorError::Other(APPROVAL_REQUIRED.to_string())));
+        }
+    }
+    let mut ids: Vec<i64> = changes.iter().map(|c| c.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() != changes.len() {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(
+            "Each record can only appear once in a save".to_string(),
+        )));
+    }
+    for &id in &ids {
+        require_unexpired(&db, id).await.map_err(EditorError::from)?;
+    }
+    let mut changes = changes;
+    if db.concurrency_mode() == ConcurrencyMode::Timestamp {
+        for change in &mut changes {
+            if let Some(token) = change.expected_updated_at.as_deref() {
+                change.expected_version = db
+                    .expected_version_of(change.id, token)
+                    .await
+                    .map_err(|e| EditorError::from(db_error(e)))?
+                    .map_err(EditorError::Other)?;
+            }
+        }
+    }
+    for change in &changes {
+        require_valid_fields(&db, &change.values).await?;
+    }
+    let conflicts = db
+        .update_many(&user.name, &changes, Some(&idempotency_key.to_string()))
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    Ok(match conflicts.is_empty() {
+        true => BatchSaveOutcome::Saved,
+        false => BatchSaveOutcome::Conflict { ids: conflicts },
+    })
 }
 
+// How often a save is sent before giving up on an unreachable server, and
+// how long to wait before the first retry; each further one waits twice as long
+const SAVE_ATTEMPTS: u32 = 3;
+const SAVE_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+// Wait in the browser without blocking it; saves are only sent from there
+async fn sleep(duration: std::time::Duration) {
+    #[cfg(feature = "hydrate")]
+    {
+        let (done, waited) = futures::channel::oneshot::channel();
+        set_timeout(move || drop(done.send(())), duration);
+        let _ = waited.await;
+    }
+    #[cfg(not(feature = "hydrate"))]
+    let _ = duration;
+}
+
+// Input placeholders in the loading skeleton, as many as a tenant starts out with
+const SKELETON_FIELDS: usize = 4;
+
+// Ask the user to confirm something in a browser dialog
+#[cfg(feature = "hydrate")]
+fn confirm(message: &str) -> bool {
+    window().confirm_with_message(message).unwrap_or(false)
+}
+
+// Only event handlers ask, and they only run in the browser
+#[cfg(not(feature = "hydrate"))]
+fn confirm(_message: &str) -> bool {
+    true
+}
+
+/// Edits the fields of one record, saving with a version check.
 #[component]
-pub fn FieldEditor() -> impl IntoView {
-    leptos::logging::debug_warn!("FieldEditor component loaded");
+pub fn FieldEditor(
+    /// The record to edit.
+    #[prop(default = 1)]
+    id: i64,
+    /// Artificial latency and contention for demonstrations.
+    #[prop(default = None)]
+    demo: Option<DemoKnobs>,
+    /// What a save does when someone else saved first.
+    #[prop(default = ConflictPolicy::Merge)]
+    conflict_policy: ConflictPolicy,
+    /// Whether discarding unsaved edits asks the user to confirm first.
+    #[prop(default = true)]
+    confirm_discard: bool,
+) -> impl IntoView {
     // Set up client state
     let source = RwSignal::new(());
     let fields = Resource::new(
         move || source.get(),
-        |_| async move {
-            let x = get_fields().await;
-            leptos::logging::debug_warn!("Got fields");
-            x
-        },
+        move |_| get_fields(id, demo),
     );
+    let expiry = Resource::new(move || source.get(), move |_| get_record_expiry(id));
+    // Past its expiry date the record can still be read but not saved
+    let expired = move || expiry.get().and_then(Result::ok).flatten().is_some_and(|e| e.expired);
 
-    let edit_field1 = RwSignal::new(String::new());
-    let edit_field2 = RwSignal::new(String::new());
-    let edit_field3 = RwSignal::new(String::new());
-    let edit_field4 = RwSignal::new(String::new());
+    // Which fields the form has and in what order; reloaded with the record
+    let definitions = Resource::new(move || source.get(), |_| get_field_schema());
+    let edits = RwSignal::new(FieldValues::default());
     let version = RwSignal::new(0);
-    let show_error = RwSignal::new(false);
+    // The last save was rejected because of a version conflict
+    let conflicted = RwSignal::new(false);
+    // The last save was combined with someone else's changes to other fields
+    let merged = RwSignal::new(false);
+    // The last save replaced someone else's newer changes
+    let overwrote = RwSignal::new(false);
+    // The last save was kept for an approver instead of applied: the change request's id
+    let pending_approval = RwSignal::new(None::<i64>);
+    // The values of the save a conflict threw away, to point out where the
+    // reloaded record differs from what the user had typed
+    let overridden = RwSignal::new(None::<FieldValues>);
+    // Whether the conflict timeline is open
+    let explain = RwSignal::new(false);
+    // The record was deleted while the user edited it: the deleted version and who deleted it
+    let deleted_meanwhile = RwSignal::new(None::<(i64, Option<String>)>);
+    // The last save was refused because of this field's value
+    let invalid = RwSignal::new(None::<ValidationError>);
+    // A delete was refused because the record changed after it was loaded
+    let delete_conflict = RwSignal::new(false);
     let saving = RwSignal::new(false);
+    let deleted = RwSignal::new(false);
+    // Seconds to wait after the server turned a request away for coming too fast
+    let rate_limited = RwSignal::new(None::<u64>);
+    let session = use_user_session();
+    let protocol = use_protocol_status();
+    let store = use_store();
+    let toasts = use_toasts();
+    let tasks = Tasks::new();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let csrf_token = move || session.csrf_token.get_untracked().flatten().unwrap_or_default();
+    // The last values we know to be on the server, to tell whether the form has unsaved edits
+    let loaded = RwSignal::new(None::<Fields>);
+    let is_dirty = move || loaded.with(|l| l.as_ref().is_some_and(|f| edits.with(|e| f.values != *e)));
+    let remote_changed = RwSignal::new(false);
+    let live_connected = RwSignal::new(true);
+    // Whether the browser has a network connection; saves made without one wait in the store
+    let online = RwSignal::new(true);
+    // Element ids carry the record id, so several editors can share a page
+    let dom_id = move |name: &str| format!("record-{}-{}", id, name);
+
+    // Show a cached copy right away; the fetch replaces it when it arrives
+    if let Some(cached) = untrack(|| store.record(id)) {
+        edits.set(cached.values.clone());
+        version.set(cached.version);
+        loaded.set(Some(cached));
+    }
+    // Edits the user left unsaved when they went to another record; kept over
+    // whatever arrives first, along with the version they started from, so a
+    // save still goes through the usual conflict handling
+    let draft = untrack(|| store.draft(id));
+    if let Some(draft) = &draft {
+        edits.set(draft.values.clone());
+        version.set(draft.version);
+    }
+    // Edits an earlier page load left unsaved, offered for restoring once the
+    // record is here. Read in the browser only, after hydration, so that the
+    // server-rendered page matches.
+    let saved_draft = RwSignal::new(None::<Draft>);
+    let found_draft = StoredValue::new(draft.is_none().then(|| untrack(|| store.saved_draft(id))).flatten());
+    Effect::new(move |_| {
+        if let Some(found) = found_draft.try_update_value(Option::take).flatten() {
+            saved_draft.set(Some(found));
+        }
+    });
+    // One that matches the record, e.g. because it was saved after all, has nothing to restore
+    Effect::new(move |_| {
+        let matches = saved_draft.with(|d| {
+            d.as_ref().is_some_and(|d| loaded.with(|l| l.as_ref().is_some_and(|l| l.values == d.values)))
+        });
+        if matches {
+            saved_draft.set(None);
+        }
+    });
+    let restoring = StoredValue::new(draft.map(|d| d.version));
+    // Numbers the loads in flight; a response is only shown if no load was
+    // started or shown after it, so a slow older one can't replace a newer one
+    let load_seq = StoredValue::new(0u64);
+
+    // Put a version fresh from the server into the form
+    let show_loaded = move |data: Fields| {
+        edits.set(data.values.clone());
+        version.set(data.version);
+        store.put_record(data.clone());
+        loaded.set(Some(data));
+        remote_changed.set(false);
+    };
 
     // Load initial data
-    create_effect(move |_| {
+    Effect::new(move |_| {
         if let Some(Ok(data)) = fields.get() {
-            edit_field1.set(data.field1.clone());
-            edit_field2.set(data.field2.clone());
-            edit_field3.set(data.field3.clone());
-            edit_field4.set(data.field4.clone());
-            version.set(data.version);
+            load_seq.update_value(|seq| *seq += 1);
+            match restoring.try_update_value(Option::take).flatten() {
+                Some(draft_version) => {
+                    store.put_record(data.clone());
+                    remote_changed.set(data.version > draft_version);
+                    loaded.set(Some(data));
+                }
+                None => show_loaded(data),
+            }
+        }
+    });
+
+    // Keep unsaved edits in the store, so they are still here after a look at
+    // another record or a reload. A draft is only dropped once a save went
+    // through, and not while the user hasn't decided about a restored one.
+    Effect::new(move |_| {
+        let dirty = is_dirty();
+        if restoring.with_value(Option::is_some) {
+            return;
+        }
+        if !dirty && (saving.get() || saved_draft.with(Option::is_some) || found_draft.with_value(Option::is_some)) {
+            return;
+        }
+        if dirty {
+            store.put_draft(id, Draft {
+                values: edits.get_untracked(),
+                version: version.get_untracked(),
+            });
+        } else {
+            store.forget_draft(id);
         }
     });
 
+    // Remember how far down the page the user was, and go back there when they return
+    #[cfg(feature = "hydrate")]
+    {
+        let listener = window_event_listener(leptos::ev::scroll, move |_| {
+            if let Ok(y) = window().scroll_y() {
+                store.put_scroll(id, y);
+            }
+        });
+        on_cleanup(move || listener.remove());
+
+        if let Some(y) = untrack(|| store.scroll(id)) {
+            // Once the form is there to scroll through; after the router's own scroll to the top
+            Effect::new(move |restored: Option<bool>| {
+                if restored == Some(true) || loaded.with(Option::is_none) {
+                    return restored.unwrap_or(false);
+                }
+                request_animation_frame(move || window().scroll_to_with_x_and_y(0.0, y));
+                true
+            });
+        }
+    }
+
+    // Live updates: reload when someone else changes this record, unless
+    // that would throw away edits the user hasn't saved yet
+    #[cfg(feature = "hydrate")]
+    {
+        use crate::changefeed::{changes_since, subscribe, LiveUpdate};
+        use crate::delta::get_changes_since;
+
+        let is_dirty = move || {
+            loaded.with_untracked(|loaded| {
+                loaded.as_ref().is_some_and(|f| edits.with_untracked(|edits| f.values != *edits))
+            })
+        };
+
+        let on_stale = move || {
+            if is_dirty() {
+                remote_changed.set(true);
+                return;
+            }
+            // Fetch only the fields that changed; reload everything if that fails
+            load_seq.update_value(|seq| *seq += 1);
+            let seq = load_seq.get_value();
+            tasks.spawn(async move {
+                let Some(mut current) = loaded.get_untracked() else {
+                    source.set(());
+                    return;
+                };
+                let delta = get_changes_since(id, current.version).await;
+                if load_seq.get_value() != seq {
+                    return;
+                }
+                match delta {
+                    // The user started typing while we were fetching
+                    Ok(_) if is_dirty() => remote_changed.set(true),
+                    Ok(delta) if loaded.with_untracked(|l| l.as_ref().map(|l| l.version)) == Some(delta.since) => {
+                        delta.apply(&mut current);
+                        show_loaded(current);
+                    }
+                    _ => source.set(()),
+                }
+            });
+        };
+
+        let subscription = subscribe(move |update| match update {
+            LiveUpdate::Change(change) => {
+                if change.record_id == id
+                    && change.event.changes_state()
+                    && change.version > version.get_untracked()
+                {
+                    on_stale();
+                }
+            }
+            LiveUpdate::Resync => on_stale(),
+            LiveUpdate::Disconnected => live_connected.set(false),
+            LiveUpdate::Reconnected => {
+                live_connected.set(true);
+                // Anything saved while we were disconnected was never announced to us
+                tasks.spawn(async move {
+                    match changes_since(id, version.get_untracked()).await {
+                        Ok(missed) if missed.is_empty() => {}
+                        _ => on_stale(),
+                    }
+                });
+            }
+        });
+        on_cleanup(move || drop(subscription));
+    }
+
+    // Follow the browser's idea of whether it is online
+    #[cfg(feature = "hydrate")]
+    {
+        online.set(window().navigator().on_line());
+        let went_online = window_event_listener(leptos::ev::online, move |_| online.set(true));
+        let went_offline = window_event_listener(leptos::ev::offline, move |_| online.set(false));
+        on_cleanup(move || {
+            went_online.remove();
+            went_offline.remove();
+        });
+    }
+    // While mounted, this editor sends the record's queued save rather than the store
+    store.open_editor(id);
+    on_cleanup(move || store.close_editor(id));
+
+    // Presence: tell others this record is open here while the user is signed in
+    #[cfg(feature = "hydrate")]
+    {
+        use crate::presence::{join_record, leave_record, PRESENCE_HEARTBEAT};
+
+        let editor_id = uuid::Uuid::new_v4().to_string();
+        let heartbeat = {
+            let editor_id = editor_id.clone();
+            move || {
+                if session.user.get_untracked().flatten().is_none() {
+                    return;
+                }
+                let editor_id = editor_id.clone();
+                tasks.spawn(async move {
+                    let _ = join_record(id, editor_id, csrf_token()).await;
+                });
+            }
+        };
+
+        // Join as soon as the session is known, and again after logging in
+        Effect::new({
+            let heartbeat = heartbeat.clone();
+            move |_| {
+                if session.user.get().flatten().is_some() && session.csrf_token.get().flatten().is_some() {
+                    heartbeat();
+                }
+            }
+        });
+        let interval = set_interval_with_handle(heartbeat, PRESENCE_HEARTBEAT).ok();
+
+        on_cleanup(move || {
+            if let Some(interval) = interval {
+                interval.clear();
+            }
+            // Outlives the editor on purpose, so others stop seeing us as soon as we go
+            if session.user.get_untracked().flatten().is_some() {
+                let csrf_token = csrf_token();
+                wasm_bindgen_futures::spawn_local(async move {
+                    let _ = leave_record(editor_id, csrf_token).await;
+                });
+            }
+        });
+    }
+
     // Handle save action
-    let on_save = move |_| {
+    let save = move || {
+        if protocol.reload_required.get_untracked() {
+            return;
+        }
         saving.set(true);
-        show_error.set(false);
+        conflicted.set(false);
+        overridden.set(None);
+        saved_draft.set(None);
+        merged.set(false);
+        overwrote.set(false);
+        pending_approval.set(None);
+        explain.set(false);
+        delete_conflict.set(false);
+        invalid.set(None);
+        rate_limited.set(None);
 
-        spawn_local(async move {
-            let result = update_fields(
-                edit_field1.get(),
-                edit_field2.get(),
-                edit_field3.get(),
-                edit_field4.get(),
-                version.get(),
-            )
-            .await;
+        // Show the save as done right away, as the version after the one the
+        // edits are based on; rolled back below unless the server applies it.
+        // The store knows it for ours, so its change event doesn't look like
+        // a remote one.
+        let previous = loaded.get_untracked();
+        let expected_version = version.get_untracked();
+        let saved = Fields {
+            id,
+            values: edits.get_untracked(),
+            version: expected_version + 1,
+            updated_by: session.user.get_untracked().flatten().map(|u| u.name),
+            updated_at: Some(now_millis()),
+        };
+        version.set(saved.version);
+        store.put_optimistic(saved.clone());
+        loaded.set(Some(saved.clone()));
+        // Back to the record as it was before the save; edits made since stay in the form
+        let roll_back = move |previous: Option<Fields>| {
+            version.set(expected_version);
+            store.settle(id, false);
+            loaded.set(previous);
+        };
+
+        // Without a connection the save waits in the store, and the form goes
+        // on showing it as saved until it is sent
+        if !online.get_untracked() {
+            let queued = store.pending_save(id);
+            store.queue_save(PendingSave {
+                user: session.user.get_untracked().flatten().map(|u| u.name).unwrap_or_default(),
+                record_id: id,
+                values: saved.values,
+                expected_version: queued.map_or(expected_version, |q| q.expected_version),
+                idempotency_key: uuid::Uuid::new_v4(),
+            });
+            saving.set(false);
+            toasts.success("Saved on this device. Your changes are sent when you're back online.");
+            return;
+        }
+
+        tasks.spawn(async move {
+            // Retry saves that didn't reach the server under the same key, so one
+            // that did get through after all isn't applied a second time
+            let idempotency_key = uuid::Uuid::new_v4();
+            let mut attempts = 0;
+            let result = loop {
+                attempts += 1;
+                let result = update_fields(
+                    id,
+                    saved.values.clone(),
+                    expected_version,
+                    csrf_token(),
+                    idempotency_key,
+                    Some(conflict_policy),
+                    demo,
+                )
+                .await;
+                match result {
+                    Err(ServerFnError::Request(_)) if attempts < SAVE_ATTEMPTS => {
+                        // Give a struggling server or connection a moment, longer each time
+                        sleep(SAVE_RETRY_DELAY * 2u32.pow(attempts - 1)).await;
+                    }
+                    result => break result,
+                }
+            };
 
             saving.set(false);
 
             match result {
-                Ok(true) => {
-                    // Successfully saved
+                Ok(SaveOutcome::Saved) => {
+                    // Successfully saved, as the form already shows
+                    store.settle(id, true);
+                    toasts.success("Saved");
                     // Refresh the data to get the new version
                     source.set(());
                 }
-                Ok(false) => {
+                Ok(SaveOutcome::Deleted { version, deleted_by }) => {
+                    // Keep the edits in the form; the user decides whether to bring the record back
+                    roll_back(previous);
+                    deleted_meanwhile.set(Some((version, deleted_by)));
+                }
+                Ok(SaveOutcome::Merged { version: merged_version }) => {
+                    // Someone else changed other fields meanwhile; show the combined record
+                    store.settle(id, false);
+                    version.set(merged_version);
+                    merged.set(true);
+                    toasts.success("Saved");
+                    source.set(());
+                }
+                Ok(SaveOutcome::Overwritten { version: saved_version }) => {
+                    // Our values replaced someone else's; say so, then show the record as saved
+                    store.settle(id, false);
+                    version.set(saved_version);
+                    overwrote.set(true);
+                    toasts.success("Saved");
+                    source.set(());
+                }
+                Ok(SaveOutcome::PendingApproval { request_id }) => {
+                    // Nothing changed yet; the form goes back to the record as it is
+                    roll_back(previous);
+                    pending_approval.set(Some(request_id));
+                    source.set(());
+                }
+                Ok(SaveOutcome::Conflict { fields }) => {
                     // Concurrency conflict - someone else updated the data
-                    show_error.set(true);
+                    let both_changed = match fields.is_empty() {
+                        true => String::new(),
+                        false => {
+                            let schema = definitions.get_untracked().and_then(Result::ok).unwrap_or_default();
+                            let names: Vec<String> = fields
+                                .iter()
+                                .map(|name| match schema.iter().find(|d| d.name == *name) {
+                                    Some(definition) => definition.label(),
+                                    None => field_label(name),
+                                })
+                                .collect();
+                            format!(" You both changed {}.", names.join(", "))
+                        }
+                    };
+                    toasts.warning(format!(
+                        "Conflict detected: someone else saved this record first. Your changes were rolled back \
+                         and the form shows the current values.{}",
+                        both_changed
+                    ));
+                    roll_back(previous);
+                    conflicted.set(true);
+                    overridden.set(Some(saved.values));
                     // Refresh the data to get the latest values
                     source.set(());
                 }
+                Err(ServerFnError::WrappedServerError(EditorError::TooManyRequests {
+                    retry_after_secs,
+                })) => {
+                    // Nothing was saved; the edits stay in the form for another try
+                    roll_back(previous);
+                    rate_limited.set(Some(retry_after_secs));
+                }
+                Err(ServerFnError::WrappedServerError(EditorError::Invalid(error))) => {
+                    // Nothing was saved; take the user to the field to fix
+                    roll_back(previous);
+                    #[cfg(feature = "hydrate")]
+                    {
+                        use wasm_bindgen::JsCast;
+
+                        let input = document()
+                            .get_element_by_id(&dom_id(&error.field))
+                            .and_then(|input| input.dyn_into::<web_sys::HtmlElement>().ok());
+                        if let Some(input) = input {
+                            let _ = input.focus();
+                        }
+                    }
+                    invalid.set(Some(error));
+                }
                 Err(_) => {
-                    // Error saving
-                    show_error.set(true);
+                    // Error saving; the edits stay in the form for another try
+                    roll_back(previous);
+                    toasts.error(
+                        "Save failed, so your changes are not saved yet. They are still in the form; \
+                         check your connection and try again.",
+                    );
                 }
             }
         });
     };
 
-    // Define the view
-    view! {
-        <div class="field-editor">
-            <h1>"Field Editor"</h1>
-
-            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
-                {move || {
-                    fields.get().map(|fields_result| match fields_result {
-                        Err(e) => view! { <div class="error">"Error loading fields: " {e.to_string()}</div> }.into_any(),
-                        Ok(data) => view! {
-                            <div>
-                                <div class="form-group">
-                                    <label for="field1">"Field 1"</label>
-                                    <input
-                                        id="field1"
-                                        type="text"
-                                        prop:value=edit_field1
-                                        on:input=move |ev| {
-                                            edit_field1.set(event_target_value(&ev));
-                                        }
-                                    />
-                                </div>
+    // Send the save kept while offline. The server checks it against the
+    // version it started from, like any other save.
+    let replay = move || {
+        let Some(pending) = store.pending_save(id) else {
+            return;
+        };
+        let me = session.user.get_untracked().flatten().map(|u| u.name);
+        if me.as_deref() != Some(pending.user.as_str()) {
+            // Queued by someone who has since signed out; never sent as this user
+            store.forget_pending_save(id);
+            store.settle(id, false);
+            return;
+        }
+        // Put values that couldn't be saved back into the form over the
+        // reloaded record, as with a draft, for the user to deal with
+        let keep_in_form = move |values: FieldValues| {
+            edits.set(values);
+            version.set(pending.expected_version);
+            restoring.set_value(Some(pending.expected_version));
+            source.set(());
+        };
+        saving.set(true);
+        tasks.spawn(async move {
+            let result = update_fields(
+                id,
+                pending.values.clone(),
+                pending.expected_version,
+                csrf_token(),
+                pending.idempotency_key,
+                Some(conflict_policy),
+                demo,
+            )
+            .await;
+            saving.set(false);
 
-                                <div class="form-group">
-                                    <label for="field2">"Field 2"</label>
-                                    <input
-                                        id="field2"
-                                        type="text"
-                                        prop:value=edit_field2
-                                        on:input=move |ev| {
-                                            edit_field2.set(event_target_value(&ev));
-                                        }
-                                    />
-                                </div>
+            match result {
+                // Still unreachable; sent again when the browser is next back online
+                Err(ServerFnError::Request(_)) => return,
+                Err(ServerFnError::WrappedServerError(EditorError::TooManyRequests { retry_after_secs })) => {
+                    rate_limited.set(Some(retry_after_secs));
+                    return;
+                }
+                _ => {
+                    store.forget_pending_save(id);
+                    store.settle(id, false);
+                }
+            }
+            match result {
+                Ok(SaveOutcome::Saved | SaveOutcome::Merged { .. } | SaveOutcome::Overwritten { .. }) => {
+                    toasts.success("The changes you made offline were saved");
+                    source.set(());
+                }
+                Ok(SaveOutcome::PendingApproval { request_id }) => {
+                    pending_approval.set(Some(request_id));
+                    source.set(());
+                }
+                Ok(SaveOutcome::Conflict { .. }) => {
+                    toasts.warning(
+                        "The changes you made offline conflicted with someone else's and were not saved. \
+                         The form shows the current values.",
+                    );
+                    conflicted.set(true);
+                    overridden.set(Some(pending.values));
+                    source.set(());
+                }
+                Ok(SaveOutcome::Deleted { version: deleted_version, deleted_by }) => {
+                    edits.set(pending.values);
+                    version.set(pending.expected_version);
+                    deleted_meanwhile.set(Some((deleted_version, deleted_by)));
+                }
+                Err(ServerFnError::WrappedServerError(EditorError::Invalid(error))) => {
+                    invalid.set(Some(error));
+                    keep_in_form(pending.values);
+                }
+                Err(_) => {
+                    toasts.error("The changes you made offline could not be saved. They are back in the form.");
+                    keep_in_form(pending.values);
+                }
+            }
+        });
+    };
+    // Once online and signed in, also for saves queued by an earlier visit
+    Effect::new(move |_| {
+        let ready = online.get() && session.csrf_token.get().flatten().is_some() && store.has_pending_save(id);
+        if ready && !saving.get_untracked() {
+            untrack(replay);
+        }
+    });
 
-                                <div class="form-group">
-                                    <label for="field3">"Field 3"</label>
-                                    <input
-                                        id="field3"
-                                        type="text"
-                                        prop:value=edit_field3
-                                        on:input=move |ev| {
-                                            edit_field3.set(event_target_value(&ev));
-                                        }
-                                    />
-                                </div>
+    // Take up the edits left unsaved by an earlier page load, based on the
+    // version they started from
+    let restore_saved_draft = move |_| {
+        if let Some(draft) = saved_draft.get_untracked() {
+            remote_changed.set(loaded.with_untracked(|l| l.as_ref().is_some_and(|l| l.version > draft.version)));
+            edits.set(draft.values);
+            version.set(draft.version);
+        }
+        saved_draft.set(None);
+    };
+    // Offered once the record is there to compare it with
+    let offered_draft = move || saved_draft.get().filter(|_| loaded.with(Option::is_some));
 
-                                <div class="form-group">
-                                    <label for="field4">"Field 4"</label>
-                                    <input
-                                        id="field4"
-                                        type="text"
-                                        prop:value=edit_field4
-                                        on:input=move |ev| {
-                                            edit_field4.set(event_target_value(&ev));
-                                        }
-                                    />
-                                </div>
+    // Put the last loaded values back into the form, without asking the server
+    let discard = move || {
+        if confirm_discard && !confirm("Discard your unsaved changes?") {
+            return;
+        }
+        if let Some(loaded) = loaded.get_untracked() {
+            edits.set(loaded.values);
+            version.set(loaded.version);
+            invalid.set(None);
+            overridden.set(None);
+        }
+        // The newer version kept back for the edits can be shown now
+        if remote_changed.get_untracked() {
+            source.set(());
+        }
+    };
+
+    // Restore the record deleted meanwhile and save the form's values into it
+    let on_restore_and_save = move |_| {
+        let Some((tombstone_version, _)) = deleted_meanwhile.get_untracked() else {
+            return;
+        };
+        if protocol.reload_required.get_untracked() {
+            return;
+        }
+        saving.set(true);
+        rate_limited.set(None);
+        tasks.spawn(async move {
+            let result = restore_and_update(id, edits.get_untracked(), tombstone_version, csrf_token()).await;
+            saving.set(false);
+            match result {
+                Ok(true) => {
+                    deleted_meanwhile.set(None);
+                    source.set(());
+                }
+                Err(ServerFnError::WrappedServerError(EditorError::TooManyRequests {
+                    retry_after_secs,
+                })) => rate_limited.set(Some(retry_after_secs)),
+                Ok(false) | Err(_) => {
+                    // Restored or changed by someone else in the meantime
+                    deleted_meanwhile.set(None);
+                    toasts.error("Your changes were not saved: someone else restored or changed the record meanwhile.");
+                    source.set(());
+                }
+            }
+        });
+    };
 
-                                <button
-                                    on:click=on_save
-                                    disabled=saving
-                                >
-                                    {move || if saving.get() { "Saving..." } else { "Save Changes" }}
-                                </button>
-
-                                {move || {
-                                    if show_error.get() {
-                                        view! {
-                                            <div class="error-message">
-                                                "Save failed. Another user has updated the fields since you loaded them.
-                                                Your changes have been discarded and the fields now show the current values. 
-                                                Please try again."
-                                            </div>
-                                        }.into_any()
-                                    } else {
-                                        view! { <div class="no-error"></div> }.into_any()
+    // Handle delete action; the record goes to the trash and can be restored
+    let on_delete = move |_| {
+        if protocol.reload_required.get_untracked() {
+            return;
+        }
+        rate_limited.set(None);
+        delete_conflict.set(false);
+        tasks.spawn(async move {
+            match delete_record(id, version.get_untracked(), csrf_token()).await {
+                Ok(true) => {
+                    deleted.set(true);
+                    store.forget_record(id);
+                }
+                Err(ServerFnError::WrappedServerError(EditorError::TooManyRequests {
+                    retry_after_secs,
+                })) => rate_limited.set(Some(retry_after_secs)),
+                Ok(false) => {
+                    // Changed or deleted elsewhere since it was loaded; show the current state
+                    delete_conflict.set(true);
+                    source.set(());
+                }
+                Err(_) => source.set(()),
+            }
+        });
+    };
+
+    // The record's fields as the schema describes them, in its order;
+    // alphabetical and with default labels until the schema arrives
+    let fields_shown = move || {
+        let names: Vec<String> = loaded.with(|l| l.iter().flat_map(|f| f.values.names()).map(str::to_string).collect());
+        match definitions.get().and_then(Result::ok) {
+            Some(definitions) => definitions.into_iter().filter(|d| names.contains(&d.name)).collect::<Vec<_>>(),
+            None => names
+                .into_iter()
+                .enumerate()
+                .map(|(position, name)| FieldDefinition::bare(name, position as i64))
+                .collect(),
+        }
+    };
+    // Required fields the form leaves blank; the record can't be saved like that
+    let blank_required = move || {
+        fields_shown()
+            .into_iter()
+            .filter(|d| d.metadata.required && edits.with(|e| e.get(&d.name).trim().is_empty()))
+            .map(|d| d.label())
+            .collect::<Vec<_>>()
+    };
+    let save_disabled = move || {
+        saving.get() || !signed_in() || !protocol.can_save() || expired() || !blank_required().is_empty()
+    };
+    let discard_disabled = move || saving.get() || !is_dirty();
+
+    // Keyboard shortcuts: Ctrl+S (Cmd+S on macOS) saves and Esc discards the
+    // edits, in the editor that has the focus, so that several on one page
+    // don't all react
+    let root = NodeRef::<leptos::html::Div>::new();
+    #[cfg(feature = "hydrate")]
+    {
+        let listener = window_event_listener(leptos::ev::keydown, move |ev| {
+            let in_editor = root.get_untracked().zip(document().active_element()).is_some_and(|(root, focused)| {
+                let focused: &web_sys::Node = &focused;
+                root.contains(Some(focused))
+            });
+            if !in_editor || ev.repeat() {
+                return;
+            }
+            if (ev.ctrl_key() || ev.meta_key()) && ev.key().eq_ignore_ascii_case("s") {
+                // Keep the browser from saving the page, even while the editor can't
+                ev.prevent_default();
+                if !save_disabled() {
+                    save();
+                }
+            } else if ev.key() == "Escape" && !discard_disabled() {
+                discard();
+            }
+        });
+        on_cleanup(move || listener.remove());
+    }
+
+    // Whether the last save was refused because of this field
+    let is_invalid = move |name: &str| invalid.with(|i| i.as_ref().is_some_and(|i| i.field == name));
+    // Whether the form's value of this field differs from the loaded one
+    let field_changed = move |name: &str| {
+        loaded.with(|l| l.as_ref().is_some_and(|f| edits.with(|e| f.values.get(name) != e.get(name))))
+    };
+    // Put the loaded value of just this field back, keeping the other edits
+    let revert_field = move |name: &str| {
+        let Some(value) = loaded.with_untracked(|l| l.as_ref().map(|f| f.values.get(name).to_string())) else {
+            return;
+        };
+        edits.update(|e| e.set(name, value));
+        if invalid.with_untracked(|i| i.as_ref().is_some_and(|i| i.field == name)) {
+            invalid.set(None);
+        }
+    };
+    // What the user had typed into this field before a conflict replaced it
+    // with a different value, as long as they haven't touched the field since
+    let overridden_value = move |name: &str| {
+        overridden.with(|mine| {
+            let mine = mine.as_ref().filter(|mine| mine.contains(name))?.get(name);
+            let theirs = loaded.with(|l| l.as_ref().map(|f| f.values.get(name).to_string()))?;
+            (mine != theirs && edits.with(|e| e.get(name) == theirs)).then(|| mine.to_string())
+        })
+    };
+    // What was wrong with the last save, for screen readers; with the field's label
+    let invalid_announcement = move || {
+        invalid.get().map(|error| {
+            let label = fields_shown()
+                .into_iter()
+                .find(|d| d.name == error.field)
+                .map(|d| d.label())
+                .unwrap_or(error.field);
+            format!("{}: {}", label, error.message)
+        })
+    };
+
+    // The form itself, shown once the record is loaded or while a cached copy stands in
+    let form = move || view! {
+        <div>
+            {move || offered_draft().map(|_| view! {
+                <div class="notice">
+                    "You have unsaved changes to this record from an earlier visit. Restore them? "
+                    <button class="link" on:click=restore_saved_draft>"Restore draft"</button>
+                    " "
+                    <button class="link" on:click=move |_| saved_draft.set(None)>"Discard it"</button>
+                </div>
+            })}
+
+            {move || (!online.get()).then(|| view! {
+                <div class="notice">
+                    "You're offline. Saves are kept on this device and sent when you're back online."
+                </div>
+            })}
+
+            {move || (!live_connected.get()).then(|| view! {
+                <div class="notice">"Live updates disconnected. Reconnecting..."</div>
+            })}
+
+            {move || deleted_meanwhile.get().map(|(_, deleted_by)| view! {
+                <div class="notice">
+                    {deleted_by.unwrap_or_else(|| "Someone".to_string())}
+                    " moved this record to the trash while you were editing it. Your changes were not saved. "
+                    <button class="link" on:click=on_restore_and_save disabled=move || saving.get() || !protocol.can_save()>
+                        "Restore it and save my changes"
+                    </button>
+                </div>
+            })}
+
+            {move || delete_conflict.get().then(|| view! {
+                <div class="error-message">
+                    "The record was not deleted: someone changed it after you loaded it. "
+                    "Check the current values and delete again if you still want to."
+                </div>
+            })}
+
+            {move || merged.get().then(|| view! {
+                <div class="notice">
+                    "Someone else saved changes to other fields while you were editing. "
+                    "Both sets of changes were kept; the form shows the combined record."
+                </div>
+            })}
+
+            {move || overwrote.get().then(|| view! {
+                <div class="notice">
+                    "Someone else saved changes while you were editing. "
+                    "Your values replaced theirs; their version is still in the history."
+                </div>
+            })}
+
+            {move || pending_approval.get().map(|request_id| view! {
+                <div class="notice">
+                    "Your changes were sent for approval as request " {request_id} ". "
+                    "The record shows them once an approver has applied them."
+                </div>
+            })}
+
+            {move || remote_changed.get().then(|| view! {
+                <div class="notice">
+                    "Someone else saved a newer version of this record. "
+                    <button class="link" on:click=move |_| source.set(())>
+                        "Load latest (discards your edits)"
+                    </button>
+                </div>
+            })}
+
+            <ExpiryNotice id=id expiry=expiry refresh=source />
+            <ReviewNotice id=id refresh=source />
+
+            <input
+                type="hidden"
+                name="csrf_token"
+                prop:value=move || session.csrf_token.get().flatten().unwrap_or_default()
+            />
+
+            <For each=fields_shown key=|definition| definition.clone() let:definition>
+                <div class="form-group">
+                    <label for=dom_id(&definition.name)>
+                        {definition.label()}
+                        {definition.metadata.required.then_some(" *")}
+                    </label>
+                    <div class="input-row">
+                        <input
+                            id=dom_id(&definition.name)
+                            type="text"
+                            placeholder=definition.metadata.placeholder.clone()
+                            required=definition.metadata.required
+                            class:invalid={
+                                let name = definition.name.clone();
+                                move || is_invalid(&name)
+                            }
+                            class:changed-remotely={
+                                let name = definition.name.clone();
+                                move || overridden_value(&name).is_some()
+                            }
+                            title={
+                                let name = definition.name.clone();
+                                move || overridden_value(&name).map(|mine| format!("Your value was: {}", mine))
+                            }
+                            aria-invalid={
+                                let name = definition.name.clone();
+                                move || if is_invalid(&name) { "true" } else { "false" }
+                            }
+                            aria-describedby=dom_id(&format!("{}-error", definition.name))
+                            prop:value={
+                                let name = definition.name.clone();
+                                move || edits.with(|edits| edits.get(&name).to_string())
+                            }
+                            on:input={
+                                let name = definition.name.clone();
+                                move |ev| {
+                                    edits.update(|edits| edits.set(name.clone(), event_target_value(&ev)));
+                                    if invalid.with_untracked(|i| i.as_ref().is_some_and(|i| i.field == name)) {
+                                        invalid.set(None);
                                     }
-                                }}
-                            </div>
-                        }.into_any()
-                    })
-                }}
+                                }
+                            }
+                        />
+                        {
+                            let name = definition.name.clone();
+                            let label = format!("Revert {} to the loaded value", definition.label());
+                            move || field_changed(&name).then(|| {
+                                let name = name.clone();
+                                view! {
+                                    <button
+                                        class="link revert"
+                                        title=label.clone()
+                                        aria-label=label.clone()
+                                        on:click=move |_| revert_field(&name)
+                                    >
+                                        "↺"
+                                    </button>
+                                }
+                            })
+                        }
+                    </div>
+                    {
+                        let name = definition.name.clone();
+                        move || overridden_value(&name).map(|mine| {
+                            let name = name.clone();
+                            let shown = mine.clone();
+                            view! {
+                                <div class="overridden-value">
+                                    "Changed by someone else. You had: " <del>{shown}</del> " "
+                                    <button class="link" on:click=move |_| edits.update(|e| e.set(name.clone(), mine.clone()))>
+                                        "Use mine"
+                                    </button>
+                                </div>
+                            }
+                        })
+                    }
+                    <div class="field-error" id=dom_id(&format!("{}-error", definition.name))>
+                        {
+                            let name = definition.name.clone();
+                            move || invalid.get().filter(|i| i.field == name).map(|i| i.message)
+                        }
+                    </div>
+                    {definition.metadata.help.clone().map(|help| view! { <small class="help">{help}</small> })}
+                    <FieldComments record_id=id field=definition.name.clone()/>
+                </div>
+            </For>
+
+            <div class="visually-hidden" aria-live="assertive">{invalid_announcement}</div>
+            {move || {
+                invalid.get().filter(|i| !fields_shown().iter().any(|d| d.name == i.field)).map(|i| view! {
+                    <div class="error-message">{i.field} ": " {i.message}</div>
+                })
+            }}
+
+            {move || {
+                let blank = blank_required();
+                (!blank.is_empty()).then(|| view! {
+                    <div class="notice">"Fill in " {blank.join(", ")} " to save."</div>
+                })
+            }}
+
+            // Which version the form is based on and how fresh it is; updated
+            // with every save and reload
+            {move || loaded.get().map(|f| view! {
+                <div class="record-meta">
+                    "Version " {f.version}
+                    {f.updated_at.map(|at| view! {
+                        ", last edited "
+                        {f.updated_by.map(|by| view! { "by " <strong>{by}</strong> " " })}
+                        <RelativeTime at=at/>
+                    })}
+                </div>
+            })}
+
+            {move || (!signed_in()).then(|| view! {
+                <div class="notice">"Log in to save changes."</div>
+            })}
+
+            <button
+                on:click=move |_| save()
+                disabled=save_disabled
+                title="Save (Ctrl+S)"
+            >
+                {move || if saving.get() { "Saving..." } else { "Save Changes" }}
+            </button>
+
+            <button on:click=move |_| discard() disabled=discard_disabled title="Discard (Esc)">
+                "Discard changes"
+            </button>
+
+            <button
+                class="danger"
+                on:click=on_delete
+                disabled=move || saving.get() || !signed_in() || !protocol.can_save()
+            >
+                "Delete"
+            </button>
+
+            <DuplicateButton record_id=id/>
+
+            <ScheduledChanges id=id edits=edits version=version refresh=source/>
+
+            {move || rate_limited.get().map(|secs| view! {
+                <div class="error-message">
+                    "You're sending changes too quickly. Please wait "
+                    {secs} {if secs == 1 { " second" } else { " seconds" }}
+                    " and try again."
+                </div>
+            })}
+
+            {move || conflicted.get().then(|| view! {
+                <div class="notice">
+                    "Your last save conflicted with someone else's and was not applied. "
+                    <button class="link" on:click=move |_| explain.update(|open| *open = !*open)>
+                        {move || if explain.get() { "Hide explanation" } else { "Explain what happened" }}
+                    </button>
+                </div>
+                {move || explain.get().then(|| view! { <ConflictExplainer record_id=id/> })}
+            })}
+        </div>
+    }.into_any();
+
+    // Define the view
+    view! {
+        <div class="field-editor" node_ref=root>
+            <h1>
+                "Field Editor"
+                {move || store.has_draft(id).then(|| view! { <span class="dirty-badge">"Unsaved changes"</span> })}
+                {move || store.has_pending_save(id).then(|| view! {
+                    <span class="dirty-badge" title="Saved on this device, sent when back online">"Pending sync"</span>
+                })}
+            </h1>
+            <ActivityToday record_id=id version=version/>
+
+            {demo.map(|knobs| view! {
+                <div class="notice demo">"Demo mode: " {knobs.describe()}</div>
+            })}
+
+            <Suspense fallback=move || {
+                if loaded.get().is_some() {
+                    form()
+                } else {
+                    view! { <FieldEditorSkeleton/> }.into_any()
+                }
+            }>
+                // A failed load shows the retry instead of the form; loading
+                // again takes it away once the record arrives
+                <ErrorBoundary fallback=move |_| view! {
+                    <div class="error-message">
+                        "The record could not be loaded. Check your connection and try again. "
+                        <button class="link" on:click=move |_| source.set(())>"Retry"</button>
+                    </div>
+                }>
+                    {move || {
+                        if deleted.get() {
+                            return Some(Ok(view! {
+                                <div class="notice">
+                                    "This record was moved to the " <a href="/trash">"trash"</a> "."
+                                </div>
+                            }.into_any()));
+                        }
+                        fields.get().map(|fields_result| fields_result.map(|_| form()))
+                    }}
+                </ErrorBoundary>
             </Suspense>
         </div>
     }
 }
+
+/// Stands in for the form while the record loads, shaped like it so that
+/// nothing moves when the fields arrive.
+#[component]
+pub fn FieldEditorSkeleton() -> impl IntoView {
+    view! {
+        <div class="skeleton" aria-busy="true">
+            <span class="visually-hidden">"Loading..."</span>
+            {(0..SKELETON_FIELDS).map(|_| view! {
+                <div class="form-group" aria-hidden="true">
+                    <div class="skeleton-label"></div>
+                    <div class="skeleton-input"></div>
+                </div>
+            }).collect_view()}
+            <div class="skeleton-button" aria-hidden="true"></div>
+        </div>
+    }
+}
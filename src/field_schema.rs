@@ -0,0 +1,644 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Records have whatever fields their tenant defines, stored one row per record
+// and field. A tenant starts out with the four default fields and can add,
+// remove and rename them at any time. Removing a field deletes its values from
+// every record; renaming keeps them. Neither changes record versions, and the
+// audit log keeps the values under the names they had when they were saved.
+
+// Longest field name accepted
+pub const MAX_FIELD_NAME_LEN: usize = 64;
+
+// Members of a serialized record that a field can't be named after
+const RESERVED_NAMES: [&str; 4] = ["id", "version", "updated_by", "updated_at"];
+
+// A field records of the tenant have, and how and where editors show it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct FieldDefinition {
+    pub name: String,
+    pub position: i64,
+    #[serde(flatten)]
+    #[cfg_attr(feature = "ssr", sqlx(flatten))]
+    pub metadata: FieldMetadata,
+}
+
+impl FieldDefinition {
+    // A definition with no metadata, for fields the schema hasn't arrived for
+    pub fn bare(name: String, position: i64) -> Self {
+        FieldDefinition {
+            name,
+            position,
+            metadata: FieldMetadata::default(),
+        }
+    }
+
+    // The label set for the field, or one made from its name
+    pub fn label(&self) -> String {
+        self.metadata.label.clone().unwrap_or_else(|| field_label(&self.name))
+    }
+}
+
+// How editors present a field
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct FieldMetadata {
+    #[serde(default)]
+    pub label: Option<String>,
+    // Shown in the empty input
+    #[serde(default)]
+    pub placeholder: Option<String>,
+    // Shown under the input
+    #[serde(default)]
+    pub help: Option<String>,
+    // Saves can't leave the field blank
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl FieldMetadata {
+    // Blank texts mean "not set"
+    pub fn normalized(self) -> Self {
+        let set = |text: Option<String>| text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+        FieldMetadata {
+            label: set(self.label),
+            placeholder: set(self.placeholder),
+            help: set(self.help),
+            required: self.required,
+        }
+    }
+}
+
+// Field names are lowercase ASCII letters, digits and underscores, starting
+// with a letter, so they can go into JSON paths and CSV headers as they are
+pub fn is_valid_field_name(name: &str) -> bool {
+    name.len() <= MAX_FIELD_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !RESERVED_NAMES.contains(&name)
+}
+
+// A readable label for a field name: `due_date` becomes "Due date", `field1` "Field 1"
+pub fn field_label(name: &str) -> String {
+    let mut label = String::with_capacity(name.len() + 2);
+    let mut previous = None::<char>;
+    for c in name.chars() {
+        match c {
+            '_' => label.push(' '),
+            c if c.is_ascii_digit() && previous.is_some_and(|p| p.is_ascii_alphabetic()) => {
+                label.push(' ');
+                label.push(c);
+            }
+            c if previous.is_none() => label.push(c.to_ascii_uppercase()),
+            c => label.push(c),
+        }
+        previous = Some(c);
+    }
+    label
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{FieldDefinition, FieldMetadata};
+    use crate::db::{ensure_field_definitions, DbManager, FieldValues, FIELD_VALUES_COLUMN};
+    use crate::errors::{EditorError, ValidationError};
+    use crate::field_editor::db_error;
+    use crate::integrity::record_checksum;
+    use crate::tenant::TenantId;
+    use sqlx::SqliteConnection;
+
+    // Stamp the tenant's records with the checksum of their values under the new
+    // field definitions, so `verify_integrity` doesn't report the change.
+    // Records that never had a checksum are left without one.
+    async fn refresh_checksums(conn: &mut SqliteConnection, tenant: &TenantId) -> Result<(), sqlx::Error> {
+        let records: Vec<(i64, i64, FieldValues)> = sqlx::query_as(&format!(
+            "SELECT id, version, {} FROM fields WHERE tenant_id = ? AND checksum IS NOT NULL",
+            FIELD_VALUES_COLUMN
+        ))
+        .bind(tenant.as_str())
+        .fetch_all(&mut *conn)
+        .await?;
+        for (id, version, values) in records {
+            sqlx::query("UPDATE fields SET checksum = ? WHERE id = ?")
+                .bind(record_checksum(id, version, &values))
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    impl DbManager {
+        // The tenant's fields in the order editors show them
+        pub async fn field_definitions(&self) -> Result<Vec<FieldDefinition>, sqlx::Error> {
+            let mut conn = self.pool().acquire().await?;
+            ensure_field_definitions(&mut conn, self.tenant()).await?;
+            sqlx::query_as(
+                r#"
+                SELECT name, position, label, placeholder, help, required != 0 AS required
+                FROM field_definitions WHERE tenant_id = ? ORDER BY position, name
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .fetch_all(&mut *conn)
+            .await
+        }
+
+        // The names in `values` the tenant has no field for
+        pub async fn undefined_fields(&self, values: &FieldValues) -> Result<Vec<String>, sqlx::Error> {
+            let defined = self.field_definitions().await?;
+            Ok(values
+                .names()
+                .filter(|name| !defined.iter().any(|d| d.name == *name))
+                .map(str::to_string)
+                .collect())
+        }
+
+        // The required fields `values` leaves blank
+        pub async fn blank_required_fields(&self, values: &FieldValues) -> Result<Vec<String>, sqlx::Error> {
+            let defined = self.field_definitions().await?;
+            Ok(defined
+                .into_iter()
+                .filter(|d| d.metadata.required && values.contains(&d.name) && values.get(&d.name).trim().is_empty())
+                .map(|d| d.name)
+                .collect())
+        }
+
+        // Add a field after the last one. Records have it empty until it is
+        // saved. Returns false if the tenant already has a field by that name.
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn add_field(&self, name: &str) -> Result<bool, sqlx::Error> {
+            self.retrying("add_field", || async {
+                let mut tx = self.pool().begin().await?;
+                ensure_field_definitions(&mut tx, self.tenant()).await?;
+                let added = sqlx::query(
+                    r#"
+                    INSERT OR IGNORE INTO field_definitions (tenant_id, name, position)
+                    SELECT ?1, ?2, COALESCE(MAX(position), -1) + 1 FROM field_definitions WHERE tenant_id = ?1
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(name)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                    > 0;
+                if added {
+                    refresh_checksums(&mut tx, self.tenant()).await?;
+                    tracing::info!("Field added");
+                }
+                tx.commit().await?;
+                Ok(added)
+            })
+            .await
+        }
+
+        // Remove a field and its values from every record of the tenant.
+        // Returns false if there is no such field or it is the last one left.
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn remove_field(&self, name: &str) -> Result<bool, sqlx::Error> {
+            self.retrying("remove_field", || async {
+                let mut tx = self.pool().begin().await?;
+                let removed = sqlx::query(
+                    r#"
+                    DELETE FROM field_definitions WHERE tenant_id = ?1 AND name = ?2
+                        AND (SELECT COUNT(*) FROM field_definitions WHERE tenant_id = ?1) > 1
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(name)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                    > 0;
+                if !removed {
+                    return Ok(false);
+                }
+                let values = sqlx::query(
+                    r#"
+                    DELETE FROM field_values
+                    WHERE field_name = ? AND record_id IN (SELECT id FROM fields WHERE tenant_id = ?)
+                    "#,
+                )
+                .bind(name)
+                .bind(self.tenant().as_str())
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                refresh_checksums(&mut tx, self.tenant()).await?;
+                tx.commit().await?;
+                tracing::info!(values, "Field removed");
+                Ok(true)
+            })
+            .await
+        }
+
+        // Rename a field, keeping its position and values. Returns false if there
+        // is no field named `from` or there already is one named `to`.
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn rename_field(&self, from: &str, to: &str) -> Result<bool, sqlx::Error> {
+            self.retrying("rename_field", || async {
+                let mut tx = self.pool().begin().await?;
+                let renamed = sqlx::query(
+                    r#"
+                    UPDATE field_definitions SET name = ?3 WHERE tenant_id = ?1 AND name = ?2
+                        AND NOT EXISTS (SELECT 1 FROM field_definitions WHERE tenant_id = ?1 AND name = ?3)
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(from)
+                .bind(to)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                    > 0;
+                if !renamed {
+                    return Ok(false);
+                }
+                sqlx::query(
+                    r#"
+                    UPDATE field_values SET field_name = ?
+                    WHERE field_name = ? AND record_id IN (SELECT id FROM fields WHERE tenant_id = ?)
+                    "#,
+                )
+                .bind(to)
+                .bind(from)
+                .bind(self.tenant().as_str())
+                .execute(&mut *tx)
+                .await?;
+                refresh_checksums(&mut tx, self.tenant()).await?;
+                tx.commit().await?;
+                tracing::info!("Field renamed");
+                Ok(true)
+            })
+            .await
+        }
+
+        // Change how editors present a field. Returns false if there is no such field.
+        #[tracing::instrument(skip(self, metadata), fields(tenant = %self.tenant()))]
+        pub async fn update_field_metadata(&self, name: &str, metadata: &FieldMetadata) -> Result<bool, sqlx::Error> {
+            self.retrying("update_field_metadata", || async {
+                let updated = sqlx::query(
+                    r#"
+                    UPDATE field_definitions SET label = ?, placeholder = ?, help = ?, required = ?
+                    WHERE tenant_id = ? AND name = ?
+                    "#,
+                )
+                .bind(&metadata.label)
+                .bind(&metadata.placeholder)
+                .bind(&metadata.help)
+                .bind(metadata.required)
+                .bind(self.tenant().as_str())
+                .bind(name)
+                .execute(self.pool())
+                .await?
+                .rows_affected()
+                    > 0;
+                Ok(updated)
+            })
+            .await
+        }
+
+        // Move a field `by` places towards the end (negative: the start) of the
+        // order editors show fields in, stopping at either end. Returns false if
+        // there is no such field.
+        #[tracing::instrument(skip(self), fields(tenant = %self.tenant()))]
+        pub async fn move_field(&self, name: &str, by: i64) -> Result<bool, sqlx::Error> {
+            self.retrying("move_field", || async {
+                let mut tx = self.pool().begin().await?;
+                ensure_field_definitions(&mut tx, self.tenant()).await?;
+                let mut names: Vec<String> = sqlx::query_scalar(
+                    "SELECT name FROM field_definitions WHERE tenant_id = ? ORDER BY position, name",
+                )
+                .bind(self.tenant().as_str())
+                .fetch_all(&mut *tx)
+                .await?;
+                let Some(from) = names.iter().position(|n| n == name) else {
+                    return Ok(false);
+                };
+                let to = (from as i64 + by).clamp(0, names.len() as i64 - 1) as usize;
+                let moved = names.remove(from);
+                names.insert(to, moved);
+                for (position, name) in names.iter().enumerate() {
+                    sqlx::query("UPDATE field_definitions SET position = ? WHERE tenant_id = ? AND name = ?")
+                        .bind(position as i64)
+                        .bind(self.tenant().as_str())
+                        .bind(name)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                Ok(true)
+            })
+            .await
+        }
+    }
+
+    // Guard for saves: fails if the values name a field the tenant doesn't have
+    // or leave a required one blank, naming the field so the form can point at it
+    pub async fn require_valid_fields(db: &DbManager, values: &FieldValues) -> Result<(), EditorError> {
+        let invalid = |field: &str, message: &str| {
+            EditorError::Invalid(ValidationError {
+                field: field.to_string(),
+                message: message.to_string(),
+            })
+        };
+        if let Some(name) = db.undefined_fields(values).await.map_err(|e| EditorError::from(db_error(e)))?.first() {
+            return Err(invalid(name, "There is no field by this name"));
+        }
+        match db.blank_required_fields(values).await.map_err(|e| EditorError::from(db_error(e)))?.first() {
+            Some(name) => Err(invalid(name, "This field is required")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+fn invalid_name(name: &str) -> ServerFnError<EditorError> {
+    ServerFnError::WrappedServerError(EditorError::Other(format!(
+        "{} can't be used as a field name. Use up to {} lowercase letters, digits and underscores, \
+         starting with a letter.",
+        name, MAX_FIELD_NAME_LEN
+    )))
+}
+
+// The tenant's fields in display order, with their labels, placeholders,
+// help texts and required flags
+#[server(GetFieldSchema)]
+pub async fn get_field_schema() -> Result<Vec<FieldDefinition>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::FieldSchema).await?;
+    db.field_definitions().await.map_err(db_error)
+}
+
+#[server(AddField)]
+pub async fn add_field(name: String, csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::ManageFields, ResourceKind::FieldSchema).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    if !is_valid_field_name(&name) {
+        return Err(invalid_name(&name));
+    }
+    match db.add_field(&name).await.map_err(|e| EditorError::from(db_error(e)))? {
+        true => Ok(()),
+        false => Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "There already is a field named {}",
+            name
+        )))),
+    }
+}
+
+#[server(RemoveField)]
+pub async fn remove_field(name: String, csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::ManageFields, ResourceKind::FieldSchema).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    match db.remove_field(&name).await.map_err(|e| EditorError::from(db_error(e)))? {
+        true => Ok(()),
+        false => Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "{} could not be removed: it doesn't exist or is the last field left",
+            name
+        )))),
+    }
+}
+
+#[server(RenameField)]
+pub async fn rename_field(from: String, to: String, csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::ManageFields, ResourceKind::FieldSchema).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    if !is_valid_field_name(&to) {
+        return Err(invalid_name(&to));
+    }
+    match db.rename_field(&from, &to).await.map_err(|e| EditorError::from(db_error(e)))? {
+        true => Ok(()),
+        false => Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "{} could not be renamed: it doesn't exist or there already is a field named {}",
+            from, to
+        )))),
+    }
+}
+
+#[server(UpdateFieldMetadata)]
+pub async fn update_field_metadata(
+    name: String,
+    metadata: FieldMetadata,
+    csrf_token: String,
+) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::ManageFields, ResourceKind::FieldSchema).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    match db
+        .update_field_metadata(&name, &metadata.normalized())
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?
+    {
+        true => Ok(()),
+        false => Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "There is no field named {}",
+            name
+        )))),
+    }
+}
+
+// Move a field `by` places later (negative: earlier) in the form
+#[server(MoveField)]
+pub async fn move_field(name: String, by: i64, csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::ManageFields, ResourceKind::FieldSchema).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    match db.move_field(&name, by).await.map_err(|e| EditorError::from(db_error(e)))? {
+        true => Ok(()),
+        false => Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "There is no field named {}",
+            name
+        )))),
+    }
+}
+
+/// Lists the fields records have and lets signed-in users add, rename,
+/// reorder and remove them, and change how editors present them.
+#[component]
+pub fn FieldSchemaEditor() -> impl IntoView {
+    let source = RwSignal::new(());
+    let definitions = Resource::new(move || source.get(), |_| get_field_schema());
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let csrf_token = move || session.csrf_token.get_untracked().flatten().unwrap_or_default();
+    let new_name = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let tasks = Tasks::new();
+
+    // Run a change, then show the fields as they are now
+    let change = move |result: Result<(), ServerFnError<EditorError>>| {
+        match result {
+            Ok(()) => error.set(None),
+            Err(ServerFnError::WrappedServerError(e)) => error.set(Some(e.to_string())),
+            Err(e) => error.set(Some(e.to_string())),
+        }
+        source.set(());
+    };
+    let on_add = move |_| {
+        let name = new_name.get_untracked().trim().to_string();
+        tasks.spawn(async move {
+            let result = add_field(name, csrf_token()).await;
+            if result.is_ok() {
+                new_name.set(String::new());
+            }
+            change(result);
+        });
+    };
+    let on_remove = move |name: String| {
+        tasks.spawn(async move { change(remove_field(name, csrf_token()).await) });
+    };
+    let on_rename = move |from: String, to: String| {
+        tasks.spawn(async move { change(rename_field(from, to.trim().to_string(), csrf_token()).await) });
+    };
+    let on_move = move |name: String, by: i64| {
+        tasks.spawn(async move { change(move_field(name, by, csrf_token()).await) });
+    };
+    let on_describe = move |name: String, metadata: FieldMetadata| {
+        tasks.spawn(async move { change(update_field_metadata(name, metadata, csrf_token()).await) });
+    };
+
+    view! {
+        <div class="field-editor">
+            <h1>"Fields"</h1>
+            <p>
+                "Every record has these fields. Removing a field deletes its values from all records; "
+                "the history keeps them."
+            </p>
+
+            {move || error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+
+            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
+                {move || definitions.get().map(|result| match result {
+                    Err(e) => view! { <div class="error">"Error loading fields: " {e.to_string()}</div> }.into_any(),
+                    Ok(definitions) => view! {
+                        <ul class="record-list">
+                            {definitions.into_iter().map(|definition| {
+                                let name = definition.name.clone();
+                                let rename_to = RwSignal::new(name.clone());
+                                let metadata = RwSignal::new(definition.metadata.clone());
+                                let controls = move || signed_in().then(|| {
+                                    let (from, remove, current) = (name.clone(), name.clone(), name.clone());
+                                    let (up, down, describe) = (name.clone(), name.clone(), name.clone());
+                                    let text = |get: fn(&FieldMetadata) -> &Option<String>,
+                                                set: fn(&mut FieldMetadata, Option<String>),
+                                                what: &'static str| view! {
+                                        <input
+                                            type="text"
+                                            placeholder=what
+                                            prop:value=move || metadata.with(|m| get(m).clone().unwrap_or_default())
+                                            on:input=move |ev| metadata.update(|m| set(m, Some(event_target_value(&ev))))
+                                        />
+                                    };
+                                    view! {
+                                        <input
+                                            type="text"
+                                            prop:value=rename_to
+                                            on:input=move |ev| rename_to.set(event_target_value(&ev))
+                                        />
+                                        <button
+                                            on:click=move |_| on_rename(from.clone(), rename_to.get_untracked())
+                                            disabled=move || rename_to.get().trim() == current
+                                        >
+                                            "Rename"
+                                        </button>
+                                        <button on:click=move |_| on_move(up.clone(), -1)>"Up"</button>
+                                        <button on:click=move |_| on_move(down.clone(), 1)>"Down"</button>
+                                        <button class="danger" on:click=move |_| on_remove(remove.clone())>
+                                            "Remove"
+                                        </button>
+                                        <div class="form-group">
+                                            {text(|m| &m.label, |m, v| m.label = v, "Label")}
+                                            {text(|m| &m.placeholder, |m, v| m.placeholder = v, "Placeholder")}
+                                            {text(|m| &m.help, |m, v| m.help = v, "Help text")}
+                                            <label>
+                                                <input
+                                                    type="checkbox"
+                                                    prop:checked=move || metadata.with(|m| m.required)
+                                                    on:change=move |ev| metadata.update(|m| m.required = event_target_checked(&ev))
+                                                />
+                                                " Required"
+                                            </label>
+                                            <button on:click=move |_| on_describe(describe.clone(), metadata.get_untracked())>
+                                                "Save"
+                                            </button>
+                                        </div>
+                                    }
+                                });
+                                view! {
+                                    <li>
+                                        <span class="record-title">
+                                            {definition.label()}
+                                            {definition.metadata.required.then_some(" *")}
+                                        </span>
+                                        <code>{definition.name}</code>
+                                        {controls}
+                                    </li>
+                                }
+                            }).collect_view()}
+                        </ul>
+                    }.into_any(),
+                })}
+            </Suspense>
+
+            {move || signed_in().then(|| view! {
+                <div class="form-group">
+                    <label for="new-field-name">"New field"</label>
+                    <input
+                        id="new-field-name"
+                        type="text"
+                        placeholder="e.g. due_date"
+                        prop:value=new_name
+                        on:input=move |ev| new_name.set(event_target_value(&ev))
+                    />
+                    <button on:click=on_add disabled=move || new_name.get().trim().is_empty()>"Add field"</button>
+                </div>
+            })}
+        </div>
+    }
+}
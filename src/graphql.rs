@@ -0,0 +1,279 @@
+use crate::auth::User;
+use crate::change_requests::{needs_approval, APPROVAL_REQUIRED};
+use crate::changefeed::ChangeFeed;
+use crate::db::{ConcurrencyMode, DbManager, FieldValues, Fields};
+use crate::errors::EditorError;
+use crate::events::EventEnvelope;
+use crate::field_schema::require_valid_fields;
+use crate::policy::{allowed, denial, Action, ResourceKind};
+use crate::rate_limit::RateLimiter;
+use crate::rest::session_id;
+use crate::tenant::TenantDb;
+use actix_web::{guard, web, HttpRequest, HttpResponse};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, Data, ErrorExtensions, Object, Schema, SimpleObject, Subscription};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+
+// GraphQL access to the same records, saves and change feed as the UI,
+// for clients that prefer it over server functions or the REST API
+
+pub type EditorSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn build_schema(db: DbManager, feed: ChangeFeed, limiter: RateLimiter) -> EditorSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(db)
+        .data(feed)
+        .data(limiter)
+        .finish()
+}
+
+// Who sent a request, attached to each request before it runs
+struct Caller {
+    session_id: String,
+    user: User,
+}
+
+fn internal_error(e: sqlx::Error) -> async_graphql::Error {
+    tracing::error!(error = %e, "GraphQL request failed");
+    async_graphql::Error::new("Internal server error")
+}
+
+// The signed-in caller; queries and mutations alike need one
+fn caller<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Caller> {
+    ctx.data_opt::<Caller>().ok_or_else(|| {
+        async_graphql::Error::new("You must be logged in to do that")
+            .extend_with(|_, e| e.set("code", "UNAUTHENTICATED"))
+    })
+}
+
+// Fails unless the policy lets `user` do `action` to `kind`
+async fn authorize(db: &DbManager, user: &User, action: Action, kind: ResourceKind) -> async_graphql::Result<()> {
+    match allowed(db, user, action, kind).await {
+        true => Ok(()),
+        false => Err(async_graphql::Error::new(denial(action, kind)).extend_with(|_, e| e.set("code", "FORBIDDEN"))),
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // A record, or null if it doesn't exist or is in the trash
+    async fn fields(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Fields>> {
+        let caller = caller(ctx)?;
+        let db = ctx.data_unchecked::<DbManager>();
+        authorize(db, &caller.user, Action::View, ResourceKind::Record(id)).await?;
+        match db.get_fields(id).await {
+            Ok(fields) => Ok(Some(fields)),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(e) => Err(internal_error(e)),
+        }
+    }
+}
+
+// The outcome of a save; on a version conflict `success` is false and
+// `fields` holds the newer version someone else saved
+#[derive(SimpleObject)]
+pub struct UpdateFieldsResult {
+    success: bool,
+    fields: Option<Fields>,
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn update_fields(
+        &self,
+        ctx: &Context<'_>,
+        id: i64,
+        input: FieldValues,
+        // The version the save is based on, or in timestamp mode when the
+        // record was loaded, in RFC 3339
+        expected_version: Option<i64>,
+        expected_updated_at: Option<String>,
+    ) -> async_graphql::Result<UpdateFieldsResult> {
+        let caller = caller(ctx)?;
+        let limiter = ctx.data_unchecked::<RateLimiter>();
+        if let Err(retry_after_secs) = limiter.check(&format!("session:{}", caller.session_id)) {
+            return Err(async_graphql::Error::new("Too many requests").extend_with(|_, e| {
+                e.set("code", "TOO_MANY_REQUESTS");
+                e.set("retryAfterSecs", retry_after_secs);
+            }));
+        }
+
+        let db = ctx.data_unchecked::<DbManager>();
+        authorize(db, &caller.user, Action::Edit, ResourceKind::Record(id)).await?;
+        if db.is_expired(id).await.map_err(internal_error)? {
+            return Err(async_graphql::Error::new("The record has expired and can no longer be changed")
+                .extend_with(|_, e| e.set("code", "EXPIRED")));
+        }
+        match require_valid_fields(db, &input).await {
+            Ok(()) => {}
+            Err(EditorError::Invalid(invalid)) => {
+                return Err(async_graphql::Error::new(invalid.message).extend_with(|_, e| {
+                    e.set("code", "INVALID_FIELD");
+                    e.set("field", invalid.field.as_str());
+                }));
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Could not check the values");
+                return Err(async_graphql::Error::new("Internal server error"));
+            }
+        }
+        if needs_approval(db, &caller.user, id).await {
+            return Err(async_graphql::Error::new(APPROVAL_REQUIRED)
+                .extend_with(|_, e| e.set("code", "APPROVAL_REQUIRED")));
+        }
+        let (token, argument) = match db.concurrency_mode() {
+            ConcurrencyMode::Version => (expected_version.map(|version| version.to_string()), "expectedVersion"),
+            ConcurrencyMode::Timestamp => (expected_updated_at, "expectedUpdatedAt"),
+        };
+        let Some(token) = token else {
+            return Err(async_graphql::Error::new(format!("{} is required", argument))
+                .extend_with(|_, e| e.set("code", "BAD_USER_INPUT")));
+        };
+        let expected_version = db
+            .expected_version_of(id, &token)
+            .await
+            .map_err(internal_error)?
+            .map_err(|message| async_graphql::Error::new(message).extend_with(|_, e| e.set("code", "BAD_USER_INPUT")))?;
+        let success = db
+            .update_fields(id, &caller.user.name, &input, expected_version, None)
+            .await
+            .map_err(internal_error)?;
+        let fields = match db.get_fields(id).await {
+            Ok(fields) => Some(fields),
+            Err(sqlx::Error::RowNotFound) => None,
+            Err(e) => return Err(internal_error(e)),
+        };
+        Ok(UpdateFieldsResult { success, fields })
+    }
+}
+
+// A new version of a record, as announced on the change feed
+#[derive(SimpleObject)]
+pub struct FieldChange {
+    // Position in the audit log
+    id: i64,
+    record_id: i64,
+    version: i64,
+    occurred_at: i64,
+    actor: Option<String>,
+    // created, updated, deleted or restored
+    kind: String,
+}
+
+impl From<EventEnvelope> for FieldChange {
+    fn from(change: EventEnvelope) -> Self {
+        FieldChange {
+            id: change.id,
+            record_id: change.record_id,
+            version: change.version,
+            occurred_at: change.occurred_at,
+            actor: change.actor,
+            kind: change.event.kind().to_string(),
+        }
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    // New versions of one record, or of all records if no id is given
+    async fn fields_changed(
+        &self,
+        ctx: &Context<'_>,
+        id: Option<i64>,
+    ) -> async_graphql::Result<impl Stream<Item = FieldChange>> {
+        let caller = caller(ctx)?;
+        let db = ctx.data_unchecked::<DbManager>();
+        let kind = id.map_or(ResourceKind::Records, ResourceKind::Record);
+        authorize(db, &caller.user, Action::View, kind).await?;
+        let tenant = db.tenant().clone();
+        let subscribed = ctx.data_unchecked::<ChangeFeed>().subscribe(tenant);
+        let changes = futures::stream::unfold(subscribed, |mut changes| async move {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => return Some((change, changes)),
+                    // A slow subscriber skips what it missed rather than being cut off
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |change| {
+            futures::future::ready(
+                change.event.changes_state() && id.is_none_or(|id| change.record_id == id),
+            )
+        })
+        .map(FieldChange::from);
+        Ok(changes)
+    }
+}
+
+async fn graphql(
+    schema: web::Data<EditorSchema>,
+    db: TenantDb,
+    req: HttpRequest,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    // Resolvers see the request's tenant rather than the schema's manager
+    let mut request = request.into_inner().data(db.0.clone());
+    if let Some(session_id) = session_id(&req) {
+        match db.session_user(&session_id).await {
+            Ok(Some(user)) => request = request.data(Caller { session_id, user }),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(error = %e, "Failed to look up GraphQL session"),
+        }
+    }
+    schema.execute(request).await.into()
+}
+
+async fn graphql_ws(
+    schema: web::Data<EditorSchema>,
+    db: TenantDb,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let mut data = Data::default();
+    if let Some(session_id) = session_id(&req) {
+        match db.session_user(&session_id).await {
+            Ok(Some(user)) => data.insert(Caller { session_id, user }),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(error = %e, "Failed to look up GraphQL session"),
+        }
+    }
+    data.insert(db.0);
+    GraphQLSubscription::new(EditorSchema::clone(&schema))
+        .with_data(data)
+        .start(&req, payload)
+}
+
+// An in-browser IDE for trying out queries
+async fn graphiql() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            GraphiQLSource::build()
+                .endpoint("/graphql")
+                .subscription_endpoint("/graphql")
+                .finish(),
+        )
+}
+
+// `/graphql`: queries and mutations by POST, subscriptions over a WebSocket,
+// and GraphiQL for a plain GET from a browser
+pub fn service() -> actix_web::Resource {
+    web::resource("/graphql")
+        .route(web::post().to(graphql))
+        .route(
+            web::get()
+                .guard(guard::Header("upgrade", "websocket"))
+                .to(graphql_ws),
+        )
+        .route(web::get().to(graphiql))
+}
@@ -0,0 +1,82 @@
+use crate::db::DbManager;
+use crate::record_cache::cache_metrics;
+use crate::retention::prune_metrics;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+// Probes for container orchestration. The server is live while it answers at
+// all, and ready while it can also reach the database. Metrics for scraping
+// are served next to them.
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    database: &'static str,
+}
+
+// 200 when the database answers, 503 otherwise. Why it doesn't answer goes
+// to the log only, since the probe is open to anyone.
+#[actix_web::get("/healthz")]
+pub async fn healthz(db: web::Data<DbManager>) -> HttpResponse {
+    match db.ping().await {
+        Ok(()) => HttpResponse::Ok().json(Health {
+            status: "ok",
+            database: "ok",
+        }),
+        Err(e) => {
+            tracing::error!(error = %e, "Health check failed");
+            HttpResponse::ServiceUnavailable().json(Health {
+                status: "unavailable",
+                database: "unreachable",
+            })
+        }
+    }
+}
+
+// Counters in the Prometheus text format
+#[actix_web::get("/metrics")]
+pub async fn metrics() -> HttpResponse {
+    use std::fmt::Write;
+    use std::sync::atomic::Ordering;
+
+    let prune = prune_metrics();
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(body, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(body, "{}{} {}", name, labels, value);
+        }
+    };
+    metric(
+        "field_editor_history_prune_runs_total",
+        "counter",
+        "Runs of the audit log pruning job",
+        &[("", prune.runs.load(Ordering::Relaxed))],
+    );
+    metric(
+        "field_editor_history_pruned_total",
+        "counter",
+        "Audit log entries pruned, by the rule that pruned them",
+        &[
+            ("{rule=\"versions\"}", prune.pruned_by_versions.load(Ordering::Relaxed)),
+            ("{rule=\"age\"}", prune.pruned_by_age.load(Ordering::Relaxed)),
+        ],
+    );
+    metric(
+        "field_editor_history_prune_last_run_timestamp_seconds",
+        "gauge",
+        "When the audit log was last pruned",
+        &[("", prune.last_run_at.load(Ordering::Relaxed).max(0) as u64 / 1000)],
+    );
+    let cache = cache_metrics();
+    metric(
+        "field_editor_record_cache_requests_total",
+        "counter",
+        "Record reads through the record cache, by whether it had the record",
+        &[
+            ("{result=\"hit\"}", cache.hits.load(Ordering::Relaxed)),
+            ("{result=\"miss\"}", cache.misses.load(Ordering::Relaxed)),
+        ],
+    );
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body)
+}
@@ -0,0 +1,403 @@
+use crate::auth::use_user_session;
+use crate::conflict::describe;
+use crate::errors::EditorError;
+use crate::events::EventEnvelope;
+use crate::timestamp::format_timestamp;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+use std::collections::BTreeMap;
+
+pub const DEFAULT_HISTORY_LIMIT: i64 = 100;
+pub const MAX_HISTORY_LIMIT: i64 = 1000;
+
+// Versions listed under the editor
+pub const RECENT_VERSIONS: i64 = 20;
+
+// An audit log entry: the event plus the record's field values right after it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub event: EventEnvelope,
+    pub values: BTreeMap<String, String>,
+}
+
+// Which audit log entries to return; filters left unset match everything
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryFilter {
+    #[serde(default)]
+    pub record_id: Option<i64>,
+    // Who made the change
+    #[serde(default)]
+    pub actor: Option<String>,
+    // Only entries that changed this field
+    #[serde(default)]
+    pub field: Option<String>,
+    // Entries at or after / before these times, in milliseconds since the epoch
+    #[serde(default)]
+    pub from: Option<i64>,
+    #[serde(default)]
+    pub to: Option<i64>,
+    // Event kinds such as `updated` or `conflict_detected`; empty for all
+    #[serde(default)]
+    pub change_types: Vec<String>,
+}
+
+// A page of matching entries, oldest first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    // Pass as `after` to get the next page; None once there are no more
+    pub next_cursor: Option<i64>,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{HistoryEntry, HistoryFilter, HistoryPage, MAX_HISTORY_LIMIT};
+    use crate::db::{DbManager, FieldValues, HistoryRow, HISTORY_COLUMNS};
+    use crate::events::EventEnvelope;
+    use crate::auth::admins;
+    use crate::field_schema::is_valid_field_name;
+    use crate::rest::{error, internal_error, request_user};
+    use crate::tenant::TenantDb;
+    use actix_web::http::StatusCode;
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use serde::Deserialize;
+    use sqlx::{QueryBuilder, Sqlite};
+
+    const EXPORT_PAGE_SIZE: i64 = 500;
+
+    // How far back `recent_versions` looks; locks and rejected saves are in
+    // there too, so this is more than the versions it returns
+    const RECENT_EVENTS: i64 = 500;
+
+    impl From<HistoryRow> for HistoryEntry {
+        fn from(row: HistoryRow) -> Self {
+            let values = FieldValues::from_stored(&row.field_values).unwrap_or_default().0;
+            HistoryEntry {
+                event: EventEnvelope::from(row),
+                values,
+            }
+        }
+    }
+
+    impl DbManager {
+        // The tenant's audit log entries with ids in (after_id, up_to], oldest first
+        pub async fn history_page(
+            &self,
+            after_id: i64,
+            up_to: i64,
+            limit: i64,
+        ) -> Result<Vec<HistoryEntry>, sqlx::Error> {
+            let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+                "SELECT {} FROM fields_history WHERE tenant_id = ? AND id > ? AND id <= ? ORDER BY id LIMIT ?",
+                HISTORY_COLUMNS
+            ))
+            .bind(self.tenant().as_str())
+            .bind(after_id)
+            .bind(up_to)
+            .bind(limit)
+            .fetch_all(self.pool())
+            .await?;
+            Ok(rows.into_iter().map(HistoryEntry::from).collect())
+        }
+
+        // A record's latest versions, newest first, from its last `RECENT_EVENTS`
+        // audit log entries
+        pub async fn recent_versions(&self, record_id: i64, limit: i64) -> Result<Vec<HistoryEntry>, sqlx::Error> {
+            let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+                "SELECT {} FROM fields_history WHERE record_id = ? AND tenant_id = ? ORDER BY id DESC LIMIT ?",
+                HISTORY_COLUMNS
+            ))
+            .bind(record_id)
+            .bind(self.tenant().as_str())
+            .bind(RECENT_EVENTS)
+            .fetch_all(self.pool())
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(HistoryEntry::from)
+                .filter(|entry| entry.event.event.changes_state())
+                .take(limit as usize)
+                .collect())
+        }
+
+        // Audit log entries after the cursor that match every filter, oldest first
+        pub async fn query_history(
+            &self,
+            filter: &HistoryFilter,
+            after: i64,
+            limit: i64,
+        ) -> Result<HistoryPage, sqlx::Error> {
+            let limit = limit.clamp(1, MAX_HISTORY_LIMIT);
+            let field = filter.field.as_deref();
+            // No entry ever changed a field that can't exist
+            if field.is_some_and(|f| !is_valid_field_name(f)) {
+                return Ok(HistoryPage {
+                    entries: Vec::new(),
+                    next_cursor: None,
+                });
+            }
+            let mut query = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM ", HISTORY_COLUMNS));
+            match field {
+                // Compare each entry with the record's entry before it to see what it changed
+                Some(_) => query.push(
+                    "(SELECT *, LAG(field_values) OVER (PARTITION BY record_id ORDER BY id) AS previous_values \
+                     FROM fields_history) AS fields_history",
+                ),
+                None => query.push("fields_history"),
+            };
+            query
+                .push(" WHERE tenant_id = ")
+                .push_bind(self.tenant().as_str().to_string())
+                .push(" AND id > ")
+                .push_bind(after);
+            if let Some(record_id) = filter.record_id {
+                query.push(" AND record_id = ").push_bind(record_id);
+            }
+            if let Some(actor) = &filter.actor {
+                query.push(" AND changed_by = ").push_bind(actor.clone());
+            }
+            if let Some(field) = field {
+                let path = format!("$.{}", field);
+                query
+                    .push(" AND json_extract(field_values, ")
+                    .push_bind(path.clone())
+                    .push(") IS NOT json_extract(previous_values, ")
+                    .push_bind(path)
+                    .push(")");
+            }
+            if let Some(from) = filter.from {
+                query.push(" AND changed_at >= ").push_bind(from);
+            }
+            if let Some(to) = filter.to {
+                query.push(" AND changed_at < ").push_bind(to);
+            }
+            if !filter.change_types.is_empty() {
+                query.push(" AND change_type IN (");
+                let mut types = query.separated(", ");
+                for change_type in &filter.change_types {
+                    types.push_bind(change_type.clone());
+                }
+                query.push(")");
+            }
+            // One more than asked for tells whether there is a next page
+            query.push(" ORDER BY id LIMIT ").push_bind(limit + 1);
+
+            let mut entries: Vec<HistoryEntry> = query
+                .build_query_as::<HistoryRow>()
+                .fetch_all(self.pool())
+                .await?
+                .into_iter()
+                .map(HistoryEntry::from)
+                .collect();
+            let more = entries.len() as i64 > limit;
+            entries.truncate(limit as usize);
+            Ok(HistoryPage {
+                next_cursor: more.then(|| entries.last().map(|e| e.event.id)).flatten(),
+                entries,
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct ExportQuery {
+        // Cursor from a previous export; omit to export everything
+        #[serde(default)]
+        since: i64,
+    }
+
+    // Stream the audit log as JSON Lines, one entry per line, for data warehouse ingestion.
+    // The export covers entries after `since` up to the end of the log at the time of the
+    // request; that end is returned in `X-Next-Cursor` so the next export picks up exactly
+    // where this one stopped. The whole log is for admins only.
+    #[actix_web::get("/api/history/export.jsonl")]
+    pub async fn export_history_jsonl(
+        db: TenantDb,
+        req: HttpRequest,
+        query: web::Query<ExportQuery>,
+    ) -> actix_web::Result<HttpResponse> {
+        match request_user(&db, &req).await {
+            Ok(Some((user, _))) if admins().contains(&user.name) => {}
+            Ok(Some(_)) => return Ok(error(StatusCode::FORBIDDEN, "Only admins may export the audit log")),
+            Ok(None) => return Ok(error(StatusCode::UNAUTHORIZED, "You must be logged in to do that")),
+            Err(e) => return Ok(internal_error(e)),
+        }
+        let up_to = db
+            .latest_change_id()
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let since = query.since;
+
+        let db = db.0;
+        let pages = futures::stream::unfold(Some(since), move |cursor| {
+            let db = db.clone();
+            async move {
+                let cursor = cursor?;
+                let page = match db.history_page(cursor, up_to, EXPORT_PAGE_SIZE).await {
+                    Ok(page) => page,
+                    Err(e) => return Some((Err(actix_web::error::ErrorInternalServerError(e)), None)),
+                };
+                if page.is_empty() {
+                    return None;
+                }
+                let next = page.last().map(|entry| entry.event.id);
+                let mut lines = String::new();
+                for entry in &page {
+                    lines.push_str(&serde_json::to_string(entry).expect("HistoryEntry serializes"));
+                    lines.push('\n');
+                }
+                Some((Ok(web::Bytes::from(lines)), next))
+            }
+        });
+
+        Ok(HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .insert_header(("X-Next-Cursor", up_to.max(since).to_string()))
+            .streaming(pages))
+    }
+}
+
+// Search the audit log, a page at a time; admins only, since it spans every record
+#[server(QueryHistory)]
+pub async fn query_history(
+    #[server(default)] filter: HistoryFilter,
+    #[server(default)] after: i64,
+    #[server(default)] limit: Option<i64>,
+) -> Result<HistoryPage, ServerFnError> {
+    use crate::auth::require_admin;
+    use crate::field_editor::{db_error, open_db};
+
+    let db = open_db().await?;
+    require_admin(&db).await?;
+    db.query_history(&filter, after, limit.unwrap_or(DEFAULT_HISTORY_LIMIT))
+        .await
+        .map_err(db_error)
+}
+
+// A record's latest versions, newest first
+#[server(RecordVersions)]
+pub async fn record_versions(record_id: i64) -> Result<Vec<HistoryEntry>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(record_id)).await?;
+    db.recent_versions(record_id, RECENT_VERSIONS).await.map_err(db_error)
+}
+
+/// Lists a record's latest versions, each of which can be brought back as a new
+/// version together with the attachments it had.
+#[component]
+pub fn RecordVersions(record_id: i64) -> impl IntoView {
+    let session = use_user_session();
+    let refresh = RwSignal::new(0u32);
+    let versions = Resource::new(move || refresh.get(), move |_| record_versions(record_id));
+    let revert = ServerAction::<RevertToVersion>::new();
+    let revert_error = RwSignal::new(None::<String>);
+
+    Effect::new(move |_| match revert.value().get() {
+        Some(Ok(true)) => {
+            revert_error.set(None);
+            refresh.update(|n| *n += 1);
+        }
+        Some(Ok(false)) => {
+            revert_error.set(Some("The record changed meanwhile; look again and retry".to_string()));
+            refresh.update(|n| *n += 1);
+        }
+        Some(Err(e)) => revert_error.set(Some(e.to_string())),
+        None => {}
+    });
+
+    #[cfg(feature = "hydrate")]
+    {
+        use crate::changefeed::{subscribe, LiveUpdate};
+
+        let subscription = subscribe(move |update| match update {
+            LiveUpdate::Change(change) if change.record_id == record_id && change.event.changes_state() => {
+                refresh.update(|n| *n += 1)
+            }
+            LiveUpdate::Resync | LiveUpdate::Reconnected => refresh.update(|n| *n += 1),
+            _ => {}
+        });
+        on_cleanup(move || drop(subscription));
+    }
+
+    view! {
+        <div class="record-versions">
+            <h2>"Versions"</h2>
+            <Suspense fallback=|| view! { <p>"Loading versions..."</p> }>
+                {move || versions.get().map(|result| match result {
+                    Err(e) => view! { <p class="error">"Could not load versions: " {e.to_string()}</p> }.into_any(),
+                    Ok(entries) => {
+                        let me = session.user.get().flatten().map(|u| u.name);
+                        let latest = entries.first().map(|entry| entry.event.version);
+                        view! {
+                            <ul>
+                                {entries.into_iter().map(|entry| {
+                                    let version = entry.event.version;
+                                    let is_latest = Some(version) == latest;
+                                    view! {
+                                        <li>
+                                            <span class="when">{format_timestamp(entry.event.occurred_at)}</span>
+                                            " "
+                                            <span class="what">{describe(&entry.event, me.as_deref())}</span>
+                                            {(!is_latest).then(|| view! {
+                                                <button
+                                                    class="link"
+                                                    disabled=move || revert.pending().get() || session.user.get().flatten().is_none()
+                                                    on:click=move |_| {
+                                                        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+                                                        revert.dispatch(RevertToVersion {
+                                                            id: record_id,
+                                                            version,
+                                                            expected_version: latest.unwrap_or_default(),
+                                                            csrf_token,
+                                                        });
+                                                    }
+                                                >
+                                                    "Restore"
+                                                </button>
+                                            })}
+                                        </li>
+                                    }
+                                }).collect_view()}
+                            </ul>
+                        }.into_any()
+                    }
+                })}
+            </Suspense>
+            {move || revert_error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+        </div>
+    }
+}
+
+// Bring back the values and attachments a record had at `version` as its
+// newest version. Fails (returns false) if the record moved past `expected_version`.
+#[server(RevertToVersion)]
+pub async fn revert_to_version(
+    id: i64,
+    version: i64,
+    expected_version: i64,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::expiry::require_unexpired;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    require_unexpired(&db, id).await.map_err(EditorError::from)?;
+    Ok(db
+        .revert_to_version(id, &user.name, version, expected_version)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
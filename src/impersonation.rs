@@ -0,0 +1,381 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::format_timestamp;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Admins can act as another user of their tenant to reproduce a permission or
+// conflict problem exactly as that user sees it. Acting as someone runs in a
+// session of its own that ends after a time limit, shows a banner on every
+// page, and logs everything done in it other than looking; stopping goes back
+// to the admin's own session.
+
+// One line of the impersonation log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct ImpersonationEntry {
+    pub id: i64,
+    pub admin: String,
+    pub user_name: String,
+    // started, ended, allowed or denied
+    pub event: String,
+    // The action and resource for allowed and denied
+    pub detail: String,
+    pub at: i64,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::ImpersonationEntry;
+    use crate::db::DbManager;
+    use crate::timestamp::format_timestamp;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ImpersonationPolicy {
+        // How long acting as someone lasts before the session ends on its own
+        pub time_limit: Duration,
+    }
+
+    impl ImpersonationPolicy {
+        // FIELD_EDITOR_IMPERSONATION_MINUTES, 30 by default
+        pub fn from_env() -> Self {
+            let minutes: u64 = std::env::var("FIELD_EDITOR_IMPERSONATION_MINUTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&minutes| minutes > 0)
+                .unwrap_or(30);
+            ImpersonationPolicy {
+                time_limit: Duration::from_secs(minutes * 60),
+            }
+        }
+    }
+
+    // The impersonation policy for this process, read from the environment once
+    pub fn impersonation_policy() -> &'static ImpersonationPolicy {
+        static POLICY: OnceLock<ImpersonationPolicy> = OnceLock::new();
+        POLICY.get_or_init(ImpersonationPolicy::from_env)
+    }
+
+    impl DbManager {
+        // Start a session in which `admin`, signed in with `admin_session`, acts
+        // as `user`. It ends after `time_limit`, or with the admin's own session
+        // if that ends first. Returns the new session and when it ends; None if
+        // `user` isn't registered with this manager's tenant.
+        #[tracing::instrument(skip(self, admin_session))]
+        pub async fn start_impersonation(
+            &self,
+            admin: &str,
+            admin_session: &str,
+            user: &str,
+            time_limit: Duration,
+        ) -> Result<Option<(String, i64)>, sqlx::Error> {
+            if self.user_tenant(user).await?.as_ref() != Some(self.tenant()) {
+                return Ok(None);
+            }
+            let admin_expires_at: Option<i64> =
+                sqlx::query_scalar("SELECT expires_at FROM sessions WHERE id = ? AND tenant_id = ?")
+                    .bind(admin_session)
+                    .bind(self.tenant().as_str())
+                    .fetch_optional(self.pool())
+                    .await?;
+            let Some(admin_expires_at) = admin_expires_at else {
+                return Ok(None);
+            };
+
+            let session_id = self.new_id();
+            let now = self.now();
+            let expires_at = (now + time_limit.as_millis() as i64).min(admin_expires_at);
+            let mut tx = self.pool().begin().await?;
+            sqlx::query(
+                r#"
+                INSERT INTO sessions
+                    (id, user_name, created_at, expires_at, csrf_token, tenant_id, impersonated_by, impersonator_session)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&session_id)
+            .bind(user)
+            .bind(now)
+            .bind(expires_at)
+            .bind(self.new_id())
+            .bind(self.tenant().as_str())
+            .bind(admin)
+            .bind(admin_session)
+            .execute(&mut *tx)
+            .await?;
+            let detail = format!("until {}", format_timestamp(expires_at));
+            self.insert_impersonation_log(&mut tx, admin, user, "started", &detail).await?;
+            tx.commit().await?;
+            Ok(Some((session_id, expires_at)))
+        }
+
+        // End an impersonation session. Returns the admin's own session and
+        // when it ends, if it is still live; None if `session_id` isn't an
+        // impersonation session of this manager's tenant.
+        #[tracing::instrument(skip(self, session_id))]
+        pub async fn end_impersonation(&self, session_id: &str) -> Result<Option<(String, i64)>, sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            let ended: Option<(String, String, Option<String>)> = sqlx::query_as(
+                r#"
+                DELETE FROM sessions WHERE id = ? AND tenant_id = ? AND impersonated_by IS NOT NULL
+                RETURNING impersonated_by, user_name, impersonator_session
+                "#,
+            )
+            .bind(session_id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some((admin, user, admin_session)) = ended else {
+                return Ok(None);
+            };
+            self.insert_impersonation_log(&mut tx, &admin, &user, "ended", "").await?;
+            let restored: Option<(String, i64)> = sqlx::query_as(
+                "SELECT id, expires_at FROM sessions WHERE id = ? AND tenant_id = ? AND expires_at > ?",
+            )
+            .bind(admin_session)
+            .bind(self.tenant().as_str())
+            .bind(self.now())
+            .fetch_optional(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok(restored)
+        }
+
+        // Record something `admin` did while acting as `user`
+        pub async fn log_impersonation(
+            &self,
+            admin: &str,
+            user: &str,
+            event: &str,
+            detail: &str,
+        ) -> Result<(), sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            self.insert_impersonation_log(&mut tx, admin, user, event, detail).await?;
+            tx.commit().await
+        }
+
+        async fn insert_impersonation_log(
+            &self,
+            tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+            admin: &str,
+            user: &str,
+            event: &str,
+            detail: &str,
+        ) -> Result<(), sqlx::Error> {
+            sqlx::query(
+                r#"
+                INSERT INTO impersonation_log (tenant_id, admin, user_name, event, detail, at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .bind(admin)
+            .bind(user)
+            .bind(event)
+            .bind(detail)
+            .bind(self.now())
+            .execute(&mut **tx)
+            .await?;
+            Ok(())
+        }
+
+        // The tenant's latest impersonation log entries, newest first
+        pub async fn recent_impersonations(&self, limit: i64) -> Result<Vec<ImpersonationEntry>, sqlx::Error> {
+            sqlx::query_as(
+                r#"
+                SELECT id, admin, user_name, event, detail, at FROM impersonation_log
+                WHERE tenant_id = ? ORDER BY id DESC LIMIT ?
+                "#,
+            )
+            .bind(self.tenant().as_str())
+            .bind(limit)
+            .fetch_all(self.pool())
+            .await
+        }
+    }
+}
+
+// Start acting as `user` in a session of its own
+#[server(StartImpersonation)]
+pub async fn start_impersonation(user: String, csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{admins, authorize, require_csrf, session_cookie, set_session_cookie};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let admin = authorize(&db, Action::Impersonate, ResourceKind::Users)
+        .await
+        .map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    let fail = |message: &str| Err(ServerFnError::WrappedServerError(EditorError::Other(message.to_string())));
+    let user = user.trim();
+    if admin.impersonated_by.is_some() {
+        return fail("Stop acting as the current user first");
+    }
+    if user == admin.name {
+        return fail("You can't act as yourself");
+    }
+    // An admin acting as another admin would act with admin rights under
+    // someone else's name
+    if admins().iter().any(|name| name == user) {
+        tracing::warn!(admin = %admin.name, %user, "Refused to impersonate an admin");
+        return fail("You can't act as another admin");
+    }
+    let Some(admin_session) = session_cookie().await.map_err(EditorError::from)? else {
+        return fail("You must be logged in to do that");
+    };
+
+    let time_limit = impersonation_policy().time_limit;
+    let started = db
+        .start_impersonation(&admin.name, &admin_session, user, time_limit)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    let Some((session_id, expires_at)) = started else {
+        return fail(&format!("There is no user named {}", user));
+    };
+    tracing::warn!(admin = %admin.name, %user, expires_at, "Impersonation started");
+    set_session_cookie(&session_id, (expires_at - db.now()) / 1000);
+    Ok(())
+}
+
+// Stop acting as someone else and go back to the admin's own session
+#[server(StopImpersonation)]
+pub async fn stop_impersonation(csrf_token: String) -> Result<(), ServerFnError<EditorError>> {
+    use crate::auth::{require_csrf, session_cookie, set_session_cookie};
+    use crate::field_editor::{db_error, open_db};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    let Some(session_id) = session_cookie().await.map_err(EditorError::from)? else {
+        return Ok(());
+    };
+    let restored = db
+        .end_impersonation(&session_id)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?;
+    match restored {
+        Some((admin_session, expires_at)) => set_session_cookie(&admin_session, (expires_at - db.now()) / 1000),
+        None => set_session_cookie("", 0),
+    }
+    Ok(())
+}
+
+// The latest impersonation log entries, for admins
+#[server(RecentImpersonations)]
+pub async fn recent_impersonations() -> Result<Vec<ImpersonationEntry>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::Impersonate, ResourceKind::Users).await?;
+    db.recent_impersonations(50).await.map_err(db_error)
+}
+
+/// Says on every page who an admin is acting as and until when, with a way
+/// to stop.
+#[component]
+pub fn ImpersonationBanner() -> impl IntoView {
+    let session = use_user_session();
+    let tasks = Tasks::new();
+    let on_stop = move |_| {
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        tasks.spawn(async move {
+            let _ = stop_impersonation(csrf_token).await;
+            session.refresh();
+        });
+    };
+
+    view! {
+        <Suspense fallback=|| ()>
+            {move || session.user.get().flatten().and_then(|user| {
+                let admin = user.impersonated_by?;
+                let until = user.impersonation_ends_at.map(format_timestamp).unwrap_or_default();
+                Some(view! {
+                    <div class="notice impersonation-banner">
+                        "You are acting as " <strong>{user.name}</strong> " on behalf of " {admin}
+                        " until " {until} ". Everything you do is logged."
+                        <button on:click=on_stop>"Stop"</button>
+                    </div>
+                })
+            })}
+        </Suspense>
+    }
+}
+
+/// Lets admins start acting as another user, and shows the latest
+/// impersonation log entries.
+#[component]
+pub fn Impersonation() -> impl IntoView {
+    let session = use_user_session();
+    let user = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let log = Resource::new(move || session.user.get(), |_| recent_impersonations());
+    let tasks = Tasks::new();
+
+    let on_start = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        tasks.spawn(async move {
+            match start_impersonation(user.get_untracked(), csrf_token).await {
+                Ok(()) => {
+                    error.set(None);
+                    user.set(String::new());
+                    session.refresh();
+                }
+                Err(e) => error.set(Some(e.to_string())),
+            }
+        });
+    };
+
+    view! {
+        <div class="field-editor impersonation">
+            <h2>"Act as a user"</h2>
+            <form on:submit=on_start>
+                <div class="form-group">
+                    <label for="impersonate-user">"User"</label>
+                    <input
+                        id="impersonate-user"
+                        type="text"
+                        prop:value=user
+                        on:input=move |ev| user.set(event_target_value(&ev))
+                    />
+                    <button type="submit" disabled=move || user.get().trim().is_empty()>"Act as this user"</button>
+                </div>
+            </form>
+            {move || error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+
+            <Suspense fallback=|| ()>
+                {move || log.get().and_then(Result::ok).filter(|entries| !entries.is_empty()).map(|entries| view! {
+                    <table class="report-table">
+                        <thead>
+                            <tr><th>"When"</th><th>"Admin"</th><th>"As"</th><th>"What"</th><th></th></tr>
+                        </thead>
+                        <tbody>
+                            {entries.into_iter().map(|entry| view! {
+                                <tr>
+                                    <td>{format_timestamp(entry.at)}</td>
+                                    <td>{entry.admin}</td>
+                                    <td>{entry.user_name}</td>
+                                    <td>{entry.event}</td>
+                                    <td>{entry.detail}</td>
+                                </tr>
+                            }).collect_view()}
+                        </tbody>
+                    </table>
+                })}
+            </Suspense>
+        </div>
+    }
+}
@@ -0,0 +1,466 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// What to do when an imported row is for a record that already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportMode {
+    // The imported values replace the current ones, whatever version the file has
+    Overwrite,
+    // Only apply rows whose version is the record's current version; skip the rest
+    SkipOnConflict,
+    // Apply every row, moving the version past both the current and the imported one
+    BumpVersion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportOutcome {
+    Created,
+    Updated,
+    Skipped,
+    // Kept as a change request, since the importer's saves need approval
+    PendingApproval,
+    Failed,
+}
+
+// What happened to one row of the file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowResult {
+    // Position in the file, starting at 1
+    pub row: usize,
+    pub id: Option<i64>,
+    pub outcome: ImportOutcome,
+    // The record's version after the import
+    pub version: Option<i64>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub rows: Vec<RowResult>,
+}
+
+impl ImportReport {
+    pub fn count(&self, outcome: ImportOutcome) -> usize {
+        self.rows.iter().filter(|r| r.outcome == outcome).count()
+    }
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ImportMode, ImportOutcome, RowResult};
+    use crate::auth::User;
+    use crate::change_requests::needs_approval;
+    use crate::db::{append_history, record_values, write_values, DbManager, FieldValues};
+    use crate::errors::{EditorError, ValidationError};
+    use crate::events::FieldEvent;
+    use crate::field_schema::require_valid_fields;
+    use crate::webhooks::{enqueue_webhook_deliveries, WebhookPayload};
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    // A record as read from the file, before it is checked
+    #[derive(Debug, Default, Deserialize)]
+    pub struct ImportRow {
+        pub id: Option<i64>,
+        pub version: Option<i64>,
+        // Everything else in the row; the values of the tenant's fields are taken from here
+        #[serde(flatten)]
+        pub rest: BTreeMap<String, serde_json::Value>,
+    }
+
+    impl ImportRow {
+        // The values of `fields`, which the row must all have
+        fn values(&self, fields: &[String]) -> Result<FieldValues, String> {
+            fields
+                .iter()
+                .map(|name| match self.rest.get(name) {
+                    Some(serde_json::Value::String(value)) => Ok((name.clone(), value.clone())),
+                    Some(_) => Err(format!("{} is not text", name)),
+                    None => Err(format!("Missing {}", name)),
+                })
+                .collect()
+        }
+    }
+
+    // Split CSV text into rows of values, following RFC 4180 quoting
+    fn parse_csv(text: &str) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    value.push('"');
+                }
+                ('"', true) => quoted = false,
+                ('"', false) if value.is_empty() => quoted = true,
+                (',', false) => row.push(std::mem::take(&mut value)),
+                ('\r', false) => {}
+                ('\n', false) => {
+                    row.push(std::mem::take(&mut value));
+                    rows.push(std::mem::take(&mut row));
+                }
+                (c, _) => value.push(c),
+            }
+        }
+        if !value.is_empty() || !row.is_empty() {
+            row.push(value);
+            rows.push(row);
+        }
+        rows.retain(|row| !(row.len() == 1 && row[0].is_empty()));
+        rows
+    }
+
+    // Records from a CSV file with a header row. Files made by the export also
+    // contain history rows; only the `record` rows are imported.
+    fn csv_rows(text: &str) -> Result<Vec<Result<ImportRow, String>>, String> {
+        let mut rows = parse_csv(text).into_iter();
+        let header = rows.next().ok_or("The file is empty")?;
+        let column = |name: &str| header.iter().position(|h| h.trim() == name);
+        let (id, version, kind) = (column("id").or(column("record_id")), column("version"), column("kind"));
+
+        Ok(rows
+            .filter(|row| kind.is_none_or(|k| row.get(k).is_some_and(|v| v == "record")))
+            .map(|row| {
+                let get = |index: Option<usize>| index.and_then(|i| row.get(i)).cloned();
+                let number = |index: Option<usize>, name: &str| match get(index).filter(|v| !v.is_empty()) {
+                    None => Ok(None),
+                    Some(v) => v.parse().map(Some).map_err(|_| format!("{} is not a number: {}", name, v)),
+                };
+                let rest = header
+                    .iter()
+                    .zip(&row)
+                    .map(|(name, value)| (name.trim().to_string(), serde_json::Value::String(value.clone())))
+                    .collect();
+                Ok(ImportRow {
+                    id: number(id, "id")?,
+                    version: number(version, "version")?,
+                    rest,
+                })
+            })
+            .collect())
+    }
+
+    // Records from JSON: the export's `{"records": [...]}` or a bare array
+    fn json_rows(text: &str) -> Result<Vec<Result<ImportRow, String>>, String> {
+        let json: serde_json::Value = serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+        let records = match json {
+            serde_json::Value::Array(records) => records,
+            serde_json::Value::Object(mut object) => match object.remove("records") {
+                Some(serde_json::Value::Array(records)) => records,
+                _ => return Err("Expected a \"records\" array".to_string()),
+            },
+            _ => return Err("Expected an array of records".to_string()),
+        };
+        Ok(records
+            .into_iter()
+            .map(|record| serde_json::from_value(record).map_err(|e| e.to_string()))
+            .collect())
+    }
+
+    // The rows of a file; JSON is recognized by its first character, anything else is CSV
+    pub fn parse_rows(payload: &str) -> Result<Vec<Result<ImportRow, String>>, String> {
+        match payload.trim_start().chars().next() {
+            Some('{') | Some('[') => json_rows(payload),
+            _ => csv_rows(payload),
+        }
+    }
+
+    impl DbManager {
+        // Create or update one record from an imported row, which must have a
+        // value for each of `fields`. Rows are checked like saves from the
+        // editor: invalid values and expired records fail, and updates by a
+        // user whose saves need approval become change requests.
+        pub async fn import_row(
+            &self,
+            row: usize,
+            record: ImportRow,
+            fields: &[String],
+            mode: ImportMode,
+            user: &User,
+        ) -> Result<RowResult, sqlx::Error> {
+            let result = |id, outcome, version, message: Option<&str>| RowResult {
+                row,
+                id,
+                outcome,
+                version,
+                message: message.map(str::to_string),
+            };
+            let (id, file_version) = (record.id, record.version);
+            let values = match record.values(fields) {
+                Ok(values) => values,
+                Err(message) => return Ok(result(id, ImportOutcome::Failed, None, Some(&message))),
+            };
+            match require_valid_fields(self, &values).await {
+                Ok(()) => {}
+                Err(EditorError::Invalid(ValidationError { field, message })) => {
+                    let message = format!("{}: {}", field, message);
+                    return Ok(result(id, ImportOutcome::Failed, None, Some(&message)));
+                }
+                Err(e) => return Ok(result(id, ImportOutcome::Failed, None, Some(&e.to_string()))),
+            }
+
+            let now = self.now();
+            let mut tx = self.pool().begin().await?;
+            let current = match id {
+                Some(id) => {
+                    sqlx::query_as::<_, (i64, Option<i64>, String)>(
+                        "SELECT version, deleted_at, tenant_id FROM fields WHERE id = ?",
+                    )
+                    .bind(id)
+                    .fetch_optional(&mut *tx)
+                    .await?
+                }
+                None => None,
+            };
+            let current = match current {
+                Some((_, _, tenant)) if tenant != self.tenant().as_str() => {
+                    return Ok(result(id, ImportOutcome::Failed, None, Some("The id is already in use")));
+                }
+                current => current.map(|(version, deleted_at, _)| (version, deleted_at)),
+            };
+
+            let Some((current_version, deleted_at)) = current else {
+                // A new record, keeping the file's id if it has one
+                let version = match mode {
+                    ImportMode::BumpVersion => file_version.unwrap_or(1).max(1),
+                    ImportMode::Overwrite | ImportMode::SkipOnConflict => 1,
+                };
+                let id: i64 = sqlx::query_scalar(
+                    r#"
+                    INSERT INTO fields (id, version, updated_by, updated_at, tenant_id)
+                    VALUES (?, ?, ?, ?, ?)
+                    RETURNING id
+                    "#,
+                )
+                .bind(id)
+                .bind(version)
+                .bind(&user.name)
+                .bind(now)
+                .bind(self.tenant().as_str())
+                .fetch_one(&mut *tx)
+                .await?;
+                let record = self.scope(&mut tx, id).await?.expect("The record was just inserted");
+                write_values(&mut tx, record, &values).await?;
+                append_history(&mut tx, record, &FieldEvent::Created, None, now).await?;
+                let change = WebhookPayload::created(id, version, &user.name, values, now);
+                enqueue_webhook_deliveries(&mut *tx, &change, now).await?;
+                tx.commit().await?;
+                self.forget_cached(id).await;
+                return Ok(result(Some(id), ImportOutcome::Created, Some(version), None));
+            };
+            let id = id.expect("Only rows with an id match a record");
+
+            if deleted_at.is_some() {
+                return Ok(result(
+                    Some(id),
+                    ImportOutcome::Failed,
+                    Some(current_version),
+                    Some("The record is in the trash; restore it first"),
+                ));
+            }
+            let version = match mode {
+                ImportMode::Overwrite => current_version + 1,
+                ImportMode::SkipOnConflict if file_version != Some(current_version) => {
+                    let message = match file_version {
+                        Some(v) => format!("The file has version {}, the record is at {}", v, current_version),
+                        None => "The file has no version to check against".to_string(),
+                    };
+                    return Ok(result(Some(id), ImportOutcome::Skipped, Some(current_version), Some(&message)));
+                }
+                ImportMode::SkipOnConflict => current_version + 1,
+                ImportMode::BumpVersion => current_version.max(file_version.unwrap_or(0)) + 1,
+            };
+            if self.is_expired(id).await? {
+                return Ok(result(
+                    Some(id),
+                    ImportOutcome::Failed,
+                    Some(current_version),
+                    Some("This record has expired and can no longer be changed"),
+                ));
+            }
+            if needs_approval(self, user, id).await {
+                // The request is filed against the version the row was checked
+                // against, so approving it later conflicts if the record moves on
+                drop(tx);
+                let request = self.request_change(id, &user.name, &values, current_version, None).await?;
+                let message = format!("Waiting for approval as change request {}", request);
+                return Ok(result(Some(id), ImportOutcome::PendingApproval, Some(current_version), Some(&message)));
+            }
+
+            let record = self.scope(&mut tx, id).await?.expect("The record is this tenant's");
+            let old_values = record_values(&mut tx, record).await?;
+            sqlx::query("UPDATE fields SET version = ?, updated_by = ?, updated_at = ? WHERE id = ?")
+                .bind(version)
+                .bind(&user.name)
+                .bind(now)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            write_values(&mut tx, record, &values).await?;
+            append_history(&mut tx, record, &FieldEvent::Updated, None, now).await?;
+            let change = WebhookPayload::updated(id, version, &user.name, old_values, values, now);
+            enqueue_webhook_deliveries(&mut *tx, &change, now).await?;
+            tx.commit().await?;
+            self.forget_cached(id).await;
+            Ok(result(Some(id), ImportOutcome::Updated, Some(version), None))
+        }
+    }
+}
+
+// Create and update records from a CSV or JSON file, such as one made by the
+// export. Each row is applied on its own; the report says what became of it.
+#[server(ImportFields)]
+pub async fn import_fields(
+    payload: String,
+    mode: ImportMode,
+    csrf_token: String,
+) -> Result<ImportReport, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Import, ResourceKind::Records).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+
+    let rows = server::parse_rows(&payload).map_err(|e| EditorError::from(ServerFnError::new(e)))?;
+    let fields: Vec<String> = db
+        .field_definitions()
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    let mut report = ImportReport { rows: Vec::new() };
+    for (index, row) in rows.into_iter().enumerate() {
+        let result = match row {
+            Ok(row) => db
+                .import_row(index + 1, row, &fields, mode, &user)
+                .await
+                .map_err(|e| EditorError::from(db_error(e)))?,
+            Err(message) => RowResult {
+                row: index + 1,
+                id: None,
+                outcome: ImportOutcome::Failed,
+                version: None,
+                message: Some(message),
+            },
+        };
+        report.rows.push(result);
+    }
+    Ok(report)
+}
+
+// Read the file picked in a file input
+#[cfg(feature = "hydrate")]
+async fn read_file(input: &web_sys::HtmlInputElement) -> Result<Option<String>, wasm_bindgen::JsValue> {
+    let Some(file) = input.files().and_then(|files| files.get(0)) else {
+        return Ok(None);
+    };
+    let text = wasm_bindgen_futures::JsFuture::from(file.text()).await?;
+    Ok(text.as_string())
+}
+
+/// Imports records from a CSV or JSON file and shows what happened to each row.
+#[component]
+pub fn ImportForm() -> impl IntoView {
+    let session = use_user_session();
+    let file_input = NodeRef::<leptos::html::Input>::new();
+    let mode = RwSignal::new(ImportMode::SkipOnConflict);
+    let importing = RwSignal::new(false);
+    let report = RwSignal::new(None::<Result<ImportReport, String>>);
+    #[cfg(feature = "hydrate")]
+    let tasks = crate::tasks::Tasks::new();
+
+    let on_import = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(input) = file_input.get_untracked() else {
+                return;
+            };
+            let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+            let mode = mode.get_untracked();
+            importing.set(true);
+            report.set(None);
+            tasks.spawn(async move {
+                let result = match read_file(&input).await {
+                    Ok(Some(payload)) => import_fields(payload, mode, csrf_token).await.map_err(|e| match e {
+                        ServerFnError::WrappedServerError(e) => e.to_string(),
+                        e => e.to_string(),
+                    }),
+                    Ok(None) => Err("Choose a file to import".to_string()),
+                    Err(e) => Err(format!("Could not read the file: {:?}", e)),
+                };
+                importing.set(false);
+                report.set(Some(result));
+            });
+        }
+    };
+
+    view! {
+        <div class="import">
+            <input type="file" accept=".csv,.json,text/csv,application/json" node_ref=file_input/>
+            <select on:change=move |ev| {
+                mode.set(match event_target_value(&ev).as_str() {
+                    "overwrite" => ImportMode::Overwrite,
+                    "bump" => ImportMode::BumpVersion,
+                    _ => ImportMode::SkipOnConflict,
+                });
+            }>
+                <option value="skip" selected=move || mode.get() == ImportMode::SkipOnConflict>"Skip conflicting rows"</option>
+                <option value="overwrite" selected=move || mode.get() == ImportMode::Overwrite>"Overwrite"</option>
+                <option value="bump" selected=move || mode.get() == ImportMode::BumpVersion>"Overwrite and bump version"</option>
+            </select>
+            <button
+                on:click=on_import
+                disabled=move || importing.get() || session.user.get().flatten().is_none()
+            >
+                {move || if importing.get() { "Importing..." } else { "Import" }}
+            </button>
+        </div>
+        {move || report.get().map(|result| match result {
+            Err(e) => view! { <div class="error-message">"Import failed: " {e}</div> }.into_any(),
+            Ok(report) => view! {
+                <div class="import-report">
+                    <p>
+                        {report.count(ImportOutcome::Created)} " created, "
+                        {report.count(ImportOutcome::Updated)} " updated, "
+                        {report.count(ImportOutcome::Skipped)} " skipped, "
+                        {report.count(ImportOutcome::PendingApproval)} " waiting for approval, "
+                        {report.count(ImportOutcome::Failed)} " failed"
+                    </p>
+                    <table>
+                        <tr><th>"Row"</th><th>"Record"</th><th>"Result"</th><th>"Version"</th><th></th></tr>
+                        {report.rows.into_iter().map(|row| {
+                            let (label, class) = match row.outcome {
+                                ImportOutcome::Created => ("Created", "created"),
+                                ImportOutcome::Updated => ("Updated", "updated"),
+                                ImportOutcome::Skipped => ("Skipped", "skipped"),
+                                ImportOutcome::PendingApproval => ("Waiting for approval", "pending"),
+                                ImportOutcome::Failed => ("Failed", "failed"),
+                            };
+                            view! {
+                                <tr class=class>
+                                    <td>{row.row}</td>
+                                    <td>{row.id.map(|id| view! { <a href=format!("/records/{}", id)>{id}</a> })}</td>
+                                    <td>{label}</td>
+                                    <td>{row.version}</td>
+                                    <td>{row.message}</td>
+                                </tr>
+                            }
+                        }).collect_view()}
+                    </table>
+                </div>
+            }.into_any(),
+        })}
+    }
+}
@@ -0,0 +1,54 @@
+use crate::store::use_store;
+use crate::timestamp::RelativeTime;
+use leptos::prelude::*;
+use leptos_router::components::A;
+
+/// Lists changes other users made while this tab was open.
+#[component]
+pub fn Inbox() -> impl IntoView {
+    let store = use_store();
+
+    view! {
+        <div class="field-editor">
+            <h1>"Inbox"</h1>
+
+            {move || {
+                let notifications = store.notifications();
+                if notifications.is_empty() {
+                    return view! { <p>"Nothing new."</p> }.into_any();
+                }
+                view! {
+                    <button class="link" on:click=move |_| store.dismiss_all()>"Dismiss all"</button>
+                    <ul class="record-list">
+                        {notifications.into_iter().map(|n| {
+                            let id = n.id;
+                            view! {
+                                <li>
+                                    <a class="record-title" href=format!("/records/{}", n.record_id)>{n.message}</a>
+                                    <span class="record-meta"><RelativeTime at=n.at/></span>
+                                    <button on:click=move |_| store.dismiss(id)>"Dismiss"</button>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }.into_any()
+            }}
+        </div>
+    }
+}
+
+/// Link to the inbox with the number of unread notifications.
+#[component]
+pub fn InboxLink() -> impl IntoView {
+    let store = use_store();
+
+    view! {
+        <A href="/inbox">
+            "Inbox"
+            {move || {
+                let count = store.notification_count();
+                (count > 0).then(|| view! { <span class="badge">{count}</span> })
+            }}
+        </A>
+    }
+}
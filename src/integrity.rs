@@ -0,0 +1,182 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Every write stores a SHA-256 checksum of the record's id, version and values,
+// on the record and on its audit log entry. Recomputing them later catches
+// values that changed without going through the editor: disk corruption, or
+// someone editing the database by hand.
+
+// Where a checksum didn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumSource {
+    // The record as it is now
+    Record,
+    // An entry in the audit log, by its id
+    History { entry_id: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChecksumMismatch {
+    pub record_id: i64,
+    pub version: i64,
+    pub source: ChecksumSource,
+    pub stored: String,
+    pub computed: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    // Records and audit log entries whose checksum was recomputed
+    pub checked: i64,
+    // Rows written before checksums were kept; they get one on their next change
+    pub without_checksum: i64,
+    pub mismatches: Vec<ChecksumMismatch>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub use server::record_checksum;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ChecksumMismatch, ChecksumSource, IntegrityReport};
+    use crate::db::{DbManager, FieldValues, FIELD_VALUES_COLUMN};
+    use crate::tenant::TenantId;
+    use sha2::{Digest, Sha256};
+
+    // Rows checked per query
+    const BATCH_SIZE: i64 = 500;
+
+    // Checksum of a record at one version, over its values in field name order.
+    // Every part is length-prefixed so moving text from one field into the next
+    // changes the checksum.
+    pub fn record_checksum(id: i64, version: i64, values: &FieldValues) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(id.to_le_bytes());
+        hasher.update(version.to_le_bytes());
+        for (_, value) in values.iter() {
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value.as_bytes());
+        }
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    impl IntegrityReport {
+        fn check(&mut self, record_id: i64, version: i64, source: ChecksumSource, stored: Option<String>, values: &FieldValues) {
+            let Some(stored) = stored else {
+                self.without_checksum += 1;
+                return;
+            };
+            self.checked += 1;
+            let computed = record_checksum(record_id, version, values);
+            if stored != computed {
+                tracing::warn!(record_id, version, ?source, "Checksum mismatch");
+                self.mismatches.push(ChecksumMismatch {
+                    record_id,
+                    version,
+                    source,
+                    stored,
+                    computed,
+                });
+            }
+        }
+    }
+
+    type RecordRow = (i64, i64, Option<String>, FieldValues);
+    type HistoryRow = (i64, i64, i64, Option<String>, String);
+
+    impl DbManager {
+        // The tenants with records or audit log entries, for checking all of them
+        pub async fn tenants_with_records(&self) -> Result<Vec<TenantId>, sqlx::Error> {
+            let tenants: Vec<String> = sqlx::query_scalar(
+                "SELECT tenant_id FROM fields UNION SELECT tenant_id FROM fields_history ORDER BY 1",
+            )
+            .fetch_all(self.pool())
+            .await?;
+            Ok(tenants.iter().filter_map(|tenant| TenantId::parse(tenant)).collect())
+        }
+
+        // Recompute the checksum of every record of this manager's tenant, in the
+        // trash or not, and of every audit log entry, and report the ones that
+        // don't match what was stored
+        #[tracing::instrument(skip_all)]
+        pub async fn verify_integrity(&self) -> Result<IntegrityReport, sqlx::Error> {
+            let mut report = IntegrityReport::default();
+
+            let mut after = 0;
+            loop {
+                let rows: Vec<RecordRow> = sqlx::query_as(&format!(
+                    "SELECT id, version, checksum, {} FROM fields WHERE tenant_id = ? AND id > ? ORDER BY id LIMIT ?",
+                    FIELD_VALUES_COLUMN
+                ))
+                .bind(self.tenant().as_str())
+                .bind(after)
+                .bind(BATCH_SIZE)
+                .fetch_all(self.pool())
+                .await?;
+                let Some(last) = rows.last() else { break };
+                after = last.0;
+                for (id, version, checksum, values) in rows {
+                    report.check(id, version, ChecksumSource::Record, checksum, &values);
+                }
+            }
+
+            let mut after = 0;
+            loop {
+                let rows: Vec<HistoryRow> = sqlx::query_as(
+                    r#"
+                    SELECT id, record_id, version, checksum, field_values
+                    FROM fields_history WHERE tenant_id = ? AND id > ? ORDER BY id LIMIT ?
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(after)
+                .bind(BATCH_SIZE)
+                .fetch_all(self.pool())
+                .await?;
+                let Some(last) = rows.last() else { break };
+                after = last.0;
+                for (entry_id, record_id, version, checksum, field_values) in rows {
+                    let source = ChecksumSource::History { entry_id };
+                    match FieldValues::from_stored(&field_values) {
+                        Ok(values) => report.check(record_id, version, source, checksum, &values),
+                        // Values that don't even parse or decrypt can't match any checksum
+                        Err(_) => report.mismatches.push(ChecksumMismatch {
+                            record_id,
+                            version,
+                            source,
+                            stored: checksum.unwrap_or_default(),
+                            computed: String::new(),
+                        }),
+                    }
+                }
+            }
+
+            tracing::info!(
+                checked = report.checked,
+                without_checksum = report.without_checksum,
+                mismatches = report.mismatches.len(),
+                "Integrity check finished"
+            );
+            Ok(report)
+        }
+    }
+}
+
+// Check every record and audit log entry of the tenant against its stored
+// checksum. Admins only.
+#[server(VerifyIntegrity)]
+pub async fn verify_integrity() -> Result<IntegrityReport, ServerFnError> {
+    use crate::auth::require_admin;
+    use crate::field_editor::{db_error, open_db};
+
+    let db = open_db().await?;
+    require_admin(&db).await?;
+    db.verify_integrity().await.map_err(db_error)
+}
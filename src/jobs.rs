@@ -0,0 +1,332 @@
+use crate::blobs::BlobStore;
+use crate::db::DbManager;
+use crate::lease::{LeaseManager, BACKGROUND_LEASE};
+use crate::notify::{notify_changes, Notifiers};
+use crate::orphans::collect_orphaned_attachments;
+use crate::retention::RetentionPolicy;
+use crate::webhooks::deliver_due_webhooks;
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Deliveries attempted per run of the webhook job
+const WEBHOOK_BATCH_SIZE: i64 = 50;
+// Audit log entries looked at per run of the notify job
+const NOTIFY_BATCH_SIZE: i64 = 100;
+
+// A periodic task that must only run on one instance at a time
+pub trait BackgroundJob: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn interval(&self) -> Duration;
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>>;
+}
+
+// Shared flag telling the rest of the server whether this instance currently
+// owns the background lease (and therefore is the one broadcasting change events)
+#[derive(Clone, Default)]
+pub struct LeaderStatus(Arc<AtomicBool>);
+
+impl LeaderStatus {
+    pub fn is_leader(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn set(&self, leader: bool) {
+        self.0.store(leader, Ordering::Release);
+    }
+}
+
+// Runs registered jobs on whichever instance holds the background lease.
+// Every instance runs the loop; the lease decides which one does the work,
+// and a standby instance takes over once the leader's lease expires.
+pub struct BackgroundRunner {
+    db: DbManager,
+    lease: LeaseManager,
+    jobs: Vec<Box<dyn BackgroundJob>>,
+    status: LeaderStatus,
+}
+
+impl BackgroundRunner {
+    pub fn new(db: DbManager, lease: LeaseManager) -> Self {
+        BackgroundRunner {
+            db,
+            lease,
+            jobs: Vec::new(),
+            status: LeaderStatus::default(),
+        }
+    }
+
+    pub fn with_job(mut self, job: impl BackgroundJob + 'static) -> Self {
+        self.jobs.push(Box::new(job));
+        self
+    }
+
+    pub fn status(&self) -> LeaderStatus {
+        self.status.clone()
+    }
+
+    // Start the lease/renew loop on the current runtime
+    pub fn spawn(self) -> LeaderStatus {
+        let status = self.status.clone();
+        actix_web::rt::spawn(self.run());
+        status
+    }
+
+    async fn run(self) {
+        // Renew well before expiry so a slow tick doesn't lose the lease
+        let mut ticker = actix_web::rt::time::interval(self.lease.ttl() / 3);
+        let mut last_run: Vec<Option<Instant>> = vec![None; self.jobs.len()];
+
+        loop {
+            ticker.tick().await;
+
+            let leader = match self.lease.try_acquire(BACKGROUND_LEASE).await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to renew background lease");
+                    false
+                }
+            };
+            if leader != self.status.is_leader() {
+                tracing::info!(
+                    holder = self.lease.holder(),
+                    "{} the background lease",
+                    if leader { "Acquired" } else { "Lost" }
+                );
+            }
+            self.status.set(leader);
+            if !leader {
+                // Forget schedules so a new term starts all jobs fresh
+                last_run.iter_mut().for_each(|t| *t = None);
+                continue;
+            }
+
+            for (job, last) in self.jobs.iter().zip(last_run.iter_mut()) {
+                if last.is_some_and(|t| t.elapsed() < job.interval()) {
+                    continue;
+                }
+                *last = Some(Instant::now());
+                if let Err(e) = job.run(&self.db).await {
+                    tracing::error!(job = job.name(), error = %e, "Background job failed");
+                }
+            }
+        }
+    }
+}
+
+// Deletes idempotency keys once no client could still be retrying with them
+pub struct PruneProcessedRequests {
+    pub max_age: Duration,
+}
+
+impl BackgroundJob for PruneProcessedRequests {
+    fn name(&self) -> &'static str {
+        "prune-processed-requests"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            db.prune_processed_requests(self.max_age.as_millis() as i64)
+                .await
+                .map(|_| ())
+        })
+    }
+}
+
+// Sends queued webhook deliveries and retries failed ones once their backoff is over
+pub struct DeliverWebhooks {
+    pub client: reqwest::Client,
+}
+
+impl BackgroundJob for DeliverWebhooks {
+    fn name(&self) -> &'static str {
+        "deliver-webhooks"
+    }
+
+    // Every tick of the runner, so changes go out within a few seconds
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            deliver_due_webhooks(db, &self.client, WEBHOOK_BATCH_SIZE)
+                .await
+                .map(|_| ())
+        })
+    }
+}
+
+// Deletes attachments no record version has used for longer than `retention`;
+// with `dry_run` it only logs what it would delete
+pub struct CollectOrphanedAttachments {
+    pub store: Arc<dyn BlobStore>,
+    pub retention: Duration,
+    pub dry_run: bool,
+}
+
+impl BackgroundJob for CollectOrphanedAttachments {
+    fn name(&self) -> &'static str {
+        "collect-orphaned-attachments"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let report = collect_orphaned_attachments(db, &*self.store, self.retention, self.dry_run).await?;
+            for orphan in &report.orphans {
+                tracing::info!(
+                    attachment = %orphan.id,
+                    record_id = orphan.record_id,
+                    filename = %orphan.filename,
+                    size = orphan.size,
+                    dry_run = self.dry_run,
+                    "Orphaned attachment"
+                );
+            }
+            if !report.orphans.is_empty() {
+                tracing::info!(
+                    found = report.orphans.len(),
+                    removed = report.removed,
+                    bytes = report.bytes(),
+                    blob_failures = report.blob_failures,
+                    dry_run = self.dry_run,
+                    "Collected orphaned attachments"
+                );
+            }
+            Ok(())
+        })
+    }
+}
+
+// Moves records past their expiry date to the trash, under the archive expiry action
+pub struct ArchiveExpiredRecords;
+
+impl BackgroundJob for ArchiveExpiredRecords {
+    fn name(&self) -> &'static str {
+        "archive-expired-records"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let archived = db.archive_expired().await?;
+            if archived > 0 {
+                tracing::info!(archived, "Archived expired records");
+            }
+            Ok(())
+        })
+    }
+}
+
+// Reminds the users who have changed a record when it isn't updated by its
+// review date, again every `interval` until it is
+pub struct RemindDueReviews {
+    pub interval: Duration,
+}
+
+impl BackgroundJob for RemindDueReviews {
+    fn name(&self) -> &'static str {
+        "remind-due-reviews"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let reminded = db.remind_due_reviews(self.interval).await?;
+            if reminded > 0 {
+                tracing::info!(reminded, "Sent review reminders");
+            }
+            Ok(())
+        })
+    }
+}
+
+// Saves scheduled changes once their publish time has come
+pub struct PublishScheduledChanges;
+
+impl BackgroundJob for PublishScheduledChanges {
+    fn name(&self) -> &'static str {
+        "publish-scheduled-changes"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let published = db.publish_due_changes().await?;
+            if published > 0 {
+                tracing::info!(published, "Published scheduled changes");
+            }
+            Ok(())
+        })
+    }
+}
+
+// Tells the configured notifiers about changes to the fields they watch
+pub struct NotifyChanges {
+    pub notifiers: Notifiers,
+}
+
+impl BackgroundJob for NotifyChanges {
+    fn name(&self) -> &'static str {
+        "notify-changes"
+    }
+
+    // Every tick of the runner, like webhooks
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            notify_changes(db, &self.notifiers, NOTIFY_BATCH_SIZE)
+                .await
+                .map(|_| ())
+        })
+    }
+}
+
+// Deletes audit log entries past the retention window or beyond the number of
+// versions kept per record
+pub struct PruneHistory {
+    pub policy: RetentionPolicy,
+}
+
+impl BackgroundJob for PruneHistory {
+    fn name(&self) -> &'static str {
+        "prune-history"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run<'a>(&'a self, db: &'a DbManager) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let report = db.prune_history(&self.policy).await?;
+            if report.total() > 0 {
+                tracing::info!(by_versions = report.by_versions, by_age = report.by_age, "Pruned audit log");
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,515 @@
+use crate::db::DbManager;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::future::BoxFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::OnceLock;
+
+// Where the keys for encrypting field values at rest come from. Every key has
+// an id that is stored next to what it encrypted. Rotating means making a new
+// key current while keeping the old ones around: values written under an old
+// key still decrypt, and are re-encrypted with the current key when they
+// change or when `DbManager::rotate_keys` runs. Search can't look into
+// encrypted values, so fields listed in FIELD_EDITOR_SEARCHABLE_FIELDS are
+// left in plain text on purpose: only they are found by full-text search and
+// the record filter. Reports only see the encrypted text of the others.
+
+// Length of a data key in bytes (AES-256)
+pub const KEY_LEN: usize = 32;
+
+// A data key and the id it is stored under
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    pub id: String,
+    pub material: [u8; KEY_LEN],
+}
+
+// Never print key material, not even into debug logs
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    // Nothing is known about a key with this id; values written under it can't be read
+    UnknownKey(String),
+    // The keys are configured wrongly
+    Config(String),
+    // The key service couldn't be reached or refused
+    Provider(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::UnknownKey(id) => write!(f, "Unknown encryption key {}", id),
+            KeyError::Config(message) => write!(f, "Encryption keys misconfigured: {}", message),
+            KeyError::Provider(message) => write!(f, "Key provider failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+pub trait KeyProvider: Send + Sync {
+    // The id of the key new values are encrypted with
+    fn current_key_id(&self) -> &str;
+
+    // A key by id, current or retired, e.g. to decrypt a value written before a rotation
+    fn key<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<DataKey, KeyError>>;
+
+    fn current_key(&self) -> BoxFuture<'_, Result<DataKey, KeyError>> {
+        self.key(self.current_key_id())
+    }
+
+    // Whether a value encrypted under `key_id` should be re-encrypted when next saved
+    fn is_retired(&self, key_id: &str) -> bool {
+        key_id != self.current_key_id()
+    }
+
+    // Every key this provider knows, current key first
+    fn key_ids(&self) -> Vec<String> {
+        vec![self.current_key_id().to_string()]
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Split `id:hex,id:hex,...` into ids and bytes, keeping the order
+fn parse_key_list(list: &str) -> Result<Vec<(String, Vec<u8>)>, KeyError> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (id, hex) = entry
+                .split_once(':')
+                .ok_or_else(|| KeyError::Config("expected id:hex entries".to_string()))?;
+            let bytes = parse_hex(hex.trim()).ok_or_else(|| KeyError::Config(format!("key {} is not hex", id)))?;
+            Ok((id.trim().to_string(), bytes))
+        })
+        .collect()
+}
+
+// Keys given directly, e.g. from the environment. The first key is current;
+// the rest are retired keys kept for decrypting older values.
+pub struct StaticKeyProvider {
+    current: String,
+    keys: HashMap<String, DataKey>,
+}
+
+impl StaticKeyProvider {
+    pub fn new(keys: Vec<DataKey>) -> Result<Self, KeyError> {
+        let current = keys
+            .first()
+            .map(|key| key.id.clone())
+            .ok_or_else(|| KeyError::Config("no keys given".to_string()))?;
+        Ok(StaticKeyProvider {
+            current,
+            keys: keys.into_iter().map(|key| (key.id.clone(), key)).collect(),
+        })
+    }
+
+    // Parse `id:hex,id:hex,...` with 64 hex digits per key, current key first
+    pub fn parse(list: &str) -> Result<Self, KeyError> {
+        let keys = parse_key_list(list)?
+            .into_iter()
+            .map(|(id, bytes)| {
+                let material = bytes
+                    .try_into()
+                    .map_err(|_| KeyError::Config(format!("key {} must be {} bytes", id, KEY_LEN)))?;
+                Ok(DataKey { id, material })
+            })
+            .collect::<Result<Vec<_>, KeyError>>()?;
+        Self::new(keys)
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn key<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<DataKey, KeyError>> {
+        let key = self.keys.get(id).cloned().ok_or_else(|| KeyError::UnknownKey(id.to_string()));
+        Box::pin(async move { key })
+    }
+
+    fn key_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.keys.keys().filter(|id| **id != self.current).cloned().collect();
+        ids.sort();
+        ids.insert(0, self.current.clone());
+        ids
+    }
+}
+
+#[cfg(feature = "kms")]
+pub use kms::KmsKeyProvider;
+
+#[cfg(feature = "kms")]
+mod kms {
+    use super::{parse_key_list, DataKey, KeyError, KeyProvider, KEY_LEN};
+    use aws_sdk_kms::primitives::Blob;
+    use futures::future::BoxFuture;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // Envelope encryption: data keys are stored encrypted by a KMS key and
+    // decrypted through KMS the first time they're needed, then kept in memory.
+    // The key material itself never has to sit in the configuration.
+    pub struct KmsKeyProvider {
+        client: aws_sdk_kms::Client,
+        current: String,
+        // Data keys as encrypted by KMS, by id
+        wrapped: HashMap<String, Vec<u8>>,
+        unwrapped: Mutex<HashMap<String, DataKey>>,
+    }
+
+    impl KmsKeyProvider {
+        // `wrapped` is `id:hex,id:hex,...` of KMS-encrypted data keys, current key first
+        pub async fn new(wrapped: &str) -> Result<Self, KeyError> {
+            let keys = parse_key_list(wrapped)?;
+            let current = keys
+                .first()
+                .map(|(id, _)| id.clone())
+                .ok_or_else(|| KeyError::Config("no keys given".to_string()))?;
+            let config = aws_config::load_from_env().await;
+            Ok(KmsKeyProvider {
+                client: aws_sdk_kms::Client::new(&config),
+                current,
+                wrapped: keys.into_iter().collect(),
+                unwrapped: Mutex::new(HashMap::new()),
+            })
+        }
+
+        // Have KMS make a new data key under `kms_key_id`; returns it encrypted, as hex.
+        // Put it first in the configured list to make it current.
+        pub async fn generate_wrapped_key(&self, kms_key_id: &str) -> Result<String, KeyError> {
+            let output = self
+                .client
+                .generate_data_key_without_plaintext()
+                .key_id(kms_key_id)
+                .number_of_bytes(KEY_LEN as i32)
+                .send()
+                .await
+                .map_err(|e| KeyError::Provider(e.to_string()))?;
+            let blob = output
+                .ciphertext_blob()
+                .ok_or_else(|| KeyError::Provider("KMS returned no key".to_string()))?;
+            Ok(blob.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+        }
+
+        async fn unwrap_key(&self, id: &str) -> Result<DataKey, KeyError> {
+            let wrapped = self.wrapped.get(id).ok_or_else(|| KeyError::UnknownKey(id.to_string()))?;
+            let output = self
+                .client
+                .decrypt()
+                .ciphertext_blob(Blob::new(wrapped.clone()))
+                .send()
+                .await
+                .map_err(|e| KeyError::Provider(e.to_string()))?;
+            let material = output
+                .plaintext()
+                .and_then(|plaintext| plaintext.as_ref().try_into().ok())
+                .ok_or_else(|| KeyError::Provider(format!("KMS returned no {}-byte key for {}", KEY_LEN, id)))?;
+            Ok(DataKey {
+                id: id.to_string(),
+                material,
+            })
+        }
+    }
+
+    impl KeyProvider for KmsKeyProvider {
+        fn current_key_id(&self) -> &str {
+            &self.current
+        }
+
+        fn key<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<DataKey, KeyError>> {
+            Box::pin(async move {
+                if let Some(key) = self.unwrapped.lock().unwrap().get(id) {
+                    return Ok(key.clone());
+                }
+                let key = self.unwrap_key(id).await?;
+                tracing::info!(key_id = id, "Unwrapped data key through KMS");
+                self.unwrapped.lock().unwrap().insert(id.to_string(), key.clone());
+                Ok(key)
+            })
+        }
+
+        fn key_ids(&self) -> Vec<String> {
+            let mut ids: Vec<String> = self.wrapped.keys().filter(|id| **id != self.current).cloned().collect();
+            ids.sort();
+            ids.insert(0, self.current.clone());
+            ids
+        }
+    }
+}
+
+// The provider configured through the environment, if any:
+// FIELD_EDITOR_KMS_KEYS holds KMS-encrypted data keys (with the `kms` feature),
+// FIELD_EDITOR_ENCRYPTION_KEYS plain ones; both as `id:hex,...`, current key first
+pub async fn key_provider_from_env() -> Result<Option<std::sync::Arc<dyn KeyProvider>>, KeyError> {
+    #[cfg(feature = "kms")]
+    if let Ok(wrapped) = std::env::var("FIELD_EDITOR_KMS_KEYS") {
+        return Ok(Some(std::sync::Arc::new(KmsKeyProvider::new(&wrapped).await?)));
+    }
+    match std::env::var("FIELD_EDITOR_ENCRYPTION_KEYS") {
+        Ok(keys) => Ok(Some(std::sync::Arc::new(StaticKeyProvider::parse(&keys)?))),
+        Err(_) => Ok(None),
+    }
+}
+
+// Stored values encrypted with a data key read `enc:v1:<key id>:<base64 of nonce and ciphertext>`
+const SEALED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+// The id of the key a stored value was encrypted with; None for a value stored in plain text
+pub fn sealed_with(stored: &str) -> Option<&str> {
+    stored.strip_prefix(SEALED_PREFIX)?.split_once(':').map(|(id, _)| id)
+}
+
+// FIELD_EDITOR_SEARCHABLE_FIELDS: the comma-separated names of the fields whose
+// values are stored in plain text even with encryption on; none by default
+pub fn searchable_fields() -> &'static [String] {
+    static FIELDS: OnceLock<Vec<String>> = OnceLock::new();
+    FIELDS.get_or_init(|| {
+        std::env::var("FIELD_EDITOR_SEARCHABLE_FIELDS")
+            .map(|v| v.split(',').map(str::trim).filter(|n| !n.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    })
+}
+
+fn is_searchable(field: &str) -> bool {
+    searchable_fields().iter().any(|name| name == field)
+}
+
+// All keys of a provider, fetched once so values can be encrypted and
+// decrypted without waiting on the provider, e.g. while decoding a row
+pub struct Keyring {
+    current: String,
+    ciphers: HashMap<String, Aes256Gcm>,
+}
+
+impl Keyring {
+    pub async fn load(provider: &dyn KeyProvider) -> Result<Self, KeyError> {
+        let mut ciphers = HashMap::new();
+        for id in provider.key_ids() {
+            let key = provider.key(&id).await?;
+            ciphers.insert(id, Aes256Gcm::new(&key.material.into()));
+        }
+        Ok(Keyring {
+            current: provider.current_key_id().to_string(),
+            ciphers,
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    // Encrypt a value with the current key. Empty values stay empty, so a
+    // blank field reads as blank without a key.
+    pub fn seal(&self, value: &str) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+        let cipher = &self.ciphers[&self.current];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(&nonce, value.as_bytes()).expect("AES-GCM encrypts any length we store"));
+        format!("{}{}:{}", SEALED_PREFIX, self.current, BASE64.encode(sealed))
+    }
+
+    // A value of `field` as it is stored: encrypted, unless the field is searchable
+    pub fn seal_field(&self, field: &str, value: &str) -> String {
+        match is_searchable(field) {
+            true => value.to_string(),
+            false => self.seal(value),
+        }
+    }
+
+    // The plain text of a stored value, whether it was encrypted or not
+    pub fn open(&self, stored: &str) -> Result<String, KeyError> {
+        let Some(id) = sealed_with(stored) else {
+            return Ok(stored.to_string());
+        };
+        let cipher = self.ciphers.get(id).ok_or_else(|| KeyError::UnknownKey(id.to_string()))?;
+        let unreadable = || KeyError::Provider(format!("value encrypted with {} can't be decrypted", id));
+        let encoded = &stored[SEALED_PREFIX.len() + id.len() + 1..];
+        let sealed = BASE64.decode(encoded).map_err(|_| unreadable())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(unreadable());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plain = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| unreadable())?;
+        String::from_utf8(plain).map_err(|_| unreadable())
+    }
+
+    // Whether a stored value isn't encrypted with the current key yet
+    pub fn needs_rotation(&self, stored: &str) -> bool {
+        !stored.is_empty() && sealed_with(stored) != Some(self.current.as_str())
+    }
+}
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+// Encrypt values at rest with these keys from now on; set once at startup
+pub fn install_keyring(keyring: Keyring) {
+    if KEYRING.set(keyring).is_err() {
+        tracing::warn!("Encryption keys were already installed");
+    }
+}
+
+// The installed keys; None when values are stored in plain text
+pub fn keyring() -> Option<&'static Keyring> {
+    KEYRING.get()
+}
+
+// The plain text of a stored value. Encrypted values can't be read without the keys.
+pub fn reveal(stored: &str) -> Result<String, KeyError> {
+    match keyring() {
+        Some(keyring) => keyring.open(stored),
+        None if sealed_with(stored).is_some() => {
+            Err(KeyError::Config("values are encrypted but no keys are configured".to_string()))
+        }
+        None => Ok(stored.to_string()),
+    }
+}
+
+// What `rotate_keys` re-encrypted
+#[derive(Debug, Default)]
+pub struct Rotation {
+    pub records: u64,
+    pub values: u64,
+    pub history_entries: u64,
+}
+
+// Records looked up at a time while rotating
+const ROTATION_BATCH: i64 = 500;
+
+// Re-encrypt a stored value of `field` with the current key, if it isn't
+// already, or decrypt it if the field has become searchable. Equal values get
+// the same new text, so audit log entries still tell which fields changed.
+fn rotate(
+    keyring: &Keyring,
+    rotated: &mut HashMap<String, String>,
+    field: &str,
+    stored: &str,
+) -> Result<Option<String>, KeyError> {
+    if is_searchable(field) {
+        return match sealed_with(stored) {
+            Some(_) => keyring.open(stored).map(Some),
+            None => Ok(None),
+        };
+    }
+    if !keyring.needs_rotation(stored) {
+        return Ok(None);
+    }
+    if let Some(sealed) = rotated.get(stored) {
+        return Ok(Some(sealed.clone()));
+    }
+    let sealed = keyring.seal(&keyring.open(stored)?);
+    rotated.insert(stored.to_string(), sealed.clone());
+    Ok(Some(sealed))
+}
+
+impl DbManager {
+    // Re-encrypt every stored value that isn't encrypted with the current key:
+    // values under retired keys, and plain text from before encryption was
+    // turned on. Values of searchable fields are decrypted instead. Covers the records and their audit log, one record per
+    // transaction, so saves can go on meanwhile. Every tenant is rotated.
+    pub async fn rotate_keys(&self, keyring: &Keyring) -> Result<Rotation, sqlx::Error> {
+        let key_error = |e: KeyError| sqlx::Error::Decode(e.into());
+        let mut rotation = Rotation::default();
+        let mut after = 0;
+        loop {
+            let records: Vec<i64> = sqlx::query_scalar(
+                r#"
+                SELECT id FROM (SELECT id FROM fields UNION SELECT record_id FROM fields_history)
+                WHERE id > ? ORDER BY id LIMIT ?
+                "#,
+            )
+            .bind(after)
+            .bind(ROTATION_BATCH)
+            .fetch_all(self.pool())
+            .await?;
+            let Some(&last) = records.last() else { break };
+            after = last;
+            for id in records {
+                let mut rotated = HashMap::new();
+                let mut changed = false;
+                let mut tx = self.pool().begin().await?;
+
+                let values: Vec<(String, String)> =
+                    sqlx::query_as("SELECT field_name, value FROM field_values WHERE record_id = ?")
+                        .bind(id)
+                        .fetch_all(&mut *tx)
+                        .await?;
+                for (name, stored) in values {
+                    let Some(sealed) = rotate(keyring, &mut rotated, &name, &stored).map_err(key_error)? else {
+                        continue;
+                    };
+                    sqlx::query("UPDATE field_values SET value = ? WHERE record_id = ? AND field_name = ?")
+                        .bind(sealed)
+                        .bind(id)
+                        .bind(name)
+                        .execute(&mut *tx)
+                        .await?;
+                    rotation.values += 1;
+                    changed = true;
+                }
+
+                let entries: Vec<(i64, String)> =
+                    sqlx::query_as("SELECT id, field_values FROM fields_history WHERE record_id = ? ORDER BY id")
+                        .bind(id)
+                        .fetch_all(&mut *tx)
+                        .await?;
+                for (entry_id, json) in entries {
+                    let mut values: BTreeMap<String, String> =
+                        serde_json::from_str(&json).map_err(|e| sqlx::Error::Decode(e.into()))?;
+                    let mut entry_changed = false;
+                    for (name, stored) in values.iter_mut() {
+                        if let Some(sealed) = rotate(keyring, &mut rotated, name, stored).map_err(key_error)? {
+                            *stored = sealed;
+                            entry_changed = true;
+                        }
+                    }
+                    if entry_changed {
+                        sqlx::query("UPDATE fields_history SET field_values = ? WHERE id = ?")
+                            .bind(serde_json::to_string(&values).expect("values serialize"))
+                            .bind(entry_id)
+                            .execute(&mut *tx)
+                            .await?;
+                        rotation.history_entries += 1;
+                        changed = true;
+                    }
+                }
+
+                tx.commit().await?;
+                if changed {
+                    rotation.records += 1;
+                }
+            }
+        }
+        tracing::info!(
+            current_key = keyring.current_key_id(),
+            records = rotation.records,
+            values = rotation.values,
+            history_entries = rotation.history_entries,
+            "Rotated encryption keys"
+        );
+        Ok(rotation)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::db::DbManager;
+use std::time::Duration;
+
+// Name of the lease that grants ownership of background jobs and change broadcasts
+pub const BACKGROUND_LEASE: &str = "background-runner";
+
+// Shortest lease allowed. Holders renew at a third of the TTL, so anything
+// shorter would have them renewing constantly, and a zero TTL can't be renewed
+// on a timer at all.
+pub const MIN_LEASE_TTL: Duration = Duration::from_secs(3);
+
+// Time-limited ownership of a named role, shared by all instances through the database.
+// Exactly one instance holds a lease at any time; if the holder stops renewing it,
+// another instance takes over once the lease expires.
+#[derive(Clone)]
+pub struct LeaseManager {
+    db: DbManager,
+    holder: String,
+    ttl: Duration,
+}
+
+impl LeaseManager {
+    // A TTL below MIN_LEASE_TTL is raised to it
+    pub fn new(db: DbManager, holder: impl Into<String>, ttl: Duration) -> Self {
+        LeaseManager {
+            db,
+            holder: holder.into(),
+            ttl: ttl.max(MIN_LEASE_TTL),
+        }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // Acquire the lease, or renew it if we already hold it.
+    // Returns false if another instance holds an unexpired lease.
+    pub async fn try_acquire(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let now = self.db.now();
+        let expires_at = now + self.ttl.as_millis() as i64;
+
+        // The conditional upsert only takes over the row if we are the holder
+        // or the current lease has expired, so two instances can't both win
+        let result = sqlx::query(
+            r#"
+            INSERT INTO leases (name, holder, expires_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(name) DO UPDATE
+            SET holder = excluded.holder, expires_at = excluded.expires_at
+            WHERE leases.holder = excluded.holder OR leases.expires_at < ?
+            "#,
+        )
+        .bind(name)
+        .bind(&self.holder)
+        .bind(expires_at)
+        .bind(now)
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Give up the lease so another instance can take over without waiting for expiry
+    pub async fn release(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM leases WHERE name = ? AND holder = ?")
+            .bind(name)
+            .bind(&self.holder)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+
+    // The current holder of a lease, if it hasn't expired
+    pub async fn current_holder(&self, name: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT holder FROM leases WHERE name = ? AND expires_at >= ?")
+            .bind(name)
+            .bind(self.db.now())
+            .fetch_optional(self.db.pool())
+            .await
+    }
+}
+
+// Identify this server instance, preferring an explicitly configured id
+pub fn instance_id() -> String {
+    if let Ok(id) = std::env::var("FIELD_EDITOR_INSTANCE_ID") {
+        return id;
+    }
+    let mut suffix = [0u8; 4];
+    getrandom::getrandom(&mut suffix).expect("Failed to generate instance id");
+    format!("instance-{}-{:08x}", std::process::id(), u32::from_be_bytes(suffix))
+}
@@ -1,6 +1,98 @@
+pub mod api_tokens;
 pub mod app;
+pub mod attachments;
+#[cfg(feature = "ssr")]
+pub mod audit_bundle;
+pub mod auth;
+#[cfg(feature = "ssr")]
+pub mod backup;
+#[cfg(feature = "ssr")]
+pub mod blobs;
+pub mod change_requests;
+pub mod changefeed;
+pub mod comments;
+pub mod conflict;
+#[cfg(feature = "d1")]
+pub mod d1;
+pub mod dashboard;
+pub mod dataset;
 pub mod db;
+pub mod delta;
+pub mod demo;
+pub mod errors;
+pub mod events;
+pub mod expiry;
+pub mod export;
 pub mod field_editor;
+pub mod field_schema;
+#[cfg(feature = "ssr")]
+pub mod graphql;
+#[cfg(feature = "ssr")]
+pub mod health;
+pub mod history;
+pub mod impersonation;
+pub mod import;
+pub mod inbox;
+pub mod integrity;
+#[cfg(feature = "ssr")]
+pub mod jobs;
+#[cfg(feature = "ssr")]
+pub mod keys;
+#[cfg(feature = "ssr")]
+pub mod lease;
+#[cfg(feature = "ssr")]
+pub mod libsql;
+#[cfg(feature = "ssr")]
+pub mod merge;
+#[cfg(feature = "ssr")]
+pub mod notify;
+#[cfg(feature = "ssr")]
+pub mod orphans;
+pub mod persistence;
+#[cfg(feature = "ssr")]
+pub mod policy;
+pub mod presence;
+#[cfg(feature = "ssr")]
+pub mod print;
+pub mod protocol;
+#[cfg(feature = "ssr")]
+pub mod providers;
+#[cfg(feature = "ssr")]
+pub mod rate_limit;
+#[cfg(feature = "ssr")]
+pub mod record_cache;
+pub mod record_list;
+pub mod records;
+pub mod reports;
+#[cfg(feature = "ssr")]
+pub mod rest;
+#[cfg(feature = "ssr")]
+pub mod routes;
+#[cfg(feature = "ssr")]
+pub mod retention;
+#[cfg(feature = "ssr")]
+pub mod retry;
+pub mod review;
+#[cfg(feature = "ssr")]
+pub mod scan;
+pub mod scenarios;
+pub mod scheduled;
+pub mod search;
+pub mod storage;
+pub mod store;
+pub mod tasks;
+#[cfg(feature = "ssr")]
+pub mod tenant;
+#[cfg(feature = "ssr")]
+pub mod thumbnails;
+pub mod timestamp;
+pub mod toast;
+pub mod trash;
+#[cfg(feature = "ssr")]
+pub mod webhooks;
+
+#[cfg(feature = "ssr")]
+pub use routes::{routes, FieldEditorRoutes, FieldEditorState};
 
 #[cfg(feature = "hydrate")]
 #[wasm_bindgen::prelude::wasm_bindgen]
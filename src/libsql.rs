@@ -0,0 +1,279 @@
+use crate::db::FieldValues;
+use crate::storage::{
+    is_version_conflict, load_statement, now_millis, save_if_version_statements, RecordStorage, SqlArg,
+    StorageError, StoredRecord,
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// Records in a remote libSQL database such as Turso, spoken to over its HTTP
+// protocol (Hrana), for hosts without a local filesystem. Each HTTP request
+// runs on a connection of its own, so there is no transaction to hold open
+// between reading the version and writing the new one. A save is therefore
+// one batch that checks the version, writes and commits on the server, with
+// each step running only if the one before it succeeded. Values are written
+// as given: encryption at rest needs the keyring, which lives with `DbManager`.
+
+// Where the database is: FIELD_EDITOR_LIBSQL_URL (https://<db>.turso.io; a
+// libsql:// URL is taken to mean the same host over HTTPS) and
+// FIELD_EDITOR_LIBSQL_AUTH_TOKEN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibSqlConfig {
+    pub url: String,
+    pub auth_token: Option<String>,
+}
+
+impl LibSqlConfig {
+    // None unless FIELD_EDITOR_LIBSQL_URL is set
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("FIELD_EDITOR_LIBSQL_URL").ok().filter(|url| !url.is_empty())?;
+        let url = match url.strip_prefix("libsql://") {
+            Some(host) => format!("https://{}", host),
+            None => url,
+        };
+        Some(LibSqlConfig {
+            url: url.trim_end_matches('/').to_string(),
+            auth_token: std::env::var("FIELD_EDITOR_LIBSQL_AUTH_TOKEN").ok().filter(|t| !t.is_empty()),
+        })
+    }
+}
+
+// A value as Hrana sends it; integers go as strings so they survive JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Value {
+    Null,
+    Integer { value: String },
+    Float { value: f64 },
+    Text { value: String },
+    Blob { base64: String },
+}
+
+impl Value {
+    fn integer(value: i64) -> Self {
+        Value::Integer { value: value.to_string() }
+    }
+
+    fn text(value: impl Into<String>) -> Self {
+        Value::Text { value: value.into() }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer { value } => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text { value } => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statement {
+    pub sql: String,
+    pub args: Vec<Value>,
+}
+
+impl Statement {
+    pub fn new(sql: impl Into<String>, args: Vec<Value>) -> Self {
+        Statement { sql: sql.into(), args }
+    }
+}
+
+// What a statement returned
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct QueryResult {
+    #[serde(default)]
+    pub rows: Vec<Vec<Value>>,
+    #[serde(default)]
+    pub affected_row_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct HranaError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PipelineResponse {
+    results: Vec<StreamResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StreamResult {
+    Ok { response: StreamResponse },
+    Error { error: HranaError },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StreamResponse {
+    Execute { result: QueryResult },
+    Batch { result: BatchResult },
+    Close,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResult {
+    step_results: Vec<Option<QueryResult>>,
+    step_errors: Vec<Option<HranaError>>,
+}
+
+// Talks to one libSQL database over HTTP
+pub struct LibSqlStorage {
+    config: LibSqlConfig,
+    client: reqwest::Client,
+}
+
+impl LibSqlStorage {
+    pub fn new(config: LibSqlConfig) -> Self {
+        LibSqlStorage {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // Send requests down a fresh stream, closing it after them
+    async fn pipeline(&self, requests: Vec<serde_json::Value>) -> Result<Vec<StreamResponse>, StorageError> {
+        let mut requests = requests;
+        requests.push(json!({ "type": "close" }));
+        let mut request = self
+            .client
+            .post(format!("{}/v2/pipeline", self.config.url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json!({ "baton": null, "requests": requests }).to_string());
+        if let Some(token) = &self.config.auth_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| StorageError::Remote(e.to_string()))?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| StorageError::Remote(e.to_string()))?;
+        if !status.is_success() {
+            return Err(StorageError::Remote(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        let response: PipelineResponse =
+            serde_json::from_slice(&body).map_err(|e| StorageError::Remote(e.to_string()))?;
+        response
+            .results
+            .into_iter()
+            .map(|result| match result {
+                StreamResult::Ok { response } => Ok(response),
+                StreamResult::Error { error } => Err(StorageError::Remote(error.message)),
+            })
+            .collect()
+    }
+
+    pub async fn execute(&self, statement: Statement) -> Result<QueryResult, StorageError> {
+        match self.pipeline(vec![json!({ "type": "execute", "stmt": statement })]).await?.into_iter().next() {
+            Some(StreamResponse::Execute { result }) => Ok(result),
+            _ => Err(StorageError::Remote("Unexpected response to execute".to_string())),
+        }
+    }
+
+    // Run statements in a transaction on the server: each runs only if the one
+    // before succeeded, and the transaction is rolled back if any failed.
+    // Returns each statement's result.
+    pub async fn transaction(&self, statements: Vec<Statement>) -> Result<Vec<QueryResult>, StorageError> {
+        let count = statements.len();
+        let mut steps = vec![json!({ "stmt": Statement::new("BEGIN", Vec::new()) })];
+        for (i, statement) in statements.into_iter().enumerate() {
+            steps.push(json!({ "stmt": statement, "condition": { "type": "ok", "step": i } }));
+        }
+        steps.push(json!({ "stmt": Statement::new("COMMIT", Vec::new()), "condition": { "type": "ok", "step": count } }));
+        steps.push(json!({
+            "stmt": Statement::new("ROLLBACK", Vec::new()),
+            "condition": { "type": "not", "cond": { "type": "ok", "step": count + 1 } },
+        }));
+
+        let result = match self.pipeline(vec![json!({ "type": "batch", "batch": { "steps": steps } })]).await?.into_iter().next() {
+            Some(StreamResponse::Batch { result }) => result,
+            _ => return Err(StorageError::Remote("Unexpected response to batch".to_string())),
+        };
+        if let Some(error) = result.step_errors.into_iter().flatten().next() {
+            return Err(StorageError::Remote(error.message));
+        }
+        // A step that didn't run has neither a result nor an error
+        let mut results = result.step_results.into_iter().skip(1);
+        (0..count)
+            .map(|_| results.next().flatten().ok_or_else(|| StorageError::Remote("Transaction step skipped".to_string())))
+            .collect()
+    }
+}
+
+impl From<SqlArg> for Value {
+    fn from(arg: SqlArg) -> Self {
+        match arg {
+            SqlArg::Integer(value) => Value::integer(value),
+            SqlArg::Text(value) => Value::text(value),
+        }
+    }
+}
+
+impl From<(String, Vec<SqlArg>)> for Statement {
+    fn from((sql, args): (String, Vec<SqlArg>)) -> Self {
+        Statement::new(sql, args.into_iter().map(Value::from).collect())
+    }
+}
+
+impl RecordStorage for LibSqlStorage {
+    fn name(&self) -> &'static str {
+        "libsql"
+    }
+
+    fn load(&self, tenant: &str, id: i64) -> BoxFuture<'_, Result<Option<StoredRecord>, StorageError>> {
+        let statement = Statement::from(load_statement(tenant, id));
+        Box::pin(async move {
+            let result = self.execute(statement).await?;
+            let Some(row) = result.rows.first() else {
+                return Ok(None);
+            };
+            let version = row.first().and_then(Value::as_i64);
+            let values = row.get(1).and_then(Value::as_str).map(FieldValues::from_stored);
+            match (version, values) {
+                (Some(version), Some(Ok(values))) => Ok(Some(StoredRecord { id, version, values })),
+                _ => Err(StorageError::Remote(format!("Record {} came back malformed", id))),
+            }
+        })
+    }
+
+    fn save_if_version<'a>(
+        &'a self,
+        tenant: &'a str,
+        id: i64,
+        user: &'a str,
+        values: &'a FieldValues,
+        expected_version: i64,
+    ) -> BoxFuture<'a, Result<bool, StorageError>> {
+        let statements = save_if_version_statements(tenant, id, user, values, expected_version, now_millis())
+            .into_iter()
+            .map(Statement::from)
+            .collect();
+        Box::pin(async move {
+            match self.transaction(statements).await {
+                Ok(_) => Ok(true),
+                // Someone else saved first
+                Err(StorageError::Remote(message)) if is_version_conflict(&message) => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+// The libSQL database configured in the environment, if any
+pub fn libsql_from_env() -> Option<LibSqlStorage> {
+    LibSqlConfig::from_env().map(LibSqlStorage::new)
+}
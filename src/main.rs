@@ -1,80 +1,431 @@
 #[cfg(feature = "ssr")]
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    use actix_files::Files;
     use actix_web::*;
-    use field_editor::app::*;
-    use field_editor::db::DbManager;
+    use field_editor::api_tokens::api_token_scopes;
+    use field_editor::audit_bundle::{read_bundle, write_bundle, BundleSigner};
+    use field_editor::backup;
+    use field_editor::changefeed::ChangeFeed;
+    use field_editor::db::{get_pool, ConcurrencyMode, ConflictPolicy, DbManager, DB_PATH};
+    use field_editor::expiry::{expiry_policy, ExpiryAction};
+    use field_editor::impersonation::impersonation_policy;
+    use field_editor::jobs::{
+        ArchiveExpiredRecords, BackgroundRunner, CollectOrphanedAttachments, DeliverWebhooks, NotifyChanges,
+        PruneHistory, PruneProcessedRequests, PublishScheduledChanges, RemindDueReviews,
+    };
+    use field_editor::keys::{install_keyring, keyring, Keyring};
+    use field_editor::lease::{instance_id, LeaseManager};
+    use field_editor::notify::notifiers_from_env;
+    use field_editor::orphans::collect_orphaned_attachments;
+    use field_editor::policy::policy;
+    use field_editor::protocol::{BUILD_ID, BUILD_ID_HEADER};
+    use field_editor::rate_limit::RateLimiter;
+    use field_editor::reports::report_catalog;
+    use field_editor::rest::bodiless_not_modified;
+    use field_editor::retention::retention_policy;
+    use field_editor::retry::RetryPolicy;
+    use field_editor::review::review_policy;
+    use field_editor::tenant::TenantId;
+    use field_editor::timestamp::parse_rfc3339;
+    use field_editor::FieldEditorState;
     use leptos::config::get_configuration;
-    use leptos::prelude::*;
-    use leptos_actix::{generate_route_list, LeptosRoutes};
-    use leptos_meta::MetaTags;
+
+    // Log to stdout; RUST_LOG overrides the default level, e.g. RUST_LOG=field_editor=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        // Span close events carry how long each database operation took
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .init();
 
     let conf = get_configuration(Some("Cargo.toml")).unwrap();
     let addr = conf.leptos_options.site_addr;
 
-    // Create the database path in a location that's writable
-    // Use a file-based database that can be shared between connections
-    let db_path = "/tmp/fields.db";
-    let mut db = DbManager::new(&format!("sqlite:{}", db_path));
-    // Initialize the database synchronously before creating the server
-    futures::executor::block_on(db.initialize()).expect("Failed to initialize database");
+    // A file-based database in a writable location, shared between connections
+    let db_path = DB_PATH;
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    println!("Database initialized at {}", db_path);
+    // `field-editor restore <full backup> [incremental backup...]` replaces the
+    // database with a full backup and replays the incremental ones on top, in
+    // the order given. Stop the server first.
+    if args.first().map(String::as_str) == Some("restore") {
+        let full = args.get(1).expect("Usage: field-editor restore <full backup> [incremental backup...]");
+        backup::restore_full(full, db_path).expect("Failed to restore the full backup");
+        let db = DbManager::connect(&format!("sqlite:{}", db_path))
+            .await
+            .expect("Failed to initialize database");
+        for path in &args[2..] {
+            let incremental = backup::read_incremental(path).expect("Failed to read incremental backup");
+            if let Err(e) = db.apply_incremental(&incremental).await {
+                panic!("Failed to replay {}: {}", path, e);
+            }
+        }
+        tracing::info!(path = db_path, "Database restored");
+        return Ok(());
+    }
 
-    println!("listening on http://{}", &addr);
+    // Connect to the database before creating the server; server functions share this pool
+    let pool = get_pool().await.expect("Failed to initialize database");
+    let mut db = DbManager::from_pool(pool)
+        .with_retry_policy(RetryPolicy::from_env())
+        .with_concurrency_mode(ConcurrencyMode::from_env())
+        .with_conflict_policy(ConflictPolicy::from_env());
 
-    HttpServer::new(move || {
-        // Generate the list of routes in your Leptos App
-        let routes = generate_route_list(App);
-        let leptos_options = &conf.leptos_options;
-        let site_root = leptos_options.site_root.clone().to_string();
+    tracing::info!(
+        path = db_path,
+        concurrency = ?db.concurrency_mode(),
+        conflicts = ?db.conflict_policy(),
+        tenants = ?field_editor::tenant::tenant_source(),
+        expiry = ?expiry_policy().action,
+        reports = report_catalog().reports().count(),
+        policy = policy().name(),
+        history_retention = ?retention_policy(),
+        impersonation_limit = ?impersonation_policy().time_limit,
+        "Database initialized"
+    );
+
+    // Keys for encrypting values at rest, if configured
+    let keys = field_editor::keys::key_provider_from_env()
+        .await
+        .expect("Failed to set up encryption keys");
+    if let Some(keys) = keys {
+        tracing::info!(current_key = keys.current_key_id(), "Encryption keys configured");
+        let keyring = Keyring::load(&*keys).await.expect("Failed to load encryption keys");
+        install_keyring(keyring);
+        db = db.with_key_provider(keys);
+    }
+
+    // Records read through Redis or memory, if configured
+    if let Some(cache) = field_editor::record_cache::record_cache_from_env() {
+        tracing::info!(cache = cache.name(), "Record cache configured");
+        db = db.with_record_cache(cache);
+    }
+
+    // `field-editor rotate-keys` re-encrypts all stored values that aren't
+    // encrypted with the current key yet, including values stored before
+    // encryption was turned on, then exits
+    if args.first().map(String::as_str) == Some("rotate-keys") {
+        let keyring = keyring().expect("rotate-keys needs FIELD_EDITOR_ENCRYPTION_KEYS or FIELD_EDITOR_KMS_KEYS");
+        db.rotate_keys(keyring).await.expect("Failed to rotate encryption keys");
+        return Ok(());
+    }
+
+    // `field-editor generate-data <count> [--seed <n>]` fills the database with
+    // fake records for load tests and demos, then exits instead of serving
+    if args.first().map(String::as_str) == Some("generate-data") {
+        let count: u32 = args
+            .get(1)
+            .and_then(|s| s.parse().ok())
+            .expect("Usage: field-editor generate-data <count> [--seed <n>]");
+        let seed = match args.iter().position(|arg| arg == "--seed") {
+            Some(i) => args.get(i + 1).and_then(|s| s.parse().ok()).expect("--seed takes a number"),
+            None => uuid::Uuid::new_v4().as_u64_pair().0,
+        };
+        let generated = db
+            .generate_records(count, seed)
+            .await
+            .expect("Failed to generate records");
+        tracing::info!(
+            count = generated.count,
+            seed = generated.seed,
+            first_id = generated.first_id,
+            last_id = generated.last_id,
+            "Generated records"
+        );
+        return Ok(());
+    }
+    // `field-editor add-user <name> [--tenant <id>]` creates an account with
+    // the password read from the first line of stdin. Signing in never
+    // creates accounts, so this is how users are set up.
+    if args.first().map(String::as_str) == Some("add-user") {
+        let name = args.get(1).expect("Usage: field-editor add-user <name> [--tenant <id>] < password");
+        let tenant = match args.iter().position(|arg| arg == "--tenant") {
+            Some(i) => args.get(i + 1).and_then(|s| TenantId::parse(s)).expect("--tenant takes a tenant id"),
+            None => TenantId::default(),
+        };
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password).expect("Failed to read the password");
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            panic!("The password must not be empty");
+        }
+        let created = db
+            .clone()
+            .with_tenant(tenant)
+            .create_user(name, password)
+            .await
+            .expect("Failed to create the user");
+        if !created {
+            tracing::error!(name = %name, "There already is a user by that name");
+            std::process::exit(1);
+        }
+        tracing::info!(name = %name, "User created");
+        return Ok(());
+    }
+    // `field-editor backup full <file>` copies the whole database; `field-editor
+    // backup incremental <file>` saves the audit log entries since the last backup
+    if args.first().map(String::as_str) == Some("backup") {
+        let usage = "Usage: field-editor backup full|incremental <file>";
+        let path = args.get(2).expect(usage);
+        match args.get(1).map(String::as_str) {
+            Some("full") => {
+                db.backup_full(path).await.expect("Failed to write full backup");
+            }
+            Some("incremental") => {
+                let incremental = db.backup_incremental().await.expect("Failed to take incremental backup");
+                backup::write_incremental(path, &incremental).expect("Failed to write incremental backup");
+            }
+            _ => panic!("{}", usage),
+        }
+        return Ok(());
+    }
+    // `field-editor verify-integrity` checks all stored checksums of every
+    // tenant and exits with status 1 if any record or audit log entry doesn't
+    // match its own
+    if args.first().map(String::as_str) == Some("verify-integrity") {
+        let tenants = db.tenants_with_records().await.expect("Failed to list tenants");
+        let mut ok = true;
+        for tenant in tenants {
+            let report = db
+                .clone()
+                .with_tenant(tenant.clone())
+                .verify_integrity()
+                .await
+                .expect("Failed to verify integrity");
+            for mismatch in &report.mismatches {
+                tracing::error!(
+                    tenant = %tenant.as_str(),
+                    record_id = mismatch.record_id,
+                    version = mismatch.version,
+                    source = ?mismatch.source,
+                    "Checksum mismatch"
+                );
+            }
+            ok &= report.is_ok();
+        }
+        if !ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // `field-editor generate-audit-key <file>` writes a new key for signing
+    // audit bundles and logs its public key, to hand to auditors
+    if args.first().map(String::as_str) == Some("generate-audit-key") {
+        let path = args.get(1).expect("Usage: field-editor generate-audit-key <file>");
+        let public_key = BundleSigner::generate(path).expect("Failed to generate an audit signing key");
+        tracing::info!(path, public_key = %public_key, "Audit signing key generated");
+        return Ok(());
+    }
+    // `field-editor export-audit-bundle <from> <to> <file> [--tenant <id>]`
+    // writes the audit log entries from one day to another, both given as
+    // YYYY-MM-DD and both included, with the records' resulting state, signed
+    // with the key in FIELD_EDITOR_AUDIT_SIGNING_KEY
+    if args.first().map(String::as_str) == Some("export-audit-bundle") {
+        let usage = "Usage: field-editor export-audit-bundle <from YYYY-MM-DD> <to YYYY-MM-DD> <file> [--tenant <id>]";
+        let day = |i: usize| {
+            args.get(i)
+                .and_then(|date| parse_rfc3339(&format!("{}T00:00:00Z", date)))
+                .expect(usage)
+        };
+        let (from, to) = (day(1), day(2) + 24 * 60 * 60 * 1000);
+        let path = args.get(3).expect(usage);
+        let tenant = match args.iter().position(|arg| arg == "--tenant") {
+            Some(i) => args.get(i + 1).and_then(|s| TenantId::parse(s)).expect("--tenant takes a tenant id"),
+            None => TenantId::default(),
+        };
+        let signer = BundleSigner::from_env().expect("Failed to load the audit signing key");
+        let contents = db
+            .clone()
+            .with_tenant(tenant)
+            .audit_bundle(from, to)
+            .await
+            .expect("Failed to collect the audit log");
+        let bundle = signer.sign(&contents).expect("Failed to sign the audit bundle");
+        write_bundle(path, &bundle).expect("Failed to write the audit bundle");
+        tracing::info!(
+            entries = contents.manifest.entries,
+            records = contents.manifest.records,
+            public_key = %bundle.public_key,
+            "Audit bundle written"
+        );
+        return Ok(());
+    }
+    // `field-editor verify-audit-bundle <file> [--public-key <base64>]` checks
+    // a bundle's signature, against the given key if any, and exits with
+    // status 1 if it doesn't match
+    if args.first().map(String::as_str) == Some("verify-audit-bundle") {
+        let path = args
+            .get(1)
+            .expect("Usage: field-editor verify-audit-bundle <file> [--public-key <base64>]");
+        let public_key = args
+            .iter()
+            .position(|arg| arg == "--public-key")
+            .map(|i| args.get(i + 1).expect("--public-key takes a base64 key").as_str());
+        match read_bundle(path).and_then(|bundle| bundle.verify(public_key)) {
+            Ok(contents) => tracing::info!(
+                tenant = %contents.manifest.tenant,
+                from = contents.manifest.from,
+                to = contents.manifest.to,
+                entries = contents.entries.len(),
+                records = contents.snapshots.len(),
+                key_checked = public_key.is_some(),
+                "Audit bundle verified"
+            ),
+            Err(e) => {
+                tracing::error!(error = %e, "Audit bundle failed verification");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Where attachment contents go, per FIELD_EDITOR_BLOB_STORE
+    let blobs = field_editor::blobs::blob_store_from_env()
+        .await
+        .expect("Failed to set up the attachment store");
+    tracing::info!(store = blobs.name(), "Attachment store configured");
+    let attachment_retention = field_editor::orphans::retention_from_env();
+
+    // `field-editor collect-attachments [--dry-run]` deletes attachments no
+    // record version has used within FIELD_EDITOR_ATTACHMENT_RETENTION_DAYS, or
+    // with --dry-run only lists them
+    if args.first().map(String::as_str) == Some("collect-attachments") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        let report = collect_orphaned_attachments(&db, &*blobs, attachment_retention, dry_run)
+            .await
+            .expect("Failed to collect orphaned attachments");
+        for orphan in &report.orphans {
+            tracing::info!(
+                attachment = %orphan.id,
+                record_id = orphan.record_id,
+                record_exists = orphan.record_exists,
+                filename = %orphan.filename,
+                size = orphan.size,
+                unlinked_at = orphan.unlinked_at,
+                "Orphaned attachment"
+            );
+        }
+        tracing::info!(
+            found = report.orphans.len(),
+            removed = report.removed,
+            bytes = report.bytes(),
+            blob_failures = report.blob_failures,
+            dry_run,
+            "Collected orphaned attachments"
+        );
+        return Ok(());
+    }
+
+    // Changes are POSTed to these URLs, given comma-separated
+    let webhook_urls: Vec<String> = std::env::var("FIELD_EDITOR_WEBHOOKS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    db.configure_webhooks(&webhook_urls)
+        .await
+        .expect("Failed to configure webhooks");
 
+    // Who hears about changes to watched fields, per FIELD_EDITOR_NOTIFY
+    let notifiers = notifiers_from_env().expect("Failed to configure change notifiers");
+
+    // Only the instance holding the background lease runs jobs; the others stand by
+    let lease_ttl = std::env::var("FIELD_EDITOR_LEASE_TTL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(15);
+    let lease = LeaseManager::new(
+        db.clone(),
+        instance_id(),
+        std::time::Duration::from_secs(lease_ttl),
+    );
+    tracing::info!(instance = lease.holder(), "Instance started");
+    let mut runner = BackgroundRunner::new(db.clone(), lease)
+        .with_job(PruneProcessedRequests {
+            max_age: std::time::Duration::from_secs(24 * 60 * 60),
+        })
+        .with_job(DeliverWebhooks {
+            client: reqwest::Client::new(),
+        })
+        .with_job(CollectOrphanedAttachments {
+            store: blobs.clone(),
+            retention: attachment_retention,
+            dry_run: std::env::var("FIELD_EDITOR_ATTACHMENT_GC_DRY_RUN").is_ok_and(|v| v == "1" || v == "true"),
+        })
+        .with_job(RemindDueReviews {
+            interval: review_policy().reminder_interval,
+        })
+        .with_job(PublishScheduledChanges);
+    if expiry_policy().action == ExpiryAction::Archive {
+        runner = runner.with_job(ArchiveExpiredRecords);
+    }
+    if !notifiers.is_empty() {
+        tracing::info!(notifiers = ?notifiers.names(), "Change notifiers configured");
+        runner = runner.with_job(NotifyChanges { notifiers });
+    }
+    if retention_policy().prunes() {
+        runner = runner.with_job(PruneHistory {
+            policy: *retention_policy(),
+        });
+    }
+    let _leader = runner.spawn();
+
+    // Every instance tails the change log so its own SSE clients see all changes
+    let feed = ChangeFeed::spawn(db.clone());
+
+    // Mutations allow a short burst, then a steady trickle per session or client address
+    let rate_burst = std::env::var("FIELD_EDITOR_RATE_BURST")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10);
+    let rate_per_sec = std::env::var("FIELD_EDITOR_RATE_PER_SEC")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(2.0);
+    let limiter = RateLimiter::new(rate_burst, rate_per_sec);
+
+    let scanner = field_editor::scan::virus_scanner_from_env();
+    match &scanner {
+        Some(scanner) => tracing::info!(scanner = scanner.name(), "Uploads are scanned for viruses"),
+        None => tracing::warn!("No virus scanner configured; uploads are not scanned"),
+    }
+
+    tracing::info!("listening on http://{}", &addr);
+
+    let routes = field_editor::routes(FieldEditorState::new(
+        db.clone(),
+        feed,
+        limiter,
+        blobs,
+        scanner,
+        conf.leptos_options,
+    ));
+    HttpServer::new(move || {
         App::new()
-            // serve JS/WASM/CSS from `pkg`
-            .service(Files::new("/pkg", format!("{site_root}/pkg")))
-            // serve other assets from the `assets` directory
-            .service(Files::new("/assets", &site_root))
-            // serve the favicon from /favicon.ico
-            .service(favicon)
-            .leptos_routes(routes, {
-                let leptos_options = leptos_options.clone();
-                move || {
-                    view! {
-                        <!DOCTYPE html>
-                        <html lang="en">
-                            <head>
-                                <meta charset="utf-8"/>
-                                <meta name="viewport" content="width=device-width, initial-scale=1"/>
-                                <AutoReload options=leptos_options.clone() />
-                                <HydrationScripts options=leptos_options.clone()/>
-                                <MetaTags/>
-                            </head>
-                            <body>
-                                <App/>
-                            </body>
-                        </html>
-                    }
-                }
-            })
-            .app_data(web::Data::new(leptos_options.to_owned()))
+            .configure(routes.all())
+            // Holds requests with an API token to the token's scopes
+            .wrap(middleware::from_fn(api_token_scopes))
+            // Keeps 304s from server functions from carrying a body
+            .wrap(middleware::from_fn(bodiless_not_modified))
+            // Lets clients and proxies tell which build answered
+            .wrap(middleware::DefaultHeaders::new().add((BUILD_ID_HEADER, BUILD_ID)))
+            // One span per request, with a request id, around everything logged while handling it
+            .wrap(tracing_actix_web::TracingLogger::default())
         //.wrap(middleware::Compress::default())
     })
     .bind(&addr)?
     .run()
-    .await
-}
+    .await?;
 
-#[cfg(feature = "ssr")]
-#[actix_web::get("favicon.ico")]
-async fn favicon(
-    leptos_options: actix_web::web::Data<leptos::config::LeptosOptions>,
-) -> actix_web::Result<actix_files::NamedFile> {
-    let leptos_options = leptos_options.into_inner();
-    let site_root = &leptos_options.site_root;
-    Ok(actix_files::NamedFile::open(format!(
-        "{site_root}/favicon.ico"
-    ))?)
+    // The server has stopped taking requests and finished the ones in flight
+    // (or given up on them), so nothing needs the database any more
+    db.close().await;
+    tracing::info!("Database closed");
+    Ok(())
 }
 
 #[cfg(not(any(feature = "ssr", feature = "csr")))]
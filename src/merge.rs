@@ -0,0 +1,153 @@
+use crate::db::{apply_update, values_at_version, FieldValues, Fields, FIELDS_COLUMNS};
+use crate::events::FieldEvent;
+use crate::tenant::ScopedRecord;
+use sqlx::SqliteConnection;
+
+// What became of a save that may be merged with changes saved meanwhile
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeResult {
+    Saved,
+    // Saved on top of someone else's changes; the record is now at `version`
+    Merged { version: i64 },
+    // Saved in place of someone else's changes; the record is now at `version`
+    Overwritten { version: i64 },
+    // Rejected. `fields` are the ones both sides changed, empty when there was
+    // nothing to merge with (record gone, or its base version unknown).
+    Conflict { fields: Vec<String> },
+}
+
+impl MergeResult {
+    pub fn is_saved(&self) -> bool {
+        !matches!(self, MergeResult::Conflict { .. })
+    }
+}
+
+// Combine my edits and theirs, both made on top of `base`, field by field:
+// a field only one side changed takes that side's value. A field left out of
+// `mine` is one I didn't change. Fails with the fields both sides changed to
+// different values.
+pub fn three_way_merge(base: &FieldValues, mine: &FieldValues, theirs: &FieldValues) -> Result<FieldValues, Vec<String>> {
+    let mut conflicts = Vec::new();
+    let mut merged = theirs.clone();
+    for (name, mine) in mine.iter() {
+        let (base, theirs) = (base.get(name), theirs.get(name));
+        if mine == base || mine == theirs {
+            continue;
+        } else if theirs == base {
+            merged.set(name, mine);
+        } else {
+            conflicts.push(name.to_string());
+            merged.set(name, mine);
+        }
+    }
+    match conflicts.is_empty() {
+        true => Ok(merged),
+        false => Err(conflicts),
+    }
+}
+
+// Try to save `mine`, made on top of `base_version`, over the record's current
+// version by merging with everything saved since. The base values come from
+// the audit log. Called inside the caller's transaction; writes nothing unless
+// the merge succeeds.
+pub(crate) async fn merge_into_current(
+    conn: &mut SqliteConnection,
+    record: ScopedRecord,
+    user: &str,
+    mine: &FieldValues,
+    base_version: i64,
+    now: i64,
+) -> Result<MergeResult, sqlx::Error> {
+    let no_merge = MergeResult::Conflict { fields: Vec::new() };
+    let current = sqlx::query_as::<_, Fields>(&format!(
+        "SELECT {} FROM fields WHERE id = ? AND deleted_at IS NULL",
+        FIELDS_COLUMNS
+    ))
+    .bind(record.id())
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(current) = current else {
+        return Ok(no_merge);
+    };
+    let Some(base) = values_at_version(&mut *conn, record.id(), base_version).await? else {
+        return Ok(no_merge);
+    };
+
+    let theirs = current.values;
+    let merged = match three_way_merge(&base, mine, &theirs) {
+        Ok(merged) => merged,
+        Err(fields) => return Ok(MergeResult::Conflict { fields }),
+    };
+    // Everything I changed, they changed the same way: nothing left to save
+    if merged == theirs {
+        return Ok(MergeResult::Merged {
+            version: current.version,
+        });
+    }
+    let event = FieldEvent::Merged { base_version };
+    apply_update(conn, record, user, &merged, current.version, &event, now).await?;
+    Ok(MergeResult::Merged {
+        version: current.version + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> FieldValues {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn takes_each_side_where_only_it_changed() {
+        let base = values(&[("title", "a"), ("owner", "x")]);
+        let mine = values(&[("title", "b"), ("owner", "x")]);
+        let theirs = values(&[("title", "a"), ("owner", "y")]);
+        assert_eq!(
+            three_way_merge(&base, &mine, &theirs),
+            Ok(values(&[("title", "b"), ("owner", "y")]))
+        );
+    }
+
+    #[test]
+    fn fields_left_out_of_mine_keep_their_value() {
+        let base = values(&[("title", "a"), ("owner", "x")]);
+        let mine = values(&[("title", "b")]);
+        let theirs = values(&[("title", "a"), ("owner", "y")]);
+        assert_eq!(
+            three_way_merge(&base, &mine, &theirs),
+            Ok(values(&[("title", "b"), ("owner", "y")]))
+        );
+    }
+
+    #[test]
+    fn the_same_change_on_both_sides_is_no_conflict() {
+        let base = values(&[("title", "a")]);
+        let both = values(&[("title", "b")]);
+        assert_eq!(three_way_merge(&base, &both, &both), Ok(both.clone()));
+    }
+
+    #[test]
+    fn different_changes_to_a_field_conflict() {
+        let base = values(&[("title", "a"), ("owner", "x"), ("notes", "n")]);
+        let mine = values(&[("title", "b"), ("owner", "z"), ("notes", "m")]);
+        let theirs = values(&[("title", "c"), ("owner", "y"), ("notes", "n")]);
+        let mut conflicts = three_way_merge(&base, &mine, &theirs).unwrap_err();
+        conflicts.sort();
+        assert_eq!(conflicts, ["owner", "title"]);
+    }
+
+    #[test]
+    fn a_field_new_on_both_sides_conflicts_unless_equal() {
+        let base = values(&[]);
+        assert_eq!(
+            three_way_merge(&base, &values(&[("tag", "x")]), &values(&[("tag", "y")])),
+            Err(vec!["tag".to_string()])
+        );
+        assert_eq!(
+            three_way_merge(&base, &values(&[("tag", "x")]), &values(&[("tag", "x")])),
+            Ok(values(&[("tag", "x")]))
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use crate::db::{DbManager, HistoryRow, HISTORY_COLUMNS};
+use crate::history::HistoryEntry;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// Operators can be told when fields they care about change. The notify job
+// follows the audit log from where it last stopped, works out which fields
+// each saved version changed, and hands the changes to every configured
+// notifier: the log, a webhook or email. A notifier that fails is logged and
+// not retried; webhooks in `webhooks.rs` are the way to get every change
+// delivered for sure.
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One field a version changed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangedField {
+    pub name: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+// A saved version, with the fields it changed
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeNotice {
+    pub tenant: String,
+    pub record_id: i64,
+    pub version: i64,
+    // The event that made the version, such as `updated` or `merged`
+    pub kind: &'static str,
+    pub actor: Option<String>,
+    pub occurred_at: i64,
+    pub changes: Vec<ChangedField>,
+}
+
+impl ChangeNotice {
+    // A one-line description, for the log and email subjects
+    pub fn summary(&self) -> String {
+        let names: Vec<&str> = self.changes.iter().map(|c| c.name.as_str()).collect();
+        format!(
+            "Record {} version {}: {} changed by {}",
+            self.record_id,
+            self.version,
+            names.join(", "),
+            self.actor.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    // The other side answered, but not with success
+    Rejected(String),
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyError::Io(e) => write!(f, "Could not reach the notifier: {}", e),
+            NotifyError::Http(e) => write!(f, "Webhook request failed: {}", e),
+            NotifyError::Rejected(message) => write!(f, "Notification refused: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+impl From<std::io::Error> for NotifyError {
+    fn from(e: std::io::Error) -> Self {
+        NotifyError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for NotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        NotifyError::Http(e)
+    }
+}
+
+// Called with every saved version that changed a watched field
+pub trait ChangeNotifier: Send + Sync {
+    // For logs
+    fn name(&self) -> &'static str;
+
+    fn notify<'a>(&'a self, notice: &'a ChangeNotice) -> BoxFuture<'a, Result<(), NotifyError>>;
+}
+
+// Writes each change to the server log
+pub struct LogNotifier;
+
+impl ChangeNotifier for LogNotifier {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn notify<'a>(&'a self, notice: &'a ChangeNotice) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            for change in &notice.changes {
+                tracing::info!(
+                    tenant = %notice.tenant,
+                    record_id = notice.record_id,
+                    version = notice.version,
+                    actor = notice.actor.as_deref().unwrap_or(""),
+                    field = %change.name,
+                    old_value = %change.old_value,
+                    new_value = %change.new_value,
+                    "Field changed"
+                );
+            }
+            Ok(())
+        })
+    }
+}
+
+// POSTs each change as JSON to a URL
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        WebhookNotifier {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl ChangeNotifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn notify<'a>(&'a self, notice: &'a ChangeNotice) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.url)
+                .timeout(SEND_TIMEOUT)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(notice).expect("ChangeNotice serializes"))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(NotifyError::Rejected(format!("Endpoint answered {}", response.status())));
+            }
+            Ok(())
+        })
+    }
+}
+
+// Mails each change through an SMTP relay. Speaks plain SMTP without TLS or
+// authentication, so the relay should be a local MTA that takes mail from this host.
+pub struct EmailNotifier {
+    addr: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl EmailNotifier {
+    pub fn new(addr: &str, from: &str, to: Vec<String>) -> Self {
+        EmailNotifier {
+            addr: addr.to_string(),
+            from: from.to_string(),
+            to,
+        }
+    }
+
+    fn message(&self, notice: &ChangeNotice) -> String {
+        let mut body = String::new();
+        for change in &notice.changes {
+            body.push_str(&format!(
+                "{}: {:?} -> {:?}\r\n",
+                change.name, change.old_value, change.new_value
+            ));
+        }
+        // A line of just "." would end the message early
+        let body = body
+            .split("\r\n")
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            self.from,
+            self.to.join(", "),
+            notice.summary().replace(['\r', '\n'], " "),
+            body
+        )
+    }
+
+    async fn send(&self, notice: &ChangeNotice) -> Result<(), NotifyError> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (read, mut write) = stream.into_split();
+        let mut replies = BufReader::new(read);
+        expect_reply(&mut replies, 220).await?;
+        smtp_command(&mut write, &mut replies, "HELO field-editor".to_string(), 250).await?;
+        smtp_command(&mut write, &mut replies, format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for to in &self.to {
+            smtp_command(&mut write, &mut replies, format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        smtp_command(&mut write, &mut replies, "DATA".to_string(), 354).await?;
+        smtp_command(&mut write, &mut replies, format!("{}\r\n.", self.message(notice)), 250).await?;
+        smtp_command(&mut write, &mut replies, "QUIT".to_string(), 221).await?;
+        Ok(())
+    }
+}
+
+// Send one SMTP command and check the reply's code
+async fn smtp_command<W, R>(write: &mut W, replies: &mut R, line: String, code: u16) -> Result<(), NotifyError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    write.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    expect_reply(replies, code).await
+}
+
+// Read one SMTP reply, which may span several `250-...` lines, and check its code
+async fn expect_reply<R: tokio::io::AsyncBufRead + Unpin>(replies: &mut R, code: u16) -> Result<(), NotifyError> {
+    loop {
+        let mut line = String::new();
+        if replies.read_line(&mut line).await? == 0 {
+            return Err(NotifyError::Rejected("SMTP server closed the connection".to_string()));
+        }
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.get(..3).and_then(|c| c.parse::<u16>().ok()) {
+            Some(answered) if answered == code => Ok(()),
+            _ => Err(NotifyError::Rejected(format!("SMTP server answered {}", line.trim_end()))),
+        };
+    }
+}
+
+impl ChangeNotifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn notify<'a>(&'a self, notice: &'a ChangeNotice) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            match tokio::time::timeout(SEND_TIMEOUT, self.send(notice)).await {
+                Ok(sent) => sent,
+                Err(_) => Err(NotifyError::Rejected("SMTP server timed out".to_string())),
+            }
+        })
+    }
+}
+
+// The notifiers and which fields they watch, as configured at startup
+#[derive(Clone, Default)]
+pub struct Notifiers {
+    notifiers: Vec<Arc<dyn ChangeNotifier>>,
+    // Empty watches every field
+    fields: BTreeSet<String>,
+}
+
+impl Notifiers {
+    pub fn new(notifiers: Vec<Arc<dyn ChangeNotifier>>, fields: BTreeSet<String>) -> Self {
+        Notifiers { notifiers, fields }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.notifiers.iter().map(|n| n.name()).collect()
+    }
+
+    fn watches(&self, field: &str) -> bool {
+        self.fields.is_empty() || self.fields.contains(field)
+    }
+
+    // Tell every notifier about the watched fields in `notice`, if it has any
+    async fn dispatch(&self, mut notice: ChangeNotice) {
+        notice.changes.retain(|change| self.watches(&change.name));
+        if notice.changes.is_empty() {
+            return;
+        }
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.notify(&notice).await {
+                tracing::warn!(
+                    notifier = notifier.name(),
+                    record_id = notice.record_id,
+                    version = notice.version,
+                    error = %e,
+                    "Change notification failed"
+                );
+            }
+        }
+    }
+}
+
+// Notifiers from the environment:
+//   FIELD_EDITOR_NOTIFY            comma-separated channels: log, webhook, email
+//   FIELD_EDITOR_NOTIFY_FIELDS     comma-separated fields to watch; all if unset
+//   FIELD_EDITOR_NOTIFY_WEBHOOK    the URL for the webhook channel
+//   FIELD_EDITOR_NOTIFY_SMTP       the relay for the email channel, `host:port`
+//   FIELD_EDITOR_NOTIFY_EMAIL_FROM / FIELD_EDITOR_NOTIFY_EMAIL_TO  sender and
+//                                  comma-separated recipients
+pub fn notifiers_from_env() -> Result<Notifiers, String> {
+    let list = |name: &str| -> Vec<String> {
+        std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let required = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| format!("{} is required by FIELD_EDITOR_NOTIFY", name))
+    };
+    let mut notifiers: Vec<Arc<dyn ChangeNotifier>> = Vec::new();
+    for channel in list("FIELD_EDITOR_NOTIFY") {
+        match channel.as_str() {
+            "log" => notifiers.push(Arc::new(LogNotifier)),
+            "webhook" => notifiers.push(Arc::new(WebhookNotifier::new(&required("FIELD_EDITOR_NOTIFY_WEBHOOK")?))),
+            "email" => {
+                let to = list("FIELD_EDITOR_NOTIFY_EMAIL_TO");
+                if to.is_empty() {
+                    return Err("FIELD_EDITOR_NOTIFY_EMAIL_TO is required by FIELD_EDITOR_NOTIFY".to_string());
+                }
+                notifiers.push(Arc::new(EmailNotifier::new(
+                    &required("FIELD_EDITOR_NOTIFY_SMTP")?,
+                    &required("FIELD_EDITOR_NOTIFY_EMAIL_FROM")?,
+                    to,
+                )));
+            }
+            other => return Err(format!("Unknown notification channel {:?}", other)),
+        }
+    }
+    Ok(Notifiers::new(notifiers, list("FIELD_EDITOR_NOTIFY_FIELDS").into_iter().collect()))
+}
+
+// The fields that differ between two versions' values, in name order
+fn changed_fields(before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) -> Vec<ChangedField> {
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_value = before.get(name).cloned().unwrap_or_default();
+            let new_value = after.get(name).cloned().unwrap_or_default();
+            (old_value != new_value).then(|| ChangedField {
+                name: name.clone(),
+                old_value,
+                new_value,
+            })
+        })
+        .collect()
+}
+
+impl DbManager {
+    // How far into the audit log notifications have been sent. The first time,
+    // that's the current end, so configuring notifiers doesn't replay old changes.
+    async fn notified_through(&self) -> Result<i64, sqlx::Error> {
+        let start = self.latest_change_id().await?;
+        sqlx::query("INSERT INTO notifier_position (id, through_change_id) VALUES (1, ?) ON CONFLICT (id) DO NOTHING")
+            .bind(start)
+            .execute(self.pool())
+            .await?;
+        sqlx::query_scalar("SELECT through_change_id FROM notifier_position WHERE id = 1")
+            .fetch_one(self.pool())
+            .await
+    }
+
+    async fn mark_notified(&self, through: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE notifier_position SET through_change_id = ? WHERE id = 1")
+            .bind(through)
+            .execute(self.pool())
+            .await?;
+        Ok(())
+    }
+
+    // The audit log entry before `entry` for the same record: the values it changed from
+    async fn previous_entry(&self, tenant: &str, entry: &HistoryEntry) -> Result<Option<HistoryEntry>, sqlx::Error> {
+        let row = sqlx::query_as::<_, HistoryRow>(&format!(
+            "SELECT {} FROM fields_history WHERE record_id = ? AND tenant_id = ? AND id < ? ORDER BY id DESC LIMIT 1",
+            HISTORY_COLUMNS
+        ))
+        .bind(entry.event.record_id)
+        .bind(tenant)
+        .bind(entry.event.id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.map(HistoryEntry::from))
+    }
+}
+
+// Notify about up to `limit` audit log entries past the last run. Returns how many were looked at.
+pub async fn notify_changes(db: &DbManager, notifiers: &Notifiers, limit: i64) -> Result<usize, sqlx::Error> {
+    let after = db.notified_through().await?;
+    let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+        "SELECT {} FROM fields_history WHERE id > ? ORDER BY id LIMIT ?",
+        HISTORY_COLUMNS
+    ))
+    .bind(after)
+    .bind(limit)
+    .fetch_all(db.pool())
+    .await?;
+    let Some(through) = rows.last().map(|row| row.id) else {
+        return Ok(0);
+    };
+    let count = rows.len();
+    for row in rows {
+        let tenant = row.tenant_id.clone();
+        let entry = HistoryEntry::from(row);
+        if !entry.event.event.changes_state() {
+            continue;
+        }
+        // A record's first entry changed every field from blank
+        let before = db.previous_entry(&tenant, &entry).await?.map(|previous| previous.values);
+        let changes = changed_fields(&before.unwrap_or_default(), &entry.values);
+        notifiers
+            .dispatch(ChangeNotice {
+                tenant,
+                record_id: entry.event.record_id,
+                version: entry.event.version,
+                kind: entry.event.event.kind(),
+                actor: entry.event.actor,
+                occurred_at: entry.event.occurred_at,
+                changes,
+            })
+            .await;
+    }
+    db.mark_notified(through).await?;
+    Ok(count)
+}
@@ -0,0 +1,142 @@
+use crate::blobs::BlobStore;
+use crate::db::DbManager;
+use crate::thumbnails::{thumbnail_key, THUMBNAIL_SIZES};
+use std::time::Duration;
+
+// Attachments every record version has moved on from. Replacing a file keeps
+// the old one so an earlier version can be brought back with it; once it has
+// been out of use for longer than the retention period it's deleted, database
+// rows and blobs alike. Attachments of records that no longer exist at all go
+// regardless of when. Reverting to a version from before that simply comes
+// back without the file.
+
+// How long a replaced attachment is kept: FIELD_EDITOR_ATTACHMENT_RETENTION_DAYS, or 30 days
+pub fn retention_from_env() -> Duration {
+    let days: u64 = std::env::var("FIELD_EDITOR_ATTACHMENT_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(days * 24 * 60 * 60)
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrphanedAttachment {
+    pub id: String,
+    pub record_id: i64,
+    pub filename: String,
+    pub size: i64,
+    // When the last version linking to it was replaced; the upload time if
+    // none ever did
+    pub unlinked_at: i64,
+    // False if the record itself is gone
+    pub record_exists: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct OrphanReport {
+    pub orphans: Vec<OrphanedAttachment>,
+    // How many were deleted; always 0 on a dry run
+    pub removed: usize,
+    // Orphans whose rows were deleted but whose blobs could not be
+    pub blob_failures: usize,
+}
+
+impl OrphanReport {
+    pub fn bytes(&self) -> i64 {
+        self.orphans.iter().map(|orphan| orphan.size).sum()
+    }
+}
+
+impl DbManager {
+    // Attachments no current record version has that were unlinked before
+    // `unlinked_before`, plus all of those whose record is gone
+    pub async fn orphaned_attachments(&self, unlinked_before: i64) -> Result<Vec<OrphanedAttachment>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT * FROM (
+                SELECT a.id, a.record_id, a.filename, a.size,
+                    COALESCE(
+                        (SELECT MAX(h.changed_at) FROM fields_history h
+                         WHERE h.record_id = a.record_id
+                         AND h.version = (SELECT MAX(l.to_version) FROM attachment_links l WHERE l.attachment_id = a.id)),
+                        a.uploaded_at
+                    ) AS unlinked_at,
+                    EXISTS (SELECT 1 FROM fields f WHERE f.id = a.record_id) AS record_exists
+                FROM attachments a
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM attachment_links l WHERE l.attachment_id = a.id AND l.to_version IS NULL
+                )
+            )
+            WHERE NOT record_exists OR unlinked_at < ?
+            ORDER BY unlinked_at, id
+            "#,
+        )
+        .bind(unlinked_before)
+        .fetch_all(self.pool())
+        .await
+    }
+
+    // Delete an attachment's rows, unless a version has been linked to it again
+    // since it was found orphaned. Returns whether it was deleted.
+    pub async fn remove_orphaned_attachment(&self, id: &str) -> Result<bool, sqlx::Error> {
+        self.retrying("remove_orphaned_attachment", || async {
+            let mut tx = self.pool().begin().await?;
+            let deleted = sqlx::query(
+                r#"
+                DELETE FROM attachments WHERE id = ?1
+                AND NOT EXISTS (SELECT 1 FROM attachment_links WHERE attachment_id = ?1 AND to_version IS NULL)
+                "#,
+            )
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                > 0;
+            if deleted {
+                sqlx::query("DELETE FROM attachment_links WHERE attachment_id = ?")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(deleted)
+        })
+        .await
+    }
+}
+
+// Find the attachments past `retention` and, unless `dry_run`, delete them and
+// their thumbnails. The rows go first, so a file can't be taken away from a
+// version that was reverted to meanwhile; a blob that then fails to delete is
+// only wasted space, and logged.
+pub async fn collect_orphaned_attachments(
+    db: &DbManager,
+    store: &dyn BlobStore,
+    retention: Duration,
+    dry_run: bool,
+) -> Result<OrphanReport, sqlx::Error> {
+    let cutoff = db.now() - retention.as_millis() as i64;
+    let mut report = OrphanReport {
+        orphans: db.orphaned_attachments(cutoff).await?,
+        ..OrphanReport::default()
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    for orphan in &report.orphans {
+        if !db.remove_orphaned_attachment(&orphan.id).await? {
+            continue;
+        }
+        report.removed += 1;
+        let thumbnails = THUMBNAIL_SIZES.iter().map(|&size| thumbnail_key(&orphan.id, size));
+        for key in std::iter::once(orphan.id.clone()).chain(thumbnails) {
+            if let Err(e) = store.delete(&key).await {
+                tracing::warn!(error = %e, attachment = %orphan.id, key, "Could not delete orphaned blob");
+                report.blob_failures += 1;
+                break;
+            }
+        }
+    }
+    Ok(report)
+}
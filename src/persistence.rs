@@ -0,0 +1,176 @@
+use futures::future::LocalBoxFuture;
+use std::sync::Arc;
+
+// Collection holding the records the store has cached, keyed by record id
+pub const RECORDS: &str = "records";
+// Collection holding saves made while offline, keyed by record id
+pub const PENDING_SAVES: &str = "pending_saves";
+
+// Where the client-side store keeps its data between page loads. Values are
+// JSON strings grouped into named collections, so other client features can
+// keep their own data next to the store's.
+pub trait StorePersistence: Send + Sync {
+    // Every value saved in a collection
+    fn load_all(&self, collection: &'static str) -> LocalBoxFuture<'static, Vec<String>>;
+
+    fn save(&self, collection: &'static str, key: String, value: String);
+
+    fn remove(&self, collection: &'static str, key: String);
+}
+
+// Keeps nothing; the store starts empty on every page load
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoPersistence;
+
+impl StorePersistence for NoPersistence {
+    fn load_all(&self, _collection: &'static str) -> LocalBoxFuture<'static, Vec<String>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    fn save(&self, _collection: &'static str, _key: String, _value: String) {}
+
+    fn remove(&self, _collection: &'static str, _key: String) {}
+}
+
+// The persistence the app uses by default: IndexedDB with the `offline`
+// feature, nothing otherwise
+pub fn default_persistence() -> Arc<dyn StorePersistence> {
+    #[cfg(all(feature = "hydrate", feature = "offline"))]
+    {
+        Arc::new(IndexedDbPersistence)
+    }
+    #[cfg(not(all(feature = "hydrate", feature = "offline")))]
+    {
+        Arc::new(NoPersistence)
+    }
+}
+
+#[cfg(all(feature = "hydrate", feature = "offline"))]
+pub use indexed_db::IndexedDbPersistence;
+
+#[cfg(all(feature = "hydrate", feature = "offline"))]
+mod indexed_db {
+    use super::{StorePersistence, PENDING_SAVES, RECORDS};
+    use futures::channel::oneshot;
+    use futures::future::LocalBoxFuture;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_futures::spawn_local;
+    use web_sys::{IdbDatabase, IdbRequest, IdbTransactionMode};
+
+    const DATABASE: &str = "field-editor";
+    // Bump when adding a collection so browsers create its object store
+    const DATABASE_VERSION: u32 = 2;
+    const COLLECTIONS: &[&str] = &[RECORDS, PENDING_SAVES];
+
+    // Stores collections as IndexedDB object stores in the browser
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct IndexedDbPersistence;
+
+    // Wait for an IndexedDB request to finish
+    async fn complete(request: &IdbRequest) -> Result<JsValue, JsValue> {
+        let (sender, receiver) = oneshot::channel();
+        let sender = Rc::new(RefCell::new(Some(sender)));
+        let on_success = {
+            let sender = sender.clone();
+            Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+                if let Some(sender) = sender.borrow_mut().take() {
+                    let _ = sender.send(true);
+                }
+            })
+        };
+        let on_error = Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+            if let Some(sender) = sender.borrow_mut().take() {
+                let _ = sender.send(false);
+            }
+        });
+        request.set_onsuccess(Some(on_success.as_ref().unchecked_ref()));
+        request.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        let succeeded = receiver.await.unwrap_or(false);
+        request.set_onsuccess(None);
+        request.set_onerror(None);
+        if succeeded {
+            request.result()
+        } else {
+            Err(JsValue::from_str("IndexedDB request failed"))
+        }
+    }
+
+    async fn open() -> Result<IdbDatabase, JsValue> {
+        let factory = web_sys::window()
+            .ok_or_else(|| JsValue::from_str("No window"))?
+            .indexed_db()?
+            .ok_or_else(|| JsValue::from_str("IndexedDB is not available"))?;
+        let request = factory.open_with_u32(DATABASE, DATABASE_VERSION)?;
+
+        let on_upgrade = {
+            let request = request.clone();
+            Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+                if let Ok(db) = request.result().and_then(|db| db.dyn_into::<IdbDatabase>()) {
+                    for collection in COLLECTIONS {
+                        // Fails for stores that already exist, which is what we want
+                        let _ = db.create_object_store(collection);
+                    }
+                }
+            })
+        };
+        request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+        let db = complete(&request).await;
+        request.set_onupgradeneeded(None);
+        db?.dyn_into::<IdbDatabase>()
+    }
+
+    async fn load_all(collection: &'static str) -> Result<Vec<String>, JsValue> {
+        let db = open().await?;
+        let store = db
+            .transaction_with_str(collection)?
+            .object_store(collection)?;
+        let values = complete(&store.get_all()?).await?;
+        Ok(js_sys::Array::from(&values)
+            .iter()
+            .filter_map(|value| value.as_string())
+            .collect())
+    }
+
+    async fn write(collection: &'static str, key: String, value: Option<String>) -> Result<(), JsValue> {
+        let db = open().await?;
+        let store = db
+            .transaction_with_str_and_mode(collection, IdbTransactionMode::Readwrite)?
+            .object_store(collection)?;
+        let key = JsValue::from_str(&key);
+        let request = match value {
+            Some(value) => store.put_with_key(&JsValue::from_str(&value), &key)?,
+            None => store.delete(&key)?,
+        };
+        complete(&request).await.map(|_| ())
+    }
+
+    impl StorePersistence for IndexedDbPersistence {
+        fn load_all(&self, collection: &'static str) -> LocalBoxFuture<'static, Vec<String>> {
+            Box::pin(async move {
+                load_all(collection).await.unwrap_or_else(|e| {
+                    leptos::logging::warn!("Failed to read {} from IndexedDB: {:?}", collection, e);
+                    Vec::new()
+                })
+            })
+        }
+
+        fn save(&self, collection: &'static str, key: String, value: String) {
+            spawn_local(async move {
+                if let Err(e) = write(collection, key, Some(value)).await {
+                    leptos::logging::warn!("Failed to write {} to IndexedDB: {:?}", collection, e);
+                }
+            });
+        }
+
+        fn remove(&self, collection: &'static str, key: String) {
+            spawn_local(async move {
+                if let Err(e) = write(collection, key, None).await {
+                    leptos::logging::warn!("Failed to delete from {} in IndexedDB: {:?}", collection, e);
+                }
+            });
+        }
+    }
+}
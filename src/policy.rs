@@ -0,0 +1,818 @@
+use crate::auth::{admins, User};
+use crate::db::DbManager;
+use crate::tenant::TenantId;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::OnceLock;
+
+// Who may do what. Guards describe the request as an actor doing an action
+// on a resource and ask the configured `Policy`; which roles exist and what
+// they allow is up to the policy rather than to each guard.
+
+// What an actor wants to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    // Look at a record's details, such as how a conflict came about
+    View,
+    // Change a record's values, review date, expiry or presence
+    Edit,
+    // Move records to the trash and back
+    Delete,
+    // Add, rename, remove and describe fields
+    ManageFields,
+    // Create records from a file
+    Import,
+    // Check stored checksums
+    Audit,
+    // Run and export admin-defined reports
+    RunReports,
+    // Act as another user to see what they see
+    Impersonate,
+    // Apply or reject the changes others asked for, when saves need approval
+    Approve,
+}
+
+impl Action {
+    // The name policy expressions use
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::View => "view",
+            Action::Edit => "edit",
+            Action::Delete => "delete",
+            Action::ManageFields => "manage_fields",
+            Action::Import => "import",
+            Action::Audit => "audit",
+            Action::RunReports => "run_reports",
+            Action::Impersonate => "impersonate",
+            Action::Approve => "approve",
+        }
+    }
+
+    // For messages: "You aren't allowed to <verb> <resource>"
+    fn verb(self) -> &'static str {
+        match self {
+            Action::View => "view",
+            Action::Edit => "edit",
+            Action::Delete => "delete",
+            Action::ManageFields => "change",
+            Action::Import => "import into",
+            Action::Audit => "audit",
+            Action::RunReports => "run",
+            Action::Impersonate => "impersonate",
+            Action::Approve => "approve changes to",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// What an action is done to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Record(i64),
+    // All of the tenant's records, e.g. for a save across several or an import
+    Records,
+    FieldSchema,
+    Reports,
+    // The tenant's other users
+    Users,
+}
+
+impl ResourceKind {
+    // The name policy expressions use
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResourceKind::Record(_) => "record",
+            ResourceKind::Records => "records",
+            ResourceKind::FieldSchema => "field_schema",
+            ResourceKind::Reports => "reports",
+            ResourceKind::Users => "users",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub tenant: TenantId,
+    pub kind: ResourceKind,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ResourceKind::Record(id) => write!(f, "{}/record {}", self.tenant.as_str(), id),
+            kind => write!(f, "{}/{}", self.tenant.as_str(), kind.as_str()),
+        }
+    }
+}
+
+// The roles the built-in configuration hands out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    // Everything, reports included
+    Admin,
+    // Everything but reports and approving changes; every signed-in user who isn't listed otherwise
+    Editor,
+    // Only looking
+    Viewer,
+}
+
+impl Role {
+    // The name policy expressions use
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+// A signed-in user as the policy sees them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor {
+    pub name: String,
+    pub roles: Vec<Role>,
+}
+
+impl Actor {
+    // A user with the roles given by FIELD_EDITOR_ADMINS and FIELD_EDITOR_VIEWERS
+    pub fn for_user(user: &User) -> Self {
+        let role = if admins().contains(&user.name) {
+            Role::Admin
+        } else if viewers().contains(&user.name) {
+            Role::Viewer
+        } else {
+            Role::Editor
+        };
+        Actor {
+            name: user.name.clone(),
+            roles: vec![role],
+        }
+    }
+}
+
+// The users who may only look, from FIELD_EDITOR_VIEWERS (comma-separated names)
+pub fn viewers() -> &'static [String] {
+    static VIEWERS: OnceLock<Vec<String>> = OnceLock::new();
+    VIEWERS.get_or_init(|| {
+        std::env::var("FIELD_EDITOR_VIEWERS")
+            .map(|v| v.split(',').map(str::trim).filter(|n| !n.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    })
+}
+
+pub trait Policy: Send + Sync {
+    // For logs
+    fn name(&self) -> &'static str;
+
+    fn can(&self, actor: &Actor, action: Action, resource: &Resource) -> bool;
+}
+
+// The default: admins may do anything, editors anything but run reports,
+// impersonate or approve changes, viewers only look
+pub struct RolePolicy;
+
+impl Policy for RolePolicy {
+    fn name(&self) -> &'static str {
+        "roles"
+    }
+
+    fn can(&self, actor: &Actor, action: Action, _resource: &Resource) -> bool {
+        actor.roles.iter().any(|role| match role {
+            Role::Admin => true,
+            Role::Editor => !matches!(action, Action::RunReports | Action::Impersonate | Action::Approve),
+            Role::Viewer => action == Action::View,
+        })
+    }
+}
+
+// Used when the configured policy can't be read: nobody may do anything
+pub struct DenyAll;
+
+impl Policy for DenyAll {
+    fn name(&self) -> &'static str {
+        "deny-all"
+    }
+
+    fn can(&self, _actor: &Actor, _action: Action, _resource: &Resource) -> bool {
+        false
+    }
+}
+
+// Rules given as expressions, in the style of Cedar: an action is allowed if a
+// `permit` rule matches and no `forbid` rule does, and denied by default.
+// Expressions compare `actor.name`, `actor.roles`, `action`, `resource.kind`,
+// `resource.tenant` and `resource.record_id` with strings, integers, booleans
+// and lists, using `== != < <= > >= in && || !` and parentheses, e.g.
+// `action == "edit" && "editor" in actor.roles && resource.tenant != "archive"`.
+pub struct ExpressionPolicy {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    Permit,
+    Forbid,
+}
+
+struct Rule {
+    effect: Effect,
+    when: Expr,
+}
+
+// A policy file: `{"rules": [{"effect": "permit", "when": "..."}, ...]}`.
+// A rule without `when` always matches.
+#[derive(Deserialize)]
+struct PolicyFile {
+    rules: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    effect: Effect,
+    #[serde(default)]
+    when: Option<String>,
+}
+
+impl ExpressionPolicy {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: PolicyFile = serde_json::from_str(json).map_err(|e| format!("Invalid policy file: {}", e))?;
+        let rules = file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let when = match rule.when {
+                    Some(source) => parse(&source).map_err(|e| format!("Rule {}: {}", i + 1, e))?,
+                    None => Expr::Literal(Value::Bool(true)),
+                };
+                Ok(Rule {
+                    effect: rule.effect,
+                    when,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(ExpressionPolicy { rules })
+    }
+}
+
+impl Policy for ExpressionPolicy {
+    fn name(&self) -> &'static str {
+        "expressions"
+    }
+
+    fn can(&self, actor: &Actor, action: Action, resource: &Resource) -> bool {
+        let request = Request { actor, action, resource };
+        let matching = |effect| {
+            self.rules
+                .iter()
+                .filter(|rule| rule.effect == effect)
+                .any(|rule| rule.when.eval(&request) == Value::Bool(true))
+        };
+        matching(Effect::Permit) && !matching(Effect::Forbid)
+    }
+}
+
+// What an expression is evaluated against
+struct Request<'a> {
+    actor: &'a Actor,
+    action: Action,
+    resource: &'a Resource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+    // An attribute the resource doesn't have, such as the record id of the field schema
+    Null,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attribute {
+    ActorName,
+    ActorRoles,
+    Action,
+    ResourceKind,
+    ResourceTenant,
+    ResourceRecordId,
+}
+
+impl Attribute {
+    fn parse(path: &str) -> Option<Self> {
+        Some(match path {
+            "actor.name" => Attribute::ActorName,
+            "actor.roles" => Attribute::ActorRoles,
+            "action" => Attribute::Action,
+            "resource.kind" => Attribute::ResourceKind,
+            "resource.tenant" => Attribute::ResourceTenant,
+            "resource.record_id" => Attribute::ResourceRecordId,
+            _ => return None,
+        })
+    }
+
+    fn value(self, request: &Request) -> Value {
+        match self {
+            Attribute::ActorName => Value::Str(request.actor.name.clone()),
+            Attribute::ActorRoles => Value::List(
+                request.actor.roles.iter().map(|role| Value::Str(role.as_str().to_string())).collect(),
+            ),
+            Attribute::Action => Value::Str(request.action.as_str().to_string()),
+            Attribute::ResourceKind => Value::Str(request.resource.kind.as_str().to_string()),
+            Attribute::ResourceTenant => Value::Str(request.resource.tenant.as_str().to_string()),
+            Attribute::ResourceRecordId => match request.resource.kind {
+                ResourceKind::Record(id) => Value::Int(id),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Attribute(Attribute),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // Comparisons between values of different types are false rather than
+    // errors, so a rule that doesn't fit a request simply doesn't match it
+    fn eval(&self, request: &Request) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Attribute(attribute) => attribute.value(request),
+            Expr::List(items) => Value::List(items.iter().map(|item| item.eval(request)).collect()),
+            Expr::Not(inner) => Value::Bool(inner.eval(request) != Value::Bool(true)),
+            Expr::And(a, b) => Value::Bool(a.eval(request) == Value::Bool(true) && b.eval(request) == Value::Bool(true)),
+            Expr::Or(a, b) => Value::Bool(a.eval(request) == Value::Bool(true) || b.eval(request) == Value::Bool(true)),
+            Expr::Compare(op, a, b) => {
+                let (a, b) = (a.eval(request), b.eval(request));
+                Value::Bool(match (op, &a, &b) {
+                    (Op::Eq, _, _) => a == b,
+                    (Op::Ne, _, _) => a != b,
+                    (Op::In, _, Value::List(items)) => items.contains(&a),
+                    (Op::Lt, Value::Int(a), Value::Int(b)) => a < b,
+                    (Op::Le, Value::Int(a), Value::Int(b)) => a <= b,
+                    (Op::Gt, Value::Int(a), Value::Int(b)) => a > b,
+                    (Op::Ge, Value::Int(a), Value::Int(b)) => a >= b,
+                    _ => false,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    Int(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err("Unterminated string".to_string()),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err("Unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Str(text));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut end = start + c.len_utf8();
+            chars.next();
+            while let Some(&(i, d)) = chars.peek() {
+                if !d.is_ascii_digit() {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+            let number = &source[start..end];
+            tokens.push(Token::Int(number.parse().map_err(|_| format!("Invalid number {}", number))?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, d)) = chars.peek() {
+                if !(d.is_ascii_alphanumeric() || d == '_' || d == '.') {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+            tokens.push(Token::Ident(source[start..end].to_string()));
+        } else {
+            let rest = &source[start..];
+            let op = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")", "[", "]", ","]
+                .into_iter()
+                .find(|op| rest.starts_with(op))
+                .ok_or_else(|| format!("Unexpected {:?}", c))?;
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent over the tokens, loosest binding first:
+// or := and ("||" and)*, and := unary ("&&" unary)*,
+// unary := "!" unary | compare, compare := operand (op operand)?
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+fn parse(source: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let expr = parser.or()?;
+    match parser.tokens.get(parser.position) {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?}", token)),
+    }
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Take the next token if it is the operator `op`
+    fn eat(&mut self, op: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Op(o)) if *o == op => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.eat(op) {
+            true => Ok(()),
+            false => Err(format!("Expected {}", op)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let op = match self.tokens.get(self.position) {
+            Some(Token::Op("==")) => Op::Eq,
+            Some(Token::Op("!=")) => Op::Ne,
+            Some(Token::Op("<")) => Op::Lt,
+            Some(Token::Op("<=")) => Op::Le,
+            Some(Token::Op(">")) => Op::Gt,
+            Some(Token::Op(">=")) => Op::Ge,
+            Some(Token::Ident(word)) if word == "in" => Op::In,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Expr::Compare(op, Box::new(left), Box::new(self.operand()?)))
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Str(text)) => Ok(Expr::Literal(Value::Str(text))),
+            Some(Token::Int(n)) => Ok(Expr::Literal(Value::Int(n))),
+            Some(Token::Ident(word)) => match word.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                path => Attribute::parse(path)
+                    .map(Expr::Attribute)
+                    .ok_or_else(|| format!("Unknown attribute {}", path)),
+            },
+            Some(Token::Op("(")) => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Op("[")) => {
+                let mut items = Vec::new();
+                if !self.eat("]") {
+                    loop {
+                        items.push(self.operand()?);
+                        if self.eat("]") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::List(items))
+            }
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+// Whether the configured policy lets `user` do `action` to `kind` in the
+// tenant `db` is scoped to. Denials are logged, for working out policies.
+pub async fn allowed(db: &DbManager, user: &User, action: Action, kind: ResourceKind) -> bool {
+    let resource = Resource {
+        tenant: db.tenant().clone(),
+        kind,
+    };
+    let allowed = policy().can(&Actor::for_user(user), action, &resource);
+    if !allowed {
+        tracing::info!(user = %user.name, %action, %resource, "Denied by authorization policy");
+    }
+    // Whatever an admin tries while acting as someone else goes on record; if
+    // it can't, the action doesn't happen
+    if let Some(admin) = user.impersonated_by.as_deref() {
+        let event = if allowed { "allowed" } else { "denied" };
+        let detail = format!("{} {}", action, resource);
+        if let Err(e) = db.log_impersonation(admin, &user.name, event, &detail).await {
+            tracing::error!(error = %e, %admin, user = %user.name, "Can't log impersonated action; denying it");
+            return false;
+        }
+    }
+    allowed
+}
+
+// What to tell a user the policy turned down
+pub fn denial(action: Action, kind: ResourceKind) -> String {
+    let resource = match kind {
+        ResourceKind::Record(id) => format!("record {}", id),
+        ResourceKind::Records => "records".to_string(),
+        ResourceKind::FieldSchema => "fields".to_string(),
+        ResourceKind::Reports => "reports".to_string(),
+        ResourceKind::Users => "other users".to_string(),
+    };
+    format!("You aren't allowed to {} {}", action.verb(), resource)
+}
+
+// The policy from FIELD_EDITOR_POLICY, a JSON rules file for an
+// `ExpressionPolicy`; the role policy if it isn't set
+pub fn policy_from_env() -> Result<Box<dyn Policy>, String> {
+    match std::env::var("FIELD_EDITOR_POLICY") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read policy file {}: {}", path, e))
+            .and_then(|json| ExpressionPolicy::from_json(&json))
+            .map(|policy| Box::new(policy) as Box<dyn Policy>),
+        Err(_) => Ok(Box::new(RolePolicy)),
+    }
+}
+
+// The policy for this process, read once. A broken policy file is logged
+// and denies everything rather than falling back to something more permissive.
+pub fn policy() -> &'static dyn Policy {
+    static POLICY: OnceLock<Box<dyn Policy>> = OnceLock::new();
+    POLICY
+        .get_or_init(|| {
+            policy_from_env().unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Authorization policy unusable; denying everything");
+                Box::new(DenyAll)
+            })
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[(&str, Option<&str>)]) -> ExpressionPolicy {
+        let rules: Vec<_> = rules
+            .iter()
+            .map(|(effect, when)| serde_json::json!({ "effect": effect, "when": when }))
+            .collect();
+        ExpressionPolicy::from_json(&serde_json::json!({ "rules": rules }).to_string()).expect("valid policy")
+    }
+
+    fn actor(role: Role) -> Actor {
+        Actor {
+            name: "ada".to_string(),
+            roles: vec![role],
+        }
+    }
+
+    fn resource(kind: ResourceKind) -> Resource {
+        Resource {
+            tenant: TenantId::parse("acme").unwrap(),
+            kind,
+        }
+    }
+
+    fn lit(value: Value) -> Box<Expr> {
+        Box::new(Expr::Literal(value))
+    }
+
+    #[test]
+    fn tokenizes_operators_numbers_and_names() {
+        assert_eq!(
+            tokenize("resource.record_id<=-12 && !x").unwrap(),
+            vec![
+                Token::Ident("resource.record_id".to_string()),
+                Token::Op("<="),
+                Token::Int(-12),
+                Token::Op("&&"),
+                Token::Op("!"),
+                Token::Ident("x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_quoted_strings_with_escapes() {
+        assert_eq!(
+            tokenize(r#""say \"hi\" && go" == a"#).unwrap(),
+            vec![
+                Token::Str(r#"say "hi" && go"#.to_string()),
+                Token::Op("=="),
+                Token::Ident("a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        assert_eq!(tokenize(r#""open"#), Err("Unterminated string".to_string()));
+        assert_eq!(tokenize(r#""open\"#), Err("Unterminated string".to_string()));
+        assert_eq!(tokenize("a @ b"), Err("Unexpected '@'".to_string()));
+        assert_eq!(tokenize("- 1"), Err("Invalid number -".to_string()));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("true || false && false").unwrap(),
+            Expr::Or(
+                lit(Value::Bool(true)),
+                Box::new(Expr::And(lit(Value::Bool(false)), lit(Value::Bool(false)))),
+            )
+        );
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert_eq!(
+            parse("!true && false").unwrap(),
+            Expr::And(Box::new(Expr::Not(lit(Value::Bool(true)))), lit(Value::Bool(false)))
+        );
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(
+            parse("(true || false) && false").unwrap(),
+            Expr::And(
+                Box::new(Expr::Or(lit(Value::Bool(true)), lit(Value::Bool(false)))),
+                lit(Value::Bool(false)),
+            )
+        );
+    }
+
+    #[test]
+    fn parses_comparisons_and_lists() {
+        assert_eq!(
+            parse(r#"action in ["edit", "view"]"#).unwrap(),
+            Expr::Compare(
+                Op::In,
+                Box::new(Expr::Attribute(Attribute::Action)),
+                Box::new(Expr::List(vec![
+                    Expr::Literal(Value::Str("edit".to_string())),
+                    Expr::Literal(Value::Str("view".to_string())),
+                ])),
+            )
+        );
+        assert_eq!(parse("[]").unwrap(), Expr::List(Vec::new()));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(parse("action =="), Err("Unexpected end of expression".to_string()));
+        assert_eq!(parse(r#"actor.email == "x""#), Err("Unknown attribute actor.email".to_string()));
+        assert_eq!(parse("(true"), Err("Expected )".to_string()));
+        assert_eq!(parse(r#"["a" "b"]"#), Err("Expected ,".to_string()));
+        assert_eq!(parse("true false"), Err(r#"Unexpected Ident("false")"#.to_string()));
+        assert_eq!(parse("1 == 1 == 1"), Err(r#"Unexpected Op("==")"#.to_string()));
+    }
+
+    #[test]
+    fn names_the_rule_that_does_not_parse() {
+        let json = r#"{"rules": [{"effect": "permit"}, {"effect": "forbid", "when": "nope"}]}"#;
+        assert_eq!(
+            ExpressionPolicy::from_json(json).err(),
+            Some("Rule 2: Unknown attribute nope".to_string())
+        );
+    }
+
+    #[test]
+    fn denies_unless_a_rule_permits() {
+        let policy = policy(&[]);
+        assert!(!policy.can(&actor(Role::Admin), Action::View, &resource(ResourceKind::Records)));
+    }
+
+    #[test]
+    fn rule_without_condition_always_matches() {
+        let policy = policy(&[("permit", None)]);
+        assert!(policy.can(&actor(Role::Viewer), Action::Delete, &resource(ResourceKind::Record(1))));
+    }
+
+    #[test]
+    fn forbid_overrides_permit() {
+        let policy = policy(&[
+            ("permit", Some(r#""editor" in actor.roles"#)),
+            ("forbid", Some(r#"action == "delete""#)),
+        ]);
+        let editor = actor(Role::Editor);
+        assert!(policy.can(&editor, Action::Edit, &resource(ResourceKind::Record(1))));
+        assert!(!policy.can(&editor, Action::Delete, &resource(ResourceKind::Record(1))));
+        assert!(!policy.can(&actor(Role::Viewer), Action::Edit, &resource(ResourceKind::Record(1))));
+    }
+
+    #[test]
+    fn compares_record_ids_and_tenants() {
+        let policy = policy(&[("permit", Some(r#"resource.tenant == "acme" && resource.record_id >= 100"#))]);
+        let editor = actor(Role::Editor);
+        assert!(policy.can(&editor, Action::Edit, &resource(ResourceKind::Record(100))));
+        assert!(!policy.can(&editor, Action::Edit, &resource(ResourceKind::Record(99))));
+        // Only records have an id
+        assert!(!policy.can(&editor, Action::Edit, &resource(ResourceKind::Records)));
+    }
+
+    #[test]
+    fn comparisons_across_types_are_false() {
+        let policy = policy(&[
+            ("permit", Some(r#"resource.record_id == "7""#)),
+            ("permit", Some(r#"actor.name > 1"#)),
+        ]);
+        assert!(!policy.can(&actor(Role::Admin), Action::View, &resource(ResourceKind::Record(7))));
+    }
+
+    #[test]
+    fn role_policy_limits_editors_and_viewers() {
+        let record = resource(ResourceKind::Record(1));
+        assert!(RolePolicy.can(&actor(Role::Admin), Action::RunReports, &record));
+        assert!(RolePolicy.can(&actor(Role::Editor), Action::Edit, &record));
+        assert!(!RolePolicy.can(&actor(Role::Editor), Action::Approve, &record));
+        assert!(RolePolicy.can(&actor(Role::Viewer), Action::View, &record));
+        assert!(!RolePolicy.can(&actor(Role::Viewer), Action::Edit, &record));
+    }
+}
@@ -0,0 +1,287 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+use std::time::Duration;
+
+// How often an open editor reports that it is still there
+pub const PRESENCE_HEARTBEAT: Duration = Duration::from_secs(10);
+
+// Someone who has a record open in an editor right now
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct Presence {
+    pub record_id: i64,
+    pub user_name: String,
+    pub last_seen: i64,
+}
+
+// What the record list shows per row, fetched for all rows in one call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct RecordMetadata {
+    pub id: i64,
+    pub version: i64,
+    pub updated_at: Option<i64>,
+    // The user who opened the record first among those editing it now
+    pub lock_holder: Option<String>,
+    // How many users have the record open, the lock holder included
+    pub watchers: i64,
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{Presence, RecordMetadata, PRESENCE_HEARTBEAT};
+    use crate::db::{DbManager, HistoryRow};
+    use crate::events::{EventEnvelope, FieldEvent};
+    use crate::tenant::{ScopedRecord, TenantId};
+    use sqlx::SqliteConnection;
+
+    // An editor that missed this many heartbeats is considered gone
+    const PRESENCE_TTL_MILLIS: i64 = 3 * PRESENCE_HEARTBEAT.as_millis() as i64;
+    // Lock events are kept about as long as anyone looks into a conflict
+    const LOCK_EVENT_TTL_MILLIS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+    // Columns to select a lock event as a `HistoryRow`; lock events carry no values
+    const LOCK_EVENT_COLUMNS: &str =
+        "id, record_id, version, change_type, event, '{}' AS field_values, changed_at, changed_by, tenant_id";
+
+    // Note an editor opening or closing a record at its current version
+    async fn append_lock_event(
+        conn: &mut SqliteConnection,
+        record: ScopedRecord,
+        event: &FieldEvent,
+        user: &str,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO lock_events (record_id, version, change_type, event, changed_at, changed_by, tenant_id)
+            SELECT id, version, ?, ?, ?, ?, tenant_id FROM fields WHERE id = ?
+            "#,
+        )
+        .bind(event.kind())
+        .bind(serde_json::to_string(event).expect("FieldEvent serializes"))
+        .bind(now)
+        .bind(user)
+        .bind(record.id())
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    // The presence registry: editors register themselves per browser tab and keep
+    // the entry alive with heartbeats. Stored in the database so every instance
+    // sees the same set. Joining and leaving are announced on the change feed as
+    // lock events, so list views can update without polling; they go to
+    // `lock_events` rather than the audit log, which only has changes.
+    impl DbManager {
+        // Register or refresh an open editor
+        pub async fn touch_presence(&self, record_id: i64, editor_id: &str, user: &str) -> Result<(), sqlx::Error> {
+            let now = self.now();
+            let mut tx = self.pool().begin().await?;
+            // Another tenant's record can't be opened, so nobody is editing it
+            let Some(record) = self.scope(&mut tx, record_id).await? else {
+                return Ok(());
+            };
+            sqlx::query("DELETE FROM presence WHERE last_seen < ?")
+                .bind(now - PRESENCE_TTL_MILLIS)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM lock_events WHERE changed_at < ?")
+                .bind(now - LOCK_EVENT_TTL_MILLIS)
+                .execute(&mut *tx)
+                .await?;
+
+            let joined = sqlx::query(
+                r#"
+                INSERT INTO presence (editor_id, record_id, user_name, last_seen, joined_at)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(editor_id) DO NOTHING
+                "#,
+            )
+            .bind(editor_id)
+            .bind(record_id)
+            .bind(user)
+            .bind(now)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                > 0;
+
+            if joined {
+                append_lock_event(&mut tx, record, &FieldEvent::LockAcquired, user, now).await?;
+            } else {
+                // An editor id only ever belongs to the user and record that registered it
+                sqlx::query("UPDATE presence SET last_seen = ? WHERE editor_id = ? AND record_id = ? AND user_name = ?")
+                    .bind(now)
+                    .bind(editor_id)
+                    .bind(record_id)
+                    .bind(user)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        }
+
+        // Remove an editor that was closed
+        pub async fn leave_presence(&self, editor_id: &str, user: &str) -> Result<(), sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            let record_id: Option<i64> = sqlx::query_scalar(
+                "DELETE FROM presence WHERE editor_id = ? AND user_name = ? RETURNING record_id",
+            )
+            .bind(editor_id)
+            .bind(user)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(record_id) = record_id {
+                if let Some(record) = self.scope(&mut tx, record_id).await? {
+                    append_lock_event(&mut tx, record, &FieldEvent::LockReleased, user, self.now()).await?;
+                }
+            }
+            tx.commit().await
+        }
+
+        pub async fn latest_lock_event_id(&self) -> Result<i64, sqlx::Error> {
+            sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM lock_events")
+                .fetch_one(self.pool())
+                .await
+        }
+
+        // Lock events after the given id, of every tenant, oldest first
+        pub async fn lock_events_after(
+            &self,
+            after_id: i64,
+            limit: i64,
+        ) -> Result<Vec<(TenantId, EventEnvelope)>, sqlx::Error> {
+            let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+                "SELECT {} FROM lock_events WHERE id > ? ORDER BY id LIMIT ?",
+                LOCK_EVENT_COLUMNS
+            ))
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(self.pool())
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| (TenantId::parse(&row.tenant_id).unwrap_or_default(), EventEnvelope::from(row)))
+                .collect())
+        }
+
+        // When editors opened a record up to `until`, newest first
+        pub async fn record_opened_before(
+            &self,
+            record_id: i64,
+            until: i64,
+            limit: i64,
+        ) -> Result<Vec<EventEnvelope>, sqlx::Error> {
+            let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+                r#"
+                SELECT {} FROM lock_events
+                WHERE record_id = ? AND tenant_id = ? AND change_type = 'lock_acquired' AND changed_at <= ?
+                ORDER BY id DESC LIMIT ?
+                "#,
+                LOCK_EVENT_COLUMNS
+            ))
+            .bind(record_id)
+            .bind(self.tenant().as_str())
+            .bind(until)
+            .bind(limit)
+            .fetch_all(self.pool())
+            .await?;
+            Ok(rows.into_iter().map(EventEnvelope::from).collect())
+        }
+
+        // Who is editing what, one entry per user and record
+        pub async fn list_presence(&self) -> Result<Vec<Presence>, sqlx::Error> {
+            sqlx::query_as::<_, Presence>(
+                r#"
+                SELECT record_id, user_name, MAX(last_seen) AS last_seen FROM presence
+                WHERE last_seen >= ? AND record_id IN (SELECT id FROM fields WHERE tenant_id = ?)
+                GROUP BY record_id, user_name
+                ORDER BY record_id, user_name
+                "#,
+            )
+            .bind(self.now() - PRESENCE_TTL_MILLIS)
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+
+        // Version, last change and presence of many records in one query.
+        // Unknown and deleted ids are left out.
+        pub async fn list_metadata(&self, ids: &[i64]) -> Result<Vec<RecordMetadata>, sqlx::Error> {
+            let ids = serde_json::to_string(ids).expect("ids serialize");
+            sqlx::query_as::<_, RecordMetadata>(
+                r#"
+                WITH live AS (
+                    SELECT record_id, user_name, COALESCE(joined_at, last_seen) AS joined_at
+                    FROM presence WHERE last_seen >= ?
+                )
+                SELECT f.id, f.version, f.updated_at,
+                    (SELECT user_name FROM live WHERE record_id = f.id
+                     ORDER BY joined_at, user_name LIMIT 1) AS lock_holder,
+                    (SELECT COUNT(DISTINCT user_name) FROM live WHERE record_id = f.id) AS watchers
+                FROM fields f
+                WHERE f.id IN (SELECT value FROM json_each(?)) AND f.tenant_id = ? AND f.deleted_at IS NULL
+                ORDER BY f.id
+                "#,
+            )
+            .bind(self.now() - PRESENCE_TTL_MILLIS)
+            .bind(ids)
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+    }
+}
+
+// Heartbeat from an open editor. Not rate limited: it runs on a fixed
+// schedule and must not use up the tokens the user needs for saving.
+#[server(JoinRecord)]
+pub async fn join_record(record_id: i64, editor_id: String, csrf_token: String) -> Result<(), ServerFnError> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    let user = authorize(&db, Action::View, ResourceKind::Record(record_id)).await?;
+    require_csrf(&db, &csrf_token).await?;
+    db.touch_presence(record_id, &editor_id, &user.name)
+        .await
+        .map_err(db_error)
+}
+
+#[server(LeaveRecord)]
+pub async fn leave_record(editor_id: String, csrf_token: String) -> Result<(), ServerFnError> {
+    use crate::auth::{require_csrf, require_user};
+    use crate::field_editor::{db_error, open_db};
+
+    let db = open_db().await?;
+    let user = require_user(&db).await?;
+    require_csrf(&db, &csrf_token).await?;
+    db.leave_presence(&editor_id, &user.name).await.map_err(db_error)
+}
+
+#[server(ListPresence)]
+pub async fn list_presence() -> Result<Vec<Presence>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.list_presence().await.map_err(db_error)
+}
+
+// Metadata for the given records, so a list view needs one request instead of one per row
+#[server(GetListMetadata)]
+pub async fn get_list_metadata(ids: Vec<i64>) -> Result<Vec<RecordMetadata>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.list_metadata(&ids).await.map_err(db_error)
+}
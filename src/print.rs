@@ -0,0 +1,200 @@
+use crate::db::{DbManager, Fields};
+use crate::field_schema::FieldDefinition;
+use crate::policy::{Action, ResourceKind};
+use crate::rest::{authorized_user, error, internal_error, not_modified};
+use crate::tenant::{TenantDb, TenantId};
+use crate::timestamp::format_rfc3339;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use leptos::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// A read-only page per record for printing and sharing, rendered on the server
+// without the editor's scripts. Most records are viewed far more often than
+// they change, so each record's page is rendered once per version and kept in
+// memory; a save drops it, and a version nobody asked for again just ages out.
+// The field definitions are checked on every view, since renaming or
+// relabelling a field changes the page without changing the record's version.
+
+// How many rendered pages are kept, FIELD_EDITOR_PRINT_CACHE_SIZE (1000 unless set)
+pub fn print_cache_size_from_env() -> usize {
+    std::env::var("FIELD_EDITOR_PRINT_CACHE_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000)
+}
+
+struct CachedPage {
+    version: i64,
+    schema: u64,
+    html: Arc<str>,
+}
+
+#[derive(Default)]
+struct Pages {
+    pages: HashMap<(TenantId, i64), CachedPage>,
+    // Oldest first, for evicting once full
+    order: VecDeque<(TenantId, i64)>,
+}
+
+// Rendered pages by record and the version they show
+pub struct PrintCache {
+    capacity: usize,
+    pages: Mutex<Pages>,
+}
+
+impl PrintCache {
+    pub fn new(capacity: usize) -> Self {
+        PrintCache {
+            capacity,
+            pages: Mutex::new(Pages::default()),
+        }
+    }
+
+    fn get(&self, tenant: &TenantId, id: i64, version: i64, schema: u64) -> Option<Arc<str>> {
+        let pages = self.pages.lock().expect("Print cache lock poisoned");
+        pages
+            .pages
+            .get(&(tenant.clone(), id))
+            .filter(|page| page.version == version && page.schema == schema)
+            .map(|page| page.html.clone())
+    }
+
+    fn put(&self, tenant: &TenantId, id: i64, version: i64, schema: u64, html: Arc<str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut pages = self.pages.lock().expect("Print cache lock poisoned");
+        let key = (tenant.clone(), id);
+        if pages.pages.insert(key.clone(), CachedPage { version, schema, html }).is_none() {
+            pages.order.push_back(key);
+        }
+        while pages.pages.len() > self.capacity {
+            let Some(oldest) = pages.order.pop_front() else {
+                break;
+            };
+            pages.pages.remove(&oldest);
+        }
+    }
+
+    // Drop a record's page after it changed
+    pub fn forget(&self, tenant: &TenantId, id: i64) {
+        let mut pages = self.pages.lock().expect("Print cache lock poisoned");
+        let key = (tenant.clone(), id);
+        if pages.pages.remove(&key).is_some() {
+            pages.order.retain(|k| *k != key);
+        }
+    }
+}
+
+static PRINT_CACHE: OnceLock<PrintCache> = OnceLock::new();
+
+pub fn print_cache() -> &'static PrintCache {
+    PRINT_CACHE.get_or_init(|| PrintCache::new(print_cache_size_from_env()))
+}
+
+fn schema_hash(definitions: &[FieldDefinition]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    definitions.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl DbManager {
+    // A record's current version without its values; None if it's missing or in the trash
+    pub async fn record_version(&self, id: i64) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT version FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL")
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(self.pool())
+            .await
+    }
+}
+
+fn render(fields: &Fields, definitions: &[FieldDefinition]) -> String {
+    let title = format!("Record {}", fields.id);
+    let rows = definitions
+        .iter()
+        .map(|definition| {
+            let label = definition.metadata.label.clone().unwrap_or_else(|| definition.name.clone());
+            let value = fields.values.0.get(&definition.name).cloned().unwrap_or_default();
+            view! {
+                <tr>
+                    <th scope="row">{label}</th>
+                    <td>{value}</td>
+                </tr>
+            }
+        })
+        .collect_view();
+    let saved = match (&fields.updated_by, fields.updated_at) {
+        (Some(user), Some(at)) => format!("Version {}, saved by {} at {}", fields.version, user, format_rfc3339(at)),
+        _ => format!("Version {}", fields.version),
+    };
+    let page = view! {
+        <html lang="en">
+            <head>
+                <meta charset="utf-8"/>
+                <title>{title.clone()}</title>
+                <link rel="stylesheet" href="/pkg/field-editor.css"/>
+            </head>
+            <body class="print-page">
+                <h1>{title}</h1>
+                <table class="print-fields">
+                    <tbody>{rows}</tbody>
+                </table>
+                <p class="print-version">{saved}</p>
+            </body>
+        </html>
+    };
+    format!("<!DOCTYPE html>{}", page.to_html())
+}
+
+// The read-only page of a record, e.g. `/records/7/print`
+#[actix_web::get("/records/{id}/print")]
+pub async fn print_record(db: TenantDb, req: HttpRequest, id: web::Path<i64>) -> HttpResponse {
+    let id = id.into_inner();
+    if let Err(response) = authorized_user(&db, &req, Action::View, ResourceKind::Record(id)).await {
+        return response;
+    }
+    let cache = print_cache();
+    let version = match db.record_version(id).await {
+        Ok(Some(version)) => version,
+        Ok(None) => return error(StatusCode::NOT_FOUND, "No such record"),
+        Err(e) => return internal_error(e),
+    };
+    let definitions = match db.field_definitions().await {
+        Ok(definitions) => definitions,
+        Err(e) => return internal_error(e),
+    };
+    let schema = schema_hash(&definitions);
+    let etag = format!("\"print-{}-{}-{:x}\"", id, version, schema);
+    if not_modified(&req, &etag) {
+        return HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish();
+    }
+
+    let html = match cache.get(db.tenant(), id, version, schema) {
+        Some(html) => html,
+        None => {
+            let fields = match db.get_fields(id).await {
+                Ok(fields) => fields,
+                Err(sqlx::Error::RowNotFound) => return error(StatusCode::NOT_FOUND, "No such record"),
+                Err(e) => return internal_error(e),
+            };
+            let html: Arc<str> = render(&fields, &definitions).into();
+            // Saved again since the version was read: render it, but don't keep it
+            // under the version it isn't
+            if fields.version == version {
+                cache.put(db.tenant(), id, version, schema, html.clone());
+            }
+            html
+        }
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(html.to_string())
+}
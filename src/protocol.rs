@@ -0,0 +1,147 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Version of the server function wire format, shared by the server and the
+// WASM bundle built from the same source. During a rolling deploy an open tab
+// may keep running an older bundle against a newer server (or the other way
+// round), and their server function payloads may no longer line up.
+//
+// Bump PROTOCOL_VERSION whenever a server function's arguments or result change
+// shape. Raise the minimums once the other side's older shapes are no longer understood.
+pub const PROTOCOL_VERSION: u32 = 2;
+// The oldest bundle this server still talks to. 2: `get_fields` is a GET.
+pub const MIN_CLIENT_VERSION: u32 = 2;
+// The oldest server this bundle still talks to
+pub const MIN_SERVER_VERSION: u32 = 2;
+
+// Identifies the build the server and bundle came from; set FIELD_EDITOR_BUILD_ID
+// when building (e.g. to the commit hash) so every deploy gets its own
+pub const BUILD_ID: &str = match option_env!("FIELD_EDITOR_BUILD_ID") {
+    Some(id) => id,
+    None => env!("CARGO_PKG_VERSION"),
+};
+// Response header carrying the server's BUILD_ID
+pub const BUILD_ID_HEADER: &str = "X-Field-Editor-Build";
+
+// The server's half of the handshake
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    pub server_version: u32,
+    pub min_client_version: u32,
+    // Added in protocol 2; a server without it is reported as an unknown build
+    #[serde(default)]
+    pub build_id: String,
+}
+
+impl ProtocolInfo {
+    // Whether a client of the given version and this server understand each other
+    pub fn compatible_with(&self, client_version: u32, min_server_version: u32) -> bool {
+        client_version >= self.min_client_version && self.server_version >= min_server_version
+    }
+}
+
+// Tell a client which protocol versions this server speaks. Kept free of
+// anything that could change shape, so every bundle can always call it.
+#[server(ProtocolVersion)]
+pub async fn protocol_version() -> Result<ProtocolInfo, ServerFnError> {
+    Ok(ProtocolInfo {
+        server_version: PROTOCOL_VERSION,
+        min_client_version: MIN_CLIENT_VERSION,
+        build_id: BUILD_ID.to_string(),
+    })
+}
+
+// What the handshake found out about the server this page talks to
+#[derive(Clone, Copy)]
+pub struct ProtocolStatus {
+    // The server speaks a protocol this bundle doesn't; saving is blocked
+    pub reload_required: RwSignal<bool>,
+    // The server runs a newer build that this bundle can still talk to
+    pub new_build: RwSignal<bool>,
+}
+
+impl ProtocolStatus {
+    // Check the server's protocol now and again whenever the change stream
+    // reconnects, which is what a deploy looks like from the browser
+    pub fn provide() -> Self {
+        let status = ProtocolStatus {
+            reload_required: RwSignal::new(false),
+            new_build: RwSignal::new(false),
+        };
+        provide_context(status);
+
+        #[cfg(feature = "hydrate")]
+        {
+            use crate::changefeed::{subscribe, LiveUpdate};
+
+            status.check();
+            let subscription = subscribe(move |update| {
+                if let LiveUpdate::Reconnected = update {
+                    status.check();
+                }
+            });
+            on_cleanup(move || drop(subscription));
+        }
+
+        status
+    }
+
+    // Whether saves may be sent; a save across incompatible protocols could
+    // be misread by the server
+    pub fn can_save(&self) -> bool {
+        !self.reload_required.get()
+    }
+
+    #[cfg(feature = "hydrate")]
+    fn check(self) {
+        wasm_bindgen_futures::spawn_local(async move {
+            // A server too old to know the handshake can't be told apart from a
+            // network error here; the next reconnect asks again
+            if let Ok(info) = protocol_version().await {
+                let compatible = info.compatible_with(PROTOCOL_VERSION, MIN_SERVER_VERSION);
+                self.reload_required.set(!compatible);
+                self.new_build.set(info.build_id != BUILD_ID);
+            }
+        });
+    }
+}
+
+pub fn use_protocol_status() -> ProtocolStatus {
+    expect_context::<ProtocolStatus>()
+}
+
+/// Asks the user to reload once a new version is deployed: as a suggestion while
+/// this page still works with the server, as a must once it no longer does.
+#[component]
+pub fn ReloadBanner() -> impl IntoView {
+    let status = use_protocol_status();
+    let reload = move |_| {
+        #[cfg(feature = "hydrate")]
+        let _ = window().location().reload();
+    };
+
+    view! {
+        {move || {
+            if status.reload_required.get() {
+                Some(view! {
+                    <div class="notice reload-banner">
+                        "A new version of the editor is available and this page can no longer save. "
+                        "Reload to continue; copy any unsaved text first."
+                        <button on:click=reload>"Reload"</button>
+                    </div>
+                }.into_any())
+            } else if status.new_build.get() {
+                Some(view! {
+                    <div class="notice reload-banner">
+                        "A new version of the editor is available. Reload when convenient."
+                        <button on:click=reload>"Reload"</button>
+                        <button class="link" on:click=move |_| status.new_build.set(false)>"Dismiss"</button>
+                    </div>
+                }.into_any())
+            } else {
+                None
+            }
+        }}
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Source of the current time for timestamps written by the storage and history layers.
+// Tests can substitute a `FixedClock` to make timestamps reproducible.
+pub trait Clock: Send + Sync {
+    // Milliseconds since the UNIX epoch
+    fn now_millis(&self) -> i64;
+}
+
+// The real wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time before UNIX epoch")
+            .as_millis() as i64
+    }
+}
+
+// A clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct FixedClock(AtomicI64);
+
+impl FixedClock {
+    pub fn new(millis: i64) -> Self {
+        FixedClock(AtomicI64::new(millis))
+    }
+
+    pub fn set(&self, millis: i64) {
+        self.0.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: i64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now_millis(&self) -> i64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Source of unique ids (session ids and the like)
+pub trait IdGenerator: Send + Sync {
+    fn new_id(&self) -> String;
+}
+
+// Random version 4 UUIDs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn new_id(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+}
+
+// Deterministic ids of the form `<prefix>-1`, `<prefix>-2`, ...
+#[derive(Debug)]
+pub struct SequentialIdGenerator {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SequentialIdGenerator {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        SequentialIdGenerator {
+            prefix: prefix.into(),
+            next: AtomicU64::new(1),
+        }
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn new_id(&self) -> String {
+        format!("{}-{}", self.prefix, self.next.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock_only_moves_when_told() {
+        let clock = FixedClock::new(1_000);
+        assert_eq!(clock.now_millis(), 1_000);
+        assert_eq!(clock.now_millis(), 1_000);
+        clock.advance(250);
+        assert_eq!(clock.now_millis(), 1_250);
+        clock.set(42);
+        assert_eq!(clock.now_millis(), 42);
+    }
+
+    #[test]
+    fn system_clock_is_the_wall_clock() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let now = SystemClock.now_millis();
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert!(before <= now && now <= after);
+    }
+
+    #[test]
+    fn sequential_ids_count_up_from_one() {
+        let ids = SequentialIdGenerator::new("session");
+        assert_eq!(ids.new_id(), "session-1");
+        assert_eq!(ids.new_id(), "session-2");
+        assert_eq!(SequentialIdGenerator::new("other").new_id(), "other-1");
+    }
+
+    #[test]
+    fn uuids_are_unique() {
+        assert_ne!(UuidGenerator.new_id(), UuidGenerator.new_id());
+    }
+}
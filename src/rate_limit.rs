@@ -0,0 +1,301 @@
+use crate::auth::SESSION_COOKIE;
+use crate::db::DbManager;
+use crate::errors::{EditorError, EditorServerError};
+use crate::field_editor::{db_error, open_db};
+use crate::providers::{Clock, SystemClock};
+use actix_web::http::header::{HeaderMap, HeaderValue, FORWARDED, RETRY_AFTER, X_FORWARDED_FOR};
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Forget a caller's bucket once it has been idle this long; it would be full again anyway
+const IDLE_EVICT_MILLIS: i64 = 10 * 60 * 1000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: i64,
+}
+
+// Token bucket rate limiter for the mutation server functions, keyed by user
+// (or client address for anonymous callers). Each caller may burst up to `capacity`
+// requests and then gets `refill_per_sec` more per second. Limits are per instance.
+#[derive(Clone)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        RateLimiter {
+            capacity: capacity.max(1) as f64,
+            refill_per_sec,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Take one token from the caller's bucket. On failure returns how many
+    // seconds until a token is available again.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        let now = self.clock.now_millis();
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned");
+        buckets.retain(|_, bucket| now - bucket.updated_at < IDLE_EVICT_MILLIS);
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+        let elapsed_secs = (now - bucket.updated_at).max(0) as f64 / 1000.0;
+        bucket.tokens = (bucket.tokens + elapsed_secs * self.refill_per_sec).min(self.capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if self.refill_per_sec > 0.0 {
+            Err(((1.0 - bucket.tokens) / self.refill_per_sec).ceil().max(1.0) as u64)
+        } else {
+            Err(u64::MAX)
+        }
+    }
+}
+
+// FIELD_EDITOR_TRUSTED_PROXIES: the comma-separated addresses of reverse proxies
+// whose Forwarded and X-Forwarded-For headers name the client; nobody's by
+// default, since any client can send those headers
+pub fn trusted_proxies() -> &'static [IpAddr] {
+    static PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    PROXIES.get_or_init(|| {
+        std::env::var("FIELD_EDITOR_TRUSTED_PROXIES")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty())
+                    .map(|a| a.parse().unwrap_or_else(|_| panic!("FIELD_EDITOR_TRUSTED_PROXIES: invalid address {}", a)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+// The addresses a request was forwarded for, the client's first and the
+// nearest proxy's last: the `for=` parts of Forwarded, or else X-Forwarded-For
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    let values = |name| headers.get_all(name).filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(','));
+    let forwarded: Vec<String> = values(FORWARDED)
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    values(X_FORWARDED_FOR)
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// An address as a hop names it: bare, with a port, or as `[v6]:port`
+fn hop_ip(hop: &str) -> Option<IpAddr> {
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<std::net::SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+// Who a trusted proxy at `peer` forwarded for. Proxies append to the header,
+// so only the entries they added can be believed: walk it from the nearest
+// hop back and take the first address that isn't one of the trusted proxies.
+// Anything left of that was written by the client.
+fn forwarded_client(peer: IpAddr, hops: &[String], trusted: &[IpAddr]) -> String {
+    for hop in hops.iter().rev() {
+        match hop_ip(hop) {
+            Some(ip) if trusted.contains(&ip) => continue,
+            Some(ip) => return ip.to_string(),
+            // Obfuscated or "unknown"; not a proxy of ours either way
+            None => return hop.clone(),
+        }
+    }
+    peer.to_string()
+}
+
+// The address of the client: the peer's, or the one a trusted proxy forwarded for
+fn client_addr(req: &HttpRequest) -> String {
+    match req.peer_addr().map(|addr| addr.ip()) {
+        Some(ip) if trusted_proxies().contains(&ip) => {
+            forwarded_client(ip, &forwarded_hops(req.headers()), trusted_proxies())
+        }
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+// Whose bucket a request draws from: its user, or its address if anonymous. A
+// cookie only counts once it names a live session, so made-up cookies don't
+// each get a full bucket.
+pub async fn client_key(db: &DbManager, req: &HttpRequest) -> Result<String, sqlx::Error> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        if let Some(user) = db.session_user(cookie.value()).await? {
+            return Ok(format!("user:{}", user.name));
+        }
+    }
+    Ok(format!("ip:{}", client_addr(req)))
+}
+
+// Guard for mutations: spends one of the caller's tokens, or fails with
+// `TooManyRequests` and a 429 response when they are sending too fast
+pub async fn rate_limit() -> Result<(), EditorServerError> {
+    let req = leptos_actix::extract::<HttpRequest>()
+        .await
+        .map_err(EditorError::from)?;
+    // Without a limiter nothing would be limited, so refuse rather than let everything through
+    let Some(limiter) = req.app_data::<actix_web::web::Data<RateLimiter>>() else {
+        tracing::error!(path = %req.path(), "No rate limiter registered; refusing the request");
+        return Err(EditorError::Other("Internal server error".to_string()).into());
+    };
+    let db = open_db().await.map_err(EditorError::from)?;
+    let key = client_key(&db, &req).await.map_err(|e| EditorError::from(db_error(e)))?;
+
+    limiter.check(&key).map_err(|retry_after_secs| {
+        tracing::info!(retry_after_secs, "Rate limited");
+        let response = leptos::prelude::expect_context::<leptos_actix::ResponseOptions>();
+        response.set_status(StatusCode::TOO_MANY_REQUESTS);
+        response.insert_header(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+        EditorError::TooManyRequests { retry_after_secs }.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::FixedClock;
+    use actix_web::test::TestRequest;
+
+    fn clocked(capacity: u32, refill_per_sec: f64) -> (RateLimiter, Arc<FixedClock>) {
+        let clock = Arc::new(FixedClock::new(1_700_000_000_000));
+        (RateLimiter::new(capacity, refill_per_sec).with_clock(clock.clone()), clock)
+    }
+
+    fn hops(hops: &[&str]) -> Vec<String> {
+        hops.iter().map(|hop| hop.to_string()).collect()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_then_refills_over_time() {
+        let (limiter, clock) = clocked(2, 1.0);
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Err(1));
+        // Other callers have buckets of their own
+        assert_eq!(limiter.check("b"), Ok(()));
+
+        clock.advance(500);
+        assert_eq!(limiter.check("a"), Err(1));
+        clock.advance(500);
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Err(1));
+    }
+
+    #[test]
+    fn buckets_refill_no_further_than_their_capacity() {
+        let (limiter, clock) = clocked(2, 1.0);
+        limiter.check("a").unwrap();
+        limiter.check("a").unwrap();
+        clock.advance(60_000);
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Err(1));
+    }
+
+    #[test]
+    fn retry_after_counts_the_seconds_until_the_next_token() {
+        let (limiter, _) = clocked(1, 0.25);
+        limiter.check("a").unwrap();
+        assert_eq!(limiter.check("a"), Err(4));
+        let (never, clock) = clocked(1, 0.0);
+        never.check("a").unwrap();
+        clock.advance(60_000);
+        assert_eq!(never.check("a"), Err(u64::MAX));
+    }
+
+    #[test]
+    fn idle_buckets_are_forgotten_full() {
+        let (limiter, clock) = clocked(1, 0.0);
+        limiter.check("a").unwrap();
+        assert!(limiter.check("a").is_err());
+        clock.advance(IDLE_EVICT_MILLIS);
+        assert_eq!(limiter.check("a"), Ok(()));
+    }
+
+    #[test]
+    fn hops_come_from_forwarded_before_x_forwarded_for() {
+        let req = TestRequest::default()
+            .insert_header((FORWARDED, r#"for=203.0.113.7;proto=https, for="[2001:db8::1]:4711""#))
+            .insert_header((X_FORWARDED_FOR, "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(forwarded_hops(req.headers()), hops(&["203.0.113.7", "[2001:db8::1]:4711"]));
+
+        let req = TestRequest::default()
+            .insert_header((X_FORWARDED_FOR, "198.51.100.1, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(forwarded_hops(req.headers()), hops(&["198.51.100.1", "10.0.0.2"]));
+    }
+
+    #[test]
+    fn hop_addresses_may_carry_ports_and_brackets() {
+        assert_eq!(hop_ip("203.0.113.7"), Some(ip("203.0.113.7")));
+        assert_eq!(hop_ip("203.0.113.7:8080"), Some(ip("203.0.113.7")));
+        assert_eq!(hop_ip("[2001:db8::1]:4711"), Some(ip("2001:db8::1")));
+        assert_eq!(hop_ip("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(hop_ip("unknown"), None);
+    }
+
+    #[test]
+    fn the_client_is_the_nearest_hop_that_isnt_a_trusted_proxy() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let peer = ip("10.0.0.1");
+        // What the client wrote itself, left of what our proxies appended, doesn't count
+        assert_eq!(
+            forwarded_client(peer, &hops(&["1.1.1.1", "203.0.113.7"]), &trusted),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            forwarded_client(peer, &hops(&["1.1.1.1", "203.0.113.7", "10.0.0.2"]), &trusted),
+            "203.0.113.7"
+        );
+        // Even when the client claims to be one of our proxies
+        assert_eq!(
+            forwarded_client(peer, &hops(&["10.0.0.2", "203.0.113.7"]), &trusted),
+            "203.0.113.7"
+        );
+        assert_eq!(forwarded_client(peer, &hops(&["1.1.1.1", "unknown"]), &trusted), "unknown");
+    }
+
+    #[test]
+    fn without_an_untrusted_hop_the_proxy_is_the_client() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(forwarded_client(ip("10.0.0.1"), &[], &trusted), "10.0.0.1");
+        assert_eq!(forwarded_client(ip("10.0.0.1"), &hops(&["10.0.0.2"]), &trusted), "10.0.0.1");
+    }
+}
@@ -0,0 +1,411 @@
+use crate::db::{FieldValues, Fields};
+use crate::tenant::TenantId;
+use futures::future::BoxFuture;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+// Records as `get_fields` last read them, kept so busy records don't cost the
+// query that gathers their values: in this process's memory for a single
+// server, or in Redis for several sharing one. Entries are keyed by version,
+// and `get_fields` looks up the record's current version first, so a read
+// never gets a version that has since been replaced, even one put back by a
+// read that raced a save. Writers also drop the entry of a record they
+// change, so memory isn't held by versions nobody reads. The cache is best
+// effort: if Redis is slow or gone, reads fall back to the database.
+
+pub trait RecordCache: Send + Sync {
+    // For logs and metrics
+    fn name(&self) -> &'static str;
+
+    // The cached record at `version`, if any. Failures count as a miss.
+    fn get<'a>(&'a self, tenant: &'a TenantId, id: i64, version: i64) -> BoxFuture<'a, Option<Fields>>;
+
+    fn put<'a>(&'a self, tenant: &'a TenantId, fields: &'a Fields) -> BoxFuture<'a, ()>;
+
+    // Drop a record after it changed
+    fn invalidate<'a>(&'a self, tenant: &'a TenantId, id: i64) -> BoxFuture<'a, ()>;
+}
+
+// How reads through the cache went, since the server started
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    pub(crate) fn record(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+pub fn cache_metrics() -> &'static CacheMetrics {
+    static METRICS: OnceLock<CacheMetrics> = OnceLock::new();
+    METRICS.get_or_init(CacheMetrics::default)
+}
+
+fn key(tenant: &TenantId, id: i64, version: i64) -> String {
+    format!("field-editor:{}:record:{}:v{}", tenant, id, version)
+}
+
+// FIELD_EDITOR_RECORD_CACHE=memory, with FIELD_EDITOR_RECORD_CACHE_TTL_SECS
+// (60 unless set) and FIELD_EDITOR_RECORD_CACHE_SIZE, the most records kept
+// (10000 unless set)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryCacheConfig {
+    pub ttl: Duration,
+    pub capacity: usize,
+}
+
+impl MemoryCacheConfig {
+    // None unless FIELD_EDITOR_RECORD_CACHE is `memory`
+    pub fn from_env() -> Option<Self> {
+        match std::env::var("FIELD_EDITOR_RECORD_CACHE").as_deref() {
+            Ok("memory") => {}
+            Ok("") | Err(_) => return None,
+            Ok(other) => panic!("Unknown FIELD_EDITOR_RECORD_CACHE {:?}; expected memory", other),
+        }
+        let var = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        Some(MemoryCacheConfig {
+            ttl: Duration::from_secs(var("FIELD_EDITOR_RECORD_CACHE_TTL_SECS", 60)),
+            capacity: var("FIELD_EDITOR_RECORD_CACHE_SIZE", 10_000) as usize,
+        })
+    }
+}
+
+// One version per record: the last one put, which is the only one worth keeping
+#[derive(Default)]
+struct MemoryEntries {
+    entries: HashMap<(TenantId, i64), (Instant, Fields)>,
+    // Oldest first, for evicting once full
+    order: VecDeque<(TenantId, i64)>,
+}
+
+// Records in this process's memory; only right while this is the one server
+// writing to the database. Values are kept as they are at rest.
+pub struct MemoryCache {
+    config: MemoryCacheConfig,
+    entries: std::sync::Mutex<MemoryEntries>,
+}
+
+impl MemoryCache {
+    pub fn new(config: MemoryCacheConfig) -> Self {
+        MemoryCache {
+            config,
+            entries: std::sync::Mutex::new(MemoryEntries::default()),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, MemoryEntries> {
+        self.entries.lock().expect("Record cache lock poisoned")
+    }
+}
+
+impl RecordCache for MemoryCache {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get<'a>(&'a self, tenant: &'a TenantId, id: i64, version: i64) -> BoxFuture<'a, Option<Fields>> {
+        let fields = self
+            .entries()
+            .entries
+            .get(&(tenant.clone(), id))
+            .filter(|(cached_at, fields)| fields.version == version && cached_at.elapsed() < self.config.ttl)
+            .and_then(|(_, fields)| revealed(fields.clone()));
+        Box::pin(async move { fields })
+    }
+
+    fn put<'a>(&'a self, tenant: &'a TenantId, fields: &'a Fields) -> BoxFuture<'a, ()> {
+        if self.config.capacity > 0 && !self.config.ttl.is_zero() {
+            let mut entries = self.entries();
+            let key = (tenant.clone(), fields.id);
+            if entries.entries.insert(key.clone(), (Instant::now(), sealed(fields))).is_none() {
+                entries.order.push_back(key);
+            }
+            while entries.entries.len() > self.config.capacity {
+                let Some(oldest) = entries.order.pop_front() else {
+                    break;
+                };
+                entries.entries.remove(&oldest);
+            }
+        }
+        Box::pin(async {})
+    }
+
+    fn invalidate<'a>(&'a self, tenant: &'a TenantId, id: i64) -> BoxFuture<'a, ()> {
+        let mut entries = self.entries();
+        let key = (tenant.clone(), id);
+        if entries.entries.remove(&key).is_some() {
+            entries.order.retain(|k| *k != key);
+        }
+        Box::pin(async {})
+    }
+}
+
+// How long a command may take before the cache is skipped for it
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(250);
+
+// FIELD_EDITOR_REDIS_URL, as redis://[:password@]host[:port][/db], and
+// FIELD_EDITOR_REDIS_TTL_SECS (300 unless set)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisConfig {
+    pub address: String,
+    pub password: Option<String>,
+    pub database: Option<u32>,
+    pub ttl: Duration,
+}
+
+impl RedisConfig {
+    // None unless FIELD_EDITOR_REDIS_URL is set
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("FIELD_EDITOR_REDIS_URL").ok().filter(|url| !url.is_empty())?;
+        let ttl = std::env::var("FIELD_EDITOR_REDIS_TTL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(300);
+        let mut config = Self::parse(&url).unwrap_or_else(|| panic!("FIELD_EDITOR_REDIS_URL is not a redis:// URL: {}", url));
+        config.ttl = Duration::from_secs(ttl);
+        Some(config)
+    }
+
+    fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("redis://")?;
+        let (credentials, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials), rest),
+            None => (None, rest),
+        };
+        // A user name before the colon is ignored; the password is what AUTH takes
+        let password = credentials
+            .map(|c| c.split_once(':').map_or(c, |(_, password)| password))
+            .filter(|password| !password.is_empty())
+            .map(str::to_string);
+        let (host, database) = match rest.split_once('/') {
+            Some((host, "")) => (host, None),
+            Some((host, database)) => (host, Some(database.parse().ok()?)),
+            None => (rest, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let address = if host.contains(':') { host.to_string() } else { format!("{}:6379", host) };
+        Some(RedisConfig {
+            address,
+            password,
+            database,
+            ttl: Duration::from_secs(300),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    Timeout,
+    // Redis answered with an error or something this client doesn't expect
+    Protocol(String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "Redis connection failed: {}", e),
+            CacheError::Timeout => write!(f, "Redis didn't answer in time"),
+            CacheError::Protocol(message) => write!(f, "Redis error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+// What Redis answers, of the kinds the commands below get
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+type Connection = BufStream<TcpStream>;
+
+// Records in Redis, over a single connection opened on first use and again
+// after a failure
+pub struct RedisCache {
+    config: RedisConfig,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl RedisCache {
+    pub fn new(config: RedisConfig) -> Self {
+        RedisCache {
+            config,
+            connection: Mutex::new(None),
+        }
+    }
+
+    // Send one command and read its reply, dropping the connection on failure
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply, CacheError> {
+        let mut connection = self.connection.lock().await;
+        let result = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let stream = connection.as_mut().expect("connected above");
+            send(stream, args).await?;
+            read_reply(stream).await
+        })
+        .await
+        .unwrap_or(Err(CacheError::Timeout));
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<Connection, CacheError> {
+        let mut stream = BufStream::new(TcpStream::connect(&self.config.address).await?);
+        if let Some(password) = &self.config.password {
+            send(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+            read_reply(&mut stream).await?;
+        }
+        if let Some(database) = self.config.database {
+            send(&mut stream, &[b"SELECT", database.to_string().as_bytes()]).await?;
+            read_reply(&mut stream).await?;
+        }
+        Ok(stream)
+    }
+}
+
+impl RecordCache for RedisCache {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get<'a>(&'a self, tenant: &'a TenantId, id: i64, version: i64) -> BoxFuture<'a, Option<Fields>> {
+        Box::pin(async move {
+            let key = key(tenant, id, version);
+            match self.command(&[b"GET", key.as_bytes()]).await {
+                Ok(Reply::Bulk(Some(json))) => match decode(&json) {
+                    Some(fields) => Some(fields),
+                    None => {
+                        tracing::warn!(record_id = id, "Dropping unreadable cache entry");
+                        if let Err(e) = self.command(&[b"DEL", key.as_bytes()]).await {
+                            tracing::warn!(error = %e, record_id = id, "Failed to drop cached record");
+                        }
+                        None
+                    }
+                },
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Record cache unavailable");
+                    None
+                }
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, tenant: &'a TenantId, fields: &'a Fields) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let key = key(tenant, fields.id, fields.version);
+            let ttl = self.config.ttl.as_secs().max(1).to_string();
+            if let Err(e) = self.command(&[b"SET", key.as_bytes(), &encode(fields), b"EX", ttl.as_bytes()]).await {
+                tracing::warn!(error = %e, "Failed to cache record");
+            }
+        })
+    }
+
+    // Nothing to do: a changed record has a new version, so its old entries
+    // are never read again and expire on their own
+    fn invalidate<'a>(&'a self, _tenant: &'a TenantId, _id: i64) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+// With encryption at rest on, values are cached encrypted too, in either
+// cache, and only decrypted for the read that wants them
+fn sealed(fields: &Fields) -> Fields {
+    let mut fields = fields.clone();
+    if let Some(keyring) = crate::keys::keyring() {
+        fields.values = fields.values.0.iter().map(|(name, value)| (name.as_str(), keyring.seal(value))).collect();
+    }
+    fields
+}
+
+// None if a value can't be decrypted, e.g. after its key was retired
+fn revealed(mut fields: Fields) -> Option<Fields> {
+    let values = fields.values.0.into_iter().map(|(name, value)| Some((name, crate::keys::reveal(&value).ok()?)));
+    fields.values = FieldValues(values.collect::<Option<_>>()?);
+    Some(fields)
+}
+
+fn encode(fields: &Fields) -> Vec<u8> {
+    serde_json::to_vec(&sealed(fields)).expect("Fields serialize")
+}
+
+fn decode(json: &[u8]) -> Option<Fields> {
+    revealed(serde_json::from_slice(json).ok()?)
+}
+
+async fn send(stream: &mut Connection, args: &[&[u8]]) -> Result<(), CacheError> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_reply(stream: &mut Connection) -> Result<Reply, CacheError> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(CacheError::Protocol("Connection closed".to_string()));
+    }
+    let line = line.trim_end_matches("\r\n");
+    let malformed = || CacheError::Protocol(format!("Unexpected reply {:?}", line));
+    match line.split_at_checked(1) {
+        Some(("+", status)) => Ok(Reply::Status(status.to_string())),
+        Some(("-", error)) => Err(CacheError::Protocol(error.to_string())),
+        Some((":", n)) => n.parse().map(Reply::Integer).map_err(|_| malformed()),
+        Some(("$", "-1")) => Ok(Reply::Bulk(None)),
+        Some(("$", len)) => {
+            let len: usize = len.parse().map_err(|_| malformed())?;
+            let mut data = vec![0; len + 2];
+            stream.read_exact(&mut data).await?;
+            data.truncate(len);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+// The cache configured in the environment, if any: Redis if
+// FIELD_EDITOR_REDIS_URL is set, else memory if FIELD_EDITOR_RECORD_CACHE says so
+pub fn record_cache_from_env() -> Option<Arc<dyn RecordCache>> {
+    if let Some(config) = RedisConfig::from_env() {
+        return Some(Arc::new(RedisCache::new(config)));
+    }
+    MemoryCacheConfig::from_env().map(|config| Arc::new(MemoryCache::new(config)) as Arc<dyn RecordCache>)
+}
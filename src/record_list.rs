@@ -0,0 +1,107 @@
+use crate::export::ExportButton;
+use crate::field_schema::get_field_schema;
+use crate::import::ImportForm;
+use crate::presence::get_list_metadata;
+use crate::records::{list_records, DuplicateButton};
+use crate::search::SearchBox;
+use crate::store::use_store;
+use crate::timestamp::RelativeTime;
+use leptos::prelude::*;
+
+/// Lists the records, with badges showing who is editing each one right now.
+#[component]
+pub fn RecordList() -> impl IntoView {
+    let source = RwSignal::new(());
+    let store = use_store();
+    // The rows, then everything shown about them in a single batch call.
+    // Refetched whenever the store hears of a change it couldn't apply itself.
+    let records = Resource::new(
+        move || (source.get(), store.revision()),
+        move |_| async move {
+            let records = list_records().await?;
+            let ids = records.iter().map(|r| r.id).collect();
+            store.put_metadata(get_list_metadata(ids).await.unwrap_or_default());
+            // Titles are the first field's values
+            let title_field = get_field_schema().await?.into_iter().next().map(|d| d.name);
+            Ok::<_, ServerFnError>((records, title_field))
+        },
+    );
+
+    // Editors that vanish without saying goodbye only expire, so also refresh on the heartbeat
+    #[cfg(feature = "hydrate")]
+    {
+        use crate::presence::PRESENCE_HEARTBEAT;
+
+        let interval = set_interval_with_handle(move || source.set(()), PRESENCE_HEARTBEAT).ok();
+        on_cleanup(move || {
+            if let Some(interval) = interval {
+                interval.clear();
+            }
+        });
+    }
+
+    view! {
+        <div class="field-editor">
+            <h1>"Records"</h1>
+            <ExportButton/>
+            <ImportForm/>
+            <SearchBox/>
+
+            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
+                {move || {
+                    records.get().map(|result| match result {
+                        Err(e) => view! { <div class="error">"Error loading records: " {e.to_string()}</div> }.into_any(),
+                        Ok((records, _)) if records.is_empty() => view! { <p>"There are no records."</p> }.into_any(),
+                        Ok((records, title_field)) => view! {
+                            <ul class="record-list">
+                                {records.into_iter().map(|record| {
+                                    let meta = store.metadata(record.id);
+                                    let mut title = record.title;
+                                    let mut version = meta.as_ref().map_or(record.version, |m| m.version);
+                                    let mut updated_at = meta.as_ref().and_then(|m| m.updated_at);
+                                    // A save made in this tab shows up before the server echoes it
+                                    if let Some(newer) = store.record(record.id).filter(|r| r.version > version) {
+                                        if let Some(name) = &title_field {
+                                            title = newer.values.get(name).to_string();
+                                        }
+                                        version = newer.version;
+                                        updated_at = newer.updated_at;
+                                    }
+                                    let lock_holder = meta.as_ref().and_then(|m| m.lock_holder.clone());
+                                    let others = meta.as_ref().map_or(0, |m| m.watchers - 1);
+                                    let id = record.id;
+                                    view! {
+                                        // Load the record while the pointer rests on it, so opening it is instant
+                                        <li on:mouseenter=move |_| store.prefetch(id)>
+                                            <a class="record-title" href=format!("/records/{}", record.id)>
+                                                {title}
+                                            </a>
+                                            <span class="record-meta">
+                                                "v" {version}
+                                                {updated_at.map(|at| view! { ", edited " <RelativeTime at=at/> })}
+                                            </span>
+                                            <span class="presence-badges">
+                                                {move || store.has_draft(id).then(|| view! {
+                                                    <span class="presence-badge dirty" title="You have unsaved changes">"Unsaved"</span>
+                                                })}
+                                                {lock_holder.map(|holder| view! {
+                                                    <span class="presence-badge" title="Editing now">{holder}</span>
+                                                })}
+                                                {(others > 0).then(|| view! {
+                                                    <span class="presence-badge watchers" title="Also has it open">
+                                                        "+" {others} " watching"
+                                                    </span>
+                                                })}
+                                            </span>
+                                            <DuplicateButton record_id=id/>
+                                        </li>
+                                    }
+                                }).collect_view()}
+                            </ul>
+                        }.into_any(),
+                    })
+                }}
+            </Suspense>
+        </div>
+    }
+}
@@ -0,0 +1,115 @@
+#[cfg(feature = "ssr")]
+use crate::auth::{authorize, require_csrf};
+use crate::auth::use_user_session;
+#[cfg(feature = "ssr")]
+use crate::field_editor::{db_error, open_db};
+use crate::db::RecordSummary;
+use crate::errors::EditorError;
+#[cfg(feature = "ssr")]
+use crate::policy::{Action, ResourceKind};
+#[cfg(feature = "ssr")]
+use crate::rate_limit::rate_limit;
+use crate::tasks::Tasks;
+use leptos::prelude::*;
+use leptos_router::hooks::use_navigate;
+use server_fn::error::ServerFnError;
+
+#[server(ListRecords)]
+pub async fn list_records() -> Result<Vec<RecordSummary>, ServerFnError> {
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.list_records(false).await.map_err(db_error)
+}
+
+#[server(ListTrash)]
+pub async fn list_trash() -> Result<Vec<RecordSummary>, ServerFnError> {
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.list_deleted().await.map_err(db_error)
+}
+
+#[server(DeleteRecord)]
+pub async fn delete_record(
+    id: i64,
+    // The version the user saw; deleting fails if the record has moved on since
+    expected_version: i64,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Delete, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .delete_record(id, &user.name, expected_version)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+#[server(RestoreRecord)]
+pub async fn restore_record(
+    id: i64,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Delete, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .restore_record(id, &user.name)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+// Copy a record's values into a new record; returns the new record's id
+#[server(CloneRecord)]
+pub async fn clone_record(id: i64, csrf_token: String) -> Result<i64, ServerFnError<EditorError>> {
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Records).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    db.clone_record(id, &user.name)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?
+        .ok_or_else(|| ServerFnError::WrappedServerError(EditorError::Other("The record no longer exists".to_string())))
+}
+
+/// Copies a record into a new one and opens the copy in the editor.
+#[component]
+pub fn DuplicateButton(
+    /// The record to copy.
+    record_id: i64,
+) -> impl IntoView {
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let navigate = use_navigate();
+    let tasks = Tasks::new();
+    let copying = RwSignal::new(false);
+    let error = RwSignal::new(None::<String>);
+
+    let on_duplicate = move |_| {
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        let navigate = navigate.clone();
+        copying.set(true);
+        tasks.spawn(async move {
+            let result = clone_record(record_id, csrf_token).await;
+            copying.set(false);
+            match result {
+                // Last, as opening the copy may unmount this button
+                Ok(id) => {
+                    error.set(None);
+                    navigate(&format!("/records/{}", id), Default::default());
+                }
+                Err(ServerFnError::WrappedServerError(e)) => error.set(Some(e.to_string())),
+                Err(e) => error.set(Some(e.to_string())),
+            }
+        });
+    };
+
+    view! {
+        <button on:click=on_duplicate disabled=move || copying.get() || !signed_in()>
+            {move || if copying.get() { "Duplicating..." } else { "Duplicate" }}
+        </button>
+        {move || error.get().map(|e| view! { <span class="error-message">{e}</span> })}
+    }
+}
@@ -0,0 +1,531 @@
+use crate::errors::EditorError;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+use std::collections::BTreeMap;
+
+// Admins define reports as named, read-only SQL templates in a JSON file given
+// by FIELD_EDITOR_REPORTS, e.g.
+//
+//     [{"name": "stale", "description": "Records nobody saved since a date",
+//       "sql": "SELECT id, updated_by FROM fields WHERE tenant_id = :tenant AND updated_at < :before",
+//       "params": [{"name": "before", "kind": "date"}]}]
+//
+// Values only ever reach the query as bound parameters, never as SQL text.
+// `:tenant` is always the caller's tenant and can't be set by them. Templates
+// must be a single SELECT (or WITH) and run on a connection that refuses writes.
+
+// Rows a report returns at most; the rest are cut off
+pub const REPORT_ROW_LIMIT: usize = 1000;
+
+// The parameter every template can use for the caller's tenant
+pub const TENANT_PARAM: &str = "tenant";
+
+// What a report parameter accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamKind {
+    #[default]
+    Text,
+    Integer,
+    Real,
+    // A YYYY-MM-DD date, bound as the start of that day (UTC) in milliseconds
+    Date,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportParam {
+    pub name: String,
+    #[serde(default)]
+    pub kind: ParamKind,
+}
+
+// A report as admins pick it, without its SQL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportInfo {
+    pub name: String,
+    pub description: String,
+    pub params: Vec<ReportParam>,
+}
+
+// The result of running a report; NULLs are None
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+    // More rows matched than REPORT_ROW_LIMIT
+    pub truncated: bool,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ParamKind, ReportInfo, ReportParam, ReportTable, REPORT_ROW_LIMIT, TENANT_PARAM};
+    use crate::db::DbManager;
+    use crate::errors::{EditorError, EditorServerError};
+    use crate::export::csv_value;
+    use crate::timestamp::parse_rfc3339;
+    use futures::{StreamExt, TryStreamExt};
+    use serde::Deserialize;
+    use sqlx::{Column, Row, TypeInfo, ValueRef};
+    use std::collections::BTreeMap;
+    use std::sync::OnceLock;
+
+    // A report as written in the reports file
+    #[derive(Debug, Clone, Deserialize)]
+    struct ReportTemplate {
+        name: String,
+        #[serde(default)]
+        description: String,
+        sql: String,
+        #[serde(default)]
+        params: Vec<ReportParam>,
+    }
+
+    // A checked template, with its `:name` placeholders turned into numbered ones
+    #[derive(Debug, Clone)]
+    pub struct Report {
+        pub info: ReportInfo,
+        sql: String,
+        // The parameter behind each numbered placeholder, in order
+        placeholders: Vec<String>,
+    }
+
+    // A parameter value, parsed according to its kind
+    #[derive(Debug, Clone, PartialEq)]
+    enum ParamValue {
+        Text(String),
+        Integer(i64),
+        Real(f64),
+    }
+
+    impl Report {
+        fn compile(template: ReportTemplate) -> Result<Self, String> {
+            let problem = |what: String| format!("Report {}: {}", template.name, what);
+            if template.name.is_empty() {
+                return Err("Every report needs a name".to_string());
+            }
+            let (sql, placeholders) = numbered_placeholders(&template.sql).map_err(problem)?;
+            let first_word = sql.split_whitespace().next().unwrap_or_default().to_ascii_uppercase();
+            if first_word != "SELECT" && first_word != "WITH" {
+                return Err(problem("only SELECT statements can be reports".to_string()));
+            }
+            for param in &template.params {
+                if param.name == TENANT_PARAM {
+                    return Err(problem(format!(":{} is set by the server", TENANT_PARAM)));
+                }
+                if !placeholders.contains(&param.name) {
+                    return Err(problem(format!("the SQL doesn't use the parameter {}", param.name)));
+                }
+            }
+            for name in &placeholders {
+                if name != TENANT_PARAM && !template.params.iter().any(|p| &p.name == name) {
+                    return Err(problem(format!(":{} isn't a declared parameter", name)));
+                }
+            }
+            Ok(Report {
+                info: ReportInfo {
+                    name: template.name,
+                    description: template.description,
+                    params: template.params,
+                },
+                sql,
+                placeholders,
+            })
+        }
+
+        // The values to bind for each placeholder, from what the caller entered
+        fn bind_values(&self, tenant: &str, params: &BTreeMap<String, String>) -> Result<Vec<ParamValue>, String> {
+            self.placeholders
+                .iter()
+                .map(|name| {
+                    if name == TENANT_PARAM {
+                        return Ok(ParamValue::Text(tenant.to_string()));
+                    }
+                    let kind = self.info.params.iter().find(|p| &p.name == name).map(|p| p.kind).unwrap_or_default();
+                    let text = params.get(name).map(|v| v.trim()).unwrap_or_default();
+                    let invalid = || format!("{} isn't a valid {:?} value for {}", text, kind, name);
+                    match kind {
+                        ParamKind::Text => Ok(ParamValue::Text(text.to_string())),
+                        ParamKind::Integer => text.parse().map(ParamValue::Integer).map_err(|_| invalid()),
+                        ParamKind::Real => text.parse().map(ParamValue::Real).map_err(|_| invalid()),
+                        ParamKind::Date => parse_rfc3339(&format!("{}T00:00:00Z", text))
+                            .map(ParamValue::Integer)
+                            .ok_or_else(invalid),
+                    }
+                })
+                .collect()
+        }
+    }
+
+    // Replace `:name` placeholders outside string literals and comments with
+    // `?1`, `?2`, ..., one number per distinct name. Rejects more than one statement.
+    fn numbered_placeholders(sql: &str) -> Result<(String, Vec<String>), String> {
+        let mut out = String::with_capacity(sql.len());
+        let mut names: Vec<String> = Vec::new();
+        let mut chars = sql.trim().trim_end_matches(';').chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' | '"' => {
+                    out.push(c);
+                    for next in chars.by_ref() {
+                        out.push(next);
+                        if next == c {
+                            break;
+                        }
+                    }
+                }
+                '-' if chars.peek() == Some(&'-') => {
+                    for next in chars.by_ref() {
+                        if next == '\n' {
+                            out.push('\n');
+                            break;
+                        }
+                    }
+                }
+                ';' => return Err("only one statement is allowed".to_string()),
+                ':' if chars.peek().is_some_and(|n| n.is_ascii_alphabetic() || *n == '_') => {
+                    let mut name = String::new();
+                    while let Some(&n) = chars.peek() {
+                        if !(n.is_ascii_alphanumeric() || n == '_') {
+                            break;
+                        }
+                        name.push(n);
+                        chars.next();
+                    }
+                    let index = match names.iter().position(|n| *n == name) {
+                        Some(index) => index,
+                        None => {
+                            names.push(name);
+                            names.len() - 1
+                        }
+                    };
+                    out.push_str(&format!("?{}", index + 1));
+                }
+                _ => out.push(c),
+            }
+        }
+        Ok((out, names))
+    }
+
+    // The reports admins can run, read from FIELD_EDITOR_REPORTS
+    #[derive(Debug, Clone, Default)]
+    pub struct ReportCatalog {
+        reports: Vec<Report>,
+    }
+
+    impl ReportCatalog {
+        // Parse and check every template in a reports file
+        pub fn from_json(json: &str) -> Result<Self, String> {
+            let templates: Vec<ReportTemplate> =
+                serde_json::from_str(json).map_err(|e| format!("Invalid reports file: {}", e))?;
+            let mut reports: Vec<Report> = Vec::new();
+            for template in templates {
+                let report = Report::compile(template)?;
+                if reports.iter().any(|r| r.info.name == report.info.name) {
+                    return Err(format!("There are two reports named {}", report.info.name));
+                }
+                reports.push(report);
+            }
+            Ok(ReportCatalog { reports })
+        }
+
+        // The reports in the file FIELD_EDITOR_REPORTS names; none without it
+        pub fn from_env() -> Result<Self, String> {
+            match std::env::var("FIELD_EDITOR_REPORTS") {
+                Ok(path) => std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Can't read reports file {}: {}", path, e))
+                    .and_then(|json| Self::from_json(&json)),
+                Err(_) => Ok(ReportCatalog::default()),
+            }
+        }
+
+        pub fn reports(&self) -> impl Iterator<Item = &ReportInfo> {
+            self.reports.iter().map(|r| &r.info)
+        }
+
+        pub fn get(&self, name: &str) -> Option<&Report> {
+            self.reports.iter().find(|r| r.info.name == name)
+        }
+    }
+
+    // The report catalog for this process, read once. A broken reports file
+    // is logged and leaves no reports rather than stopping the server.
+    pub fn report_catalog() -> &'static ReportCatalog {
+        static CATALOG: OnceLock<ReportCatalog> = OnceLock::new();
+        CATALOG.get_or_init(|| {
+            ReportCatalog::from_env().unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Reports disabled");
+                ReportCatalog::default()
+            })
+        })
+    }
+
+    // Why a report couldn't be run
+    #[derive(Debug)]
+    pub enum ReportError {
+        // The caller's parameters don't fit the report
+        InvalidParams(String),
+        Db(sqlx::Error),
+    }
+
+    impl From<sqlx::Error> for ReportError {
+        fn from(e: sqlx::Error) -> Self {
+            ReportError::Db(e)
+        }
+    }
+
+    impl ReportTable {
+        pub fn to_csv(&self) -> String {
+            let mut csv = String::new();
+            let mut line = |cells: Vec<String>| {
+                csv.push_str(&cells.iter().map(|c| csv_value(c)).collect::<Vec<_>>().join(","));
+                csv.push('\n');
+            };
+            line(self.columns.clone());
+            for row in &self.rows {
+                line(row.iter().map(|cell| cell.clone().unwrap_or_default()).collect());
+            }
+            csv
+        }
+    }
+
+    impl DbManager {
+        // Run a report for this manager's tenant on a connection that refuses
+        // writes, keeping the first REPORT_ROW_LIMIT rows
+        #[tracing::instrument(skip(self, report, params), fields(report = %report.info.name, tenant = %self.tenant()))]
+        pub async fn run_report(
+            &self,
+            report: &Report,
+            params: &BTreeMap<String, String>,
+        ) -> Result<ReportTable, ReportError> {
+            let values = report
+                .bind_values(self.tenant().as_str(), params)
+                .map_err(ReportError::InvalidParams)?;
+            // Taken out of the pool for good: it is closed when dropped, however
+            // the report ends, so no read-only connection goes back for a save
+            let mut conn = self.pool().acquire().await?.detach();
+            sqlx::query("PRAGMA query_only = ON").execute(&mut conn).await?;
+            let rows = {
+                let mut query = sqlx::query(&report.sql);
+                for value in values {
+                    query = match value {
+                        ParamValue::Text(text) => query.bind(text),
+                        ParamValue::Integer(n) => query.bind(n),
+                        ParamValue::Real(x) => query.bind(x),
+                    };
+                }
+                query.fetch(&mut conn).take(REPORT_ROW_LIMIT + 1).try_collect::<Vec<_>>().await
+            };
+            let mut rows = rows?;
+
+            let truncated = rows.len() > REPORT_ROW_LIMIT;
+            rows.truncate(REPORT_ROW_LIMIT);
+            let columns = rows
+                .first()
+                .map(|row| row.columns().iter().map(|c| c.name().to_string()).collect())
+                .unwrap_or_default();
+            let rows = rows
+                .iter()
+                .map(|row| (0..row.len()).map(|i| cell(row, i)).collect::<Result<_, _>>())
+                .collect::<Result<_, sqlx::Error>>()?;
+            tracing::info!(truncated, "Report run");
+            Ok(ReportTable { columns, rows, truncated })
+        }
+    }
+
+    // One value of a result row as text, going by what SQLite actually stored
+    fn cell(row: &sqlx::sqlite::SqliteRow, i: usize) -> Result<Option<String>, sqlx::Error> {
+        let raw = row.try_get_raw(i)?;
+        if raw.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(match raw.type_info().name() {
+            "INTEGER" => row.try_get::<i64, _>(i)?.to_string(),
+            "REAL" => row.try_get::<f64, _>(i)?.to_string(),
+            "BLOB" => format!("({} bytes)", row.try_get::<Vec<u8>, _>(i)?.len()),
+            _ => row.try_get::<String, _>(i)?,
+        }))
+    }
+
+    // Run a report from the catalog, checking that the caller may run reports
+    pub(crate) async fn run_named_report(name: &str, params: &BTreeMap<String, String>) -> Result<ReportTable, EditorServerError> {
+        use crate::auth::authorize;
+        use crate::field_editor::{db_error, open_db};
+        use crate::policy::{Action, ResourceKind};
+        use crate::rate_limit::rate_limit;
+
+        rate_limit().await?;
+        let db = open_db().await.map_err(EditorError::from)?;
+        authorize(&db, Action::RunReports, ResourceKind::Reports).await.map_err(EditorError::from)?;
+        let report = report_catalog()
+            .get(name)
+            .ok_or_else(|| EditorError::Other(format!("There is no report named {}", name)))?;
+        db.run_report(report, params).await.map_err(|e| match e {
+            ReportError::InvalidParams(message) => EditorError::Other(message).into(),
+            ReportError::Db(e) => EditorError::from(db_error(e)).into(),
+        })
+    }
+}
+
+// The reports admins can run
+#[server(ListReports)]
+pub async fn list_reports() -> Result<Vec<ReportInfo>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::open_db;
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::RunReports, ResourceKind::Reports).await?;
+    Ok(report_catalog().reports().cloned().collect())
+}
+
+#[server(RunReport)]
+pub async fn run_report(
+    name: String,
+    #[server(default)] params: BTreeMap<String, String>,
+) -> Result<ReportTable, ServerFnError<EditorError>> {
+    run_named_report(&name, &params).await
+}
+
+// A report's result as a CSV download
+#[server(ExportReport)]
+pub async fn export_report(
+    name: String,
+    #[server(default)] params: BTreeMap<String, String>,
+) -> Result<crate::export::ExportFile, ServerFnError<EditorError>> {
+    let table = run_named_report(&name, &params).await?;
+    Ok(crate::export::ExportFile {
+        filename: format!("{}.csv", name),
+        content_type: "text/csv".to_string(),
+        content: table.to_csv(),
+    })
+}
+
+/// Lets admins pick a report, fill in its parameters, see the result as a
+/// table and download it as CSV.
+#[component]
+pub fn Reports() -> impl IntoView {
+    let reports = Resource::new(|| (), |_| list_reports());
+    let selected = RwSignal::new(None::<ReportInfo>);
+    let params = RwSignal::new(BTreeMap::<String, String>::new());
+    let run = Action::new(|(name, params): &(String, BTreeMap<String, String>)| {
+        run_report(name.clone(), params.clone())
+    });
+    let export = Action::new(|(name, params): &(String, BTreeMap<String, String>)| {
+        let (name, params) = (name.clone(), params.clone());
+        async move {
+            let file = export_report(name, params).await?;
+            #[cfg(feature = "hydrate")]
+            crate::export::download(&file)
+                .map_err(|e| ServerFnError::WrappedServerError(EditorError::Other(format!("{:?}", e))))?;
+            #[cfg(not(feature = "hydrate"))]
+            let _ = file;
+            Ok::<_, ServerFnError<EditorError>>(())
+        }
+    });
+    let request = move || selected.get_untracked().map(|r| (r.name, params.get_untracked()));
+
+    view! {
+        <div class="field-editor reports">
+            <h2>"Reports"</h2>
+            <Suspense fallback=|| view! { <p>"Loading reports..."</p> }>
+                {move || reports.get().map(|result| match result {
+                    Err(e) => view! { <div class="error-message">{e.to_string()}</div> }.into_any(),
+                    Ok(list) if list.is_empty() => view! { <p>"No reports are set up."</p> }.into_any(),
+                    Ok(list) => {
+                        let options = list.clone();
+                        view! {
+                            <select on:change=move |ev| {
+                                let name = event_target_value(&ev);
+                                params.set(BTreeMap::new());
+                                selected.set(options.iter().find(|r| r.name == name).cloned());
+                            }>
+                                <option value="" selected=move || selected.get().is_none()>"Pick a report"</option>
+                                {list.into_iter().map(|r| {
+                                    let label = r.name.clone();
+                                    view! { <option value=r.name>{label}</option> }
+                                }).collect_view()}
+                            </select>
+                        }
+                        .into_any()
+                    }
+                })}
+            </Suspense>
+
+            {move || selected.get().map(|report| view! {
+                <p class="record-meta">{report.description}</p>
+                {report.params.into_iter().map(|param| {
+                    let name = param.name.clone();
+                    let input_type = match param.kind {
+                        ParamKind::Text => "text",
+                        ParamKind::Integer | ParamKind::Real => "number",
+                        ParamKind::Date => "date",
+                    };
+                    view! {
+                        <div class="form-group">
+                            <label for=format!("report-param-{}", param.name)>{param.name.clone()}</label>
+                            <input
+                                id=format!("report-param-{}", param.name)
+                                type=input_type
+                                on:input=move |ev| {
+                                    let value = event_target_value(&ev);
+                                    params.update(|p| {
+                                        p.insert(name.clone(), value);
+                                    });
+                                }
+                            />
+                        </div>
+                    }
+                }).collect_view()}
+                <button
+                    on:click=move |_| {
+                        if let Some(request) = request() {
+                            run.dispatch(request);
+                        }
+                    }
+                    disabled=move || run.pending().get()
+                >
+                    {move || if run.pending().get() { "Running..." } else { "Run" }}
+                </button>
+                <button
+                    on:click=move |_| {
+                        if let Some(request) = request() {
+                            export.dispatch(request);
+                        }
+                    }
+                    disabled=move || export.pending().get()
+                >
+                    "Export CSV"
+                </button>
+            })}
+
+            {move || export.value().get().and_then(Result::err).map(|e| view! {
+                <div class="error-message">"Export failed: " {e.to_string()}</div>
+            })}
+
+            {move || run.value().get().map(|result| match result {
+                Err(e) => view! { <div class="error-message">{e.to_string()}</div> }.into_any(),
+                Ok(table) if table.rows.is_empty() => view! { <p>"No rows."</p> }.into_any(),
+                Ok(table) => view! {
+                    <table class="report-table">
+                        <thead>
+                            <tr>{table.columns.into_iter().map(|c| view! { <th>{c}</th> }).collect_view()}</tr>
+                        </thead>
+                        <tbody>
+                            {table.rows.into_iter().map(|row| view! {
+                                <tr>{row.into_iter().map(|cell| view! { <td>{cell.unwrap_or_default()}</td> }).collect_view()}</tr>
+                            }).collect_view()}
+                        </tbody>
+                    </table>
+                    {table.truncated.then(|| view! {
+                        <p class="record-meta">{format!("Only the first {} rows are shown.", REPORT_ROW_LIMIT)}</p>
+                    })}
+                }
+                .into_any(),
+            })}
+        </div>
+    }
+}
@@ -0,0 +1,517 @@
+use crate::api_tokens::{ApiAccess, TokenGrant};
+use crate::auth::{User, SESSION_COOKIE};
+use crate::change_requests::{needs_approval, APPROVAL_REQUIRED};
+use crate::db::{ConcurrencyMode, DbManager, FieldValues, Fields, RecordChange, RecordUpdateResult};
+use crate::policy::{allowed, denial, Action, ResourceKind};
+use crate::rate_limit::RateLimiter;
+use crate::tenant::TenantDb;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+use utoipa::OpenApi;
+
+// Plain JSON access to records for clients that don't speak server functions.
+// The record version is the ETag: reads return it, and writes must send it back
+// in If-Match so they can't overwrite a change they haven't seen. In the
+// timestamp concurrency mode the ETag is the record's `updated_at` instead.
+
+// The body of every error response
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ApiError {
+    error: String,
+    // Set on 429 responses; the same value as the Retry-After header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+// The body of a 202 response: the save was kept for an approver instead of applied
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ChangeRequested {
+    change_request_id: i64,
+}
+
+// A concurrency token (a version or a timestamp) as an ETag
+pub(crate) fn etag(token: impl std::fmt::Display) -> String {
+    format!("\"{}\"", token)
+}
+
+// Whether If-None-Match names the current ETag, so the client's copy is still good
+pub(crate) fn not_modified(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        })
+}
+
+// Drop the body of every 304. Server functions always send what they return,
+// so `get_fields` answering 304 would otherwise still send the whole record.
+pub async fn bodiless_not_modified(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody, ()>>, actix_web::Error> {
+    let res = next.call(req).await?;
+    if res.status() == actix_web::http::StatusCode::NOT_MODIFIED {
+        return Ok(res.map_body(|_, _| ()).map_into_right_body());
+    }
+    Ok(res.map_into_left_body())
+}
+
+pub(crate) fn error(status: actix_web::http::StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ApiError {
+        error: message.to_string(),
+        retry_after_secs: None,
+    })
+}
+
+pub(crate) fn too_many_requests(retry_after_secs: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, HeaderValue::from(retry_after_secs)))
+        .json(ApiError {
+            error: "Too many requests".to_string(),
+            retry_after_secs: Some(retry_after_secs),
+        })
+}
+
+pub(crate) fn internal_error(e: sqlx::Error) -> HttpResponse {
+    tracing::error!(error = %e, "REST request failed");
+    error(
+        actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error",
+    )
+}
+
+// Caches may keep a record but must ask whether its version is still current
+const CACHE_CONTROL: &str = "no-cache";
+
+fn fields_response(db: &DbManager, fields: Fields) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::ETAG, etag(db.concurrency_token(&fields))))
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .json(fields)
+}
+
+// The session id from the cookie, or from `Authorization: Bearer <session id>`
+// for clients that don't keep cookies
+pub(crate) fn session_id(req: &HttpRequest) -> Option<String> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        return Some(cookie.value().to_string());
+    }
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+// Who a request is from, with the key to rate limit it by: the user an API
+// token acts for, once the token middleware has let it through, or the user
+// of the session
+pub(crate) async fn request_user(db: &DbManager, req: &HttpRequest) -> Result<Option<(User, String)>, sqlx::Error> {
+    if let Some(grant) = req.extensions().get::<TokenGrant>() {
+        return Ok(Some((User::named(grant.user.as_str()), format!("token:{}", grant.token_id))));
+    }
+    let Some(session_id) = session_id(req) else {
+        return Ok(None);
+    };
+    Ok(db
+        .session_user(&session_id)
+        .await?
+        .map(|user| (user, format!("session:{}", session_id))))
+}
+
+// Who a request is from, as `request_user` tells, if the policy lets them do
+// `action` to `kind`; the response to send back otherwise
+pub(crate) async fn authorized_user(
+    db: &DbManager,
+    req: &HttpRequest,
+    action: Action,
+    kind: ResourceKind,
+) -> Result<(User, String), HttpResponse> {
+    use actix_web::http::StatusCode;
+
+    let (user, rate_key) = match request_user(db, req).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(error(StatusCode::UNAUTHORIZED, "You must be logged in to do that")),
+        Err(e) => return Err(internal_error(e)),
+    };
+    if !allowed(db, &user, action, kind).await {
+        return Err(error(StatusCode::FORBIDDEN, &denial(action, kind)));
+    }
+    Ok((user, rate_key))
+}
+
+// The concurrency token an If-Match header names; `*` matches whatever is current
+fn if_match(req: &HttpRequest, current: String) -> Option<String> {
+    let value = req.headers().get(header::IF_MATCH)?.to_str().ok()?.trim();
+    if value == "*" {
+        return Some(current);
+    }
+    Some(value.trim_start_matches("W/").trim_matches('"').to_string())
+}
+
+async fn load(db: &DbManager, id: i64) -> Result<Fields, HttpResponse> {
+    db.get_fields(id).await.map_err(|e| match e {
+        sqlx::Error::RowNotFound => error(actix_web::http::StatusCode::NOT_FOUND, "No such record"),
+        e => internal_error(e),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/fields/{id}",
+    tag = "fields",
+    params(
+        ("id" = i64, Path, description = "Record id"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag from an earlier read"),
+    ),
+    responses(
+        (status = 200, description = "The record; its version is in the ETag header", body = Fields),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Not allowed to see the record, or outside the API token's scopes", body = ApiError),
+        (status = 404, description = "No such record", body = ApiError),
+    ),
+    security(("session" = []))
+)]
+#[actix_web::get("/api/fields/{id}")]
+pub async fn get_fields(db: TenantDb, req: HttpRequest, id: web::Path<i64>) -> HttpResponse {
+    let id = id.into_inner();
+    if let Err(response) = authorized_user(&db, &req, Action::View, ResourceKind::Record(id)).await {
+        return response;
+    }
+    let fields = match load(&db, id).await {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let etag = etag(db.concurrency_token(&fields));
+    if not_modified(&req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .finish();
+    }
+    fields_response(&db, fields)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/fields/{id}",
+    tag = "fields",
+    params(
+        ("id" = i64, Path, description = "Record id"),
+        ("If-Match" = String, Header, description = "ETag of the version being replaced, or `*`"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key are applied once"),
+    ),
+    request_body = FieldValues,
+    responses(
+        (status = 200, description = "The saved record with its new ETag", body = Fields),
+        (status = 202, description = "Saves need approval; the values were kept as a change request", body = ChangeRequested),
+        (status = 400, description = "A field the record doesn't have, or a required field left blank", body = ApiError),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Not allowed to change the record, or outside the API token's scopes", body = ApiError),
+        (status = 404, description = "No such record", body = ApiError),
+        (status = 412, description = "The record changed since the given ETag", body = ApiError),
+        (status = 428, description = "If-Match is missing", body = ApiError),
+        (status = 429, description = "Too many requests", body = ApiError),
+    ),
+    security(("session" = []))
+)]
+#[actix_web::put("/api/fields/{id}")]
+pub async fn put_fields(
+    db: TenantDb,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    body: web::Json<FieldValues>,
+) -> HttpResponse {
+    // Replacing the record: fields left out are emptied
+    let values = body.into_inner();
+    write_fields(&db, &req, id.into_inner(), |current| {
+        current.values.names().map(|name| (name, "")).collect::<FieldValues>().updated_with(&values)
+    })
+    .await
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/fields/{id}",
+    tag = "fields",
+    params(
+        ("id" = i64, Path, description = "Record id"),
+        ("If-Match" = String, Header, description = "ETag of the version being changed, or `*`"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key are applied once"),
+    ),
+    request_body(content = FieldValues, description = "The fields to change; fields left out keep their values"),
+    responses(
+        (status = 200, description = "The saved record with its new ETag", body = Fields),
+        (status = 202, description = "Saves need approval; the values were kept as a change request", body = ChangeRequested),
+        (status = 400, description = "A field the record doesn't have, or a required field left blank", body = ApiError),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Not allowed to change the record, or outside the API token's scopes", body = ApiError),
+        (status = 404, description = "No such record", body = ApiError),
+        (status = 412, description = "The record changed since the given ETag", body = ApiError),
+        (status = 428, description = "If-Match is missing", body = ApiError),
+        (status = 429, description = "Too many requests", body = ApiError),
+    ),
+    security(("session" = []))
+)]
+#[actix_web::patch("/api/fields/{id}")]
+pub async fn patch_fields(
+    db: TenantDb,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    body: web::Json<FieldValues>,
+) -> HttpResponse {
+    let patch = body.into_inner();
+    write_fields(&db, &req, id.into_inner(), |current| current.values.updated_with(&patch)).await
+}
+
+// The shared write path: authenticate, rate limit, check the precondition, save
+async fn write_fields(
+    db: &DbManager,
+    req: &HttpRequest,
+    id: i64,
+    values: impl FnOnce(&Fields) -> FieldValues,
+) -> HttpResponse {
+    use actix_web::http::StatusCode;
+
+    let (user, rate_key) = match authorized_user(db, req, Action::Edit, ResourceKind::Record(id)).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        if let Err(retry_after_secs) = limiter.check(&rate_key) {
+            return too_many_requests(retry_after_secs);
+        }
+    }
+
+    let current = match load(db, id).await {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    match db.is_expired(id).await {
+        Ok(false) => {}
+        Ok(true) => return error(StatusCode::CONFLICT, "The record has expired and can no longer be changed"),
+        Err(e) => return internal_error(e),
+    }
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return error(
+            StatusCode::PRECONDITION_REQUIRED,
+            "Send the record's ETag in If-Match to update it",
+        );
+    }
+    let stale = || {
+        HttpResponse::PreconditionFailed()
+            .insert_header((header::ETAG, etag(db.concurrency_token(&current))))
+            .json(ApiError {
+                error: "The record was changed by someone else".to_string(),
+                retry_after_secs: None,
+            })
+    };
+    let Some(token) = if_match(req, db.concurrency_token(&current)) else {
+        return error(StatusCode::PRECONDITION_FAILED, "If-Match is not a version of this record");
+    };
+    let expected_version = match db.expected_version(&current, &token) {
+        Ok(Some(version)) => version,
+        Ok(None) => return stale(),
+        Err(message) => return error(StatusCode::PRECONDITION_FAILED, &format!("If-Match: {}", message)),
+    };
+
+    let idempotency_key = req
+        .headers()
+        .get("Idempotency-Key")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let values = values(&current);
+    // The current record has every field its tenant defines
+    if let Some(unknown) = values.names().find(|name| !current.values.contains(name)) {
+        return error(StatusCode::BAD_REQUEST, &format!("There is no field named {}", unknown));
+    }
+    match db.blank_required_fields(&values).await {
+        Ok(blank) if blank.is_empty() => {}
+        Ok(blank) => return error(StatusCode::BAD_REQUEST, &format!("{} is required", blank[0])),
+        Err(e) => return internal_error(e),
+    }
+    if needs_approval(db, &user, id).await {
+        return match db
+            .request_change(id, &user.name, &values, expected_version, idempotency_key.as_deref())
+            .await
+        {
+            Ok(change_request_id) => HttpResponse::Accepted().json(ChangeRequested { change_request_id }),
+            Err(e) => internal_error(e),
+        };
+    }
+    match db
+        .update_fields(id, &user.name, &values, expected_version, idempotency_key.as_deref())
+        .await
+    {
+        Ok(true) => match load(db, id).await {
+            Ok(fields) => fields_response(db, fields),
+            Err(response) => response,
+        },
+        // The record moved on since the client read it
+        Ok(false) => stale(),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/fields/batch",
+    tag = "fields",
+    request_body(
+        content = Vec<RecordChange>,
+        description = "Changes to several records, each with the version (or in timestamp mode the updated_at) it is based on; fields left out keep their values",
+    ),
+    responses(
+        (status = 200, description = "Per change, in order: saved with its new version, or conflicted", body = Vec<RecordUpdateResult>),
+        (status = 400, description = "A record appears twice, has no such field, a required field left blank, or a missing or malformed expected_updated_at", body = ApiError),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Not allowed to change one of the records, outside the API token's scopes, or saves need approval", body = ApiError),
+        (status = 409, description = "One of the records has expired", body = ApiError),
+        (status = 429, description = "Too many requests", body = ApiError),
+    ),
+    security(("session" = []))
+)]
+#[actix_web::post("/api/fields/batch")]
+pub async fn update_batch(db: TenantDb, req: HttpRequest, body: web::Json<Vec<RecordChange>>) -> HttpResponse {
+    use actix_web::http::StatusCode;
+
+    let mut changes = body.into_inner();
+    let (user, rate_key) = match request_user(&db, &req).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return error(StatusCode::UNAUTHORIZED, "You must be logged in to do that"),
+        Err(e) => return internal_error(e),
+    };
+    let grant = req.extensions().get::<TokenGrant>().cloned();
+    for change in &changes {
+        let id = change.id;
+        if grant.as_ref().is_some_and(|grant| !grant.scopes.permits(ApiAccess::Write(id))) {
+            return error(StatusCode::FORBIDDEN, "The API token's scopes don't allow this");
+        }
+        if !allowed(&db, &user, Action::Edit, ResourceKind::Record(id)).await {
+            return error(StatusCode::FORBIDDEN, &denial(Action::Edit, ResourceKind::Record(id)));
+        }
+        if needs_approval(&db, &user, id).await {
+            return error(StatusCode::FORBIDDEN, APPROVAL_REQUIRED);
+        }
+    }
+
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        if let Err(retry_after_secs) = limiter.check(&rate_key) {
+            return too_many_requests(retry_after_secs);
+        }
+    }
+
+    let mut ids: Vec<i64> = changes.iter().map(|c| c.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() != changes.len() {
+        return error(StatusCode::BAD_REQUEST, "Each record can only appear once in a batch");
+    }
+    let definitions = match db.field_definitions().await {
+        Ok(definitions) => definitions,
+        Err(e) => return internal_error(e),
+    };
+    for change in &mut changes {
+        if db.concurrency_mode() == ConcurrencyMode::Timestamp {
+            let Some(token) = change.expected_updated_at.as_deref() else {
+                return error(StatusCode::BAD_REQUEST, &format!("Record {} needs expected_updated_at", change.id));
+            };
+            change.expected_version = match db.expected_version_of(change.id, token).await {
+                Ok(Ok(version)) => version,
+                Ok(Err(message)) => return error(StatusCode::BAD_REQUEST, &message),
+                Err(e) => return internal_error(e),
+            };
+        }
+        match db.is_expired(change.id).await {
+            Ok(false) => {}
+            Ok(true) => return error(StatusCode::CONFLICT, &format!("Record {} has expired", change.id)),
+            Err(e) => return internal_error(e),
+        }
+        if let Some(unknown) = change.values.names().find(|name| !definitions.iter().any(|d| d.name == *name)) {
+            return error(StatusCode::BAD_REQUEST, &format!("There is no field named {}", unknown));
+        }
+        match db.blank_required_fields(&change.values).await {
+            Ok(blank) if blank.is_empty() => {}
+            Ok(blank) => return error(StatusCode::BAD_REQUEST, &format!("{} is required", blank[0])),
+            Err(e) => return internal_error(e),
+        }
+    }
+
+    match db.update_each(&user.name, &changes).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => internal_error(e),
+    }
+}
+
+// The OpenAPI description of the routes above
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Field Editor API", description = "Read and write records with optimistic concurrency via ETags"),
+    paths(get_fields, put_fields, patch_fields, update_batch),
+    components(schemas(Fields, FieldValues, RecordChange, RecordUpdateResult, ApiError, ChangeRequested)),
+    modifiers(&SessionAuth),
+    tags((name = "fields", description = "Records and the values of their fields"))
+)]
+pub struct ApiDoc;
+
+// Sessions come from logging in through the app; send the id, or an API token,
+// as a bearer token
+struct SessionAuth;
+
+impl utoipa::Modify for SessionAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "The session id, as set in the field_editor_session cookie, or an API token (fet_...)",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[actix_web::get("/api/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// Swagger UI for exploring the API, loaded from a CDN so nothing is bundled
+#[actix_web::get("/api/docs")]
+pub async fn api_docs() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8"/>
+    <title>Field Editor API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css"/>
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.onload = () => {
+            window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+        };
+    </script>
+</body>
+</html>"##,
+    )
+}
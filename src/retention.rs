@@ -0,0 +1,153 @@
+use crate::db::DbManager;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Audit log entries deleted per statement, so pruning never holds the write lock for long
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+// How much of the audit log to keep. Each record's latest entry is always
+// kept, as are entries the next incremental backup still has to pick up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Entries older than this are pruned
+    pub max_age: Option<Duration>,
+    // Entries of all but a record's latest this many versions are pruned
+    pub max_versions: Option<u32>,
+}
+
+impl RetentionPolicy {
+    // FIELD_EDITOR_HISTORY_RETENTION_DAYS and FIELD_EDITOR_HISTORY_MAX_VERSIONS;
+    // the whole log is kept unless one is set
+    pub fn from_env() -> Self {
+        let days: Option<u64> = std::env::var("FIELD_EDITOR_HISTORY_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&days| days > 0);
+        let max_versions: Option<u32> = std::env::var("FIELD_EDITOR_HISTORY_MAX_VERSIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&versions| versions > 0);
+        RetentionPolicy {
+            max_age: days.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            max_versions,
+        }
+    }
+
+    pub fn prunes(&self) -> bool {
+        self.max_age.is_some() || self.max_versions.is_some()
+    }
+}
+
+// The retention policy for this process, read from the environment once
+pub fn retention_policy() -> &'static RetentionPolicy {
+    static POLICY: OnceLock<RetentionPolicy> = OnceLock::new();
+    POLICY.get_or_init(RetentionPolicy::from_env)
+}
+
+// How many audit log entries one run pruned, by the rule that pruned them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub by_versions: u64,
+    pub by_age: u64,
+}
+
+impl PruneReport {
+    pub fn total(&self) -> u64 {
+        self.by_versions + self.by_age
+    }
+}
+
+// Pruning totals since the server started, for /metrics
+#[derive(Debug, Default)]
+pub struct PruneMetrics {
+    pub runs: AtomicU64,
+    pub pruned_by_versions: AtomicU64,
+    pub pruned_by_age: AtomicU64,
+    // When the last run finished, in milliseconds since the epoch; 0 before the first
+    pub last_run_at: AtomicI64,
+}
+
+impl PruneMetrics {
+    fn record(&self, report: &PruneReport, now: i64) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.pruned_by_versions.fetch_add(report.by_versions, Ordering::Relaxed);
+        self.pruned_by_age.fetch_add(report.by_age, Ordering::Relaxed);
+        self.last_run_at.store(now, Ordering::Relaxed);
+    }
+}
+
+pub fn prune_metrics() -> &'static PruneMetrics {
+    static METRICS: OnceLock<PruneMetrics> = OnceLock::new();
+    METRICS.get_or_init(PruneMetrics::default)
+}
+
+impl DbManager {
+    // Delete the audit log entries `policy` no longer keeps, across all tenants
+    #[tracing::instrument(skip(self))]
+    pub async fn prune_history(&self, policy: &RetentionPolicy) -> Result<PruneReport, sqlx::Error> {
+        let mut report = PruneReport::default();
+        // Once incremental backups are in use, entries after the last backup
+        // must stay until a backup has them
+        let backed_up: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM backup_markers)")
+            .fetch_one(self.pool())
+            .await?;
+        let prunable_through = match backed_up {
+            true => self.last_backup_through().await?,
+            false => i64::MAX,
+        };
+
+        if let Some(max_versions) = policy.max_versions {
+            report.by_versions = self
+                .prune_history_batches(
+                    r#"
+                    h.version <= (SELECT MAX(v.version) FROM fields_history v WHERE v.record_id = h.record_id) - ?
+                    "#,
+                    i64::from(max_versions),
+                    prunable_through,
+                )
+                .await?;
+        }
+        if let Some(max_age) = policy.max_age {
+            report.by_age = self
+                .prune_history_batches("h.changed_at < ?", self.now() - max_age.as_millis() as i64, prunable_through)
+                .await?;
+        }
+
+        prune_metrics().record(&report, self.now());
+        Ok(report)
+    }
+
+    // Delete entries matching `condition` (on `h`, with one parameter) batch by
+    // batch, sparing each record's latest entry. Returns how many were deleted.
+    async fn prune_history_batches(&self, condition: &str, param: i64, through: i64) -> Result<u64, sqlx::Error> {
+        let sql = format!(
+            r#"
+            DELETE FROM fields_history WHERE id IN (
+                SELECT h.id FROM fields_history h
+                WHERE h.id <= ? AND {}
+                AND h.id < (SELECT MAX(l.id) FROM fields_history l WHERE l.record_id = h.record_id)
+                LIMIT ?
+            )
+            "#,
+            condition
+        );
+        let mut pruned = 0;
+        loop {
+            let deleted = self
+                .retrying("prune_history", || {
+                    sqlx::query(&sql)
+                        .bind(through)
+                        .bind(param)
+                        .bind(PRUNE_BATCH_SIZE)
+                        .execute(self.pool())
+                })
+                .await?
+                .rows_affected();
+            pruned += deleted;
+            if deleted < PRUNE_BATCH_SIZE as u64 {
+                return Ok(pruned);
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::future::Future;
+use std::time::Duration;
+
+// Some database errors go away if the operation is simply tried again: another
+// writer held the lock for longer than the busy timeout, or a connection broke.
+// Those are retried with an exponential backoff; everything else, from a
+// missing row to a constraint violation, is returned at once. Operations are
+// only retried as a whole, with their transaction rolled back in between.
+
+// SQLITE_BUSY and SQLITE_LOCKED; extended codes such as SQLITE_BUSY_SNAPSHOT
+// carry these in their low byte
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+// Whether an error may not happen again on the next attempt
+pub fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)),
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Tries in total, the first one included; 1 turns retrying off
+    pub max_attempts: u32,
+    // The wait before the first retry, doubled for every one after it
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Never retry
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // The defaults, overridden by FIELD_EDITOR_DB_RETRY_ATTEMPTS,
+    // FIELD_EDITOR_DB_RETRY_BACKOFF_MS and FIELD_EDITOR_DB_RETRY_MAX_BACKOFF_MS where set
+    pub fn from_env() -> Self {
+        let defaults = RetryPolicy::default();
+        let var = |name: &str| std::env::var(name).ok();
+        let millis = |name: &str| var(name).and_then(|v| v.parse().ok()).map(Duration::from_millis);
+        RetryPolicy {
+            max_attempts: var("FIELD_EDITOR_DB_RETRY_ATTEMPTS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_attempts)
+                .max(1),
+            initial_backoff: millis("FIELD_EDITOR_DB_RETRY_BACKOFF_MS").unwrap_or(defaults.initial_backoff),
+            max_backoff: millis("FIELD_EDITOR_DB_RETRY_MAX_BACKOFF_MS").unwrap_or(defaults.max_backoff),
+        }
+    }
+
+    // The wait before retry number `retry` (from 0). Somewhere in the upper half
+    // of the backoff, so writers that collided don't collide again in lockstep.
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let mut random = [0u8; 4];
+        getrandom::getrandom(&mut random).expect("Failed to generate retry jitter");
+        let fraction = u32::from_be_bytes(random) as f64 / u32::MAX as f64;
+        backoff.mul_f64(0.5 + fraction / 2.0)
+    }
+
+    // Run `attempt` until it succeeds, fails with an error that isn't transient,
+    // or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, operation: &'static str, mut attempt: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            match attempt().await {
+                Err(e) if tries < self.max_attempts && is_transient(&e) => {
+                    let delay = self.delay(tries - 1);
+                    tracing::warn!(operation, attempt = tries, ?delay, error = %e, "Transient database error, retrying");
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) if tries > 1 => {
+                    tracing::warn!(operation, attempts = tries, error = %e, "Database operation failed after retrying");
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+}
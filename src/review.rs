@@ -0,0 +1,318 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::{format_timestamp, parse_rfc3339};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Records can be given a date to be reviewed by. A record that hasn't been
+// updated by then is due: the background runner reminds everyone who has
+// changed it, again every reminder interval, until someone saves it, snoozes
+// the reminders or moves the date.
+
+// Longest a review can be snoozed for at once
+pub const MAX_SNOOZE_DAYS: u32 = 90;
+
+// A record's review date and whether it is due right now
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordReview {
+    pub review_by: Option<i64>,
+    // The date has passed without the record being updated
+    pub due: bool,
+    // Reminders are put off until then
+    pub snoozed_until: Option<i64>,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::RecordReview;
+    use crate::db::{append_history, DbManager};
+    use crate::events::FieldEvent;
+    use crate::tenant::TenantId;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ReviewPolicy {
+        // How long after one reminder about a record the next one goes out
+        pub reminder_interval: Duration,
+    }
+
+    impl ReviewPolicy {
+        // FIELD_EDITOR_REVIEW_REMINDER_HOURS, 24 by default
+        pub fn from_env() -> Self {
+            let hours: u64 = std::env::var("FIELD_EDITOR_REVIEW_REMINDER_HOURS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(24);
+            ReviewPolicy {
+                reminder_interval: Duration::from_secs(hours * 60 * 60),
+            }
+        }
+    }
+
+    // The review policy for this process, read from the environment once
+    pub fn review_policy() -> &'static ReviewPolicy {
+        static POLICY: OnceLock<ReviewPolicy> = OnceLock::new();
+        POLICY.get_or_init(ReviewPolicy::from_env)
+    }
+
+    impl DbManager {
+        // A record's review date; None if the record isn't this tenant's
+        pub async fn record_review(&self, id: i64) -> Result<Option<RecordReview>, sqlx::Error> {
+            let row: Option<(Option<i64>, Option<i64>, Option<i64>)> = sqlx::query_as(
+                "SELECT review_by, review_snoozed_until, updated_at FROM fields WHERE id = ? AND tenant_id = ?",
+            )
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(self.pool())
+            .await?;
+            let now = self.now();
+            Ok(row.map(|(review_by, snoozed_until, updated_at)| RecordReview {
+                review_by,
+                due: review_by.is_some_and(|at| at <= now && updated_at.unwrap_or(0) < at),
+                snoozed_until: snoozed_until.filter(|&until| until > now),
+            }))
+        }
+
+        // Give a record a review date, or remove it with None. A new date also
+        // ends any snooze. Returns false if the record isn't this tenant's.
+        #[tracing::instrument(skip(self))]
+        pub async fn set_review_by(&self, id: i64, user: &str, review_by: Option<i64>) -> Result<bool, sqlx::Error> {
+            self.retrying("set_review_by", || async {
+                let mut tx = self.pool().begin().await?;
+                let Some(record) = self.scope(&mut tx, id).await? else {
+                    return Ok(false);
+                };
+                sqlx::query(
+                    "UPDATE fields SET review_by = ?, review_snoozed_until = NULL, review_reminded_at = NULL WHERE id = ?",
+                )
+                .bind(review_by)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+                let event = FieldEvent::ReviewBySet { review_by };
+                append_history(&mut tx, record, &event, Some(user), self.now()).await?;
+                tx.commit().await?;
+                Ok(true)
+            })
+            .await
+        }
+
+        // Put off review reminders for a record until `until`. Returns false
+        // if the record isn't this tenant's.
+        #[tracing::instrument(skip(self))]
+        pub async fn snooze_review(&self, id: i64, user: &str, until: i64) -> Result<bool, sqlx::Error> {
+            self.retrying("snooze_review", || async {
+                let mut tx = self.pool().begin().await?;
+                let Some(record) = self.scope(&mut tx, id).await? else {
+                    return Ok(false);
+                };
+                sqlx::query("UPDATE fields SET review_snoozed_until = ? WHERE id = ?")
+                    .bind(until)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                let event = FieldEvent::ReviewSnoozed { until };
+                append_history(&mut tx, record, &event, Some(user), self.now()).await?;
+                tx.commit().await?;
+                Ok(true)
+            })
+            .await
+        }
+
+        // Remind the users who have changed them about the records of every
+        // tenant that are due for review, unless snoozed or reminded within
+        // `interval`. Returns how many records reminders went out for.
+        #[tracing::instrument(skip(self))]
+        pub async fn remind_due_reviews(&self, interval: Duration) -> Result<usize, sqlx::Error> {
+            self.retrying("remind_due_reviews", || async {
+                let now = self.now();
+                let mut tx = self.pool().begin().await?;
+                let due: Vec<(i64, String, i64)> = sqlx::query_as(
+                    r#"
+                    UPDATE fields SET review_reminded_at = ?1
+                    WHERE review_by <= ?1 AND COALESCE(updated_at, 0) < review_by AND deleted_at IS NULL
+                      AND COALESCE(review_snoozed_until, 0) <= ?1
+                      AND COALESCE(review_reminded_at, 0) <= ?1 - ?2
+                    RETURNING id, tenant_id, review_by
+                    "#,
+                )
+                .bind(now)
+                .bind(interval.as_millis() as i64)
+                .fetch_all(&mut *tx)
+                .await?;
+                for (id, tenant, review_by) in &due {
+                    let recipients: Vec<String> = sqlx::query_scalar(
+                        r#"
+                        SELECT DISTINCT changed_by FROM fields_history
+                        WHERE record_id = ? AND changed_by IS NOT NULL
+                        ORDER BY changed_by
+                        "#,
+                    )
+                    .bind(id)
+                    .fetch_all(&mut *tx)
+                    .await?;
+                    let db = self.clone().with_tenant(TenantId::parse(tenant).unwrap_or_default());
+                    if let Some(record) = db.scope(&mut tx, *id).await? {
+                        let event = FieldEvent::ReviewDue {
+                            review_by: *review_by,
+                            recipients,
+                        };
+                        append_history(&mut tx, record, &event, None, now).await?;
+                    }
+                }
+                tx.commit().await?;
+                Ok(due.len())
+            })
+            .await
+        }
+    }
+}
+
+#[server(GetRecordReview)]
+pub async fn get_record_review(id: i64) -> Result<Option<RecordReview>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.record_review(id).await.map_err(db_error)
+}
+
+// Set or remove (None) the review date of a record, in milliseconds since the epoch
+#[server(SetRecordReviewBy)]
+pub async fn set_record_review_by(
+    id: i64,
+    review_by: Option<i64>,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .set_review_by(id, &user.name, review_by)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+// Put off review reminders for a record by a number of days
+#[server(SnoozeReview)]
+pub async fn snooze_review(id: i64, days: u32, csrf_token: String) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    if !(1..=MAX_SNOOZE_DAYS).contains(&days) {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(format!(
+            "Reviews can be snoozed for 1 to {} days",
+            MAX_SNOOZE_DAYS
+        ))));
+    }
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    let until = db.now() + i64::from(days) * 24 * 60 * 60 * 1000;
+    Ok(db
+        .snooze_review(id, &user.name, until)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+/// Says when a record is due for review, lets signed-in users snooze the
+/// reminders once it is, and set or remove the date.
+#[component]
+pub fn ReviewNotice(
+    /// The record.
+    id: i64,
+    /// Set after the date changes, to make the editor reload.
+    refresh: RwSignal<()>,
+) -> impl IntoView {
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let review = Resource::new(move || refresh.get(), move |_| get_record_review(id));
+    let date = RwSignal::new(String::new());
+    let failed = RwSignal::new(false);
+    let tasks = Tasks::new();
+
+    let current = move || review.get().and_then(Result::ok).flatten();
+
+    let done = move |result: Result<bool, ServerFnError<EditorError>>| match result {
+        Ok(true) => {
+            date.set(String::new());
+            refresh.set(());
+        }
+        Ok(false) | Err(_) => failed.set(true),
+    };
+    let save = move |review_by: Option<i64>| {
+        failed.set(false);
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        tasks.spawn(async move { done(set_record_review_by(id, review_by, csrf_token).await) });
+    };
+    let snooze = move |days: u32| {
+        failed.set(false);
+        let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+        tasks.spawn(async move { done(snooze_review(id, days, csrf_token).await) });
+    };
+    let on_set = move |_| {
+        // A date input gives YYYY-MM-DD; the review is due at the start of that day, UTC
+        match parse_rfc3339(&format!("{}T00:00:00Z", date.get_untracked())) {
+            Some(review_by) => save(Some(review_by)),
+            None => failed.set(true),
+        }
+    };
+
+    view! {
+        {move || current().and_then(|r| Some((r.review_by?, r))).map(|(at, r)| {
+            let when = format_timestamp(at);
+            if r.due {
+                view! {
+                    <div class="notice">
+                        "This record was due for review on " {when} ". "
+                        {r.snoozed_until.map(|until| format!("Reminders are snoozed until {}.", format_timestamp(until)))}
+                        {move || signed_in().then(|| view! {
+                            <button class="link" on:click=move |_| snooze(1)>"Snooze for a day"</button>
+                            <button class="link" on:click=move |_| snooze(7)>"Snooze for a week"</button>
+                        })}
+                    </div>
+                }
+                .into_any()
+            } else {
+                view! { <div class="record-meta">"Review by " {when}</div> }.into_any()
+            }
+        })}
+
+        {move || signed_in().then(|| view! {
+            <div class="form-group">
+                <label for=format!("record-{}-review-by", id)>"Review by"</label>
+                <input
+                    id=format!("record-{}-review-by", id)
+                    type="date"
+                    prop:value=date
+                    on:input=move |ev| date.set(event_target_value(&ev))
+                />
+                <button on:click=on_set disabled=move || date.get().is_empty()>"Set review date"</button>
+                {move || current().is_some_and(|r| r.review_by.is_some()).then(|| view! {
+                    <button class="link" on:click=move |_| save(None)>"Remove review date"</button>
+                })}
+            </div>
+        })}
+
+        {move || failed.get().then(|| view! {
+            <div class="error-message">"The review date could not be changed."</div>
+        })}
+    }
+}
@@ -0,0 +1,178 @@
+use crate::app::App;
+use crate::attachments;
+use crate::blobs::BlobStore;
+use crate::changefeed::{events_stream, ChangeFeed};
+use crate::db::DbManager;
+use crate::graphql::{self, EditorSchema};
+use crate::health::{healthz, metrics};
+use crate::history::export_history_jsonl;
+use crate::print;
+use crate::rate_limit::RateLimiter;
+use crate::rest;
+use crate::scan::VirusScanner;
+use crate::thumbnails;
+use actix_files::Files;
+use actix_web::web::{self, ServiceConfig};
+use leptos::config::LeptosOptions;
+use leptos::prelude::*;
+use leptos_actix::{generate_route_list, LeptosRoutes};
+use leptos_meta::MetaTags;
+use std::sync::Arc;
+
+// Everything the field editor serves, for host apps that mount it next to
+// their own routes instead of running the field-editor binary:
+//
+//     let routes = field_editor::routes(state);
+//     App::new()
+//         .service(web::scope("/records-api").configure(routes.api()))
+//         .configure(routes.app())
+//         .wrap(middleware::from_fn(field_editor::api_tokens::api_token_scopes))
+//         .wrap(middleware::from_fn(field_editor::rest::bodiless_not_modified))
+//
+// The HTTP API (REST, OpenAPI, GraphQL, the SSE stream, history export,
+// attachments, print pages, health and metrics) can go under any prefix. The
+// app itself (its pages, server functions and compiled assets) has to stay at
+// the root, since the client calls server functions and links pages and
+// `/pkg` by absolute path. API tokens are only honoured on API routes at the
+// root, where `api_token_scopes` knows their paths; under a prefix they are
+// refused. The app's pages end in a catch-all route, so the host's own routes
+// have to be registered before it.
+
+// What the routes share, built once and cloned into every worker
+#[derive(Clone)]
+pub struct FieldEditorState {
+    pub db: DbManager,
+    pub feed: ChangeFeed,
+    pub limiter: RateLimiter,
+    pub schema: EditorSchema,
+    pub blobs: Arc<dyn BlobStore>,
+    // Uploads aren't scanned without one
+    pub scanner: Option<Arc<dyn VirusScanner>>,
+    pub leptos_options: LeptosOptions,
+}
+
+impl FieldEditorState {
+    // The GraphQL schema is built from the same database, feed and limiter
+    pub fn new(
+        db: DbManager,
+        feed: ChangeFeed,
+        limiter: RateLimiter,
+        blobs: Arc<dyn BlobStore>,
+        scanner: Option<Arc<dyn VirusScanner>>,
+        leptos_options: LeptosOptions,
+    ) -> Self {
+        let schema = graphql::build_schema(db.clone(), feed.clone(), limiter.clone());
+        FieldEditorState {
+            db,
+            feed,
+            limiter,
+            schema,
+            blobs,
+            scanner,
+            leptos_options,
+        }
+    }
+
+    // The shared state as app data, for handlers and server functions to extract
+    fn register(&self, cfg: &mut ServiceConfig) {
+        cfg.app_data(web::Data::new(self.feed.clone()))
+            .app_data(web::Data::new(self.db.clone()))
+            .app_data(web::Data::new(self.limiter.clone()))
+            .app_data(web::Data::new(self.schema.clone()))
+            .app_data(web::Data::from(self.blobs.clone()));
+        if let Some(scanner) = &self.scanner {
+            cfg.app_data(web::Data::from(scanner.clone()));
+        }
+    }
+}
+
+// The field editor's routes over `state`, to mount with `App::configure` or
+// `Scope::configure`
+pub fn routes(state: FieldEditorState) -> FieldEditorRoutes {
+    FieldEditorRoutes { state }
+}
+
+#[derive(Clone)]
+pub struct FieldEditorRoutes {
+    state: FieldEditorState,
+}
+
+impl FieldEditorRoutes {
+    // The HTTP API, with the state its handlers take
+    pub fn api(&self) -> impl Fn(&mut ServiceConfig) + Clone {
+        let state = self.state.clone();
+        move |cfg| {
+            cfg.service(healthz)
+                .service(metrics)
+                .service(events_stream)
+                .service(export_history_jsonl)
+                .service(rest::get_fields)
+                .service(rest::put_fields)
+                .service(rest::patch_fields)
+                .service(rest::update_batch)
+                .service(rest::openapi_json)
+                .service(rest::api_docs)
+                .service(attachments::upload_attachment)
+                .service(attachments::download_attachment)
+                .service(thumbnails::attachment_thumbnail)
+                .service(print::print_record)
+                .service(graphql::service());
+            state.register(cfg);
+        }
+    }
+
+    // The app: compiled assets, server functions and pages. Goes at the root.
+    pub fn app(&self) -> impl Fn(&mut ServiceConfig) + Clone {
+        let state = self.state.clone();
+        move |cfg| {
+            let leptos_options = state.leptos_options.clone();
+            let site_root = leptos_options.site_root.to_string();
+            cfg
+                // serve JS/WASM/CSS from `pkg`
+                .service(Files::new("/pkg", format!("{site_root}/pkg")))
+                // serve other assets from the `assets` directory
+                .service(Files::new("/assets", &site_root))
+                // serve the favicon from /favicon.ico
+                .service(favicon)
+                .app_data(web::Data::new(leptos_options.clone()))
+                .leptos_routes(generate_route_list(App), move || shell(leptos_options.clone()));
+            // Server functions take the database and rate limiter from here,
+            // wherever `api` is mounted
+            state.register(cfg);
+        }
+    }
+
+    // The API and the app, both at the root, as the field-editor binary serves them
+    pub fn all(&self) -> impl Fn(&mut ServiceConfig) + Clone {
+        let (api, app) = (self.api(), self.app());
+        move |cfg| {
+            api(cfg);
+            app(cfg);
+        }
+    }
+}
+
+// The HTML document every page is rendered into
+fn shell(options: LeptosOptions) -> impl IntoView {
+    view! {
+        <!DOCTYPE html>
+        <html lang="en">
+            <head>
+                <meta charset="utf-8"/>
+                <meta name="viewport" content="width=device-width, initial-scale=1"/>
+                <AutoReload options=options.clone() />
+                <HydrationScripts options/>
+                <MetaTags/>
+            </head>
+            <body>
+                <App/>
+            </body>
+        </html>
+    }
+}
+
+#[actix_web::get("favicon.ico")]
+async fn favicon(leptos_options: web::Data<LeptosOptions>) -> actix_web::Result<actix_files::NamedFile> {
+    let site_root = &leptos_options.site_root;
+    Ok(actix_files::NamedFile::open(format!("{site_root}/favicon.ico"))?)
+}
@@ -0,0 +1,143 @@
+use futures::future::BoxFuture;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Uploads can be checked for malware before they are stored. A file the
+// scanner flags never reaches the blob store or the database: it's moved to
+// the quarantine directory for an administrator to look at, and the uploader
+// is told why it was refused.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    // With the name of the signature that matched
+    Infected(String),
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    Io(std::io::Error),
+    // The scanner answered, but not with a verdict
+    Scanner(String),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::Io(e) => write!(f, "Could not reach the virus scanner: {}", e),
+            ScanError::Scanner(message) => write!(f, "Virus scanner failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+impl From<std::io::Error> for ScanError {
+    fn from(e: std::io::Error) -> Self {
+        ScanError::Io(e)
+    }
+}
+
+pub trait VirusScanner: Send + Sync {
+    // For logs
+    fn name(&self) -> &'static str;
+
+    // Check the file at `path`
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<ScanVerdict, ScanError>>;
+}
+
+// Chunk size for streaming a file to clamd; well under its StreamMaxLength
+const CLAMAV_CHUNK: usize = 64 * 1024;
+
+// clamd over TCP, using its INSTREAM command
+pub struct ClamAvScanner {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamAvScanner {
+    pub fn new(addr: &str) -> Self {
+        ClamAvScanner {
+            addr: addr.to_string(),
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    async fn instream(&self, path: &Path) -> Result<ScanVerdict, ScanError> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0u8; CLAMAV_CHUNK];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..read]).await?;
+        }
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        parse_clamav_reply(&String::from_utf8_lossy(&reply))
+    }
+}
+
+// clamd answers `stream: OK`, `stream: <signature> FOUND` or `... ERROR`
+fn parse_clamav_reply(reply: &str) -> Result<ScanVerdict, ScanError> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else {
+        Err(ScanError::Scanner(reply.to_string()))
+    }
+}
+
+impl VirusScanner for ClamAvScanner {
+    fn name(&self) -> &'static str {
+        "clamav"
+    }
+
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<ScanVerdict, ScanError>> {
+        Box::pin(async move {
+            match tokio::time::timeout(self.timeout, self.instream(path)).await {
+                Ok(verdict) => verdict,
+                Err(_) => Err(ScanError::Scanner("timed out".to_string())),
+            }
+        })
+    }
+}
+
+// Where flagged uploads are kept; FIELD_EDITOR_QUARANTINE_DIR or a directory in /tmp
+pub fn quarantine_dir() -> PathBuf {
+    std::env::var("FIELD_EDITOR_QUARANTINE_DIR")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/tmp/field-editor-quarantine"))
+}
+
+// Move a flagged upload out of the way, under the id it would have had
+pub async fn quarantine(path: &Path, id: &str) -> Result<PathBuf, std::io::Error> {
+    let dir = quarantine_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let target = dir.join(id);
+    // A rename can't cross file systems; copy then
+    if tokio::fs::rename(path, &target).await.is_err() {
+        tokio::fs::copy(path, &target).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(target)
+}
+
+// The scanner at FIELD_EDITOR_CLAMAV_ADDR (`host:port`); None scans nothing
+pub fn virus_scanner_from_env() -> Option<Arc<dyn VirusScanner>> {
+    let addr = std::env::var("FIELD_EDITOR_CLAMAV_ADDR").ok().filter(|v| !v.is_empty())?;
+    Some(Arc::new(ClamAvScanner::new(&addr)))
+}
@@ -0,0 +1,351 @@
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// Scripted interleavings of several users working on one record, replayed
+// step by step against a scratch record in a database of their own, which is
+// thrown away afterwards. Runs start from the same empty database on the same
+// clock, so the trace is the same every time and docs can show it verbatim.
+
+// The scenarios `run_scenario` knows, with what each one shows
+pub const SCENARIOS: &[(&str, &str)] = &[
+    ("two_writers", "Two users edit the same version; the second save is rejected"),
+    ("writer_deleter", "A record is moved to the trash while someone is still editing it"),
+    (
+        "lock_steal",
+        "The lock holder's tab goes silent and the lock passes to the next editor; locks are advisory, \
+         so the first holder can still save",
+    ),
+];
+
+// One thing that happened in a scenario
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioStep {
+    pub step: usize,
+    pub actor: String,
+    pub action: String,
+    pub outcome: String,
+    // The record's version after the step
+    pub version: Option<i64>,
+    // Milliseconds since the scenario started, on the scenario's own clock
+    pub at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioTrace {
+    pub name: String,
+    pub record_id: i64,
+    pub steps: Vec<ScenarioStep>,
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{ScenarioStep, ScenarioTrace};
+    use crate::db::{append_history, write_values, DbManager, FieldValues};
+    use crate::events::FieldEvent;
+    use crate::presence::PRESENCE_HEARTBEAT;
+    use crate::providers::FixedClock;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    // Where the scenario's clock starts; any time will do on a database of its own
+    const CLOCK_START_MILLIS: i64 = 1_700_000_000_000;
+    // Time between two steps
+    const STEP_MILLIS: i64 = 1000;
+
+    struct Run {
+        db: DbManager,
+        clock: Arc<FixedClock>,
+        started_at: i64,
+        record_id: i64,
+        steps: Vec<ScenarioStep>,
+    }
+
+    impl Run {
+        async fn start(path: &Path, name: &str) -> Result<Self, sqlx::Error> {
+            let started_at = CLOCK_START_MILLIS;
+            let clock = Arc::new(FixedClock::new(started_at));
+            let db = DbManager::builder()
+                .url(&format!("sqlite:{}?mode=rwc", path.display()))
+                .build()
+                .await?
+                .with_clock(clock.clone());
+
+            let fields = db.field_definitions().await?;
+            let mut tx = db.pool().begin().await?;
+            let record_id: i64 = sqlx::query_scalar(
+                r#"
+                INSERT INTO fields (version, updated_by, updated_at, tenant_id)
+                VALUES (1, ?, ?, ?)
+                RETURNING id
+                "#,
+            )
+            .bind("setup")
+            .bind(started_at)
+            .bind(db.tenant().as_str())
+            .fetch_one(&mut *tx)
+            .await?;
+            let record = db.scope(&mut tx, record_id).await?.expect("The record was just inserted");
+            if let Some(title) = fields.first() {
+                let values: FieldValues = [(title.name.as_str(), format!("Scenario {}", name))].into_iter().collect();
+                write_values(&mut tx, record, &values).await?;
+            }
+            append_history(&mut tx, record, &FieldEvent::Created, None, started_at).await?;
+            tx.commit().await?;
+
+            Ok(Run {
+                db,
+                clock,
+                started_at,
+                record_id,
+                steps: Vec::new(),
+            })
+        }
+
+        // The record's version, also while it is in the trash
+        async fn version(&self) -> Result<Option<i64>, sqlx::Error> {
+            sqlx::query_scalar("SELECT version FROM fields WHERE id = ?")
+                .bind(self.record_id)
+                .fetch_optional(self.db.pool())
+                .await
+        }
+
+        async fn record(&mut self, actor: &str, action: String, outcome: String) -> Result<(), sqlx::Error> {
+            let version = self.version().await?;
+            self.clock.advance(STEP_MILLIS);
+            self.steps.push(ScenarioStep {
+                step: self.steps.len() + 1,
+                actor: actor.to_string(),
+                action,
+                outcome,
+                version,
+                at: self.db.now() - self.started_at,
+            });
+            Ok(())
+        }
+
+        // Read the record the way an editor does; returns the version it saw
+        async fn load(&mut self, actor: &str) -> Result<i64, sqlx::Error> {
+            let outcome = match self.db.get_fields(self.record_id).await {
+                Ok(fields) => fields.version,
+                Err(sqlx::Error::RowNotFound) => {
+                    self.record(actor, "loads the record".into(), "not found (in the trash)".into()).await?;
+                    return Ok(0);
+                }
+                Err(e) => return Err(e),
+            };
+            self.record(actor, "loads the record".into(), format!("sees version {}", outcome)).await?;
+            Ok(outcome)
+        }
+
+        async fn save(&mut self, actor: &str, expected_version: i64, text: &str) -> Result<bool, sqlx::Error> {
+            // The title goes into the first field, the text into the second, and the rest are emptied
+            let values: FieldValues = self
+                .db
+                .field_definitions()
+                .await?
+                .into_iter()
+                .enumerate()
+                .map(|(i, field)| match i {
+                    0 => (field.name, format!("Scenario record {}", self.record_id)),
+                    1 => (field.name, text.to_string()),
+                    _ => (field.name, String::new()),
+                })
+                .collect();
+            let saved = self
+                .db
+                .update_fields(self.record_id, actor, &values, expected_version, None)
+                .await?;
+            let outcome = match (saved, self.db.tombstone(self.record_id).await?) {
+                (true, _) => "saved".to_string(),
+                (false, Some(_)) => "rejected: the record was deleted meanwhile".to_string(),
+                (false, None) => "rejected: the record is no longer at that version".to_string(),
+            };
+            self.record(actor, format!("saves changes to version {}", expected_version), outcome)
+                .await?;
+            Ok(saved)
+        }
+
+        async fn lock_holder(&self) -> Result<String, sqlx::Error> {
+            let metadata = self.db.list_metadata(&[self.record_id]).await?;
+            Ok(metadata
+                .into_iter()
+                .next()
+                .and_then(|m| m.lock_holder)
+                .unwrap_or_else(|| "nobody".to_string()))
+        }
+
+        // An editor's heartbeat; the first one opens the record
+        async fn heartbeat(&mut self, actor: &str, action: &str) -> Result<(), sqlx::Error> {
+            let editor_id = format!("{}-{}", actor, self.record_id);
+            self.db.touch_presence(self.record_id, &editor_id, actor).await?;
+            let holder = self.lock_holder().await?;
+            self.record(actor, action.to_string(), format!("lock held by {}", holder))
+                .await
+        }
+
+        async fn finish(self, name: &str) -> Result<ScenarioTrace, sqlx::Error> {
+            self.db.pool().close().await;
+            Ok(ScenarioTrace {
+                name: name.to_string(),
+                record_id: self.record_id,
+                steps: self.steps,
+            })
+        }
+    }
+
+    async fn two_writers(run: &mut Run) -> Result<(), sqlx::Error> {
+        let alice = run.load("alice").await?;
+        let bob = run.load("bob").await?;
+        run.save("alice", alice, "Alice's text").await?;
+        run.save("bob", bob, "Bob's text").await?;
+        let bob = run.load("bob").await?;
+        run.save("bob", bob, "Bob's text, on top of Alice's").await?;
+        Ok(())
+    }
+
+    async fn writer_deleter(run: &mut Run) -> Result<(), sqlx::Error> {
+        let alice = run.load("alice").await?;
+        let bob = run.load("bob").await?;
+        let deleted = run.db.delete_record(run.record_id, "bob", bob).await?;
+        let outcome = if deleted { "moved to the trash" } else { "already gone" };
+        run.record("bob", "deletes the record".into(), outcome.into()).await?;
+        run.save("alice", alice, "Alice's text").await?;
+        let restored = run.db.restore_record(run.record_id, "bob").await?;
+        let outcome = if restored { "restored" } else { "not in the trash" };
+        run.record("bob", "restores the record".into(), outcome.into()).await?;
+        let alice = run.load("alice").await?;
+        run.save("alice", alice, "Alice's text").await?;
+        Ok(())
+    }
+
+    async fn lock_steal(run: &mut Run) -> Result<(), sqlx::Error> {
+        let ttl = 3 * PRESENCE_HEARTBEAT.as_millis() as i64;
+        run.heartbeat("alice", "opens the record").await?;
+        run.heartbeat("bob", "opens the record").await?;
+        // Alice's tab stops sending heartbeats; bob's keeps going
+        run.clock.advance(ttl);
+        run.heartbeat("bob", "sends a heartbeat after alice went quiet").await?;
+        run.heartbeat("alice", "comes back").await?;
+        // Locks are advisory: they only say who is editing, and saving still
+        // goes by version, so alice's save goes through
+        let alice = run.load("alice").await?;
+        run.save("alice", alice, "Alice's text").await?;
+        Ok(())
+    }
+
+    async fn run_on(path: &Path, name: &str) -> Result<ScenarioTrace, sqlx::Error> {
+        let mut run = Run::start(path, name).await?;
+        match name {
+            "two_writers" => two_writers(&mut run).await?,
+            "writer_deleter" => writer_deleter(&mut run).await?,
+            "lock_steal" => lock_steal(&mut run).await?,
+            _ => unreachable!("Checked against SCENARIOS in `run`"),
+        }
+        run.finish(name).await
+    }
+
+    // Run a scenario on a new database in the temp directory, deleted again
+    // however the run ends
+    pub async fn run(name: &str) -> Result<Option<ScenarioTrace>, sqlx::Error> {
+        if !super::SCENARIOS.iter().any(|(known, _)| *known == name) {
+            return Ok(None);
+        }
+        let path: PathBuf = std::env::temp_dir().join(format!("field-editor-scenario-{}.db", uuid::Uuid::new_v4()));
+        let trace = run_on(&path, name).await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        trace.map(Some)
+    }
+}
+
+// Run one of the `SCENARIOS` and return what happened step by step.
+// Only available where the demo knobs are.
+#[server(RunScenario)]
+pub async fn run_scenario(name: String) -> Result<ScenarioTrace, ServerFnError> {
+    use crate::demo::demo_enabled;
+    use crate::field_editor::db_error;
+
+    if !demo_enabled() {
+        let response = expect_context::<leptos_actix::ResponseOptions>();
+        response.set_status(actix_web::http::StatusCode::NOT_FOUND);
+        return Err(ServerFnError::new("Scenarios are only available in demo mode"));
+    }
+    server::run(&name)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| {
+            let known: Vec<&str> = SCENARIOS.iter().map(|(name, _)| *name).collect();
+            ServerFnError::new(format!("Unknown scenario {}; try one of {}", name, known.join(", ")))
+        })
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    async fn trace(name: &str) -> ScenarioTrace {
+        server::run(name).await.expect("scenario runs").expect("known scenario")
+    }
+
+    fn outcomes(trace: &ScenarioTrace) -> Vec<&str> {
+        trace.steps.iter().map(|step| step.outcome.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn two_writers_rejects_the_second_save() {
+        let trace = trace("two_writers").await;
+        assert_eq!(
+            outcomes(&trace),
+            [
+                "sees version 1",
+                "sees version 1",
+                "saved",
+                "rejected: the record is no longer at that version",
+                "sees version 2",
+                "saved",
+            ]
+        );
+        assert_eq!(trace.steps.last().and_then(|step| step.version), Some(3));
+    }
+
+    #[actix_web::test]
+    async fn writer_deleter_rejects_saves_into_the_trash() {
+        let trace = trace("writer_deleter").await;
+        let outcomes = outcomes(&trace);
+        assert_eq!(outcomes[2], "moved to the trash");
+        assert_eq!(outcomes[3], "rejected: the record was deleted meanwhile");
+        assert_eq!(outcomes[4], "restored");
+        assert!(outcomes[5].starts_with("sees version"));
+        assert_eq!(outcomes[6], "saved");
+    }
+
+    #[actix_web::test]
+    async fn lock_steal_passes_the_lock_but_not_the_right_to_save() {
+        let trace = trace("lock_steal").await;
+        assert_eq!(
+            outcomes(&trace),
+            [
+                "lock held by alice",
+                "lock held by alice",
+                "lock held by bob",
+                "lock held by bob",
+                "sees version 1",
+                "saved",
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn runs_are_reproducible() {
+        for (name, _) in SCENARIOS {
+            assert_eq!(trace(name).await, trace(name).await);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unknown_scenarios_are_refused() {
+        assert_eq!(server::run("nonsense").await.expect("no database needed"), None);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::auth::use_user_session;
+use crate::db::FieldValues;
+use crate::errors::EditorError;
+use crate::tasks::Tasks;
+use crate::timestamp::{format_timestamp, parse_rfc3339};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use server_fn::error::ServerFnError;
+
+// A change can be saved to take effect later: it waits as a scheduled change
+// until its publish time, when the background runner saves it as its author.
+// By then others may have saved the record, so publishing merges like an
+// editor save does: fields only the scheduled change touched are applied, and
+// if someone changed one of the same fields since, the change isn't published
+// and stays listed as failed until it is cancelled.
+
+// A change waiting for its publish time, or one that couldn't be published
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct ScheduledChange {
+    pub id: i64,
+    pub record_id: i64,
+    // The version the change was made against
+    pub base_version: i64,
+    #[cfg_attr(feature = "ssr", sqlx(rename = "field_values"))]
+    pub values: FieldValues,
+    pub scheduled_by: String,
+    pub publish_at: i64,
+    // The record had moved on in the same fields when it was due
+    pub failed: bool,
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::ScheduledChange;
+    use crate::db::{apply_update, DbManager, FieldValues};
+    use crate::events::FieldEvent;
+    use crate::merge::{merge_into_current, MergeResult};
+    use crate::tenant::TenantId;
+
+    const SCHEDULED_COLUMNS: &str =
+        "id, record_id, base_version, field_values, scheduled_by, publish_at, status = 'failed' AS failed";
+
+    impl DbManager {
+        // Save `values`, made against `base_version`, at `publish_at`. Returns
+        // the scheduled change's id, or None if the record isn't this tenant's.
+        #[tracing::instrument(skip(self, values))]
+        pub async fn schedule_change(
+            &self,
+            id: i64,
+            user: &str,
+            values: &FieldValues,
+            base_version: i64,
+            publish_at: i64,
+        ) -> Result<Option<i64>, sqlx::Error> {
+            // Sealed like the values in the audit log, if keys are installed
+            let stored = match crate::keys::keyring() {
+                Some(keyring) => values.0.iter().map(|(name, value)| (name.as_str(), keyring.seal_field(name, value))).collect(),
+                None => values.clone(),
+            };
+            let stored = serde_json::to_string(&stored).expect("FieldValues serialize");
+            self.retrying("schedule_change", || async {
+                let mut tx = self.pool().begin().await?;
+                let Some(record) = self.scope(&mut tx, id).await? else {
+                    return Ok(None);
+                };
+                let scheduled = sqlx::query(
+                    r#"
+                    INSERT INTO scheduled_changes
+                        (tenant_id, record_id, base_version, field_values, scheduled_by, scheduled_at, publish_at)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                    "#,
+                )
+                .bind(self.tenant().as_str())
+                .bind(record.id())
+                .bind(base_version)
+                .bind(&stored)
+                .bind(user)
+                .bind(self.now())
+                .bind(publish_at)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(Some(scheduled.last_insert_rowid()))
+            })
+            .await
+        }
+
+        // A record's scheduled changes that haven't been published or cancelled,
+        // soonest first
+        pub async fn scheduled_changes(&self, id: i64) -> Result<Vec<ScheduledChange>, sqlx::Error> {
+            sqlx::query_as(&format!(
+                r#"
+                SELECT {} FROM scheduled_changes
+                WHERE record_id = ? AND tenant_id = ? AND status IN ('pending', 'failed')
+                ORDER BY publish_at, id
+                "#,
+                SCHEDULED_COLUMNS
+            ))
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_all(self.pool())
+            .await
+        }
+
+        // Drop a scheduled change of a record before it is published. Returns
+        // false if it was published or cancelled already.
+        #[tracing::instrument(skip(self))]
+        pub async fn cancel_scheduled_change(&self, id: i64, change_id: i64, user: &str) -> Result<bool, sqlx::Error> {
+            let result = sqlx::query(
+                r#"
+                UPDATE scheduled_changes SET status = 'cancelled', cancelled_by = ?
+                WHERE id = ? AND record_id = ? AND tenant_id = ? AND status IN ('pending', 'failed')
+                "#,
+            )
+            .bind(user)
+            .bind(change_id)
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .execute(self.pool())
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        // Publish every tenant's scheduled changes that are due, each in its
+        // own transaction. Returns how many were published.
+        #[tracing::instrument(skip(self))]
+        pub async fn publish_due_changes(&self) -> Result<usize, sqlx::Error> {
+            let due: Vec<(i64, String)> = sqlx::query_as(
+                "SELECT id, tenant_id FROM scheduled_changes WHERE status = 'pending' AND publish_at <= ? ORDER BY publish_at, id",
+            )
+            .bind(self.now())
+            .fetch_all(self.pool())
+            .await?;
+            let mut published = 0;
+            for (change_id, tenant) in due {
+                let db = self.clone().with_tenant(TenantId::parse(&tenant).unwrap_or_default());
+                if let Some(record_id) = db.retrying("publish_scheduled_change", || db.publish_in_tx(change_id)).await? {
+                    db.forget_cached(record_id).await;
+                    published += 1;
+                }
+            }
+            Ok(published)
+        }
+
+        // Publish one due change; returns its record's id if it was saved
+        async fn publish_in_tx(&self, change_id: i64) -> Result<Option<i64>, sqlx::Error> {
+            let mut tx = self.pool().begin().await?;
+            let change: Option<ScheduledChange> = sqlx::query_as(&format!(
+                "SELECT {} FROM scheduled_changes WHERE id = ? AND status = 'pending'",
+                SCHEDULED_COLUMNS
+            ))
+            .bind(change_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(change) = change else {
+                return Ok(None);
+            };
+            let now = self.now();
+            let result = match self.scope(&mut tx, change.record_id).await? {
+                Some(record) => {
+                    let live: Option<i64> =
+                        sqlx::query_scalar("SELECT version FROM fields WHERE id = ? AND deleted_at IS NULL")
+                            .bind(change.record_id)
+                            .fetch_optional(&mut *tx)
+                            .await?;
+                    match live {
+                        Some(version) if version == change.base_version => {
+                            let event = FieldEvent::Updated;
+                            match apply_update(&mut tx, record, &change.scheduled_by, &change.values, version, &event, now)
+                                .await?
+                            {
+                                true => MergeResult::Saved,
+                                false => MergeResult::Conflict { fields: Vec::new() },
+                            }
+                        }
+                        Some(_) => {
+                            merge_into_current(&mut tx, record, &change.scheduled_by, &change.values, change.base_version, now)
+                                .await?
+                        }
+                        // In the trash
+                        None => MergeResult::Conflict { fields: Vec::new() },
+                    }
+                }
+                None => MergeResult::Conflict { fields: Vec::new() },
+            };
+            let status = match &result {
+                MergeResult::Conflict { fields } => {
+                    tracing::info!(change_id, record_id = change.record_id, ?fields, "Scheduled change conflicts; not published");
+                    "failed"
+                }
+                _ => "published",
+            };
+            sqlx::query("UPDATE scheduled_changes SET status = ?, published_at = ? WHERE id = ?")
+                .bind(status)
+                .bind(now)
+                .bind(change_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Ok(result.is_saved().then_some(change.record_id))
+        }
+    }
+}
+
+// Save a record's values at `publish_at`, in milliseconds since the epoch
+#[server(ScheduleChange)]
+pub async fn schedule_change(
+    id: i64,
+    values: FieldValues,
+    expected_version: i64,
+    publish_at: i64,
+    csrf_token: String,
+) -> Result<i64, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::change_requests::{needs_approval, APPROVAL_REQUIRED};
+    use crate::expiry::require_unexpired;
+    use crate::field_editor::{db_error, open_db};
+    use crate::field_schema::require_valid_fields;
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    require_unexpired(&db, id).await.map_err(EditorError::from)?;
+    require_valid_fields(&db, &values).await?;
+    // A scheduled change is published without an approver looking at it
+    if needs_approval(&db, &user, id).await {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(APPROVAL_REQUIRED.to_string())));
+    }
+    if publish_at <= db.now() {
+        return Err(ServerFnError::WrappedServerError(EditorError::Other(
+            "Pick a time in the future, or save the change now".to_string(),
+        )));
+    }
+    db.schedule_change(id, &user.name, &values, expected_version, publish_at)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?
+        .ok_or_else(|| ServerFnError::WrappedServerError(EditorError::Other("No such record".to_string())))
+}
+
+#[server(GetScheduledChanges)]
+pub async fn get_scheduled_changes(id: i64) -> Result<Vec<ScheduledChange>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Record(id)).await?;
+    db.scheduled_changes(id).await.map_err(db_error)
+}
+
+#[server(CancelScheduledChange)]
+pub async fn cancel_scheduled_change(
+    id: i64,
+    change_id: i64,
+    csrf_token: String,
+) -> Result<bool, ServerFnError<EditorError>> {
+    use crate::auth::{authorize, require_csrf};
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+    use crate::rate_limit::rate_limit;
+
+    rate_limit().await?;
+    let db = open_db().await.map_err(EditorError::from)?;
+    let user = authorize(&db, Action::Edit, ResourceKind::Record(id)).await.map_err(EditorError::from)?;
+    require_csrf(&db, &csrf_token).await.map_err(EditorError::from)?;
+    Ok(db
+        .cancel_scheduled_change(id, change_id, &user.name)
+        .await
+        .map_err(|e| EditorError::from(db_error(e)))?)
+}
+
+/// Lists a record's scheduled changes with a way to cancel each, and lets
+/// signed-in users schedule the editor's current values instead of saving them.
+#[component]
+pub fn ScheduledChanges(
+    /// The record.
+    id: i64,
+    /// The values in the editor, to schedule.
+    edits: RwSignal<FieldValues>,
+    /// The version the editor's values are based on.
+    version: RwSignal<i64>,
+    /// Set after scheduling, to make the editor reload.
+    refresh: RwSignal<()>,
+) -> impl IntoView {
+    let session = use_user_session();
+    let signed_in = move || session.user.get().flatten().is_some();
+    let changed = RwSignal::new(());
+    let scheduled = Resource::new(move || (changed.get(), version.get()), move |_| get_scheduled_changes(id));
+    let publish_at = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let tasks = Tasks::new();
+    let csrf_token = move || session.csrf_token.get_untracked().flatten().unwrap_or_default();
+
+    let failed = move |e: ServerFnError<EditorError>| match e {
+        ServerFnError::WrappedServerError(e) => error.set(Some(e.to_string())),
+        e => error.set(Some(e.to_string())),
+    };
+    let on_schedule = move |_| {
+        error.set(None);
+        // A datetime-local input gives YYYY-MM-DDTHH:MM, taken as UTC like the times shown
+        let Some(at) = parse_rfc3339(&format!("{}:00Z", publish_at.get_untracked())) else {
+            error.set(Some("Pick a date and time to publish at".to_string()));
+            return;
+        };
+        let (values, expected_version, csrf_token) = (edits.get_untracked(), version.get_untracked(), csrf_token());
+        tasks.spawn(async move {
+            match schedule_change(id, values, expected_version, at, csrf_token).await {
+                Ok(_) => {
+                    publish_at.set(String::new());
+                    changed.set(());
+                    // The values are scheduled now rather than unsaved edits
+                    refresh.set(());
+                }
+                Err(e) => failed(e),
+            }
+        });
+    };
+    let on_cancel = move |change_id: i64| {
+        error.set(None);
+        let csrf_token = csrf_token();
+        tasks.spawn(async move {
+            match cancel_scheduled_change(id, change_id, csrf_token).await {
+                Ok(_) => changed.set(()),
+                Err(e) => failed(e),
+            }
+        });
+    };
+
+    view! {
+        <Transition fallback=|| ()>
+            {move || scheduled.get().and_then(Result::ok).filter(|changes| !changes.is_empty()).map(|changes| view! {
+                <div class="scheduled-changes">
+                    <h2>"Scheduled changes"</h2>
+                    <ul>
+                        {changes.into_iter().map(|change| {
+                            let change_id = change.id;
+                            let values = change
+                                .values
+                                .iter()
+                                .map(|(name, value)| format!("{}: {}", name, value))
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            view! {
+                                <li class:failed=change.failed>
+                                    <span class="record-meta">
+                                        {if change.failed {
+                                            format!(
+                                                "Not published at {}: someone changed the same fields since version {}",
+                                                format_timestamp(change.publish_at),
+                                                change.base_version,
+                                            )
+                                        } else {
+                                            format!("Publishes at {}", format_timestamp(change.publish_at))
+                                        }}
+                                        {format!(" (by {}): {}", change.scheduled_by, values)}
+                                    </span>
+                                    {move || signed_in().then(|| view! {
+                                        <button class="link" on:click=move |_| on_cancel(change_id)>"Cancel"</button>
+                                    })}
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                </div>
+            })}
+        </Transition>
+
+        {move || signed_in().then(|| view! {
+            <div class="form-group">
+                <label for=format!("record-{}-publish-at", id)>"Publish at (UTC)"</label>
+                <input
+                    id=format!("record-{}-publish-at", id)
+                    type="datetime-local"
+                    prop:value=publish_at
+                    on:input=move |ev| publish_at.set(event_target_value(&ev))
+                />
+                <button on:click=on_schedule disabled=move || publish_at.get().is_empty()>"Schedule changes"</button>
+            </div>
+        })}
+
+        {move || error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+    }
+}
@@ -0,0 +1,328 @@
+use crate::db::RecordSummary;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use leptos::suspense::Suspense;
+use server_fn::error::ServerFnError;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 25;
+pub const MAX_PAGE_SIZE: u32 = 100;
+// Most matches the full-text search returns
+pub const FULLTEXT_LIMIT: i64 = 20;
+
+// Narrow the results down beyond the search text
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordFilters {
+    // Match the search text against this field only, instead of all of them
+    #[serde(default)]
+    pub field: Option<String>,
+    // Also search the trash
+    #[serde(default)]
+    pub include_deleted: bool,
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    // Last change at or after / before these times, in milliseconds since the epoch
+    #[serde(default)]
+    pub updated_after: Option<i64>,
+    #[serde(default)]
+    pub updated_before: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordSort {
+    #[default]
+    IdAsc,
+    IdDesc,
+    TitleAsc,
+    RecentlyUpdated,
+}
+
+// Which page to return; pages start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageRequest {
+    pub page: u32,
+    pub page_size: u32,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        PageRequest {
+            page: 1,
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+// One page of search results and where it sits among all of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordPage {
+    pub records: Vec<RecordSummary>,
+    // Matches across all pages
+    pub total: i64,
+    pub page: u32,
+    pub page_size: u32,
+    pub total_pages: u32,
+}
+
+// A piece of a full-text match's snippet; highlighted pieces are what matched
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlighted: bool,
+}
+
+// A record matching a full-text search, best matches first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FulltextMatch {
+    pub id: i64,
+    pub title: String,
+    // The best-matching stretch of the record's values
+    pub snippet: Vec<SnippetPart>,
+    // BM25 score; lower is a better match
+    pub rank: f64,
+}
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::{
+        FulltextMatch, PageRequest, RecordFilters, RecordPage, RecordSort, SnippetPart, FULLTEXT_LIMIT,
+        MAX_PAGE_SIZE,
+    };
+    use crate::db::{reveal_title, reveal_titles, DbManager, RecordSummary, TITLE_EXPR};
+    use crate::tenant::TenantId;
+    use sqlx::{QueryBuilder, Sqlite};
+
+    // Marks around matched terms in FTS5 snippets, split out again by `snippet_parts`
+    const HIGHLIGHT_START: char = '\u{1}';
+    const HIGHLIGHT_END: char = '\u{2}';
+
+    // Match `text` anywhere in a value; the user's % and _ are taken literally
+    fn like_pattern(text: &str) -> String {
+        let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        format!("%{}%", escaped)
+    }
+
+    fn order_by(sort: RecordSort) -> &'static str {
+        match sort {
+            RecordSort::IdAsc => "id ASC",
+            RecordSort::IdDesc => "id DESC",
+            RecordSort::TitleAsc => "title COLLATE NOCASE ASC, id ASC",
+            RecordSort::RecentlyUpdated => "COALESCE(updated_at, 0) DESC, id DESC",
+        }
+    }
+
+    // The WHERE clause shared by the count and the page query
+    fn push_conditions(builder: &mut QueryBuilder<'_, Sqlite>, tenant: &TenantId, query: &str, filters: &RecordFilters) {
+        builder.push(" WHERE tenant_id = ").push_bind(tenant.as_str().to_string());
+        if !filters.include_deleted {
+            builder.push(" AND deleted_at IS NULL");
+        }
+        let query = query.trim();
+        if !query.is_empty() {
+            builder
+                .push(" AND EXISTS (SELECT 1 FROM field_values v WHERE v.record_id = fields.id AND v.value LIKE ")
+                .push_bind(like_pattern(query))
+                // Encrypted values can't match what the user typed, only by chance
+                .push(" ESCAPE '\\' AND v.value NOT LIKE 'enc:v1:%'");
+            if let Some(field) = &filters.field {
+                builder.push(" AND v.field_name = ").push_bind(field.clone());
+            }
+            builder.push(")");
+        }
+        if let Some(user) = &filters.updated_by {
+            builder.push(" AND updated_by = ").push_bind(user.clone());
+        }
+        if let Some(after) = filters.updated_after {
+            builder.push(" AND updated_at >= ").push_bind(after);
+        }
+        if let Some(before) = filters.updated_before {
+            builder.push(" AND updated_at < ").push_bind(before);
+        }
+    }
+
+    // Turn what the user typed into an FTS5 query: every word must appear, the
+    // last one also as the start of a longer word since the user may still be
+    // typing it. Words are quoted so FTS5 syntax in them is taken literally.
+    fn fts_query(text: &str) -> Option<String> {
+        let mut words: Vec<String> = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        words.last_mut()?.push('*');
+        Some(words.join(" "))
+    }
+
+    fn snippet_parts(snippet: &str) -> Vec<SnippetPart> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut highlighted = false;
+        for c in snippet.chars() {
+            if c == HIGHLIGHT_START || c == HIGHLIGHT_END {
+                if !text.is_empty() {
+                    parts.push(SnippetPart {
+                        text: std::mem::take(&mut text),
+                        highlighted,
+                    });
+                }
+                highlighted = c == HIGHLIGHT_START;
+            } else {
+                text.push(c);
+            }
+        }
+        if !text.is_empty() {
+            parts.push(SnippetPart { text, highlighted });
+        }
+        parts
+    }
+
+    impl DbManager {
+        // Records matching all words of `query` through the full-text index, best first
+        pub async fn search_fulltext(&self, query: &str) -> Result<Vec<FulltextMatch>, sqlx::Error> {
+            let Some(query) = fts_query(query) else {
+                return Ok(Vec::new());
+            };
+            let rows: Vec<(i64, String, String, f64)> = sqlx::query_as(&format!(
+                r#"
+                SELECT fields.id, {},
+                       snippet(records_fts, 0, ?, ?, '...', 12), bm25(records_fts) AS rank
+                FROM records_fts JOIN fields ON fields.id = records_fts.rowid
+                WHERE records_fts MATCH ? AND fields.tenant_id = ? AND fields.deleted_at IS NULL
+                ORDER BY rank
+                LIMIT ?
+                "#,
+                TITLE_EXPR
+            ))
+            .bind(HIGHLIGHT_START.to_string())
+            .bind(HIGHLIGHT_END.to_string())
+            .bind(query)
+            .bind(self.tenant().as_str())
+            .bind(FULLTEXT_LIMIT)
+            .fetch_all(self.pool())
+            .await?;
+            rows.into_iter()
+                .map(|(id, title, snippet, rank)| {
+                    Ok(FulltextMatch {
+                        id,
+                        title: reveal_title(title)?,
+                        snippet: snippet_parts(&snippet),
+                        rank,
+                    })
+                })
+                .collect()
+        }
+
+        // Records whose fields contain `query` (case-insensitively for ASCII), filtered,
+        // sorted and cut into pages
+        pub async fn search_records(
+            &self,
+            query: &str,
+            filters: &RecordFilters,
+            sort: RecordSort,
+            page: PageRequest,
+        ) -> Result<RecordPage, sqlx::Error> {
+            let page_size = page.page_size.clamp(1, MAX_PAGE_SIZE);
+            let page_number = page.page.max(1);
+
+            let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM fields");
+            push_conditions(&mut count, self.tenant(), query, filters);
+            let total: i64 = count.build_query_scalar().fetch_one(self.pool()).await?;
+
+            let mut select = QueryBuilder::<Sqlite>::new(format!(
+                "SELECT id, {} AS title, version, deleted_at FROM fields",
+                TITLE_EXPR
+            ));
+            push_conditions(&mut select, self.tenant(), query, filters);
+            select
+                .push(" ORDER BY ")
+                .push(order_by(sort))
+                .push(" LIMIT ")
+                .push_bind(page_size as i64)
+                .push(" OFFSET ")
+                .push_bind((page_number as i64 - 1) * page_size as i64);
+            let records = select
+                .build_query_as::<RecordSummary>()
+                .fetch_all(self.pool())
+                .await
+                .and_then(reveal_titles)?;
+
+            Ok(RecordPage {
+                records,
+                total,
+                page: page_number,
+                page_size,
+                total_pages: (total as u64).div_ceil(page_size as u64) as u32,
+            })
+        }
+    }
+}
+
+// Search and filter records, one page at a time
+#[server(SearchRecords)]
+pub async fn search_records(
+    query: String,
+    #[server(default)] filters: RecordFilters,
+    #[server(default)] sort: RecordSort,
+    #[server(default)] page: PageRequest,
+) -> Result<RecordPage, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.search_records(&query, &filters, sort, page)
+        .await
+        .map_err(db_error)
+}
+
+// Rank records by how well they match the words typed, through the full-text index
+#[server(SearchFulltext)]
+pub async fn search_fulltext(query: String) -> Result<Vec<FulltextMatch>, ServerFnError> {
+    use crate::auth::authorize;
+    use crate::field_editor::{db_error, open_db};
+    use crate::policy::{Action, ResourceKind};
+
+    let db = open_db().await?;
+    authorize(&db, Action::View, ResourceKind::Records).await?;
+    db.search_fulltext(&query).await.map_err(db_error)
+}
+
+/// Searches all records as you type and links to the best matches.
+#[component]
+pub fn SearchBox() -> impl IntoView {
+    let query = RwSignal::new(String::new());
+    let matches = Resource::new(move || query.get(), search_fulltext);
+
+    view! {
+        <div class="search-box">
+            <input
+                type="search"
+                placeholder="Search records"
+                prop:value=query
+                on:input=move |ev| query.set(event_target_value(&ev))
+            />
+            <Suspense fallback=|| ()>
+                {move || matches.get().map(|result| match result {
+                    Err(e) => view! { <div class="error">"Search failed: " {e.to_string()}</div> }.into_any(),
+                    Ok(matches) if matches.is_empty() => {
+                        (!query.get().trim().is_empty()).then(|| view! { <p>"No matches."</p> }).into_any()
+                    }
+                    Ok(matches) => view! {
+                        <ul class="search-results">
+                            {matches.into_iter().map(|found| view! {
+                                <li>
+                                    <a class="record-title" href=format!("/records/{}", found.id)>{found.title}</a>
+                                    <span class="snippet">
+                                        {found.snippet.into_iter().map(|part| match part.highlighted {
+                                            true => view! { <mark>{part.text}</mark> }.into_any(),
+                                            false => part.text.into_any(),
+                                        }).collect_view()}
+                                    </span>
+                                </li>
+                            }).collect_view()}
+                        </ul>
+                    }.into_any(),
+                })}
+            </Suspense>
+        </div>
+    }
+}
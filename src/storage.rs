@@ -0,0 +1,233 @@
+#[cfg(feature = "ssr")]
+use crate::db::DbManager;
+use crate::db::FieldValues;
+#[cfg(any(feature = "ssr", feature = "d1"))]
+use crate::db::FIELD_VALUES_COLUMN;
+#[cfg(any(feature = "ssr", feature = "d1"))]
+use crate::events::FieldEvent;
+#[cfg(feature = "ssr")]
+use crate::tenant::TenantId;
+use futures::future::BoxFuture;
+use std::fmt;
+
+// The part of the database the editor can't do without: reading a record and
+// saving it on top of the version it was read at. Besides SQLite through
+// `DbManager`, this can be served by databases only reachable over HTTP, such
+// as libSQL/Turso, for deployments with no local filesystem, and by Cloudflare
+// D1 from inside a Worker with the `d1` feature. Everything else
+// (sessions, the audit log's queries, attachments, reports) still needs
+// SQLite through `DbManager`.
+
+// A record as a storage backend returns it
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredRecord {
+    pub id: i64,
+    pub version: i64,
+    pub values: FieldValues,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    #[cfg(feature = "ssr")]
+    Sqlite(sqlx::Error),
+    // The remote database couldn't be reached or turned the request down
+    Remote(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "ssr")]
+            StorageError::Sqlite(e) => write!(f, "Database error: {}", e),
+            StorageError::Remote(message) => write!(f, "Remote database error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+#[cfg(feature = "ssr")]
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+// A statement parameter, for backends that take SQL as text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqlArg {
+    Integer(i64),
+    Text(String),
+}
+
+// Fails the statement, and so the transaction, unless the one before changed
+// exactly one row; json() rejects anything that isn't JSON. Lets a backend
+// without interactive transactions stop a save whose version check failed
+// without a round trip.
+#[cfg(any(feature = "ssr", feature = "d1"))]
+const REQUIRE_ONE_CHANGE: &str = "SELECT CASE changes() WHEN 1 THEN 1 ELSE json('version conflict') END";
+
+// Whether a failed save transaction failed on `REQUIRE_ONE_CHANGE`
+#[cfg(any(feature = "ssr", feature = "d1"))]
+pub(crate) fn is_version_conflict(message: &str) -> bool {
+    message.contains("malformed JSON")
+}
+
+// The statements of `RecordStorage::save_if_version` for a backend that runs
+// them in one transaction, in order, rolling back if any fails: bump the
+// version if it is still the expected one, stop unless that happened, write
+// the values and add the audit log entry. The entry has no checksum, so the
+// integrity check counts it as written before checksums were kept.
+#[cfg(any(feature = "ssr", feature = "d1"))]
+pub(crate) fn save_if_version_statements(
+    tenant: &str,
+    id: i64,
+    user: &str,
+    values: &FieldValues,
+    expected_version: i64,
+    now: i64,
+) -> Vec<(String, Vec<SqlArg>)> {
+    let event = FieldEvent::Updated;
+    vec![
+        (
+            r#"
+            UPDATE fields SET version = version + 1, updated_by = ?, updated_at = ?
+            WHERE id = ? AND tenant_id = ? AND version = ? AND deleted_at IS NULL
+            "#
+            .to_string(),
+            vec![
+                SqlArg::Text(user.to_string()),
+                SqlArg::Integer(now),
+                SqlArg::Integer(id),
+                SqlArg::Text(tenant.to_string()),
+                SqlArg::Integer(expected_version),
+            ],
+        ),
+        (REQUIRE_ONE_CHANGE.to_string(), Vec::new()),
+        (
+            r#"
+            INSERT INTO field_values (record_id, field_name, value, version)
+            SELECT f.id, j.key, j.value, f.version
+            FROM fields f
+            JOIN json_each(?) j
+            JOIN field_definitions d ON d.tenant_id = f.tenant_id AND d.name = j.key
+            WHERE f.id = ?
+            ON CONFLICT (record_id, field_name) DO UPDATE SET value = excluded.value, version = excluded.version
+            WHERE value IS NOT excluded.value
+            "#
+            .to_string(),
+            vec![
+                SqlArg::Text(serde_json::to_string(values).expect("FieldValues serialize")),
+                SqlArg::Integer(id),
+            ],
+        ),
+        (
+            format!(
+                r#"
+                INSERT INTO fields_history
+                    (record_id, version, change_type, event, field_values, changed_at, changed_by, tenant_id)
+                SELECT id, version, ?, ?, {}, ?, ?, tenant_id
+                FROM fields WHERE id = ?
+                "#,
+                FIELD_VALUES_COLUMN
+            ),
+            vec![
+                SqlArg::Text(event.kind().to_string()),
+                SqlArg::Text(serde_json::to_string(&event).expect("FieldEvent serializes")),
+                SqlArg::Integer(now),
+                SqlArg::Text(user.to_string()),
+                SqlArg::Integer(id),
+            ],
+        ),
+    ]
+}
+
+// The statement of `RecordStorage::load`: a record's version and its values as JSON
+#[cfg(any(feature = "ssr", feature = "d1"))]
+pub(crate) fn load_statement(tenant: &str, id: i64) -> (String, Vec<SqlArg>) {
+    (
+        format!(
+            "SELECT version, {} FROM fields WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL",
+            FIELD_VALUES_COLUMN
+        ),
+        vec![SqlArg::Integer(id), SqlArg::Text(tenant.to_string())],
+    )
+}
+
+// Milliseconds since the epoch, for backends without `DbManager`'s clock
+#[cfg(any(feature = "ssr", feature = "d1"))]
+pub(crate) fn now_millis() -> i64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now() as i64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    }
+}
+
+pub trait RecordStorage: Send + Sync {
+    // For logs
+    fn name(&self) -> &'static str;
+
+    // A tenant's record that isn't in the trash; None for missing ones and other tenants'
+    fn load(&self, tenant: &str, id: i64) -> BoxFuture<'_, Result<Option<StoredRecord>, StorageError>>;
+
+    // Save `values` by `user` as the next version, if the record is still at
+    // `expected_version`. Fields left out keep their values. Returns false if
+    // someone else saved first or the record is gone.
+    fn save_if_version<'a>(
+        &'a self,
+        tenant: &'a str,
+        id: i64,
+        user: &'a str,
+        values: &'a FieldValues,
+        expected_version: i64,
+    ) -> BoxFuture<'a, Result<bool, StorageError>>;
+}
+
+#[cfg(feature = "ssr")]
+impl RecordStorage for DbManager {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn load(&self, tenant: &str, id: i64) -> BoxFuture<'_, Result<Option<StoredRecord>, StorageError>> {
+        let db = TenantId::parse(tenant).map(|tenant| self.clone().with_tenant(tenant));
+        Box::pin(async move {
+            let Some(db) = db else {
+                return Ok(None);
+            };
+            match db.get_fields(id).await {
+                Ok(fields) => Ok(Some(StoredRecord {
+                    id: fields.id,
+                    version: fields.version,
+                    values: fields.values,
+                })),
+                Err(sqlx::Error::RowNotFound) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn save_if_version<'a>(
+        &'a self,
+        tenant: &'a str,
+        id: i64,
+        user: &'a str,
+        values: &'a FieldValues,
+        expected_version: i64,
+    ) -> BoxFuture<'a, Result<bool, StorageError>> {
+        let db = TenantId::parse(tenant).map(|tenant| self.clone().with_tenant(tenant));
+        Box::pin(async move {
+            match db {
+                Some(db) => Ok(db.update_fields(id, user, values, expected_version, None).await?),
+                None => Ok(false),
+            }
+        })
+    }
+}
@@ -0,0 +1,638 @@
+#[cfg(feature = "hydrate")]
+use crate::auth::{use_user_session, UserSession};
+use crate::db::{FieldValues, Fields};
+use crate::events::{EventEnvelope, FieldEvent};
+use crate::persistence::{default_persistence, StorePersistence, PENDING_SAVES, RECORDS};
+use crate::presence::RecordMetadata;
+use crate::timestamp::now_millis;
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// Hover prefetches allowed in flight at once, so sweeping the pointer down
+// the list doesn't queue up a request per row
+const PREFETCH_LIMIT: usize = 2;
+
+// localStorage key prefix of the drafts kept across reloads, followed by
+// `<user>/<record id>`
+#[cfg(feature = "hydrate")]
+const DRAFT_KEY_PREFIX: &str = "field-editor-draft-";
+// Drafts kept across reloads are dropped after this long
+#[cfg(feature = "hydrate")]
+const DRAFT_TTL_MILLIS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+// Edits not saved yet, kept while the user looks at other records, and in
+// localStorage in case the tab is reloaded or the browser crashes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Draft {
+    pub values: FieldValues,
+    // The version the edits started from
+    pub version: i64,
+}
+
+// A draft as kept in localStorage, with when it was last written
+#[cfg(feature = "hydrate")]
+#[derive(Serialize, Deserialize)]
+struct SavedDraft {
+    #[serde(flatten)]
+    draft: Draft,
+    saved_at: i64,
+}
+
+// A save made while offline, sent once the connection is back. Later saves
+// to the record replace its values but keep the version the first one
+// started from, so the server still checks them against that.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingSave {
+    // Who made the save; only they may send it. Saves queued before this was
+    // kept have no user and are dropped.
+    #[serde(default)]
+    pub user: String,
+    pub record_id: i64,
+    pub values: FieldValues,
+    pub expected_version: i64,
+    pub idempotency_key: uuid::Uuid,
+}
+
+// Something that happened while the user was looking elsewhere
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub id: u64,
+    pub record_id: i64,
+    pub message: String,
+    pub at: i64,
+}
+
+// Client-side state shared by the editor, record list, header and inbox, so each
+// of them reads the same cached records instead of fetching its own copy.
+// Holds one live connection for all of them; views that only need to know
+// "something changed" watch `revision` instead of opening their own.
+#[derive(Clone, Copy)]
+pub struct FieldEditorStore {
+    records: RwSignal<HashMap<i64, Fields>>,
+    // Saves shown as done before the server confirmed them, over the cached
+    // records; kept apart so a rollback brings back the cached record as it
+    // was, and the live feed still tells the confirmed versions from these
+    optimistic: RwSignal<HashMap<i64, Fields>>,
+    metadata: RwSignal<HashMap<i64, RecordMetadata>>,
+    notifications: RwSignal<Vec<Notification>>,
+    next_notification: StoredValue<u64>,
+    // Bumped whenever the server reports a change this tab doesn't know about yet
+    revision: RwSignal<u64>,
+    // Per record: unsaved edits and how far down the page the user was,
+    // picked up again when they come back to it
+    drafts: StoredValue<HashMap<i64, Draft>>,
+    scroll: StoredValue<HashMap<i64, f64>>,
+    // Records with a draft, for badges; only changes when one starts or ends
+    dirty: RwSignal<HashSet<i64>>,
+    // Records being prefetched right now
+    prefetching: StoredValue<HashSet<i64>>,
+    // Saves waiting for the connection to come back, per record; kept in the
+    // persistence so they survive a reload
+    pending: RwSignal<HashMap<i64, PendingSave>>,
+    // Editors open per record; they send their record's queued save
+    // themselves, so the user sees how it went in the form
+    editors: StoredValue<HashMap<i64, usize>>,
+    persistence: StoredValue<Arc<dyn StorePersistence>>,
+    // Whose drafts this tab keeps
+    #[cfg(feature = "hydrate")]
+    session: UserSession,
+}
+
+impl FieldEditorStore {
+    pub fn provide() -> Self {
+        Self::provide_with(default_persistence())
+    }
+
+    // Provide a store that keeps its cached records in the given persistence
+    pub fn provide_with(persistence: Arc<dyn StorePersistence>) -> Self {
+        let store = FieldEditorStore {
+            records: RwSignal::new(HashMap::new()),
+            optimistic: RwSignal::new(HashMap::new()),
+            metadata: RwSignal::new(HashMap::new()),
+            notifications: RwSignal::new(Vec::new()),
+            next_notification: StoredValue::new(1),
+            revision: RwSignal::new(0),
+            drafts: StoredValue::new(HashMap::new()),
+            scroll: StoredValue::new(HashMap::new()),
+            dirty: RwSignal::new(HashSet::new()),
+            prefetching: StoredValue::new(HashSet::new()),
+            pending: RwSignal::new(HashMap::new()),
+            editors: StoredValue::new(HashMap::new()),
+            persistence: StoredValue::new(persistence),
+            #[cfg(feature = "hydrate")]
+            session: use_user_session(),
+        };
+        provide_context(store);
+
+        // Records saved by an earlier visit; anything fresher loaded meanwhile wins
+        #[cfg(feature = "hydrate")]
+        {
+            let saved = store.persistence.with_value(|p| p.load_all(RECORDS));
+            wasm_bindgen_futures::spawn_local(async move {
+                let saved: Vec<Fields> = saved
+                    .await
+                    .iter()
+                    .filter_map(|json| serde_json::from_str(json).ok())
+                    .collect();
+                store.records.update(|records| {
+                    for fields in saved {
+                        if records.get(&fields.id).is_none_or(|cached| cached.version < fields.version) {
+                            records.insert(fields.id, fields);
+                        }
+                    }
+                });
+            });
+
+            // Saves still waiting from an earlier visit; any queued meanwhile are newer
+            let pending = store.persistence.with_value(|p| p.load_all(PENDING_SAVES));
+            wasm_bindgen_futures::spawn_local(async move {
+                let pending: Vec<PendingSave> = pending
+                    .await
+                    .iter()
+                    .filter_map(|json| serde_json::from_str(json).ok())
+                    .collect();
+                if pending.is_empty() {
+                    return;
+                }
+                store.pending.update(|queued| {
+                    for save in pending {
+                        queued.entry(save.record_id).or_insert(save);
+                    }
+                });
+            });
+        }
+
+        #[cfg(feature = "hydrate")]
+        {
+            use crate::changefeed::{subscribe, LiveUpdate};
+
+            let session = store.session;
+
+            // Drafts that are too old, or of an older layout without a user
+            let now = now_millis();
+            forget_saved_drafts(|user, json| {
+                user.is_none()
+                    || serde_json::from_str::<SavedDraft>(json).map_or(true, |saved| now - saved.saved_at > DRAFT_TTL_MILLIS)
+            });
+            // Someone signing out, or another user signing in, takes the
+            // drafts of whoever was signed in before off this device
+            Effect::new(move |previous: Option<Option<String>>| {
+                let Some(user) = session.user.get() else {
+                    return previous.flatten();
+                };
+                let me = user.map(|u| u.name);
+                if let Some(Some(before)) = previous.filter(|before| *before != me) {
+                    store.forget_drafts_of(&before);
+                }
+                me
+            });
+            let subscription = subscribe(move |update| match update {
+                LiveUpdate::Change(change) => {
+                    let me = session.user.get_untracked().flatten().map(|u| u.name);
+                    store.apply_change(&change, me.as_deref());
+                }
+                LiveUpdate::Resync | LiveUpdate::Reconnected => store.bump(),
+                LiveUpdate::Disconnected => {}
+            });
+            on_cleanup(move || drop(subscription));
+
+            // Send queued saves whenever the browser is back online, or someone
+            // signs in with saves waiting, whichever page is showing
+            let send_queued = move || {
+                let Some(me) = session.user.get_untracked().flatten().map(|u| u.name) else {
+                    return;
+                };
+                let Some(csrf_token) = session.csrf_token.get_untracked().flatten() else {
+                    return;
+                };
+                store.drop_others_saves(&me);
+                if !window().navigator().on_line() {
+                    return;
+                }
+                let queued: Vec<PendingSave> = store.pending.with_untracked(|p| p.values().cloned().collect());
+                for save in queued {
+                    if store.editors.with_value(|e| e.contains_key(&save.record_id)) {
+                        continue;
+                    }
+                    let csrf_token = csrf_token.clone();
+                    wasm_bindgen_futures::spawn_local(async move { store.send_queued(save, csrf_token).await });
+                }
+            };
+            let went_online = window_event_listener(leptos::ev::online, move |_| send_queued());
+            on_cleanup(move || went_online.remove());
+            Effect::new(move |_| {
+                let signed_in = session.user.get().flatten().is_some() && session.csrf_token.get().flatten().is_some();
+                if signed_in && store.pending.with(|p| !p.is_empty()) {
+                    untrack(send_queued);
+                }
+            });
+        }
+
+        store
+    }
+
+    // A cached record, if any view has loaded or saved it, as a save still in
+    // flight will leave it
+    pub fn record(&self, id: i64) -> Option<Fields> {
+        self.optimistic
+            .with(|optimistic| optimistic.get(&id).cloned())
+            .or_else(|| self.records.with(|records| records.get(&id).cloned()))
+    }
+
+    // The newest version of a record the server confirmed to this tab, leaving
+    // out saves still in flight
+    pub fn confirmed_version(&self, id: i64) -> Option<i64> {
+        self.records.with_untracked(|records| records.get(&id).map(|f| f.version))
+    }
+
+    // Cache a record unless we already hold a newer version of it
+    pub fn put_record(&self, fields: Fields) {
+        let json = serde_json::to_string(&fields).expect("Fields serialize");
+        let (id, version) = (fields.id, fields.version);
+        let mut stored = false;
+        self.records.update(|records| {
+            if records.get(&id).is_none_or(|cached| cached.version <= version) {
+                records.insert(id, fields);
+                stored = true;
+            }
+        });
+        if stored {
+            self.persistence.with_value(|p| p.save(RECORDS, id.to_string(), json));
+        }
+    }
+
+    // Show a save as done until `settle` is called with its outcome; only in
+    // this tab's memory, never persisted
+    pub fn put_optimistic(&self, fields: Fields) {
+        self.optimistic.update(|optimistic| {
+            optimistic.insert(fields.id, fields);
+        });
+    }
+
+    // The server answered the save shown by `put_optimistic`: keep it as
+    // cached if it was applied as shown, or drop it to fall back to the cached
+    // record otherwise
+    pub fn settle(&self, id: i64, applied: bool) {
+        let mut shown = None;
+        self.optimistic.update(|optimistic| shown = optimistic.remove(&id));
+        if let Some(fields) = shown.filter(|_| applied) {
+            self.put_record(fields);
+        }
+    }
+
+    // Load a record into the cache ahead of the editor asking for it, e.g. while
+    // the pointer rests on its row. Skipped if the cache already holds the
+    // version the list shows, or too many prefetches are still out.
+    pub fn prefetch(&self, id: i64) {
+        let listed = self.metadata.with_untracked(|m| m.get(&id).map(|m| m.version));
+        let cached = self.records.with_untracked(|r| r.get(&id).map(|f| f.version));
+        if cached.is_some_and(|cached| listed.is_none_or(|listed| cached >= listed)) {
+            return;
+        }
+        let started = self.prefetching.try_update_value(|inflight| {
+            inflight.len() < PREFETCH_LIMIT && inflight.insert(id)
+        });
+        if started != Some(true) {
+            return;
+        }
+
+        let store = *self;
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Ok(fields) = crate::field_editor::get_fields(id, None).await {
+                store.put_record(fields);
+            }
+            store.prefetching.update_value(|inflight| {
+                inflight.remove(&id);
+            });
+        });
+    }
+
+    // Drop a record from the cache, e.g. after it was deleted
+    pub fn forget_record(&self, id: i64) {
+        self.records.update(|records| {
+            records.remove(&id);
+        });
+        self.optimistic.update(|optimistic| {
+            optimistic.remove(&id);
+        });
+        self.forget_draft(id);
+        self.persistence.with_value(|p| p.remove(RECORDS, id.to_string()));
+    }
+
+    // The localStorage key of the signed-in user's draft of a record; None
+    // while nobody is signed in, as drafts are only kept for their author
+    #[cfg(feature = "hydrate")]
+    fn draft_key(&self, id: i64) -> Option<String> {
+        let user = self.session.user.get_untracked().flatten()?;
+        Some(format!("{}{}/{}", DRAFT_KEY_PREFIX, user.name, id))
+    }
+
+    // Keep unsaved edits to a record for when the user comes back to it
+    pub fn put_draft(&self, id: i64, draft: Draft) {
+        #[cfg(feature = "hydrate")]
+        if let (Some(storage), Some(key)) = (local_storage(), self.draft_key(id)) {
+            let saved = SavedDraft {
+                draft: draft.clone(),
+                saved_at: now_millis(),
+            };
+            let json = serde_json::to_string(&saved).expect("Draft serialize");
+            let _ = storage.set_item(&key, &json);
+        }
+        self.drafts.update_value(|drafts| {
+            drafts.insert(id, draft);
+        });
+        if !self.dirty.with_untracked(|dirty| dirty.contains(&id)) {
+            self.dirty.update(|dirty| {
+                dirty.insert(id);
+            });
+        }
+    }
+
+    // The unsaved edits to a record, leaving them in place until they are saved or discarded
+    pub fn draft(&self, id: i64) -> Option<Draft> {
+        self.drafts.with_value(|drafts| drafts.get(&id).cloned())
+    }
+
+    // The edits to a record an earlier page load left unsaved, e.g. before a
+    // reload or crash, unless they have expired; only in the browser
+    pub fn saved_draft(&self, id: i64) -> Option<Draft> {
+        #[cfg(feature = "hydrate")]
+        {
+            let storage = local_storage()?;
+            let key = self.draft_key(id)?;
+            let saved: SavedDraft = serde_json::from_str(&storage.get_item(&key).ok()??).ok()?;
+            if now_millis() - saved.saved_at > DRAFT_TTL_MILLIS {
+                let _ = storage.remove_item(&key);
+                return None;
+            }
+            Some(saved.draft)
+        }
+        #[cfg(not(feature = "hydrate"))]
+        {
+            let _ = id;
+            None
+        }
+    }
+
+    pub fn forget_draft(&self, id: i64) {
+        #[cfg(feature = "hydrate")]
+        if let (Some(storage), Some(key)) = (local_storage(), self.draft_key(id)) {
+            let _ = storage.remove_item(&key);
+        }
+        self.drafts.update_value(|drafts| {
+            drafts.remove(&id);
+        });
+        if self.dirty.with_untracked(|dirty| dirty.contains(&id)) {
+            self.dirty.update(|dirty| {
+                dirty.remove(&id);
+            });
+        }
+    }
+
+    // Drop every draft of a user who is no longer signed in here, kept in
+    // localStorage or held by this tab
+    #[cfg(feature = "hydrate")]
+    fn forget_drafts_of(&self, user: &str) {
+        forget_saved_drafts(|owner, _| owner == Some(user));
+        self.drafts.update_value(HashMap::clear);
+        self.dirty.update(HashSet::clear);
+    }
+
+    // Tracked: whether a record has edits that weren't saved
+    pub fn has_draft(&self, id: i64) -> bool {
+        self.dirty.with(|dirty| dirty.contains(&id))
+    }
+
+    // Keep a save for when the connection is back, in place of any earlier one
+    // to the same record
+    pub fn queue_save(&self, save: PendingSave) {
+        let json = serde_json::to_string(&save).expect("PendingSave serialize");
+        let id = save.record_id;
+        self.pending.update(|pending| {
+            pending.insert(id, save);
+        });
+        self.persistence.with_value(|p| p.save(PENDING_SAVES, id.to_string(), json));
+    }
+
+    // Tracked: the save waiting to be sent for a record, if any
+    pub fn pending_save(&self, id: i64) -> Option<PendingSave> {
+        self.pending.with(|pending| pending.get(&id).cloned())
+    }
+
+    // Tracked: whether a record has a save waiting to be sent
+    pub fn has_pending_save(&self, id: i64) -> bool {
+        self.pending.with(|pending| pending.contains_key(&id))
+    }
+
+    pub fn forget_pending_save(&self, id: i64) {
+        self.pending.update(|pending| {
+            pending.remove(&id);
+        });
+        self.persistence.with_value(|p| p.remove(PENDING_SAVES, id.to_string()));
+    }
+
+    // Drop the saves someone other than `me` queued on this device, e.g.
+    // before they signed out; they can't be sent as them
+    pub fn drop_others_saves(&self, me: &str) {
+        let others: Vec<i64> = self
+            .pending
+            .with_untracked(|p| p.values().filter(|save| save.user != me).map(|save| save.record_id).collect());
+        for id in others {
+            self.forget_pending_save(id);
+        }
+    }
+
+    // An editor for the record was mounted; until it is closed again, it
+    // sends the record's queued save
+    pub fn open_editor(&self, id: i64) {
+        self.editors.update_value(|editors| *editors.entry(id).or_default() += 1);
+    }
+
+    pub fn close_editor(&self, id: i64) {
+        self.editors.update_value(|editors| {
+            if let Some(count) = editors.get_mut(&id) {
+                *count -= 1;
+                if *count == 0 {
+                    editors.remove(&id);
+                }
+            }
+        });
+    }
+
+    // Send a queued save for a record no editor is showing. What couldn't be
+    // saved becomes the record's draft, for the user to take up in the editor.
+    #[cfg(feature = "hydrate")]
+    async fn send_queued(&self, save: PendingSave, csrf_token: String) {
+        use crate::errors::EditorError;
+        use crate::field_editor::{update_fields, SaveOutcome};
+        use server_fn::error::ServerFnError;
+
+        let id = save.record_id;
+        let result = update_fields(
+            id,
+            save.values.clone(),
+            save.expected_version,
+            csrf_token,
+            save.idempotency_key,
+            None,
+            None,
+        )
+        .await;
+        match result {
+            // Still unreachable, or asked to slow down; sent again next time
+            Err(ServerFnError::Request(_)) | Err(ServerFnError::WrappedServerError(EditorError::TooManyRequests { .. })) => {
+                return;
+            }
+            _ => {
+                self.forget_pending_save(id);
+                self.settle(id, false);
+            }
+        }
+        let message = match result {
+            Ok(SaveOutcome::Saved | SaveOutcome::Merged { .. } | SaveOutcome::Overwritten { .. }) => {
+                format!("The changes you made offline to record {} were saved", id)
+            }
+            Ok(SaveOutcome::PendingApproval { .. }) => {
+                format!("The changes you made offline to record {} are waiting for approval", id)
+            }
+            _ => {
+                self.put_draft(id, Draft {
+                    values: save.values,
+                    version: save.expected_version,
+                });
+                format!("The changes you made offline to record {} could not be saved; open it to review them", id)
+            }
+        };
+        self.notify(id, message);
+        self.bump();
+    }
+
+    pub fn put_scroll(&self, id: i64, y: f64) {
+        self.scroll.update_value(|scroll| {
+            scroll.insert(id, y);
+        });
+    }
+
+    // Where the page was scrolled to when the user last had the record open
+    pub fn scroll(&self, id: i64) -> Option<f64> {
+        self.scroll.with_value(|scroll| scroll.get(&id).copied())
+    }
+
+    pub fn metadata(&self, id: i64) -> Option<RecordMetadata> {
+        self.metadata.with(|metadata| metadata.get(&id).cloned())
+    }
+
+    pub fn put_metadata(&self, rows: Vec<RecordMetadata>) {
+        self.metadata.update(|metadata| {
+            for row in rows {
+                metadata.insert(row.id, row);
+            }
+        });
+    }
+
+    // Newest version of a record this tab knows the server has
+    fn known_version(&self, id: i64) -> Option<i64> {
+        let cached = self.records.with_untracked(|r| r.get(&id).map(|f| f.version));
+        let listed = self.metadata.with_untracked(|m| m.get(&id).map(|m| m.version));
+        cached.max(listed)
+    }
+
+    // Tracked counter for views that refetch when the server reports news
+    pub fn revision(&self) -> u64 {
+        self.revision.get()
+    }
+
+    fn bump(&self) {
+        self.revision.update(|r| *r += 1);
+    }
+
+    // Take in a change from the live feed. Echoes of our own saves are already
+    // in the cache; other users' edits and reviews that fall to us also land
+    // in the inbox.
+    pub fn apply_change(&self, change: &EventEnvelope, me: Option<&str>) {
+        let own_save = me.is_some_and(|me| change.actor.as_deref() == Some(me))
+            && self.optimistic.with_untracked(|o| o.get(&change.record_id).is_some_and(|f| f.version == change.version));
+        if change.event.changes_state()
+            && (own_save || self.known_version(change.record_id).is_some_and(|v| v >= change.version))
+        {
+            return;
+        }
+        self.bump();
+
+        if let FieldEvent::ReviewDue { recipients, .. } = &change.event {
+            if me.is_some_and(|me| recipients.iter().any(|r| r == me)) {
+                self.notify(change.record_id, format!("Record {} is due for review", change.record_id));
+            }
+        }
+        if let Some(actor) = change.actor.as_deref() {
+            if change.event.changes_state() && Some(actor) != me {
+                self.notify(
+                    change.record_id,
+                    format!("{} {} record {}", actor, change.event.kind(), change.record_id),
+                );
+            }
+        }
+    }
+
+    pub fn notify(&self, record_id: i64, message: String) {
+        let id = self.next_notification.get_value();
+        self.next_notification.set_value(id + 1);
+        self.notifications.update(|n| {
+            n.push(Notification {
+                id,
+                record_id,
+                message,
+                at: now_millis(),
+            })
+        });
+    }
+
+    // Newest first
+    pub fn notifications(&self) -> Vec<Notification> {
+        self.notifications.with(|n| n.iter().rev().cloned().collect())
+    }
+
+    pub fn notification_count(&self) -> usize {
+        self.notifications.with(Vec::len)
+    }
+
+    pub fn dismiss(&self, id: u64) {
+        self.notifications.update(|n| n.retain(|n| n.id != id));
+    }
+
+    pub fn dismiss_all(&self) {
+        self.notifications.update(Vec::clear);
+    }
+}
+
+pub fn use_store() -> FieldEditorStore {
+    expect_context::<FieldEditorStore>()
+}
+
+// The browser's localStorage, unless it is unavailable, e.g. disabled by the user
+#[cfg(feature = "hydrate")]
+fn local_storage() -> Option<web_sys::Storage> {
+    window().local_storage().ok().flatten()
+}
+
+// Remove the drafts in localStorage `drop` picks, given the user a draft
+// belongs to (None for keys without one) and its JSON
+#[cfg(feature = "hydrate")]
+fn forget_saved_drafts(drop: impl Fn(Option<&str>, &str) -> bool) {
+    let Some(storage) = local_storage() else {
+        return;
+    };
+    let keys: Vec<String> = (0..storage.length().unwrap_or(0))
+        .filter_map(|i| storage.key(i).ok().flatten())
+        .filter(|key| key.starts_with(DRAFT_KEY_PREFIX))
+        .collect();
+    for key in keys {
+        let user = key[DRAFT_KEY_PREFIX.len()..].rsplit_once('/').map(|(user, _)| user);
+        let json = storage.get_item(&key).ok().flatten().unwrap_or_default();
+        if drop(user, &json) {
+            let _ = storage.remove_item(&key);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use futures::future::{AbortHandle, Abortable};
+use leptos::prelude::*;
+use std::collections::HashMap;
+use std::future::Future;
+
+// Async work a component starts in the browser, like a save or a reload, tied
+// to the component's lifetime. Whatever is still running when the component
+// is unmounted, e.g. after navigating to another record, is dropped at its
+// next await instead of resuming and writing into signals that were disposed
+// with the component. Create the `Tasks` in the component's body, where
+// there is an owner to clean up with, and spawn through it from event
+// handlers and effects. State that lives as long as the app, like the store,
+// spawns with plain `spawn_local`.
+
+#[derive(Default)]
+struct Running {
+    next: u64,
+    tasks: HashMap<u64, AbortHandle>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Tasks {
+    running: StoredValue<Running>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        let running = StoredValue::new(Running::default());
+        on_cleanup(move || {
+            running.try_update_value(|running| {
+                for (_, task) in running.tasks.drain() {
+                    task.abort();
+                }
+            });
+        });
+        Tasks { running }
+    }
+
+    // Run `future` until it finishes or the component goes away; nothing
+    // happens if it is already gone
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        let (handle, registration) = AbortHandle::new_pair();
+        let Some(id) = self.running.try_update_value(|running| {
+            let id = running.next;
+            running.next += 1;
+            running.tasks.insert(id, handle);
+            id
+        }) else {
+            return;
+        };
+        let running = self.running;
+        wasm_bindgen_futures::spawn_local(async move {
+            if Abortable::new(future, registration).await.is_ok() {
+                running.try_update_value(|running| running.tasks.remove(&id));
+            }
+        });
+    }
+}
@@ -0,0 +1,242 @@
+use crate::api_tokens::TokenGrant;
+use crate::db::DbManager;
+use crate::rest::{error, session_id};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use futures::future::LocalBoxFuture;
+use sqlx::SqliteConnection;
+use std::fmt;
+use std::sync::OnceLock;
+
+// Several organisations can share one server and one database. Every record
+// belongs to a tenant, and a `DbManager` works for exactly one: its queries
+// only see that tenant's records, and anything that changes a record takes a
+// `ScopedRecord`, which only `DbManager::scope` hands out after checking the
+// record belongs to the manager's tenant. Which tenant a request is for comes
+// from a header, the subdomain, or the signed-in user's session. A header or
+// subdomain only picks the tenant: a signed-in request must name the tenant
+// of its session or API token.
+
+pub const DEFAULT_TENANT: &str = "default";
+
+// Set by a trusted proxy when tenants come from a header; checked against the
+// tenant of the session or API token
+pub const TENANT_HEADER: &str = "X-Tenant-Id";
+
+// The id of a tenant: lowercase letters, digits and dashes, as in a host name
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TenantId(String);
+
+impl TenantId {
+    pub fn parse(id: &str) -> Option<Self> {
+        let valid = (1..=63).contains(&id.len())
+            && id.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            && !id.starts_with('-')
+            && !id.ends_with('-');
+        valid.then(|| TenantId(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for TenantId {
+    fn default() -> Self {
+        TenantId(DEFAULT_TENANT.to_string())
+    }
+}
+
+impl fmt::Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Where a request's tenant comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TenantSource {
+    // Everything belongs to the default tenant
+    Single,
+    // The `X-Tenant-Id` header
+    Header,
+    // The first label of the host name, below `domain`
+    Subdomain { domain: String },
+    // The tenant of the signed-in user; the default one before signing in
+    Session,
+}
+
+impl TenantSource {
+    // FIELD_EDITOR_TENANT_SOURCE: `header`, `subdomain` (below
+    // FIELD_EDITOR_TENANT_DOMAIN, e.g. `editor.example.com`), `session`, or
+    // a single tenant if unset
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        match var("FIELD_EDITOR_TENANT_SOURCE").as_deref() {
+            Some("header") => TenantSource::Header,
+            Some("subdomain") => TenantSource::Subdomain {
+                domain: var("FIELD_EDITOR_TENANT_DOMAIN")
+                    .expect("FIELD_EDITOR_TENANT_DOMAIN must be set for subdomain tenants")
+                    .to_ascii_lowercase(),
+            },
+            Some("session") => TenantSource::Session,
+            Some(other) if other != "single" => panic!("Unknown tenant source {}", other),
+            _ => TenantSource::Single,
+        }
+    }
+}
+
+// The tenant source for this process, read from the environment once
+pub fn tenant_source() -> &'static TenantSource {
+    static SOURCE: OnceLock<TenantSource> = OnceLock::new();
+    SOURCE.get_or_init(TenantSource::from_env)
+}
+
+#[derive(Debug)]
+pub enum TenantError {
+    // The request doesn't say which tenant it's for
+    Missing,
+    Invalid(String),
+    // The request names a tenant other than its session's or API token's
+    Mismatch(TenantId),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantError::Missing => write!(f, "The request does not say which tenant it is for"),
+            TenantError::Invalid(id) => write!(f, "Invalid tenant {:?}", id),
+            TenantError::Mismatch(id) => write!(f, "You are not signed in to tenant {}", id),
+            TenantError::Database(e) => write!(f, "Could not look up the tenant: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TenantError {}
+
+// The tenant a request is for, per `tenant_source()`
+pub async fn resolve_tenant(db: &DbManager, req: &HttpRequest) -> Result<TenantId, TenantError> {
+    let parse = |id: &str| TenantId::parse(id).ok_or_else(|| TenantError::Invalid(id.to_string()));
+    match tenant_source() {
+        TenantSource::Single => Ok(TenantId::default()),
+        TenantSource::Header => {
+            let value = req.headers().get(TENANT_HEADER).ok_or(TenantError::Missing)?;
+            let tenant = parse(value.to_str().map_err(|_| TenantError::Invalid(format!("{:?}", value)))?.trim())?;
+            check_caller_tenant(db, req, tenant).await
+        }
+        TenantSource::Subdomain { domain } => {
+            let host = req
+                .headers()
+                .get(header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(TenantError::Missing)?;
+            let host = host.rsplit_once(':').map_or(host, |(name, _)| name).to_ascii_lowercase();
+            let subdomain = host
+                .strip_suffix(domain.as_str())
+                .and_then(|rest| rest.strip_suffix('.'))
+                .ok_or(TenantError::Missing)?;
+            let tenant = parse(subdomain)?;
+            check_caller_tenant(db, req, tenant).await
+        }
+        TenantSource::Session => Ok(caller_tenant(db, req).await?.unwrap_or_default()),
+    }
+}
+
+// The tenant of the request's API token or session, if it has either
+async fn caller_tenant(db: &DbManager, req: &HttpRequest) -> Result<Option<TenantId>, TenantError> {
+    // An API token is for the tenant it was created with
+    if let Some(grant) = req.extensions().get::<TokenGrant>() {
+        return Ok(Some(grant.tenant.clone()));
+    }
+    match session_id(req) {
+        Some(session_id) => db.session_tenant(&session_id).await.map_err(TenantError::Database),
+        None => Ok(None),
+    }
+}
+
+// `tenant`, unless the request is signed in to another one: the client picks
+// the header and the host, so they can't be trusted on their own
+async fn check_caller_tenant(db: &DbManager, req: &HttpRequest, tenant: TenantId) -> Result<TenantId, TenantError> {
+    match caller_tenant(db, req).await? {
+        Some(bound) if bound != tenant => Err(TenantError::Mismatch(tenant)),
+        _ => Ok(tenant),
+    }
+}
+
+// A record id checked to belong to a `DbManager`'s tenant. Writes to a record
+// take one of these, so a write that skipped the check doesn't compile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScopedRecord {
+    id: i64,
+}
+
+impl ScopedRecord {
+    pub fn id(self) -> i64 {
+        self.id
+    }
+}
+
+impl DbManager {
+    // The record `id` if it belongs to this manager's tenant, whether or not
+    // it's in the trash; None for other tenants' records and missing ones alike
+    pub async fn scope(&self, conn: &mut SqliteConnection, id: i64) -> Result<Option<ScopedRecord>, sqlx::Error> {
+        let found: Option<i64> = sqlx::query_scalar("SELECT id FROM fields WHERE id = ? AND tenant_id = ?")
+            .bind(id)
+            .bind(self.tenant().as_str())
+            .fetch_optional(&mut *conn)
+            .await?;
+        Ok(found.map(|id| ScopedRecord { id }))
+    }
+
+    // The tenant of a live session, whatever tenant this manager is for
+    pub async fn session_tenant(&self, session_id: &str) -> Result<Option<TenantId>, sqlx::Error> {
+        let tenant: Option<String> =
+            sqlx::query_scalar("SELECT tenant_id FROM sessions WHERE id = ? AND expires_at > ?")
+                .bind(session_id)
+                .bind(self.now())
+                .fetch_optional(self.pool())
+                .await?;
+        Ok(tenant.and_then(|id| TenantId::parse(&id)))
+    }
+}
+
+// The shared `DbManager`, scoped to the tenant of the request. Routes take this
+// instead of `web::Data<DbManager>`.
+pub struct TenantDb(pub DbManager);
+
+impl std::ops::Deref for TenantDb {
+    type Target = DbManager;
+
+    fn deref(&self) -> &DbManager {
+        &self.0
+    }
+}
+
+impl FromRequest for TenantDb {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let db = req
+                .app_data::<web::Data<DbManager>>()
+                .expect("DbManager is registered as app data");
+            match resolve_tenant(db, &req).await {
+                Ok(tenant) => Ok(TenantDb(DbManager::clone(db).with_tenant(tenant))),
+                Err(e) => {
+                    let response = match &e {
+                        TenantError::Database(e) => {
+                            tracing::error!(error = %e, "Could not resolve tenant");
+                            error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+                        }
+                        TenantError::Mismatch(_) => error(StatusCode::FORBIDDEN, &e.to_string()),
+                        e => error(StatusCode::BAD_REQUEST, &e.to_string()),
+                    };
+                    Err(actix_web::error::InternalError::from_response(e, response).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use crate::attachments::MAX_ATTACHMENT_BYTES;
+use crate::blobs::{BlobError, BlobStore};
+use crate::policy::{Action, ResourceKind};
+use crate::rest::{authorized_user, error, internal_error};
+use crate::tenant::TenantDb;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use image::{ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+// Scaled-down copies of image attachments. Each size is made the first time
+// it's asked for and kept in the blob store next to the original; attachments
+// never change, so browsers may cache them for good.
+
+// The sizes thumbnails come in, as the longest side in pixels. Only these, so
+// requests can't fill the blob store with arbitrary sizes.
+pub const THUMBNAIL_SIZES: [u32; 3] = [64, 200, 800];
+
+const DEFAULT_SIZE: u32 = 200;
+
+// Refuse to decode images larger than this, however small the file
+const MAX_DIMENSION: u32 = 10_000;
+const MAX_DECODE_BYTES: u64 = 512 * 1024 * 1024;
+
+fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    limits
+}
+
+// Width and height of the image in the file at `path`, from its header alone
+pub async fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let path = path.to_owned();
+    let read = move || -> Result<(u32, u32), image::ImageError> {
+        ImageReader::open(path)?.with_guessed_format()?.into_dimensions()
+    };
+    tokio::task::spawn_blocking(read).await.ok()?.ok()
+}
+
+// JPEGs stay JPEGs; everything else becomes a PNG so transparency survives
+fn thumbnail_format(original_content_type: &str) -> (ImageFormat, &'static str) {
+    match original_content_type {
+        "image/jpeg" => (ImageFormat::Jpeg, "image/jpeg"),
+        _ => (ImageFormat::Png, "image/png"),
+    }
+}
+
+// Scale an image down to fit in a `size` by `size` square, keeping its aspect
+// ratio. Images that already fit keep their size.
+fn make_thumbnail(original: &[u8], size: u32, format: ImageFormat) -> Result<Vec<u8>, image::ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(original)).with_guessed_format()?;
+    reader.limits(decode_limits());
+    let image = reader.decode()?;
+    let image = match image.width() > size || image.height() > size {
+        true => image.thumbnail(size, size),
+        false => image,
+    };
+    let image = match format {
+        // JPEG has no alpha channel
+        ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image,
+    };
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format)?;
+    Ok(encoded.into_inner())
+}
+
+pub(crate) fn thumbnail_key(attachment_id: &str, size: u32) -> String {
+    format!("{}_thumb{}", attachment_id, size)
+}
+
+// Read a whole blob; attachments are small enough to decode in memory
+async fn read_blob(store: &dyn BlobStore, key: &str) -> Result<Vec<u8>, BlobError> {
+    let mut contents = Vec::new();
+    let mut stream = store.get(key).await?;
+    while let Some(chunk) = stream.try_next().await? {
+        contents.extend_from_slice(&chunk);
+        if contents.len() as u64 > MAX_ATTACHMENT_BYTES {
+            return Err(BlobError::Backend(format!("Blob {} is larger than any attachment", key)));
+        }
+    }
+    Ok(contents)
+}
+
+// Stores take files, so the thumbnail goes through a temporary one
+async fn put_bytes(store: &dyn BlobStore, key: &str, bytes: &[u8], content_type: &str) -> Result<(), BlobError> {
+    let path: PathBuf = std::env::temp_dir().join(format!("field-editor-{}", key));
+    tokio::fs::write(&path, bytes).await?;
+    let result = store.put(key, &path, content_type).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    result
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailQuery {
+    size: Option<u32>,
+}
+
+// An image attachment scaled down to `?size=` (one of `THUMBNAIL_SIZES`)
+#[actix_web::get("/api/attachments/{id}/thumbnail")]
+pub async fn attachment_thumbnail(
+    db: TenantDb,
+    store: web::Data<dyn BlobStore>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ThumbnailQuery>,
+) -> HttpResponse {
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !THUMBNAIL_SIZES.contains(&size) {
+        return error(StatusCode::BAD_REQUEST, "Unsupported thumbnail size");
+    }
+    let attachment = match db.attachment(&id).await {
+        Ok(Some(attachment)) if attachment.is_image() => attachment,
+        Ok(_) => return error(StatusCode::NOT_FOUND, "No such image"),
+        Err(e) => return internal_error(e),
+    };
+    let record = ResourceKind::Record(attachment.record_id);
+    if let Err(response) = authorized_user(&db, &req, Action::View, record).await {
+        return response;
+    }
+
+    let etag = format!("\"{}-{}\"", attachment.id, size);
+    let cached = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+    if cached {
+        return HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish();
+    }
+
+    let (format, content_type) = thumbnail_format(&attachment.content_type);
+    let key = thumbnail_key(&attachment.id, size);
+    let thumbnail = match read_blob(&**store, &key).await {
+        Ok(thumbnail) => thumbnail,
+        Err(BlobError::NotFound) => {
+            let original = match read_blob(&**store, &attachment.id).await {
+                Ok(original) => original,
+                Err(e) => {
+                    tracing::error!(error = %e, attachment = %attachment.id, "Could not read image");
+                    return error(StatusCode::BAD_GATEWAY, "Could not read the file");
+                }
+            };
+            let scaled = tokio::task::spawn_blocking(move || make_thumbnail(&original, size, format)).await;
+            let thumbnail = match scaled {
+                Ok(Ok(thumbnail)) => thumbnail,
+                Ok(Err(e)) => {
+                    tracing::warn!(error = %e, attachment = %attachment.id, "Could not make thumbnail");
+                    return error(StatusCode::UNPROCESSABLE_ENTITY, "The image could not be read");
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, attachment = %attachment.id, "Thumbnail task failed");
+                    return error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+                }
+            };
+            // Serving it matters more than keeping it; the next request can try again
+            if let Err(e) = put_bytes(&**store, &key, &thumbnail, content_type).await {
+                tracing::warn!(error = %e, attachment = %attachment.id, size, "Could not keep thumbnail");
+            }
+            tracing::info!(attachment = %attachment.id, size, bytes = thumbnail.len(), "Thumbnail made");
+            thumbnail
+        }
+        Err(e) => {
+            tracing::error!(error = %e, attachment = %attachment.id, size, "Could not read thumbnail");
+            return error(StatusCode::BAD_GATEWAY, "Could not read the file");
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, immutable"))
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(thumbnail)
+}
@@ -0,0 +1,110 @@
+use leptos::prelude::*;
+#[cfg(feature = "hydrate")]
+use std::time::Duration;
+
+// How long a toast stays up unless it is dismissed first; errors stay
+// longer, since they usually ask the user to do something
+#[cfg(feature = "hydrate")]
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+#[cfg(feature = "hydrate")]
+const ERROR_TOAST_DURATION: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToastKind {
+    Success,
+    Warning,
+    Error,
+}
+
+impl ToastKind {
+    fn class(self) -> &'static str {
+        match self {
+            ToastKind::Success => "toast success",
+            ToastKind::Warning => "toast warning",
+            ToastKind::Error => "toast error",
+        }
+    }
+}
+
+// A short message about something the user just did
+#[derive(Debug, Clone, PartialEq)]
+pub struct Toast {
+    pub id: u64,
+    pub kind: ToastKind,
+    pub message: String,
+}
+
+// The toasts on screen, shared by every view so they stack in one place
+// however many editors the page has
+#[derive(Clone, Copy)]
+pub struct Toasts {
+    shown: RwSignal<Vec<Toast>>,
+    next: StoredValue<u64>,
+}
+
+impl Toasts {
+    pub fn provide() -> Self {
+        let toasts = Toasts {
+            shown: RwSignal::new(Vec::new()),
+            next: StoredValue::new(1),
+        };
+        provide_context(toasts);
+        toasts
+    }
+
+    pub fn success(&self, message: impl Into<String>) {
+        self.show(ToastKind::Success, message.into());
+    }
+
+    pub fn warning(&self, message: impl Into<String>) {
+        self.show(ToastKind::Warning, message.into());
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        self.show(ToastKind::Error, message.into());
+    }
+
+    // Put up a toast that goes away by itself
+    fn show(&self, kind: ToastKind, message: String) {
+        let id = self.next.get_value();
+        self.next.set_value(id + 1);
+        self.shown.update(|shown| shown.push(Toast { id, kind, message }));
+
+        #[cfg(feature = "hydrate")]
+        {
+            let toasts = *self;
+            let duration = match kind {
+                ToastKind::Error => ERROR_TOAST_DURATION,
+                _ => TOAST_DURATION,
+            };
+            set_timeout(move || toasts.dismiss(id), duration);
+        }
+    }
+
+    pub fn dismiss(&self, id: u64) {
+        self.shown.try_update(|shown| shown.retain(|t| t.id != id));
+    }
+}
+
+pub fn use_toasts() -> Toasts {
+    expect_context::<Toasts>()
+}
+
+/// Shows the toasts in a corner of the page, newest at the bottom.
+#[component]
+pub fn Toaster() -> impl IntoView {
+    let toasts = use_toasts();
+
+    view! {
+        <div class="toaster" role="status" aria-live="polite">
+            <For each=move || toasts.shown.get() key=|toast| toast.id let:toast>
+                <div class=toast.kind.class()>
+                    <span>{toast.message}</span>
+                    <button class="link" aria-label="Dismiss" on:click=move |_| toasts.dismiss(toast.id)>
+                        "×"
+                    </button>
+                </div>
+            </For>
+        </div>
+    }
+}
@@ -0,0 +1,65 @@
+use crate::auth::use_user_session;
+use crate::errors::EditorError;
+use crate::records::{list_trash, restore_record};
+use crate::tasks::Tasks;
+use crate::timestamp::format_timestamp;
+use leptos::prelude::*;
+
+/// Lists soft-deleted records and lets the user restore them.
+#[component]
+pub fn Trash() -> impl IntoView {
+    let source = RwSignal::new(());
+    let records = Resource::new(move || source.get(), |_| list_trash());
+    let error = RwSignal::new(None::<String>);
+    let session = use_user_session();
+    let tasks = Tasks::new();
+
+    let on_restore = move |id: i64| {
+        tasks.spawn(async move {
+            let csrf_token = session.csrf_token.get_untracked().flatten().unwrap_or_default();
+            match restore_record(id, csrf_token).await {
+                Err(ServerFnError::WrappedServerError(e @ EditorError::TooManyRequests { .. })) => {
+                    error.set(Some(e.to_string()));
+                }
+                _ => {
+                    // Refresh either way: a failed restore means someone else already did it
+                    error.set(None);
+                    source.set(());
+                }
+            }
+        });
+    };
+
+    view! {
+        <div class="field-editor">
+            <h1>"Trash"</h1>
+
+            {move || error.get().map(|e| view! { <div class="error-message">{e}</div> })}
+
+            <Suspense fallback=move || view! { <div>"Loading..."</div> }>
+                {move || {
+                    records.get().map(|result| match result {
+                        Err(e) => view! { <div class="error">"Error loading trash: " {e.to_string()}</div> }.into_any(),
+                        Ok(records) if records.is_empty() => view! { <p>"The trash is empty."</p> }.into_any(),
+                        Ok(records) => view! {
+                            <ul class="record-list">
+                                {records.into_iter().map(|record| {
+                                    let id = record.id;
+                                    view! {
+                                        <li>
+                                            <span class="record-title">{record.title}</span>
+                                            <span class="record-meta">
+                                                "Deleted " {record.deleted_at.map(format_timestamp)}
+                                            </span>
+                                            <button on:click=move |_| on_restore(id)>"Restore"</button>
+                                        </li>
+                                    }
+                                }).collect_view()}
+                            </ul>
+                        }.into_any(),
+                    })
+                }}
+            </Suspense>
+        </div>
+    }
+}
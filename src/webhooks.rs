@@ -0,0 +1,212 @@
+use crate::db::{DbManager, FieldValues};
+use serde::Serialize;
+use sqlx::{FromRow, Sqlite};
+use std::time::Duration;
+
+// Webhooks tell external systems about saved changes. Saves write one delivery
+// per active webhook into `webhook_deliveries` in the same transaction as the
+// change, so a change is never announced without having happened or lost if the
+// server stops; the delivery job then POSTs them and retries with backoff.
+
+// Give up on a delivery after this many failed attempts
+pub const MAX_DELIVERY_ATTEMPTS: i64 = 8;
+// Wait before the first retry; doubles with every further failure
+const RETRY_BASE_MILLIS: i64 = 10_000;
+const RETRY_MAX_MILLIS: i64 = 60 * 60 * 1000;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The JSON body POSTed to every webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub event: &'static str,
+    pub record_id: i64,
+    // The record's version after the change
+    pub version: i64,
+    pub user: String,
+    pub old_values: FieldValues,
+    pub new_values: FieldValues,
+    pub occurred_at: i64,
+}
+
+impl WebhookPayload {
+    pub fn created(record_id: i64, version: i64, user: &str, values: FieldValues, occurred_at: i64) -> Self {
+        WebhookPayload {
+            event: "fields.created",
+            record_id,
+            version,
+            user: user.to_string(),
+            old_values: FieldValues::default(),
+            new_values: values,
+            occurred_at,
+        }
+    }
+
+    pub fn updated(
+        record_id: i64,
+        version: i64,
+        user: &str,
+        old_values: FieldValues,
+        new_values: FieldValues,
+        occurred_at: i64,
+    ) -> Self {
+        WebhookPayload {
+            event: "fields.updated",
+            record_id,
+            version,
+            user: user.to_string(),
+            old_values,
+            new_values,
+            occurred_at,
+        }
+    }
+}
+
+// A delivery waiting to be sent, with where to send it
+#[derive(Debug, Clone, FromRow)]
+pub struct PendingDelivery {
+    pub id: i64,
+    pub url: String,
+    pub payload: String,
+    pub attempts: i64,
+}
+
+// Queue a change for every active webhook. Called inside the transaction that made the change.
+pub(crate) async fn enqueue_webhook_deliveries<'e, E>(
+    executor: E,
+    payload: &WebhookPayload,
+    now: i64,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let json = serde_json::to_string(payload).expect("WebhookPayload serializes");
+    sqlx::query(
+        r#"
+        INSERT INTO webhook_deliveries (webhook_id, record_id, payload, status, attempts, next_attempt_at, created_at)
+        SELECT id, ?, ?, 'pending', 0, ?, ? FROM webhooks WHERE active = 1
+        "#,
+    )
+    .bind(payload.record_id)
+    .bind(json)
+    .bind(now)
+    .bind(now)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// How long to wait after the given number of failed attempts
+fn retry_delay_millis(attempts: i64) -> i64 {
+    let doublings = (attempts - 1).clamp(0, 20) as u32;
+    (RETRY_BASE_MILLIS << doublings).min(RETRY_MAX_MILLIS)
+}
+
+impl DbManager {
+    // Make exactly the given URLs the active webhooks
+    pub async fn configure_webhooks(&self, urls: &[String]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool().begin().await?;
+        sqlx::query("UPDATE webhooks SET active = 0")
+            .execute(&mut *tx)
+            .await?;
+        for url in urls {
+            sqlx::query(
+                r#"
+                INSERT INTO webhooks (url, active, created_at) VALUES (?, 1, ?)
+                ON CONFLICT(url) DO UPDATE SET active = 1
+                "#,
+            )
+            .bind(url)
+            .bind(self.now())
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    // Deliveries whose next attempt is due, oldest first. Those of webhooks
+    // that were deactivated stay pending until the URL is configured again.
+    pub async fn due_webhook_deliveries(&self, limit: i64) -> Result<Vec<PendingDelivery>, sqlx::Error> {
+        sqlx::query_as::<_, PendingDelivery>(
+            r#"
+            SELECT d.id, w.url, d.payload, d.attempts
+            FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+            WHERE d.status = 'pending' AND d.next_attempt_at <= ? AND w.active = 1
+            ORDER BY d.id
+            LIMIT ?
+            "#,
+        )
+        .bind(self.now())
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await
+    }
+
+    // Record the outcome of one attempt: delivered, retry later, or give up
+    pub async fn record_webhook_attempt(
+        &self,
+        delivery: &PendingDelivery,
+        status_code: Option<u16>,
+        error: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let now = self.now();
+        let attempts = delivery.attempts + 1;
+        let status = match (error, attempts) {
+            (None, _) => "delivered",
+            (Some(_), n) if n >= MAX_DELIVERY_ATTEMPTS => "failed",
+            (Some(_), _) => "pending",
+        };
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = ?, attempts = ?, next_attempt_at = ?, last_status_code = ?, last_error = ?,
+                delivered_at = CASE WHEN ? = 'delivered' THEN ? ELSE delivered_at END
+            WHERE id = ?
+            "#,
+        )
+        .bind(status)
+        .bind(attempts)
+        .bind(now + retry_delay_millis(attempts))
+        .bind(status_code)
+        .bind(error)
+        .bind(status)
+        .bind(now)
+        .bind(delivery.id)
+        .execute(self.pool())
+        .await?;
+        Ok(())
+    }
+}
+
+// POST every due delivery once. Returns how many were attempted.
+pub async fn deliver_due_webhooks(db: &DbManager, client: &reqwest::Client, limit: i64) -> Result<usize, sqlx::Error> {
+    let due = db.due_webhook_deliveries(limit).await?;
+    for delivery in &due {
+        let response = client
+            .post(&delivery.url)
+            .timeout(DELIVERY_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Delivery", delivery.id.to_string())
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+        let (status_code, error) = match response {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("Endpoint answered {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        if let Some(error) = &error {
+            tracing::warn!(
+                delivery = delivery.id,
+                url = %delivery.url,
+                attempt = delivery.attempts + 1,
+                error = %error,
+                "Webhook delivery failed"
+            );
+        }
+        db.record_webhook_attempt(delivery, status_code, error.as_deref()).await?;
+    }
+    Ok(due.len())
+}